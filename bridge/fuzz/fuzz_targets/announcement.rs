@@ -0,0 +1,22 @@
+//! Discovery topic payloads, parsed and verified as the bridge polls them.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use matrix_mycelium_bridge::gossip;
+use matrix_mycelium_bridge::types::{KeyRevocation, ServerAnnouncement, ServerInfo};
+use matrix_mycelium_bridge::MatrixMyceliumBridge;
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(message) = serde_json::from_slice::<serde_json::Value>(data) else {
+        return;
+    };
+    if let Ok(revocation) = serde_json::from_value::<KeyRevocation>(message.clone()) {
+        MatrixMyceliumBridge::verify_key_revocation(&revocation);
+    }
+    if let Ok(announcement) = serde_json::from_value::<ServerAnnouncement>(message) {
+        MatrixMyceliumBridge::verify_server_announcement(&announcement);
+        gossip::parse_timestamp(&announcement.timestamp);
+        let _ = ServerInfo::from(announcement);
+    }
+});
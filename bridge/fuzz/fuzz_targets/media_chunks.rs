@@ -0,0 +1,44 @@
+//! Sequences of media chunks fed to the assembler for one transfer. The
+//! first byte decides whether chunk hashes are fixed up first, so that
+//! inputs get past the per-chunk integrity check to reassembly.
+
+#![no_main]
+
+use std::sync::LazyLock;
+
+use base64::Engine;
+use libfuzzer_sys::fuzz_target;
+use matrix_mycelium_bridge::media::{self, MediaAssembler, MediaChunk};
+
+const MAX_MEDIA_BYTES: usize = 64 * 1024;
+
+static RUNTIME: LazyLock<tokio::runtime::Runtime> =
+    LazyLock::new(|| tokio::runtime::Builder::new_current_thread().build().unwrap());
+
+fuzz_target!(|data: &[u8]| {
+    let Some((&repair, data)) = data.split_first() else {
+        return;
+    };
+    let Ok(mut chunks) = serde_json::from_slice::<Vec<MediaChunk>>(data) else {
+        return;
+    };
+    if repair & 1 == 1 {
+        let engine = base64::engine::general_purpose::STANDARD;
+        for chunk in &mut chunks {
+            if let Ok(bytes) = engine.decode(&chunk.data) {
+                chunk.sha256 = media::sha256_hex(&bytes);
+            }
+        }
+    }
+
+    RUNTIME.block_on(async {
+        let assembler = MediaAssembler::new(MAX_MEDIA_BYTES);
+        let mut done = assembler.register("request", "origin.test").await;
+        for chunk in chunks {
+            assembler.add_chunk("origin.test", chunk).await;
+        }
+        if let Ok(Ok(file)) = done.try_recv() {
+            assert!(file.data.len() <= MAX_MEDIA_BYTES);
+        }
+    });
+});
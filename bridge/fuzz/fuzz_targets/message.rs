@@ -0,0 +1,39 @@
+//! Federation message envelopes, taken as far through the inbound path as
+//! they get without a running bridge: expiry, version, signature,
+//! decompression, upgrade and payload parsing.
+
+#![no_main]
+
+use std::sync::LazyLock;
+
+use ed25519_dalek::SigningKey;
+use libfuzzer_sys::fuzz_target;
+use matrix_mycelium_bridge::compression::{self, ZSTD_ENCODING};
+use matrix_mycelium_bridge::protocol::{self, ProtocolVersion};
+use matrix_mycelium_bridge::types::MyceliumMessage;
+use matrix_mycelium_bridge::{signer, verify_signature};
+
+static PUBLIC_KEY: LazyLock<String> =
+    LazyLock::new(|| signer::encode_public_key(&SigningKey::from_bytes(&[1; 32]).verifying_key()));
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(mut message) = serde_json::from_slice::<MyceliumMessage>(data) else {
+        return;
+    };
+    message.is_expired();
+    if let Ok(payload) = message.signing_payload() {
+        verify_signature(&PUBLIC_KEY, &payload, &message.signature);
+    }
+
+    let Ok(version) = ProtocolVersion::for_inbound(&message.version) else {
+        return;
+    };
+    if message.content_encoding.as_deref() == Some(ZSTD_ENCODING) {
+        let Ok(payload) = compression::decompress_payload(&message.payload) else {
+            return;
+        };
+        message.payload = payload;
+    }
+    protocol::upgrade(&mut message, version);
+    let _ = message.payload::<serde_json::Value>();
+});
@@ -0,0 +1,27 @@
+//! Signature verification with arbitrary keys, messages and signatures,
+//! which must never panic, and never accept a signature it didn't make.
+
+#![no_main]
+
+use std::sync::LazyLock;
+
+use base64::Engine;
+use ed25519_dalek::{Signer, SigningKey};
+use libfuzzer_sys::fuzz_target;
+use matrix_mycelium_bridge::{signer, verify_signature};
+
+static SIGNING_KEY: LazyLock<SigningKey> = LazyLock::new(|| SigningKey::from_bytes(&[1; 32]));
+static PUBLIC_KEY: LazyLock<String> =
+    LazyLock::new(|| signer::encode_public_key(&SIGNING_KEY.verifying_key()));
+
+fuzz_target!(|input: (&str, &str, &str)| {
+    let (public_key, message, signature) = input;
+    verify_signature(public_key, message, signature);
+
+    let engine = base64::engine::general_purpose::STANDARD;
+    let genuine = engine.encode(SIGNING_KEY.sign(message.as_bytes()).to_bytes());
+    assert!(verify_signature(&PUBLIC_KEY, message, &genuine));
+    if signature != genuine {
+        assert!(!verify_signature(&PUBLIC_KEY, message, signature));
+    }
+});
@@ -0,0 +1,106 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::{BTreeMap, VecDeque};
+use tokio::sync::Mutex;
+
+use crate::federation_error::FederationError;
+use crate::types::MyceliumMessage;
+
+/// Verification failures by what failed to verify, and by claimed sender.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct VerificationFailures {
+    pub by_kind: BTreeMap<&'static str, u64>,
+    pub by_server: BTreeMap<String, u64>,
+}
+
+/// A message as it was sent, kept so an operator can resend it.
+#[derive(Debug, Clone)]
+pub struct SentMessage {
+    pub topic: String,
+    pub message: MyceliumMessage,
+}
+
+/// A `federation_error` a peer sent back for one of our messages.
+#[derive(Debug, Clone, Serialize)]
+pub struct Rejection {
+    pub server_name: String,
+    pub received_at: DateTime<Utc>,
+    #[serde(flatten)]
+    pub error: FederationError,
+}
+
+/// Bookkeeping behind the admin API.
+pub struct AdminStats {
+    verification_failures: Mutex<VerificationFailures>,
+    recent_sent: Mutex<VecDeque<SentMessage>>,
+    recent_rejections: Mutex<VecDeque<Rejection>>,
+    max_recent: usize,
+}
+
+impl AdminStats {
+    pub fn new(max_recent: usize) -> Self {
+        Self {
+            verification_failures: Mutex::new(VerificationFailures::default()),
+            recent_sent: Mutex::new(VecDeque::new()),
+            recent_rejections: Mutex::new(VecDeque::new()),
+            max_recent,
+        }
+    }
+
+    pub async fn record_verification_failure(&self, kind: &'static str, server_name: &str) {
+        let mut failures = self.verification_failures.lock().await;
+        *failures.by_kind.entry(kind).or_default() += 1;
+        *failures.by_server.entry(server_name.to_string()).or_default() += 1;
+    }
+
+    pub async fn verification_failures(&self) -> VerificationFailures {
+        self.verification_failures.lock().await.clone()
+    }
+
+    /// Remember a sent message, forgetting the oldest beyond the limit.
+    pub async fn record_sent(&self, topic: &str, message: &MyceliumMessage) {
+        if self.max_recent == 0 {
+            return;
+        }
+        let mut recent = self.recent_sent.lock().await;
+        if recent.len() >= self.max_recent {
+            recent.pop_front();
+        }
+        recent.push_back(SentMessage {
+            topic: topic.to_string(),
+            message: message.clone(),
+        });
+    }
+
+    /// The most recent message sent with `correlation_id`.
+    pub async fn find_sent(&self, correlation_id: &str) -> Option<SentMessage> {
+        self.recent_sent
+            .lock()
+            .await
+            .iter()
+            .rev()
+            .find(|sent| sent.message.correlation_id.as_deref() == Some(correlation_id))
+            .cloned()
+    }
+
+    /// Remember a rejection, forgetting the oldest beyond the limit.
+    pub async fn record_rejection(&self, server_name: &str, error: FederationError) {
+        if self.max_recent == 0 {
+            return;
+        }
+        let mut recent = self.recent_rejections.lock().await;
+        if recent.len() >= self.max_recent {
+            recent.pop_front();
+        }
+        recent.push_back(Rejection {
+            server_name: server_name.to_string(),
+            received_at: Utc::now(),
+            error,
+        });
+    }
+
+    /// Recent rejections, newest first.
+    pub async fn rejections(&self) -> Vec<Rejection> {
+        self.recent_rejections.lock().await.iter().rev().cloned().collect()
+    }
+}
@@ -0,0 +1,445 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::path::Path;
+use tokio::sync::{Mutex, RwLock};
+use tracing::{info, warn};
+
+use crate::config::AppserviceConfig;
+use crate::http_client::HttpClient;
+
+/// Transaction IDs remembered to drop retried appservice transactions.
+const SEEN_TRANSACTIONS: usize = 1000;
+
+/// Application service registration, as loaded by the homeserver.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Registration {
+    pub id: String,
+    pub url: String,
+    pub as_token: String,
+    pub hs_token: String,
+    pub sender_localpart: String,
+    pub rate_limited: bool,
+    pub namespaces: Namespaces,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Namespaces {
+    pub users: Vec<Namespace>,
+    pub aliases: Vec<Namespace>,
+    pub rooms: Vec<Namespace>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Namespace {
+    pub exclusive: bool,
+    pub regex: String,
+}
+
+fn random_token() -> String {
+    use rand::Rng;
+    rand::thread_rng()
+        .sample_iter(&rand::distributions::Alphanumeric)
+        .take(64)
+        .map(char::from)
+        .collect()
+}
+
+/// Load the registration file, or generate one with fresh tokens so it can
+/// be added to the homeserver's `app_service_config_files`.
+pub fn load_or_create_registration(config: &AppserviceConfig, server_name: &str) -> Result<Registration> {
+    let path = Path::new(&config.registration_path);
+    if path.exists() {
+        let registration: Registration = serde_yaml::from_str(&std::fs::read_to_string(path)?)?;
+        return Ok(registration);
+    }
+
+    let escaped_server = regex_escape(server_name);
+    let registration = Registration {
+        id: config.id.clone(),
+        url: config.url.clone(),
+        as_token: random_token(),
+        hs_token: random_token(),
+        sender_localpart: config.sender_localpart.clone(),
+        rate_limited: false,
+        namespaces: Namespaces {
+            users: vec![Namespace {
+                exclusive: true,
+                regex: format!("@{}.*:{}", regex_escape(&config.user_prefix), escaped_server),
+            }],
+            aliases: vec![Namespace {
+                exclusive: true,
+                regex: format!("#{}.*:{}", regex_escape(&config.user_prefix), escaped_server),
+            }],
+            rooms: Vec::new(),
+        },
+    };
+
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(path, serde_yaml::to_string(&registration)?)?;
+    info!("Wrote appservice registration to {}", path.display());
+
+    Ok(registration)
+}
+
+fn regex_escape(value: &str) -> String {
+    value
+        .chars()
+        .flat_map(|c| {
+            let escape = ".*+?()[]{}|^$\\".contains(c);
+            escape.then_some('\\').into_iter().chain(std::iter::once(c))
+        })
+        .collect()
+}
+
+fn server_of(id: &str) -> Option<&str> {
+    id.split_once(':').map(|(_, server)| server)
+}
+
+/// A local room linked to a room on other servers: either a portal created
+/// for a remote room, or one of our own rooms that remote users joined.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct RoomLink {
+    pub remote_room: String,
+    pub servers: HashSet<String>,
+}
+
+/// Homeserver integration through the application service API, for
+/// homeservers that don't accept federation traffic from the bridge.
+///
+/// Remote users are represented by puppet users in the appservice namespace
+/// and remote rooms by portal rooms with an alias in the same namespace.
+pub struct Appservice {
+    registration: Registration,
+    server_name: String,
+    user_prefix: String,
+    homeserver_url: String,
+    http_client: HttpClient,
+    seen_transactions: Mutex<VecDeque<String>>,
+    rooms: RwLock<HashMap<String, RoomLink>>,
+    portals: RwLock<HashMap<String, String>>,
+    joined: Mutex<HashSet<(String, String)>>,
+}
+
+impl Appservice {
+    pub fn new(
+        config: &AppserviceConfig,
+        server_name: &str,
+        homeserver_url: &str,
+        http_client: HttpClient,
+    ) -> Result<Self> {
+        Ok(Self {
+            registration: load_or_create_registration(config, server_name)?,
+            server_name: server_name.to_string(),
+            user_prefix: config.user_prefix.clone(),
+            homeserver_url: homeserver_url.to_string(),
+            http_client,
+            seen_transactions: Mutex::new(VecDeque::new()),
+            rooms: RwLock::new(HashMap::new()),
+            portals: RwLock::new(HashMap::new()),
+            joined: Mutex::new(HashSet::new()),
+        })
+    }
+
+    pub fn check_hs_token(&self, token: &str) -> bool {
+        token == self.registration.hs_token
+    }
+
+    /// Returns `false` for a transaction that was already processed.
+    pub async fn is_new_transaction(&self, txn_id: &str) -> bool {
+        let mut seen = self.seen_transactions.lock().await;
+        if seen.iter().any(|id| id == txn_id) {
+            return false;
+        }
+        if seen.len() >= SEEN_TRANSACTIONS {
+            seen.pop_front();
+        }
+        seen.push_back(txn_id.to_string());
+        true
+    }
+
+    fn bot_user_id(&self) -> String {
+        format!("@{}:{}", self.registration.sender_localpart, self.server_name)
+    }
+
+    /// Local puppet for a remote user, e.g. `@bob:remote.org` becomes
+    /// `@_mycelium_bob=remote.org:local.org`.
+    pub fn puppet_user_id(&self, remote_user: &str) -> String {
+        let remote = remote_user.trim_start_matches('@').replacen(':', "=", 1);
+        format!("@{}{}:{}", self.user_prefix, remote, self.server_name)
+    }
+
+    /// The remote user behind a puppet, or `None` for other users.
+    pub fn remote_user_id(&self, user_id: &str) -> Option<String> {
+        let local = user_id.strip_suffix(&format!(":{}", self.server_name))?;
+        let remote = local.strip_prefix(&format!("@{}", self.user_prefix))?;
+        let (localpart, server) = remote.rsplit_once('=')?;
+        Some(format!("@{}:{}", localpart, server))
+    }
+
+    pub fn is_bridge_user(&self, user_id: &str) -> bool {
+        user_id == self.bot_user_id() || self.remote_user_id(user_id).is_some()
+    }
+
+    pub async fn room_links(&self) -> HashMap<String, RoomLink> {
+        self.rooms.read().await.clone()
+    }
+
+    /// Translate events from an appservice transaction into PDUs for the
+    /// linked remote servers. Returns `(destination, pdu)` pairs.
+    pub async fn outbound_events(&self, events: &[serde_json::Value]) -> Vec<(String, serde_json::Value)> {
+        let mut outbound = Vec::new();
+
+        for event in events {
+            let (Some(room_id), Some(sender)) = (event["room_id"].as_str(), event["sender"].as_str()) else {
+                continue;
+            };
+            // Events we injected ourselves come back in transactions too
+            if self.is_bridge_user(sender) {
+                continue;
+            }
+
+            let mut pdu = event.clone();
+            pdu["origin"] = serde_json::json!(self.server_name);
+
+            // Inviting a puppet links our room to the puppet's server
+            if event["type"] == "m.room.member" {
+                if let Some(remote_user) = event["state_key"].as_str().and_then(|k| self.remote_user_id(k)) {
+                    if let Some(server) = server_of(&remote_user) {
+                        let mut rooms = self.rooms.write().await;
+                        let link = rooms.entry(room_id.to_string()).or_insert_with(|| RoomLink {
+                            remote_room: room_id.to_string(),
+                            servers: HashSet::new(),
+                        });
+                        link.servers.insert(server.to_string());
+                    }
+                    pdu["state_key"] = serde_json::json!(remote_user);
+                }
+            }
+
+            let Some(link) = self.rooms.read().await.get(room_id).cloned() else {
+                continue;
+            };
+            pdu["room_id"] = serde_json::json!(link.remote_room);
+            for server in link.servers {
+                outbound.push((server, pdu.clone()));
+            }
+        }
+
+        outbound
+    }
+
+    /// Inject a PDU received from `origin` into the homeserver as the
+    /// sender's puppet.
+    pub async fn inject(&self, origin: &str, pdu: &serde_json::Value) -> Result<()> {
+        let (Some(room_id), Some(sender), Some(event_type)) =
+            (pdu["room_id"].as_str(), pdu["sender"].as_str(), pdu["type"].as_str())
+        else {
+            return Err(anyhow::anyhow!("PDU is missing room_id, sender or type"));
+        };
+
+        let local_room = self.local_room_for(room_id, origin).await?;
+        let puppet = self.puppet_user_id(sender);
+        self.ensure_registered(&puppet).await?;
+
+        if event_type == "m.room.member" {
+            return self.inject_membership(&local_room, &puppet, pdu).await;
+        }
+
+        self.ensure_joined(&local_room, &puppet).await?;
+        let content = &pdu["content"];
+        if let Some(state_key) = pdu["state_key"].as_str() {
+            let path = format!(
+                "/_matrix/client/v3/rooms/{}/state/{}/{}",
+                encode(&local_room), encode(event_type), encode(state_key)
+            );
+            self.request(reqwest::Method::PUT, &path, Some(&puppet), Some(content)).await?;
+        } else {
+            let txn_id = pdu["event_id"].as_str().unwrap_or_default();
+            let mut path = format!(
+                "/_matrix/client/v3/rooms/{}/send/{}/{}",
+                encode(&local_room), encode(event_type), encode(txn_id)
+            );
+            if let Some(ts) = pdu["origin_server_ts"].as_i64() {
+                path.push_str(&format!("?ts={}", ts));
+            }
+            self.request(reqwest::Method::PUT, &path, Some(&puppet), Some(content)).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn inject_membership(&self, room_id: &str, puppet: &str, pdu: &serde_json::Value) -> Result<()> {
+        let state_key = pdu["state_key"].as_str().unwrap_or_default();
+        let membership = pdu["content"]["membership"].as_str().unwrap_or_default();
+
+        match membership {
+            "join" => self.ensure_joined(room_id, puppet).await,
+            "leave" => {
+                let path = format!("/_matrix/client/v3/rooms/{}/leave", encode(room_id));
+                self.request(reqwest::Method::POST, &path, Some(puppet), Some(&serde_json::json!({})))
+                    .await?;
+                self.joined.lock().await.remove(&(room_id.to_string(), puppet.to_string()));
+                Ok(())
+            }
+            "invite" => {
+                self.ensure_joined(room_id, puppet).await?;
+                let path = format!("/_matrix/client/v3/rooms/{}/invite", encode(room_id));
+                let body = serde_json::json!({ "user_id": state_key });
+                self.request(reqwest::Method::POST, &path, Some(puppet), Some(&body)).await?;
+                Ok(())
+            }
+            other => {
+                warn!("Not injecting {} membership for {} in {}", other, state_key, room_id);
+                Ok(())
+            }
+        }
+    }
+
+    /// The local room for `room_id`: our own room, or a portal for a room
+    /// hosted elsewhere, created on first use.
+    async fn local_room_for(&self, room_id: &str, origin: &str) -> Result<String> {
+        let local_room = if server_of(room_id) == Some(self.server_name.as_str()) {
+            room_id.to_string()
+        } else if let Some(portal) = self.portals.read().await.get(room_id).cloned() {
+            portal
+        } else {
+            let portal = self.create_portal(room_id).await?;
+            self.portals.write().await.insert(room_id.to_string(), portal.clone());
+            portal
+        };
+
+        let mut rooms = self.rooms.write().await;
+        let link = rooms.entry(local_room.clone()).or_insert_with(|| RoomLink {
+            remote_room: room_id.to_string(),
+            servers: HashSet::new(),
+        });
+        link.servers.insert(origin.to_string());
+
+        Ok(local_room)
+    }
+
+    async fn create_portal(&self, remote_room: &str) -> Result<String> {
+        let alias_localpart: String = format!("{}{}", self.user_prefix, remote_room.trim_start_matches('!'))
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() || "_-.=".contains(c) { c } else { '_' })
+            .collect();
+        let alias = format!("#{}:{}", alias_localpart, self.server_name);
+
+        let existing = self
+            .request(
+                reqwest::Method::GET,
+                &format!("/_matrix/client/v3/directory/room/{}", encode(&alias)),
+                None,
+                None,
+            )
+            .await;
+        if let Ok(existing) = existing {
+            if let Some(room_id) = existing["room_id"].as_str() {
+                return Ok(room_id.to_string());
+            }
+        }
+
+        let body = serde_json::json!({
+            "room_alias_name": alias_localpart,
+            "name": remote_room,
+            "preset": "private_chat",
+        });
+        let created = self
+            .request(reqwest::Method::POST, "/_matrix/client/v3/createRoom", None, Some(&body))
+            .await?;
+        let room_id = created["room_id"]
+            .as_str()
+            .ok_or_else(|| anyhow::anyhow!("Homeserver did not return a room ID"))?;
+
+        info!("Created portal {} for {}", room_id, remote_room);
+        Ok(room_id.to_string())
+    }
+
+    async fn ensure_registered(&self, user_id: &str) -> Result<()> {
+        let localpart = user_id
+            .trim_start_matches('@')
+            .split(':')
+            .next()
+            .unwrap_or_default();
+        let body = serde_json::json!({
+            "type": "m.login.application_service",
+            "username": localpart,
+        });
+
+        match self.request(reqwest::Method::POST, "/_matrix/client/v3/register", None, Some(&body)).await {
+            Ok(_) => Ok(()),
+            Err(e) if e.to_string().contains("M_USER_IN_USE") => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn ensure_joined(&self, room_id: &str, user_id: &str) -> Result<()> {
+        let key = (room_id.to_string(), user_id.to_string());
+        if self.joined.lock().await.contains(&key) {
+            return Ok(());
+        }
+
+        // Portals are private, so the bot invites puppets before they join
+        let invite = format!("/_matrix/client/v3/rooms/{}/invite", encode(room_id));
+        let body = serde_json::json!({ "user_id": user_id });
+        if let Err(e) = self.request(reqwest::Method::POST, &invite, None, Some(&body)).await {
+            warn!("Failed to invite {} to {}: {}", user_id, room_id, e);
+        }
+
+        let join = format!("/_matrix/client/v3/rooms/{}/join", encode(room_id));
+        self.request(reqwest::Method::POST, &join, Some(user_id), Some(&serde_json::json!({})))
+            .await?;
+        self.joined.lock().await.insert(key);
+
+        Ok(())
+    }
+
+    /// Call the client-server API with the appservice token, optionally
+    /// acting as `user_id`.
+    async fn request(
+        &self,
+        method: reqwest::Method,
+        path: &str,
+        user_id: Option<&str>,
+        body: Option<&serde_json::Value>,
+    ) -> Result<serde_json::Value> {
+        let mut request = self
+            .http_client
+            .request(method, format!("{}{}", self.homeserver_url, path))
+            .bearer_auth(&self.registration.as_token);
+        if let Some(user_id) = user_id {
+            request = request.query(&[("user_id", user_id)]);
+        }
+        if let Some(body) = body {
+            request = request.json(body);
+        }
+
+        let response = self.http_client.send(request).await?;
+        let status = response.status();
+        let body: serde_json::Value = response.json().await.unwrap_or_default();
+        if !status.is_success() {
+            return Err(anyhow::anyhow!(
+                "Homeserver returned {} for {}: {}",
+                status,
+                path,
+                body["errcode"].as_str().unwrap_or("unknown error")
+            ));
+        }
+
+        Ok(body)
+    }
+}
+
+/// Percent-encode a path segment.
+pub fn encode(segment: &str) -> String {
+    segment
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (byte as char).to_string(),
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
@@ -0,0 +1,236 @@
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+use tracing::{error, info};
+
+use crate::config::ArchiveConfig;
+use crate::types::MyceliumMessage;
+
+const BYTES_PER_MB: u64 = 1024 * 1024;
+
+/// Segment files are named `archive-<first timestamp in ms>.jsonl`.
+const SEGMENT_PREFIX: &str = "archive-";
+const SEGMENT_SUFFIX: &str = ".jsonl";
+
+/// A new segment is started at least this often, so old messages can
+/// expire even when little is archived.
+const SEGMENT_SPAN: chrono::Duration = chrono::Duration::hours(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ArchiveDirection {
+    Inbound,
+    Outbound,
+}
+
+/// An envelope as sent or received.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ArchivedMessage {
+    pub recorded_at: DateTime<Utc>,
+    pub direction: ArchiveDirection,
+    /// The other end: the source of inbound messages, the destination of
+    /// outbound ones.
+    pub server: String,
+    /// Size of the payload in bytes, kept when the payload is redacted.
+    pub payload_bytes: usize,
+    pub message: MyceliumMessage,
+}
+
+/// Filters of `GET /admin/archive`, all optional.
+#[derive(Debug, Default, Deserialize)]
+pub struct ArchiveQuery {
+    pub server: Option<String>,
+    pub message_type: Option<String>,
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+    pub limit: Option<usize>,
+}
+
+struct Segment {
+    path: PathBuf,
+    started: DateTime<Utc>,
+    file: fs::File,
+    bytes: u64,
+}
+
+/// Every envelope sent and received, appended to JSON lines files in a
+/// directory. A new file is started every `segment_mb` or hour, and the
+/// oldest files are deleted once over `max_mb` in total or `max_age_hours`
+/// old.
+pub struct MessageArchive {
+    directory: PathBuf,
+    redact_payloads: bool,
+    segment_bytes: u64,
+    max_bytes: u64,
+    max_age: Duration,
+    max_results: usize,
+    current: Mutex<Option<Segment>>,
+}
+
+impl MessageArchive {
+    pub async fn open(config: &ArchiveConfig) -> Result<Self> {
+        let directory = PathBuf::from(&config.path);
+        fs::create_dir_all(&directory).await?;
+        info!("Archiving messages in {}", directory.display());
+        Ok(Self {
+            directory,
+            redact_payloads: config.redact_payloads,
+            segment_bytes: config.segment_mb * BYTES_PER_MB,
+            max_bytes: config.max_mb * BYTES_PER_MB,
+            max_age: Duration::from_secs(config.max_age_hours * 60 * 60),
+            max_results: config.max_results,
+            current: Mutex::new(None),
+        })
+    }
+
+    /// Append `message`. Failures are logged, never stopping the message.
+    pub async fn record(&self, direction: ArchiveDirection, message: &MyceliumMessage) {
+        if let Err(e) = self.append(direction, message).await {
+            error!("Failed to archive a {} message: {}", message.message_type, e);
+        }
+    }
+
+    async fn append(&self, direction: ArchiveDirection, message: &MyceliumMessage) -> Result<()> {
+        let server = match direction {
+            ArchiveDirection::Inbound => message.source_server.clone(),
+            ArchiveDirection::Outbound => message.destination_server.clone(),
+        };
+        let mut message = message.clone();
+        let payload_bytes = message.payload.get().len();
+        if self.redact_payloads {
+            message.payload = serde_json::value::to_raw_value(&serde_json::Value::Null)?;
+        }
+        let recorded_at = Utc::now();
+        let mut line = serde_json::to_vec(&ArchivedMessage {
+            recorded_at,
+            direction,
+            server,
+            payload_bytes,
+            message,
+        })?;
+        line.push(b'\n');
+
+        let mut current = self.current.lock().await;
+        let rolled = current.as_ref().is_none_or(|segment| {
+            segment.bytes + line.len() as u64 > self.segment_bytes
+                || recorded_at - segment.started > SEGMENT_SPAN
+        });
+        if rolled {
+            let path = self.directory.join(format!(
+                "{}{}{}",
+                SEGMENT_PREFIX,
+                recorded_at.timestamp_millis(),
+                SEGMENT_SUFFIX
+            ));
+            let file = fs::OpenOptions::new().create(true).append(true).open(&path).await?;
+            let bytes = file.metadata().await?.len();
+            *current = Some(Segment {
+                path,
+                started: recorded_at,
+                file,
+                bytes,
+            });
+            self.apply_retention(current.as_ref().map(|segment| segment.path.as_path()))
+                .await?;
+        }
+        if let Some(segment) = current.as_mut() {
+            segment.file.write_all(&line).await?;
+            segment.bytes += line.len() as u64;
+        }
+        Ok(())
+    }
+
+    /// Delete the oldest segments beyond the size and age limits, sparing
+    /// the one being written.
+    async fn apply_retention(&self, current: Option<&Path>) -> Result<()> {
+        let segments = self.segments().await?;
+        let mut total: u64 = segments.iter().map(|(_, _, bytes)| bytes).sum();
+        let oldest_kept = Utc::now() - chrono::Duration::from_std(self.max_age)?;
+        for (index, (path, _, bytes)) in segments.iter().enumerate() {
+            if Some(path.as_path()) == current {
+                break;
+            }
+            // A segment ends where the next one starts
+            let ended = segments.get(index + 1).map(|(_, started, _)| *started);
+            let expired = ended.is_some_and(|ended| ended < oldest_kept);
+            if total <= self.max_bytes && !expired {
+                break;
+            }
+            fs::remove_file(path).await?;
+            total -= bytes;
+        }
+        Ok(())
+    }
+
+    /// Segment files with their start time and size, oldest first.
+    async fn segments(&self) -> Result<Vec<(PathBuf, DateTime<Utc>, u64)>> {
+        let mut segments = Vec::new();
+        let mut entries = fs::read_dir(&self.directory).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let started = name
+                .strip_prefix(SEGMENT_PREFIX)
+                .and_then(|name| name.strip_suffix(SEGMENT_SUFFIX))
+                .and_then(|millis| millis.parse().ok())
+                .and_then(DateTime::from_timestamp_millis);
+            if let Some(started) = started {
+                segments.push((entry.path(), started, entry.metadata().await?.len()));
+            }
+        }
+        segments.sort_by_key(|(_, started, _)| *started);
+        Ok(segments)
+    }
+
+    /// Archived messages matching `query`, newest first.
+    pub async fn query(&self, query: &ArchiveQuery) -> Result<Vec<ArchivedMessage>> {
+        let limit = query.limit.unwrap_or(self.max_results).min(self.max_results);
+        // Keep appends out while reading the segment being written
+        let _current = self.current.lock().await;
+        let segments = self.segments().await?;
+        let mut found = Vec::new();
+        for (index, (path, started, _)) in segments.iter().enumerate().rev() {
+            if query.until.is_some_and(|until| *started > until) {
+                continue;
+            }
+            let ended = segments.get(index + 1).map(|(_, started, _)| *started);
+            if query.since.is_some_and(|since| ended.is_some_and(|ended| ended < since)) {
+                break;
+            }
+            let contents = match fs::read_to_string(path).await {
+                Ok(contents) => contents,
+                // Deleted by retention since listed
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            };
+            for line in contents.lines().rev() {
+                let Ok(archived) = serde_json::from_str::<ArchivedMessage>(line) else {
+                    continue;
+                };
+                if query.matches(&archived) {
+                    found.push(archived);
+                    if found.len() >= limit {
+                        return Ok(found);
+                    }
+                }
+            }
+        }
+        Ok(found)
+    }
+}
+
+impl ArchiveQuery {
+    fn matches(&self, archived: &ArchivedMessage) -> bool {
+        self.server.as_ref().is_none_or(|server| *server == archived.server)
+            && self
+                .message_type
+                .as_ref()
+                .is_none_or(|message_type| *message_type == archived.message.message_type)
+            && self.since.is_none_or(|since| archived.recorded_at >= since)
+            && self.until.is_none_or(|until| archived.recorded_at <= until)
+    }
+}
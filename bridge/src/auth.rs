@@ -0,0 +1,47 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// What a bearer token may be used for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TokenScope {
+    /// The `/federation` endpoints used to send events and search peers.
+    Send,
+    /// The `/admin` endpoints.
+    Admin,
+    /// `/metrics` and `/stats`, which only read.
+    Metrics,
+}
+
+/// A shared secret accepted as a bearer token.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApiToken {
+    /// Shown in logs instead of the token itself.
+    pub name: String,
+    pub token: String,
+    pub scopes: Vec<TokenScope>,
+}
+
+/// Compare secrets in time independent of where they differ. Hashing
+/// first also hides their lengths.
+pub fn tokens_match(given: &str, expected: &str) -> bool {
+    let given = Sha256::digest(given.as_bytes());
+    let expected = Sha256::digest(expected.as_bytes());
+    given
+        .iter()
+        .zip(expected.iter())
+        .fold(0u8, |difference, (a, b)| difference | (a ^ b))
+        == 0
+}
+
+/// The configured token matching `given`, if any.
+pub fn authenticate<'a>(tokens: &'a [ApiToken], given: &str) -> Option<&'a ApiToken> {
+    // Compare against every token, so timing doesn't reveal which matched
+    let mut found = None;
+    for token in tokens {
+        if tokens_match(given, &token.token) && found.is_none() {
+            found = Some(token);
+        }
+    }
+    found
+}
@@ -0,0 +1,269 @@
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt::Write;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+use tracing::{info, warn};
+
+use crate::config::BandwidthConfig;
+use crate::peer_metrics::escape_label;
+
+/// Window the quotas apply over.
+const QUOTA_WINDOW: Duration = Duration::from_secs(60 * 60);
+
+/// Usage is counted in buckets of this length.
+const BUCKET: Duration = Duration::from_secs(60);
+
+const BYTES_PER_MB: u64 = 1024 * 1024;
+
+/// Direction of traffic with a peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+impl Direction {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Direction::Sent => "outbound",
+            Direction::Received => "inbound",
+        }
+    }
+}
+
+/// Bytes counted per minute over the last hour, oldest first.
+#[derive(Default)]
+struct Usage {
+    buckets: VecDeque<(Instant, u64)>,
+    over_quota: bool,
+    exceeded: u64,
+}
+
+impl Usage {
+    fn prune(&mut self, now: Instant) {
+        while self.buckets.front().is_some_and(|(start, _)| now.duration_since(*start) >= QUOTA_WINDOW) {
+            self.buckets.pop_front();
+        }
+    }
+
+    fn add(&mut self, now: Instant, bytes: u64) {
+        self.prune(now);
+        match self.buckets.back_mut() {
+            Some((start, total)) if now.duration_since(*start) < BUCKET => *total += bytes,
+            _ => self.buckets.push_back((now, bytes)),
+        }
+    }
+
+    fn total(&self) -> u64 {
+        self.buckets.iter().map(|(_, bytes)| bytes).sum()
+    }
+
+    /// How long until less than `quota` bytes were used in the window.
+    fn under_quota_in(&self, now: Instant, quota: u64) -> Duration {
+        let mut total = self.total();
+        for (start, bytes) in &self.buckets {
+            if total < quota {
+                break;
+            }
+            total -= bytes;
+            if total < quota {
+                return (*start + QUOTA_WINDOW).saturating_duration_since(now);
+            }
+        }
+        Duration::ZERO
+    }
+}
+
+#[derive(Default)]
+struct Peer {
+    sent: Usage,
+    received: Usage,
+}
+
+impl Peer {
+    fn usage(&mut self, direction: Direction) -> &mut Usage {
+        match direction {
+            Direction::Sent => &mut self.sent,
+            Direction::Received => &mut self.received,
+        }
+    }
+}
+
+/// A peer's traffic over the last hour, against its quotas.
+#[derive(Debug, Clone, Serialize)]
+pub struct PeerBandwidth {
+    pub sent_last_hour: u64,
+    pub received_last_hour: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sent_quota: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub received_quota: Option<u64>,
+    pub over_quota: bool,
+    /// Messages throttled or dropped for going over quota.
+    pub quota_exceeded: u64,
+}
+
+/// Bytes exchanged with each peer per hour, against `[bandwidth]` quotas.
+pub struct BandwidthTracker {
+    config: BandwidthConfig,
+    peers: Mutex<HashMap<String, Peer>>,
+}
+
+impl BandwidthTracker {
+    pub fn new(config: BandwidthConfig) -> Self {
+        Self {
+            config,
+            peers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The hourly quota in bytes for `server_name`, if it has one.
+    fn quota(&self, server_name: &str, direction: Direction) -> Option<u64> {
+        let quota = self.config.servers.iter().find(|quota| quota.server_name == server_name);
+        let (sent, received) = match quota {
+            Some(quota) => (quota.sent_mb_per_hour, quota.received_mb_per_hour),
+            None => (self.config.sent_mb_per_hour, self.config.received_mb_per_hour),
+        };
+        let mb_per_hour = match direction {
+            Direction::Sent => sent,
+            Direction::Received => received,
+        };
+        (mb_per_hour > 0).then_some(mb_per_hour * BYTES_PER_MB)
+    }
+
+    /// Count `bytes` exchanged with `server_name`.
+    pub async fn record(&self, server_name: &str, direction: Direction, bytes: usize) {
+        let mut peers = self.peers.lock().await;
+        let usage = peers.entry(server_name.to_string()).or_default().usage(direction);
+        usage.add(Instant::now(), bytes as u64);
+    }
+
+    /// Whether traffic with `server_name` is within its quota. Returns how
+    /// long until it is back under quota if not.
+    pub async fn check(&self, server_name: &str, direction: Direction) -> Result<(), Duration> {
+        if !self.config.enabled {
+            return Ok(());
+        }
+        let quota = self.quota(server_name, direction);
+        let now = Instant::now();
+        let mut peers = self.peers.lock().await;
+        let usage = peers.entry(server_name.to_string()).or_default().usage(direction);
+        usage.prune(now);
+        let over = quota.is_some_and(|quota| usage.total() >= quota);
+        if over && !usage.over_quota {
+            warn!("{} is over its {} bandwidth quota for the hour", server_name, direction.as_str());
+        } else if !over && usage.over_quota {
+            info!("{} is back under its {} bandwidth quota", server_name, direction.as_str());
+        }
+        usage.over_quota = over;
+        match quota {
+            Some(quota) if over => {
+                usage.exceeded += 1;
+                Err(usage.under_quota_in(now, quota))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub async fn snapshot(&self) -> BTreeMap<String, PeerBandwidth> {
+        let now = Instant::now();
+        let mut peers = self.peers.lock().await;
+        peers
+            .iter_mut()
+            .map(|(server_name, peer)| {
+                peer.sent.prune(now);
+                peer.received.prune(now);
+                let bandwidth = PeerBandwidth {
+                    sent_last_hour: peer.sent.total(),
+                    received_last_hour: peer.received.total(),
+                    sent_quota: self.quota(server_name, Direction::Sent),
+                    received_quota: self.quota(server_name, Direction::Received),
+                    over_quota: peer.sent.over_quota || peer.received.over_quota,
+                    quota_exceeded: peer.sent.exceeded + peer.received.exceeded,
+                };
+                (server_name.clone(), bandwidth)
+            })
+            .collect()
+    }
+
+    /// Quota counters in the Prometheus text format.
+    pub async fn render_prometheus(&self) -> String {
+        let peers = self.snapshot().await;
+        let mut output = String::new();
+        let name = "matrix_mycelium_bridge_bandwidth_quota_exceeded_total";
+        let _ = writeln!(output, "# HELP {} Messages throttled or dropped over bandwidth quota.", name);
+        let _ = writeln!(output, "# TYPE {} counter", name);
+        for (server_name, bandwidth) in &peers {
+            let server_name = escape_label(server_name);
+            let _ = writeln!(output, "{}{{server=\"{}\"}} {}", name, server_name, bandwidth.quota_exceeded);
+        }
+        let name = "matrix_mycelium_bridge_bandwidth_over_quota";
+        let _ = writeln!(output, "# HELP {} Whether each server is over its bandwidth quota.", name);
+        let _ = writeln!(output, "# TYPE {} gauge", name);
+        for (server_name, bandwidth) in &peers {
+            let server_name = escape_label(server_name);
+            let over_quota = u8::from(bandwidth.over_quota);
+            let _ = writeln!(output, "{}{{server=\"{}\"}} {}", name, server_name, over_quota);
+        }
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::BandwidthQuota;
+
+    fn tracker(received_mb_per_hour: u64, servers: Vec<BandwidthQuota>) -> BandwidthTracker {
+        BandwidthTracker::new(BandwidthConfig {
+            enabled: true,
+            received_mb_per_hour,
+            servers,
+            ..BandwidthConfig::default()
+        })
+    }
+
+    #[tokio::test]
+    async fn traffic_over_quota_waits_out_the_hour() {
+        let tracker = tracker(1, Vec::new());
+        tracker.record("a.test", Direction::Received, BYTES_PER_MB as usize - 1).await;
+        assert_eq!(tracker.check("a.test", Direction::Received).await, Ok(()));
+        tracker.record("a.test", Direction::Received, 1).await;
+        let wait = tracker.check("a.test", Direction::Received).await.unwrap_err();
+        assert!(wait > Duration::ZERO && wait <= QUOTA_WINDOW);
+        // Only the direction and the server over quota are held back
+        assert_eq!(tracker.check("a.test", Direction::Sent).await, Ok(()));
+        assert_eq!(tracker.check("b.test", Direction::Received).await, Ok(()));
+
+        let snapshot = tracker.snapshot().await;
+        assert!(snapshot["a.test"].over_quota);
+        assert_eq!(snapshot["a.test"].received_last_hour, BYTES_PER_MB);
+        assert_eq!(snapshot["a.test"].quota_exceeded, 1);
+    }
+
+    #[tokio::test]
+    async fn servers_can_have_their_own_quota() {
+        let quota = BandwidthQuota {
+            server_name: "a.test".to_string(),
+            sent_mb_per_hour: 0,
+            received_mb_per_hour: 2,
+        };
+        let tracker = tracker(1, vec![quota]);
+        tracker.record("a.test", Direction::Received, BYTES_PER_MB as usize).await;
+        tracker.record("b.test", Direction::Received, BYTES_PER_MB as usize).await;
+        assert_eq!(tracker.check("a.test", Direction::Received).await, Ok(()));
+        assert!(tracker.check("b.test", Direction::Received).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn usage_is_counted_without_enforcing() {
+        let tracker = BandwidthTracker::new(BandwidthConfig {
+            received_mb_per_hour: 1,
+            ..BandwidthConfig::default()
+        });
+        tracker.record("a.test", Direction::Received, 2 * BYTES_PER_MB as usize).await;
+        assert_eq!(tracker.check("a.test", Direction::Received).await, Ok(()));
+        assert_eq!(tracker.snapshot().await["a.test"].received_last_hour, 2 * BYTES_PER_MB);
+    }
+}
@@ -0,0 +1,112 @@
+use serde::Serialize;
+use std::collections::HashMap;
+use tokio::sync::{oneshot, Mutex};
+
+use crate::config::BatchingConfig;
+use crate::delivery::SendOutcome;
+
+/// Message type used for a batch of PDUs sent as a single Mycelium message.
+pub const TRANSACTION_MESSAGE_TYPE: &str = "transaction";
+
+/// An event waiting to be flushed, together with the channel used to report
+/// the outcome of the transaction it ends up in.
+pub struct PendingEvent {
+    pub event_data: serde_json::Value,
+    pub done: oneshot::Sender<Result<SendOutcome, String>>,
+}
+
+/// Gets the outcome of the transaction a pushed event ends up in.
+pub type SendDone = oneshot::Receiver<Result<SendOutcome, String>>;
+
+/// What the caller should do after pushing an event into the batcher.
+pub enum BatchAction {
+    /// The batch reached `max_events` and must be flushed right away.
+    Flush(Vec<PendingEvent>),
+    /// This is the first event for the destination; schedule a delayed flush.
+    ScheduleFlush,
+    /// A flush is already scheduled for this destination.
+    Wait,
+}
+
+/// Coalesces outbound events per destination server into transactions.
+pub struct TransactionBatcher {
+    config: BatchingConfig,
+    pending: Mutex<HashMap<String, Vec<PendingEvent>>>,
+}
+
+impl TransactionBatcher {
+    pub fn new(config: BatchingConfig) -> Self {
+        Self {
+            config,
+            pending: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn max_delay(&self) -> std::time::Duration {
+        std::time::Duration::from_millis(self.config.max_delay_ms)
+    }
+
+    pub async fn push(
+        &self,
+        destination: &str,
+        event_data: serde_json::Value,
+    ) -> (SendDone, BatchAction) {
+        let (done, receiver) = oneshot::channel();
+        let mut pending = self.pending.lock().await;
+        let batch = pending.entry(destination.to_string()).or_default();
+        batch.push(PendingEvent { event_data, done });
+
+        let action = if batch.len() >= self.config.max_events.max(1) {
+            BatchAction::Flush(pending.remove(destination).unwrap_or_default())
+        } else if batch.len() == 1 {
+            BatchAction::ScheduleFlush
+        } else {
+            BatchAction::Wait
+        };
+
+        (receiver, action)
+    }
+
+    /// Number of events waiting per destination.
+    pub async fn queue_depths(&self) -> HashMap<String, usize> {
+        self.pending
+            .lock()
+            .await
+            .iter()
+            .map(|(destination, events)| (destination.clone(), events.len()))
+            .collect()
+    }
+
+    /// Take whatever is pending for `destination`, leaving nothing behind.
+    pub async fn take(&self, destination: &str) -> Vec<PendingEvent> {
+        self.pending
+            .lock()
+            .await
+            .remove(destination)
+            .unwrap_or_default()
+    }
+}
+
+/// Transaction payload carrying a batch of PDUs, borrowed from the pending
+/// events so they are serialized straight into the message.
+#[derive(Serialize)]
+pub struct Transaction<'a> {
+    pub txn_id: String,
+    pub pdus: Vec<&'a serde_json::Value>,
+}
+
+/// Build the transaction payload carrying a batch of PDUs.
+pub fn build_transaction(events: &[PendingEvent]) -> Transaction<'_> {
+    Transaction {
+        txn_id: uuid::Uuid::new_v4().to_string(),
+        pdus: events.iter().map(|e| &e.event_data).collect(),
+    }
+}
+
+/// Unpack the PDUs carried by a transaction payload.
+pub fn unpack_transaction(mut payload: serde_json::Value) -> Vec<serde_json::Value> {
+    match payload.get_mut("pdus").map(serde_json::Value::take) {
+        Some(serde_json::Value::Array(pdus)) => pdus,
+        _ => Vec::new(),
+    }
+}
@@ -0,0 +1,370 @@
+use anyhow::Result;
+use base64::Engine;
+use clap::{Parser, Subcommand};
+use ed25519_dalek::{Signer, SigningKey};
+use matrix_mycelium_bridge::config::MyceliumConfig;
+use matrix_mycelium_bridge::mycelium::{Destination, FederationTransport, MyceliumClient};
+use matrix_mycelium_bridge::protocol::ProtocolVersion;
+use matrix_mycelium_bridge::{edu, signer, FederationEvent, MyceliumMessage};
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+
+/// How often the bridge's memory is sampled with --pid.
+const MEMORY_SAMPLE_INTERVAL: Duration = Duration::from_millis(500);
+
+#[derive(Parser)]
+#[command(name = "bridge-loadgen")]
+#[command(about = "Flood a bridge with synthetic federation traffic and report how it keeps up")]
+struct Cli {
+    /// Requests per second; 0 sends as fast as --concurrency allows
+    #[arg(long, default_value_t = 100, global = true)]
+    rate: u32,
+    /// Seconds to send for
+    #[arg(long, default_value_t = 30, global = true)]
+    duration: u64,
+    /// Bytes of message body in each event
+    #[arg(long, default_value_t = 1024, global = true)]
+    size: usize,
+    /// Requests in flight at once
+    #[arg(long, default_value_t = 32, global = true)]
+    concurrency: usize,
+    /// URL of the bridge
+    #[arg(long, default_value = "http://127.0.0.1:8080", global = true)]
+    url: String,
+    /// Bearer token for the bridge, with the send scope to post events or
+    /// the metrics scope to read /stats
+    #[arg(long, global = true)]
+    token: Option<String>,
+    /// PID of the bridge, to sample its resident memory (Linux only)
+    #[arg(long, global = true)]
+    pid: Option<u32>,
+    /// Fail if the 99th percentile latency is above this many milliseconds
+    #[arg(long, global = true)]
+    max_p99_ms: Option<f64>,
+    /// Fail if the bridge's resident memory grows by more than this many
+    /// MiB; needs --pid
+    #[arg(long, global = true)]
+    max_memory_growth_mb: Option<f64>,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Post events to /federation/send, through the bridge's queue, signing
+    /// and send path
+    Events {
+        /// Server to send the events to, which must be in the directory
+        destination: String,
+        /// Queue each event and return at once (Prefer: respond-async)
+        /// instead of waiting for it to be sent
+        #[arg(long)]
+        queue: bool,
+    },
+    /// Send signed federation messages to the bridge through a mycelium
+    /// node, for its inbound path. Latency is the node taking the message;
+    /// what the bridge took in is read from /stats.
+    Messages {
+        /// Mycelium address or public key of the bridge's node
+        bridge_address: String,
+        /// Server name of the bridge
+        bridge_server_name: String,
+        /// API URL of the mycelium node to send from
+        #[arg(long, default_value = "http://127.0.0.1:8989")]
+        mycelium_url: String,
+        /// Server the messages claim to come from
+        #[arg(long, default_value = "loadgen.test")]
+        source: String,
+    },
+}
+
+/// Outcomes of the requests sent so far.
+#[derive(Default)]
+struct Results {
+    latencies: Vec<Duration>,
+    failures: u64,
+    /// Up to a few distinct failure reasons, to show what went wrong.
+    reasons: Vec<String>,
+}
+
+impl Results {
+    fn record(&mut self, outcome: Result<Duration>) {
+        match outcome {
+            Ok(latency) => self.latencies.push(latency),
+            Err(e) => {
+                self.failures += 1;
+                let reason = e.to_string();
+                if self.reasons.len() < 5 && !self.reasons.contains(&reason) {
+                    self.reasons.push(reason);
+                }
+            }
+        }
+    }
+
+    /// Latency at `quantile` of the successful requests, in milliseconds.
+    fn percentile(&self, quantile: f64) -> f64 {
+        let index = ((self.latencies.len() as f64 * quantile).ceil() as usize).saturating_sub(1);
+        self.latencies[index.min(self.latencies.len() - 1)].as_secs_f64() * 1000.0
+    }
+}
+
+/// Resident memory of the bridge process across the run, in bytes.
+#[derive(Default)]
+struct Memory {
+    first: Option<u64>,
+    last: u64,
+    peak: u64,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let cli = Cli::parse();
+    let client = reqwest::Client::new();
+
+    let memory = Arc::new(Mutex::new(Memory::default()));
+    if let Some(pid) = cli.pid {
+        let memory = memory.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(MEMORY_SAMPLE_INTERVAL);
+            loop {
+                interval.tick().await;
+                if let Some(rss) = resident_memory(pid) {
+                    let mut memory = memory.lock().unwrap();
+                    memory.first.get_or_insert(rss);
+                    memory.last = rss;
+                    memory.peak = memory.peak.max(rss);
+                }
+            }
+        });
+    }
+
+    let (results, elapsed, received) = match &cli.command {
+        Command::Events { destination, queue } => {
+            let url = format!("{}/federation/send", cli.url.trim_end_matches('/'));
+            let send = || {
+                let event = FederationEvent {
+                    destination: destination.clone(),
+                    event_type: "m.room.message".to_string(),
+                    event_data: pdu("loadgen.test", cli.size),
+                    expires_at: None,
+                };
+                let mut request = client.post(&url).json(&event);
+                if *queue {
+                    request = request.header("prefer", "respond-async");
+                }
+                if let Some(token) = &cli.token {
+                    request = request.bearer_auth(token);
+                }
+                async move {
+                    let response = request.send().await?;
+                    if !response.status().is_success() {
+                        anyhow::bail!("Bridge answered {}", response.status());
+                    }
+                    Ok(())
+                }
+            };
+            let (results, elapsed) = run(&cli, send).await;
+            (results, elapsed, None)
+        }
+        Command::Messages {
+            bridge_address,
+            bridge_server_name,
+            mycelium_url,
+            source,
+        } => {
+            // Failures are what is being measured, so they aren't retried
+            let config = MyceliumConfig {
+                max_retries: 0,
+                ..MyceliumConfig::default()
+            };
+            let mycelium = Arc::new(MyceliumClient::new(mycelium_url.clone(), &config)?);
+            let signing_key = Arc::new(signer::generate_keypair());
+            let destination = Arc::new(Destination::parse(bridge_address));
+            let topic = Arc::new(edu::federation_topic(bridge_server_name));
+            let received_before = received_messages(&client, &cli).await;
+
+            let send = || {
+                let message = signed_message(&signing_key, source, bridge_server_name, cli.size);
+                let (mycelium, destination, topic) = (mycelium.clone(), destination.clone(), topic.clone());
+                async move {
+                    let data = serde_json::to_vec(&message?)?;
+                    mycelium.send_message(&destination, &topic, &data).await
+                }
+            };
+            let (results, elapsed) = run(&cli, send).await;
+            // Give the bridge a moment to poll what is still on its way
+            tokio::time::sleep(Duration::from_secs(2)).await;
+            let received_after = received_messages(&client, &cli).await;
+            let received = received_before.zip(received_after).map(|(before, after)| after - before);
+            (results, elapsed, received)
+        }
+    };
+
+    let sent = results.latencies.len() as u64 + results.failures;
+    let seconds = elapsed.as_secs_f64();
+    println!("--- bridge-loadgen against {} ---", cli.url);
+    println!(
+        "{} sent in {:.1}s: {} ok, {} failed, {:.1}/s ok",
+        sent,
+        seconds,
+        results.latencies.len(),
+        results.failures,
+        results.latencies.len() as f64 / seconds
+    );
+    for reason in &results.reasons {
+        println!("  failed: {}", reason);
+    }
+    if let Some(received) = received {
+        println!("bridge received {} ({:.1}/s)", received, received as f64 / seconds);
+    }
+
+    let mut problems = Vec::new();
+    let mut results = results;
+    if !results.latencies.is_empty() {
+        results.latencies.sort();
+        let p99 = results.percentile(0.99);
+        println!(
+            "latency p50/p90/p99/max = {:.1}/{:.1}/{:.1}/{:.1} ms",
+            results.percentile(0.5),
+            results.percentile(0.9),
+            p99,
+            results.percentile(1.0)
+        );
+        if let Some(max_p99) = cli.max_p99_ms.filter(|max_p99| p99 > *max_p99) {
+            problems.push(format!("p99 latency {:.1} ms is over {:.1} ms", p99, max_p99));
+        }
+    }
+
+    let memory = memory.lock().unwrap();
+    if let Some(first) = memory.first {
+        let mib = |bytes: u64| bytes as f64 / (1024.0 * 1024.0);
+        let growth = mib(memory.last) - mib(first);
+        println!(
+            "memory {:.1} MiB -> {:.1} MiB, peak {:.1} MiB ({:+.1} MiB)",
+            mib(first),
+            mib(memory.last),
+            mib(memory.peak),
+            growth
+        );
+        if let Some(max_growth) = cli.max_memory_growth_mb.filter(|max_growth| growth > *max_growth) {
+            problems.push(format!("memory grew by {:.1} MiB, over {:.1} MiB", growth, max_growth));
+        }
+    } else if cli.pid.is_some() {
+        println!("memory not sampled: no such process, or not on Linux");
+    }
+
+    if !problems.is_empty() {
+        anyhow::bail!("{}", problems.join("; "));
+    }
+    Ok(())
+}
+
+/// Call `send` at `--rate` for `--duration`, with at most `--concurrency`
+/// calls in flight, and wait for the last of them. Returns the outcomes and
+/// how long it all took.
+async fn run<F, Fut>(cli: &Cli, mut send: F) -> (Results, Duration)
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<()>> + Send + 'static,
+{
+    let permits = Arc::new(Semaphore::new(cli.concurrency.max(1)));
+    let mut interval = (cli.rate > 0).then(|| {
+        let mut interval = tokio::time::interval(Duration::from_secs(1) / cli.rate);
+        // Behind schedule, catch up with a burst as a steady sender would
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Burst);
+        interval
+    });
+    let mut results = Results::default();
+    let mut tasks = JoinSet::new();
+    let started = Instant::now();
+    let deadline = started + Duration::from_secs(cli.duration);
+
+    while Instant::now() < deadline {
+        if let Some(interval) = &mut interval {
+            interval.tick().await;
+        }
+        let permit = permits.clone().acquire_owned().await.expect("semaphore is never closed");
+        let request = send();
+        tasks.spawn(async move {
+            let sent = Instant::now();
+            let outcome = request.await.map(|()| sent.elapsed());
+            drop(permit);
+            outcome
+        });
+        while let Some(finished) = tasks.try_join_next() {
+            results.record(finished.map_err(anyhow::Error::from).and_then(|outcome| outcome));
+        }
+    }
+    while let Some(finished) = tasks.join_next().await {
+        results.record(finished.map_err(anyhow::Error::from).and_then(|outcome| outcome));
+    }
+    (results, started.elapsed())
+}
+
+/// A PDU with `size` bytes of body, in a room on `origin`.
+fn pdu(origin: &str, size: usize) -> serde_json::Value {
+    serde_json::json!({
+        "event_id": format!("${}", uuid::Uuid::new_v4().simple()),
+        "room_id": format!("!loadgen:{}", origin),
+        "sender": format!("@loadgen:{}", origin),
+        "type": "m.room.message",
+        "origin_server_ts": chrono::Utc::now().timestamp_millis(),
+        "content": { "msgtype": "m.text", "body": "x".repeat(size) },
+    })
+}
+
+/// A federation message carrying a PDU from `source`, signed as the bridge
+/// signs its own. The bridge doesn't know the key, so it counts the message
+/// but can't vouch for the sender.
+fn signed_message(
+    signing_key: &SigningKey,
+    source: &str,
+    destination: &str,
+    size: usize,
+) -> Result<MyceliumMessage> {
+    let mut message = MyceliumMessage {
+        version: ProtocolVersion::CURRENT.as_str().to_string(),
+        source_server: source.to_string(),
+        destination_server: destination.to_string(),
+        message_type: "federation_event".to_string(),
+        timestamp: chrono::Utc::now().to_rfc3339(),
+        payload: serde_json::value::to_raw_value(&pdu(source, size))?,
+        signature: String::new(),
+        content_encoding: None,
+        encryption: None,
+        trace_context: None,
+        correlation_id: Some(uuid::Uuid::new_v4().to_string()),
+        expires_at: None,
+    };
+    let signature = signing_key.sign(message.signing_payload()?.as_bytes());
+    message.signature = base64::engine::general_purpose::STANDARD.encode(signature.to_bytes());
+    Ok(message)
+}
+
+/// Messages the bridge has received from every peer, per its /stats, if
+/// they can be read.
+async fn received_messages(client: &reqwest::Client, cli: &Cli) -> Option<u64> {
+    let mut request = client.get(format!("{}/stats", cli.url.trim_end_matches('/')));
+    if let Some(token) = &cli.token {
+        request = request.bearer_auth(token);
+    }
+    let response = request.send().await.ok()?;
+    if !response.status().is_success() {
+        eprintln!("Can't read {}/stats: {}", cli.url, response.status());
+        return None;
+    }
+    let stats: serde_json::Value = response.json().await.ok()?;
+    stats["messages"]["received"].as_u64()
+}
+
+/// Resident memory of process `pid` in bytes, from /proc.
+fn resident_memory(pid: u32) -> Option<u64> {
+    let status = std::fs::read_to_string(format!("/proc/{}/status", pid)).ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmRSS:"))?;
+    let kilobytes: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kilobytes * 1024)
+}
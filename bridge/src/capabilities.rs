@@ -0,0 +1,82 @@
+use serde::Serialize;
+use std::collections::HashSet;
+use std::sync::RwLock;
+use tracing::debug;
+
+use crate::config::CapabilitiesConfig;
+
+/// Advertised by every bridge unless configured otherwise.
+pub const BASE_CAPABILITIES: [&str; 2] = ["matrix_federation", "tf_connect_auth"];
+
+/// A capability and the part of the bridge that provides it.
+#[derive(Debug, Clone, Serialize)]
+pub struct Registration {
+    pub capability: String,
+    pub subsystem: String,
+}
+
+/// The capabilities this bridge announces. Each subsystem registers what it
+/// supports at startup, so announcements only claim what is actually
+/// running, on top of `capabilities.advertise`.
+pub struct CapabilityRegistry {
+    withheld: HashSet<String>,
+    registrations: RwLock<Vec<Registration>>,
+}
+
+impl CapabilityRegistry {
+    pub fn new(config: &CapabilitiesConfig) -> Self {
+        let registry = Self {
+            withheld: config.withhold.iter().cloned().collect(),
+            registrations: RwLock::new(Vec::new()),
+        };
+        for capability in &config.advertise {
+            registry.register(capability, "config");
+        }
+        registry
+    }
+
+    /// Advertise `capability` on behalf of `subsystem`, unless it is
+    /// withheld or already registered.
+    pub fn register(&self, capability: &str, subsystem: &str) {
+        if self.withheld.contains(capability) {
+            debug!("Not advertising {} from {}, it is withheld", capability, subsystem);
+            return;
+        }
+        let mut registrations = self.registrations.write().unwrap();
+        if registrations.iter().any(|registration| registration.capability == capability) {
+            return;
+        }
+        registrations.push(Registration {
+            capability: capability.to_string(),
+            subsystem: subsystem.to_string(),
+        });
+    }
+
+    pub fn supports(&self, capability: &str) -> bool {
+        self.registrations
+            .read()
+            .unwrap()
+            .iter()
+            .any(|registration| registration.capability == capability)
+    }
+
+    /// The capabilities to announce, in registration order.
+    pub fn advertised(&self) -> Vec<String> {
+        self.registrations
+            .read()
+            .unwrap()
+            .iter()
+            .map(|registration| registration.capability.clone())
+            .collect()
+    }
+
+    pub fn registrations(&self) -> Vec<Registration> {
+        self.registrations.read().unwrap().clone()
+    }
+
+    pub fn withheld(&self) -> Vec<String> {
+        let mut withheld: Vec<String> = self.withheld.iter().cloned().collect();
+        withheld.sort();
+        withheld
+    }
+}
@@ -0,0 +1,127 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use serde::Deserialize;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::config::{BridgeConfig, CapacityConfig, CapacityProviderKind};
+use crate::http_client::HttpClient;
+use crate::synapse_admin;
+
+/// Local user counts, as announced to other servers.
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct UserCounts {
+    #[serde(rename = "current_users")]
+    pub total: u32,
+    #[serde(default, rename = "monthly_active_users")]
+    pub monthly_active: Option<u32>,
+}
+
+/// Counts the homeserver's users for announcements.
+#[async_trait]
+pub trait CapacityProvider: Send + Sync {
+    /// Shown in logs.
+    fn name(&self) -> &'static str;
+
+    /// Local user counts, or `None` if they can't be known.
+    async fn user_counts(&self) -> Result<Option<UserCounts>>;
+}
+
+/// Counts users through Synapse's admin API, when given an admin token.
+pub struct SynapseAdminProvider {
+    http_client: HttpClient,
+    homeserver_url: String,
+    admin_token: Option<String>,
+}
+
+#[async_trait]
+impl CapacityProvider for SynapseAdminProvider {
+    fn name(&self) -> &'static str {
+        "synapse"
+    }
+
+    async fn user_counts(&self) -> Result<Option<UserCounts>> {
+        let Some(token) = self.admin_token.as_deref() else {
+            return Ok(None);
+        };
+        let counts = synapse_admin::count_users(&self.http_client, &self.homeserver_url, token).await?;
+        Ok(Some(counts))
+    }
+}
+
+/// Reports the counts set in `capacity.current_users`, for homeservers with
+/// no way to count their users.
+pub struct StaticProvider {
+    counts: UserCounts,
+}
+
+#[async_trait]
+impl CapacityProvider for StaticProvider {
+    fn name(&self) -> &'static str {
+        "static"
+    }
+
+    async fn user_counts(&self) -> Result<Option<UserCounts>> {
+        Ok(Some(self.counts))
+    }
+}
+
+/// Runs `capacity.command`, which prints the counts as JSON, e.g.
+/// `{"current_users": 42, "monthly_active_users": 17}`.
+pub struct CommandProvider {
+    command: Vec<String>,
+    timeout: Duration,
+}
+
+#[async_trait]
+impl CapacityProvider for CommandProvider {
+    fn name(&self) -> &'static str {
+        "command"
+    }
+
+    async fn user_counts(&self) -> Result<Option<UserCounts>> {
+        let Some((program, args)) = self.command.split_first() else {
+            return Ok(None);
+        };
+        let output = tokio::process::Command::new(program)
+            .args(args)
+            .kill_on_drop(true)
+            .output();
+        let output = tokio::time::timeout(self.timeout, output)
+            .await
+            .map_err(|_| anyhow::anyhow!("{} did not finish within {:?}", program, self.timeout))??;
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(anyhow::anyhow!("{} failed with {}: {}", program, output.status, stderr.trim()));
+        }
+        Ok(Some(serde_json::from_slice(&output.stdout)?))
+    }
+}
+
+/// The provider selected by `capacity.provider`.
+pub fn from_config(config: &BridgeConfig, http_client: HttpClient) -> Arc<dyn CapacityProvider> {
+    let CapacityConfig {
+        provider,
+        current_users,
+        monthly_active_users,
+        command,
+        timeout_seconds,
+    } = &config.capacity;
+    match provider {
+        CapacityProviderKind::Synapse => Arc::new(SynapseAdminProvider {
+            http_client,
+            homeserver_url: config.matrix_homeserver_url.clone(),
+            admin_token: config.homeserver.admin_token.clone(),
+        }),
+        CapacityProviderKind::Static => Arc::new(StaticProvider {
+            counts: UserCounts {
+                total: *current_users,
+                monthly_active: *monthly_active_users,
+            },
+        }),
+        CapacityProviderKind::Command => Arc::new(CommandProvider {
+            command: command.clone(),
+            timeout: Duration::from_secs(*timeout_seconds),
+        }),
+    }
+}
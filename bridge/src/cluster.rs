@@ -0,0 +1,389 @@
+use anyhow::Result;
+use redis::aio::{ConnectionManager, MultiplexedConnection};
+use redis::AsyncCommands;
+use std::time::Duration;
+use tokio::sync::watch;
+use tracing::{error, info, warn};
+
+use crate::config::ClusterConfig;
+use crate::delivery::{DeliveryState, DeliveryStatus, QueuedSend, SendOutcome};
+use crate::persistence::LoadedDirectory;
+
+/// How long the status of a message sent through the shared send queue is
+/// kept in Redis.
+const DELIVERY_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Takes the lease when it is free, or extends it when held by this node.
+const ACQUIRE_LEASE: &str = r#"
+local holder = redis.call('GET', KEYS[1])
+if holder == ARGV[1] then
+    redis.call('PEXPIRE', KEYS[1], ARGV[2])
+    return 1
+end
+if not holder then
+    redis.call('SET', KEYS[1], ARGV[1], 'PX', ARGV[2])
+    return 1
+end
+return 0
+"#;
+
+/// Gives the lease up, if this node still holds it.
+const RELEASE_LEASE: &str = r#"
+if redis.call('GET', KEYS[1]) == ARGV[1] then
+    return redis.call('DEL', KEYS[1])
+end
+return 0
+"#;
+
+/// Adds a send to the queue unless it already holds as many as allowed, in
+/// one step so nodes pushing at once can't overfill it.
+const PUSH_SEND: &str = r#"
+if redis.call('LLEN', KEYS[1]) >= tonumber(ARGV[2]) then
+    return 0
+end
+redis.call('LPUSH', KEYS[1], ARGV[1])
+return 1
+"#;
+
+/// Moves the sends a node took but didn't finish back to the front of the
+/// queue, oldest first.
+const REQUEUE_SENDS: &str = r#"
+local moved = 0
+while redis.call('LMOVE', KEYS[1], KEYS[2], 'LEFT', 'RIGHT') do
+    moved = moved + 1
+end
+return moved
+"#;
+
+/// This bridge's place in a cluster of bridges serving the same homeserver.
+/// The instances elect a leader through a lease in Redis, and share a send
+/// queue, the status of the messages sent through it, and the directory.
+pub struct Cluster {
+    node_id: String,
+    /// Prefix of every key, scoped to the server name.
+    prefix: String,
+    lease: Duration,
+    client: redis::Client,
+    connection: ConnectionManager,
+    leader: watch::Sender<bool>,
+}
+
+impl Cluster {
+    pub async fn connect(config: &ClusterConfig, server_name: &str) -> Result<Self> {
+        let client = redis::Client::open(config.redis_url.as_str())?;
+        let connection = ConnectionManager::new(client.clone()).await?;
+        let node_id = config
+            .node_id
+            .clone()
+            .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+        info!("Joined cluster at {} as node {}", config.redis_url, node_id);
+        Ok(Self {
+            node_id,
+            prefix: format!("{}:{}", config.key_prefix, server_name),
+            lease: Duration::from_secs(config.lease_seconds),
+            client,
+            connection,
+            leader: watch::channel(false).0,
+        })
+    }
+
+    pub fn node_id(&self) -> &str {
+        &self.node_id
+    }
+
+    pub fn lease(&self) -> Duration {
+        self.lease
+    }
+
+    pub fn is_leader(&self) -> bool {
+        *self.leader.borrow()
+    }
+
+    fn key(&self, name: &str) -> String {
+        format!("{}:{}", self.prefix, name)
+    }
+
+    /// Take the leader lease if it is free, or extend it if this node holds
+    /// it. A node that can't reach Redis steps down, as its lease may have
+    /// run out meanwhile. Returns true when this node just became the leader.
+    pub async fn renew_lease(&self) -> bool {
+        let held = redis::Script::new(ACQUIRE_LEASE)
+            .key(self.key("leader"))
+            .arg(&self.node_id)
+            .arg(self.lease.as_millis() as u64)
+            .invoke_async::<i64>(&mut self.connection.clone())
+            .await;
+        let leader = match held {
+            Ok(held) => held == 1,
+            Err(e) => {
+                error!("Failed to renew the cluster lease: {}", e);
+                false
+            }
+        };
+        let was_leader = self.leader.send_replace(leader);
+        match (was_leader, leader) {
+            (false, true) => info!("Node {} is now the cluster leader", self.node_id),
+            (true, false) => warn!("Node {} is no longer the cluster leader", self.node_id),
+            _ => {}
+        }
+        leader && !was_leader
+    }
+
+    /// Step down, letting another node take over without waiting for the
+    /// lease to run out.
+    pub async fn release_lease(&self) {
+        if !self.leader.send_replace(false) {
+            return;
+        }
+        let released = redis::Script::new(RELEASE_LEASE)
+            .key(self.key("leader"))
+            .arg(&self.node_id)
+            .invoke_async::<i64>(&mut self.connection.clone())
+            .await;
+        match released {
+            Ok(_) => info!("Node {} released the cluster leadership", self.node_id),
+            Err(e) => error!("Failed to release the cluster lease: {}", e),
+        }
+    }
+
+    /// Add a send to the shared queue, unless `capacity` sends are waiting.
+    pub async fn push_send(&self, queued: &QueuedSend, capacity: usize) -> Result<()> {
+        let pushed: i64 = redis::Script::new(PUSH_SEND)
+            .key(self.key("send_queue"))
+            .arg(serde_json::to_string(queued)?)
+            .arg(capacity)
+            .invoke_async(&mut self.connection.clone())
+            .await?;
+        if pushed == 0 {
+            return Err(anyhow::anyhow!("the shared send queue is full"));
+        }
+        Ok(())
+    }
+
+    /// The list of sends this node took from the queue and hasn't finished.
+    fn processing_key(&self, node_id: &str) -> String {
+        self.key(&format!("processing:{}", node_id))
+    }
+
+    /// A connection of its own for [`Cluster::pop_send`], which blocks it.
+    pub async fn queue_connection(&self) -> Result<MultiplexedConnection> {
+        Ok(self.client.get_multiplexed_async_connection().await?)
+    }
+
+    /// Take the oldest send from the shared queue, waiting up to `timeout`
+    /// for one. It stays in this node's processing list until
+    /// [`Cluster::finish_send`] or [`Cluster::return_send`], so it is sent
+    /// by another node if this one dies first.
+    pub async fn pop_send(
+        &self,
+        connection: &mut MultiplexedConnection,
+        timeout: Duration,
+    ) -> Result<Option<QueuedSend>> {
+        let processing = self.processing_key(&self.node_id);
+        let popped: Option<String> = redis::cmd("BLMOVE")
+            .arg(self.key("send_queue"))
+            .arg(&processing)
+            .arg("RIGHT")
+            .arg("LEFT")
+            .arg(timeout.as_secs_f64())
+            .query_async(connection)
+            .await?;
+        let Some(popped) = popped else {
+            return Ok(None);
+        };
+        match serde_json::from_str(&popped) {
+            Ok(queued) => Ok(Some(queued)),
+            Err(e) => {
+                let _: () = connection.lrem(&processing, 1, &popped).await?;
+                Err(e.into())
+            }
+        }
+    }
+
+    /// Put a popped send back at the front of the queue for another node.
+    pub async fn return_send(&self, queued: &QueuedSend) -> Result<()> {
+        let queued = serde_json::to_string(queued)?;
+        redis::pipe()
+            .atomic()
+            .lrem(self.processing_key(&self.node_id), 1, &queued)
+            .ignore()
+            .rpush(self.key("send_queue"), &queued)
+            .ignore()
+            .query_async::<()>(&mut self.connection.clone())
+            .await?;
+        Ok(())
+    }
+
+    /// Drop a popped send from this node's processing list once it has been
+    /// sent, or has failed for good.
+    pub async fn finish_send(&self, queued: &QueuedSend) {
+        let finished = match serde_json::to_string(queued) {
+            Ok(queued) => {
+                let mut connection = self.connection.clone();
+                connection
+                    .lrem::<_, _, ()>(self.processing_key(&self.node_id), 1, queued)
+                    .await
+                    .map_err(anyhow::Error::from)
+            }
+            Err(e) => Err(e.into()),
+        };
+        if let Err(e) = finished {
+            error!("Failed to finish message {} in the shared queue: {}", queued.message_id, e);
+        }
+    }
+
+    /// Mark this node alive for a lease, so its unfinished sends are left to
+    /// it.
+    pub async fn keep_alive(&self) {
+        let alive = redis::pipe()
+            .sadd(self.key("nodes"), &self.node_id)
+            .ignore()
+            .pset_ex(self.key(&format!("node:{}", self.node_id)), 1, self.lease.as_millis() as u64)
+            .ignore()
+            .query_async::<()>(&mut self.connection.clone())
+            .await;
+        if let Err(e) = alive {
+            error!("Failed to mark node {} alive: {}", self.node_id, e);
+        }
+    }
+
+    /// Put the unfinished sends of nodes no longer alive back in the queue,
+    /// and this node's own from before it restarted when `include_own`.
+    pub async fn requeue_abandoned_sends(&self, include_own: bool) -> Result<()> {
+        let mut connection = self.connection.clone();
+        let nodes: Vec<String> = connection.smembers(self.key("nodes")).await?;
+        for node_id in nodes {
+            let own = node_id == self.node_id;
+            if own && !include_own {
+                continue;
+            }
+            if !own && connection.exists(self.key(&format!("node:{}", node_id))).await? {
+                continue;
+            }
+            let moved: i64 = redis::Script::new(REQUEUE_SENDS)
+                .key(self.processing_key(&node_id))
+                .key(self.key("send_queue"))
+                .invoke_async(&mut connection)
+                .await?;
+            if !own {
+                let _: () = connection.srem(self.key("nodes"), &node_id).await?;
+            }
+            if moved > 0 {
+                warn!("Put {} unfinished sends of node {} back in the shared queue", moved, node_id);
+            }
+        }
+        Ok(())
+    }
+
+    /// Start tracking a message as queued. Returns its message ID.
+    pub async fn queue_delivery(&self, destination: &str, event_id: Option<&str>) -> Result<String> {
+        let message_id = uuid::Uuid::new_v4().to_string();
+        let status = DeliveryStatus::queued(&message_id, destination, event_id);
+        self.save_status(&status).await?;
+        if let Some(event_id) = event_id {
+            let mut connection = self.connection.clone();
+            let key = self.key(&format!("delivery_event:{}:{}", destination, event_id));
+            let _: () = connection.set_ex(key, &message_id, DELIVERY_TTL.as_secs()).await?;
+        }
+        Ok(message_id)
+    }
+
+    /// As [`crate::delivery::DeliveryTracker::sent`].
+    pub async fn delivery_sent(&self, message_id: &str, result: Result<SendOutcome, String>) {
+        let update = |status: &mut DeliveryStatus| {
+            if status.state == DeliveryState::Queued {
+                match result {
+                    Ok(outcome) => status.set(outcome.state(), None),
+                    Err(e) => status.set(DeliveryState::Failed, Some(e)),
+                }
+            }
+        };
+        if let Err(e) = self.update_status(Some(message_id.to_string()), update).await {
+            error!("Failed to update the status of message {}: {}", message_id, e);
+        }
+    }
+
+    /// As [`crate::delivery::DeliveryTracker::acknowledge`].
+    pub async fn acknowledge_deliveries(&self, source: &str, event_ids: &[String]) {
+        for event_id in event_ids {
+            let acknowledge = |status: &mut DeliveryStatus| {
+                if status.state != DeliveryState::Failed {
+                    status.set(DeliveryState::Acked, None);
+                }
+            };
+            let updated = match self.message_for_event(source, event_id).await {
+                Ok(message_id) => self.update_status(message_id, acknowledge).await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = updated {
+                error!("Failed to record the ack of {} from {}: {}", event_id, source, e);
+            }
+        }
+    }
+
+    /// As [`crate::delivery::DeliveryTracker::reject`].
+    pub async fn reject_delivery(&self, source: &str, event_id: &str, reason: &str) {
+        let reject = |status: &mut DeliveryStatus| {
+            if status.destination == source {
+                status.set(DeliveryState::Failed, Some(reason.to_string()));
+            }
+        };
+        let updated = match self.message_for_event(source, event_id).await {
+            Ok(message_id) => self.update_status(message_id, reject).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = updated {
+            error!("Failed to record the rejection of {} by {}: {}", event_id, source, e);
+        }
+    }
+
+    pub async fn delivery(&self, message_id: &str) -> Result<Option<DeliveryStatus>> {
+        let mut connection = self.connection.clone();
+        let status: Option<String> = connection.get(self.key(&format!("delivery:{}", message_id))).await?;
+        Ok(status.map(|status| serde_json::from_str(&status)).transpose()?)
+    }
+
+    async fn message_for_event(&self, destination: &str, event_id: &str) -> Result<Option<String>> {
+        let mut connection = self.connection.clone();
+        let key = self.key(&format!("delivery_event:{}:{}", destination, event_id));
+        Ok(connection.get(key).await?)
+    }
+
+    async fn update_status(
+        &self,
+        message_id: Option<String>,
+        update: impl FnOnce(&mut DeliveryStatus),
+    ) -> Result<()> {
+        let Some(message_id) = message_id else {
+            return Ok(());
+        };
+        let Some(mut status) = self.delivery(&message_id).await? else {
+            return Ok(());
+        };
+        update(&mut status);
+        self.save_status(&status).await
+    }
+
+    async fn save_status(&self, status: &DeliveryStatus) -> Result<()> {
+        let mut connection = self.connection.clone();
+        let key = self.key(&format!("delivery:{}", status.message_id));
+        let _: () = connection
+            .set_ex(key, serde_json::to_string(status)?, DELIVERY_TTL.as_secs())
+            .await?;
+        Ok(())
+    }
+
+    /// Publish the leader's directory for the followers.
+    pub async fn share_directory(&self, directory: &LoadedDirectory) -> Result<()> {
+        let mut connection = self.connection.clone();
+        let _: () = connection.set(self.key("directory"), serde_json::to_string(directory)?).await?;
+        Ok(())
+    }
+
+    /// The directory last shared by a leader, if any.
+    pub async fn shared_directory(&self) -> Result<Option<LoadedDirectory>> {
+        let mut connection = self.connection.clone();
+        let directory: Option<String> = connection.get(self.key("directory")).await?;
+        Ok(directory.map(|directory| serde_json::from_str(&directory)).transpose()?)
+    }
+}
@@ -0,0 +1,62 @@
+use anyhow::Result;
+use base64::Engine;
+use serde_json::value::RawValue;
+
+/// Capability advertised by bridges that accept zstd-compressed payloads.
+pub const ZSTD_CAPABILITY: &str = "compression.zstd";
+
+/// `content_encoding` value for zstd-compressed payloads.
+pub const ZSTD_ENCODING: &str = "zstd";
+
+/// Upper bound on the decompressed size of a payload, to guard against
+/// decompression bombs from the overlay.
+const MAX_DECOMPRESSED_SIZE: usize = 16 * 1024 * 1024;
+
+/// Compress a serialized JSON payload with zstd. The result is carried in the
+/// message as a base64 string so the envelope stays valid JSON.
+pub fn compress_payload(payload: &RawValue, level: i32) -> Result<Box<RawValue>> {
+    let compressed = zstd::encode_all(payload.get().as_bytes(), level)?;
+    let encoded = base64::engine::general_purpose::STANDARD.encode(compressed);
+
+    Ok(serde_json::value::to_raw_value(&encoded)?)
+}
+
+/// Reverse of [`compress_payload`].
+pub fn decompress_payload(payload: &RawValue) -> Result<Box<RawValue>> {
+    let encoded: String = serde_json::from_str(payload.get())
+        .map_err(|_| anyhow::anyhow!("Compressed payload must be a string"))?;
+    let compressed = base64::engine::general_purpose::STANDARD.decode(encoded)?;
+    let raw = zstd::bulk::decompress(&compressed, MAX_DECOMPRESSED_SIZE)?;
+
+    Ok(RawValue::from_string(String::from_utf8(raw)?)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn payloads_survive_a_round_trip() {
+        let body = serde_json::json!({ "body": "hello ".repeat(100) });
+        let payload = serde_json::value::to_raw_value(&body).unwrap();
+        let compressed = compress_payload(&payload, 3).unwrap();
+        assert!(compressed.get().len() < payload.get().len());
+        assert_eq!(decompress_payload(&compressed).unwrap().get(), payload.get());
+    }
+
+    #[test]
+    fn malformed_payloads_are_refused() {
+        let not_a_string = serde_json::value::to_raw_value(&serde_json::json!({ "body": "hello" })).unwrap();
+        assert!(decompress_payload(&not_a_string).is_err());
+        let not_zstd = serde_json::value::to_raw_value("aGVsbG8=").unwrap();
+        assert!(decompress_payload(&not_zstd).is_err());
+    }
+
+    #[test]
+    fn decompression_bombs_are_refused() {
+        let bomb = zstd::encode_all(vec![b' '; MAX_DECOMPRESSED_SIZE + 1].as_slice(), 3).unwrap();
+        let encoded = base64::engine::general_purpose::STANDARD.encode(bomb);
+        let payload = serde_json::value::to_raw_value(&encoded).unwrap();
+        assert!(decompress_payload(&payload).is_err());
+    }
+}
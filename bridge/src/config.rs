@@ -1,38 +1,1743 @@
 use anyhow::Result;
+use base64::Engine;
 use serde::{Deserialize, Serialize};
-use std::fs;
+use std::collections::BTreeMap;
+
+use crate::auth::ApiToken;
+use crate::capabilities::BASE_CAPABILITIES;
+use crate::server_acl::glob_match;
+use crate::signer::UNIX_SCHEME;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct BridgeConfig {
     pub server_name: String,
     pub bind_address: String,
     pub matrix_homeserver_url: String,
-    pub mycelium_api_url: String,
+    /// One mycelium API URL, or a list of them in order of preference. A
+    /// `unix:///path` URL reaches the API over a Unix socket.
+    #[serde(deserialize_with = "string_or_list")]
+    pub mycelium_api_url: Vec<String>,
     pub signing_key_path: String,
     pub max_users: u32,
+    /// Origins browsers may call the HTTP API from; `*` allows any. None
+    /// are allowed by default.
+    #[serde(default)]
+    pub cors_origins: Vec<String>,
+    #[serde(default)]
+    pub homeserver: HomeserverConfig,
+    #[serde(default)]
+    pub appservice: AppserviceConfig,
+    #[serde(default)]
+    pub mycelium: MyceliumConfig,
+    #[serde(default)]
+    pub http: HttpConfig,
+    #[serde(default)]
+    pub batching: BatchingConfig,
+    #[serde(default)]
+    pub compression: CompressionConfig,
+    #[serde(default)]
+    pub encryption: EncryptionConfig,
+    #[serde(default)]
+    pub queries: QueryConfig,
+    #[serde(default)]
+    pub edu: EduConfig,
+    #[serde(default)]
+    pub media: MediaConfig,
+    #[serde(default)]
+    pub federation: FederationConfig,
+    #[serde(default)]
+    pub rate_limit: RateLimitConfig,
+    #[serde(default)]
+    pub validation: ValidationConfig,
+    #[serde(default)]
+    pub idempotency: IdempotencyConfig,
+    #[serde(default)]
+    pub send_queue: SendQueueConfig,
+    #[serde(default)]
+    pub fanout: FanoutConfig,
+    #[serde(default)]
+    pub priority: PriorityConfig,
+    #[serde(default)]
+    pub stream: StreamConfig,
+    #[serde(default)]
+    pub metrics: MetricsConfig,
+    #[serde(default)]
+    pub cluster: ClusterConfig,
+    #[serde(default)]
+    pub signer: SignerConfig,
+    #[serde(default)]
+    pub capabilities: CapabilitiesConfig,
+    #[serde(default)]
+    pub capacity: CapacityConfig,
+    #[serde(default)]
+    pub bandwidth: BandwidthConfig,
+    #[serde(default)]
+    pub archive: ArchiveConfig,
+    #[serde(default)]
+    pub liveness: LivenessConfig,
+    #[serde(default)]
+    pub directory: DirectoryConfig,
+    #[serde(default)]
+    pub telemetry: TelemetryConfig,
+    #[serde(default)]
+    pub logging: LoggingConfig,
+    #[serde(default)]
+    pub admin: AdminConfig,
+    #[serde(default)]
+    pub shutdown: ShutdownConfig,
+    #[serde(default)]
+    pub persistence: PersistenceConfig,
+    #[serde(default)]
+    pub discovery: DiscoveryConfig,
+    #[serde(default)]
+    pub gossip: GossipConfig,
+    #[serde(default)]
+    pub tls: TlsConfig,
+    #[serde(default)]
+    pub auth: AuthConfig,
+    /// Peers federated with manually, routable without announcing.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub peers: Vec<PeerConfig>,
+}
+
+fn string_or_list<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum StringOrList {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    Ok(match StringOrList::deserialize(deserializer)? {
+        StringOrList::One(url) => vec![url],
+        StringOrList::Many(urls) => urls,
+    })
+}
+
+/// Homeserver implementation the bridge is deployed in front of.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HomeserverFlavor {
+    #[default]
+    Synapse,
+    Conduit,
+    Dendrite,
+}
+
+/// How the bridge talks to the local homeserver.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HomeserverConfig {
+    pub flavor: HomeserverFlavor,
+    /// Sign the requests relayed to the homeserver with an `X-Matrix`
+    /// Authorization header from the bridge's key, with the remote server
+    /// they come from as the origin.
+    pub sign_requests: bool,
+    /// The homeserver's own server name, used as the request destination.
+    /// Defaults to `server_name`.
+    pub server_name: Option<String>,
+    /// Admin access token, used to report capacity where the homeserver's
+    /// admin API supports it.
+    pub admin_token: Option<String>,
+    /// How long a capacity measurement is reused between announcements.
+    pub capacity_cache_seconds: u64,
+    /// Attempts at delivering an inbound event before it is buffered.
+    pub delivery_attempts: u32,
+    /// Wait before the first retry, doubling after each failure.
+    pub retry_delay_ms: u64,
+    /// Longest wait between attempts at replaying buffered events.
+    pub max_retry_delay_seconds: u64,
+    /// Where events are buffered while the homeserver is unreachable, so
+    /// they survive a restart. Buffered in memory only if unset.
+    pub buffer_path: Option<String>,
+    /// Most events buffered; the oldest are dropped beyond this.
+    pub buffer_max_events: usize,
+}
+
+/// Integration as a Matrix application service instead of through the
+/// homeserver's federation endpoints.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AppserviceConfig {
+    pub enabled: bool,
+    pub id: String,
+    /// URL the homeserver uses to reach the bridge.
+    pub url: String,
+    /// Where the registration file is read from, or generated if missing.
+    pub registration_path: String,
+    pub sender_localpart: String,
+    /// Prefix for puppet users and portal room aliases.
+    pub user_prefix: String,
+}
+
+/// How inbound messages are read from the mycelium API.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MyceliumConfig {
+    /// Block in the message read until something arrives instead of
+    /// polling on a fixed interval.
+    pub long_poll: bool,
+    /// How long a single long-poll read may wait.
+    pub poll_timeout_seconds: u64,
+    /// Polling interval used when long polling is disabled.
+    pub poll_interval_ms: u64,
+    /// Speak the old `topic`/`data` JSON API of the development mocks
+    /// instead of mycelium's message API.
+    pub legacy_api: bool,
+    /// Mycelium addresses (IP or node public key) that announcements are
+    /// sent to in addition to the servers already in the directory.
+    pub announce_peers: Vec<String>,
+    pub connect_timeout_seconds: u64,
+    /// Timeout for API calls other than long-poll reads.
+    pub request_timeout_seconds: u64,
+    /// How often the mycelium endpoints are health-checked.
+    pub health_check_interval_seconds: u64,
+    /// Retries for failed sends, with exponential backoff and jitter.
+    pub max_retries: u32,
+    pub retry_base_delay_ms: u64,
+    pub pool_max_idle_per_host: usize,
+    /// Polled messages processed at the same time, per topic. Messages from
+    /// the same server are still processed in order.
+    pub inbound_concurrency: usize,
+    /// Polled messages waiting to be processed before polling pauses.
+    pub inbound_max_pending: usize,
+}
+
+/// Outbound HTTP requests other than to mycelium: the homeserver, the
+/// discovery service and the signer.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HttpConfig {
+    pub connect_timeout_seconds: u64,
+    /// Timeout for a whole request, reading the response included.
+    pub request_timeout_seconds: u64,
+    /// Proxy every request goes through: an `http://`, `https://`,
+    /// `socks5://` or `socks5h://` URL, with credentials if it needs them.
+    pub proxy: Option<String>,
+    /// Hosts, domains or IP ranges reached without the proxy.
+    pub no_proxy: Vec<String>,
+    /// Interval of TCP keep-alive probes on open connections. 0 disables
+    /// them.
+    pub tcp_keepalive_seconds: u64,
+    /// How long an idle connection is kept for reuse.
+    pub pool_idle_timeout_seconds: u64,
+    /// Retries for connection failures, timeouts, 429s and 5xx responses,
+    /// with exponential backoff and jitter.
+    pub max_retries: u32,
+    pub retry_base_delay_ms: u64,
+}
+
+/// Which remote servers this bridge federates with.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FederationConfig {
+    /// If non-empty, only these servers are federated with.
+    pub allowed_servers: Vec<String>,
+    pub blocked_servers: Vec<String>,
+    /// If non-empty, only events in these rooms are sent. Room IDs or
+    /// aliases, with `*` and `?` wildcards.
+    pub allowed_rooms: Vec<String>,
+    /// Rooms whose events are never sent, e.g. `#*-internal:example.org`.
+    pub blocked_rooms: Vec<String>,
+}
+
+impl FederationConfig {
+    pub fn is_allowed(&self, server_name: &str) -> bool {
+        if self.blocked_servers.iter().any(|s| s == server_name) {
+            return false;
+        }
+        self.allowed_servers.is_empty() || self.allowed_servers.iter().any(|s| s == server_name)
+    }
+
+    /// Whether events in a room may be sent, given its ID and the aliases
+    /// known for it.
+    pub fn is_room_allowed(&self, room_id: &str, aliases: &[String]) -> bool {
+        let matches = |pattern: &String| {
+            glob_match(pattern, room_id) || aliases.iter().any(|alias| glob_match(pattern, alias))
+        };
+        if self.blocked_rooms.iter().any(matches) {
+            return false;
+        }
+        self.allowed_rooms.is_empty() || self.allowed_rooms.iter().any(matches)
+    }
+}
+
+/// Checks on inbound PDUs and EDUs before they reach the homeserver.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ValidationConfig {
+    pub enabled: bool,
+    /// Largest PDU or EDU accepted, in bytes of JSON. Matrix allows 65536.
+    pub max_event_bytes: usize,
+    /// Most PDUs accepted in one transaction. Matrix allows 50.
+    pub max_transaction_pdus: usize,
+    /// Room versions whose rooms may be created over the bridge; empty
+    /// allows any.
+    pub room_versions: Vec<String>,
+}
+
+/// Deduplication of `/federation/send` retries.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct IdempotencyConfig {
+    /// Answer repeated sends with the same `Idempotency-Key` header, or the
+    /// same event ID, with the original response instead of sending again.
+    pub enabled: bool,
+    /// How long a key is remembered; should outlast the homeserver's retries.
+    pub ttl_seconds: u64,
+    /// Most keys remembered at once; the oldest are forgotten first.
+    pub max_keys: usize,
 }
 
+/// Queue behind the asynchronous send API.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SendQueueConfig {
+    /// Events waiting to be sent before new ones are refused with 503.
+    pub capacity: usize,
+    /// Events sent at the same time.
+    pub concurrency: usize,
+    /// How many messages' status is kept for `/federation/status`.
+    pub tracked_messages: usize,
+}
+
+/// Sends to many servers at once: events through `/federation/broadcast`,
+/// announcements and gossip digests.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FanoutConfig {
+    /// Destinations sent to at the same time.
+    pub concurrency: usize,
+    /// Broadcasts to more servers than this are refused.
+    pub max_destinations: usize,
+}
+
+/// Outbound sends per destination, scheduled by priority: interactive
+/// traffic (EDUs, acks, queries), then PDUs, then bulk media and backfill.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PriorityConfig {
+    /// Sends to one destination at the same time; more wait their turn.
+    pub max_in_flight_per_destination: usize,
+    /// Share of the freed slots each priority gets while several wait.
+    pub interactive_weight: u32,
+    pub normal_weight: u32,
+    pub bulk_weight: u32,
+}
+
+/// WebSocket stream of inbound events at `/federation/stream`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct StreamConfig {
+    pub enabled: bool,
+    /// Events a slow consumer may fall behind by before missing some.
+    pub buffer: usize,
+    /// Only stream inbound events instead of also delivering them to the
+    /// homeserver.
+    pub exclusive: bool,
+}
+
+/// Per-peer traffic and latency, served at `/metrics` and `/admin/peers`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MetricsConfig {
+    /// Warn when the average send to ack latency of a peer exceeds this;
+    /// 0 never warns.
+    pub latency_warning_ms: u64,
+}
+
+/// Several bridges serving one homeserver, coordinated through Redis. One
+/// instance, the leader, polls mycelium and announces; every instance
+/// takes sends and shares the directory.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ClusterConfig {
+    pub enabled: bool,
+    pub mode: ClusterMode,
+    pub redis_url: String,
+    /// Identifies this instance in the leader lease; random if unset.
+    pub node_id: Option<String>,
+    /// Prefix of the Redis keys, followed by the server name.
+    pub key_prefix: String,
+    /// How long the leader holds the lease without renewing it. A crashed
+    /// leader is replaced after at most this long.
+    pub lease_seconds: u64,
+    /// How often followers pick up the directory the leader shares.
+    pub sync_interval_seconds: u64,
+}
+
+/// Bounds on the server directory, which anyone on the overlay can
+/// announce themselves into.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DirectoryConfig {
+    /// Once full, the least recently seen servers make room for new ones,
+    /// offline ones first. Static peers are never evicted, nor are online or
+    /// verified ones for a new server.
+    pub max_servers: usize,
+    /// Servers new to the directory taken in per hour from each overlay
+    /// node, whether it announces them or relays them by gossip.
+    pub new_servers_per_hour: u32,
+}
+
+impl DirectoryConfig {
+    /// The token bucket for new servers from each overlay node.
+    pub fn new_server_limits(&self) -> RateLimitConfig {
+        RateLimitConfig {
+            enabled: true,
+            burst: self.new_servers_per_hour,
+            per_second: f64::from(self.new_servers_per_hour) / 3600.0,
+        }
+    }
+}
+
+/// How peers that stop announcing are marked `unknown`, then `offline`,
+/// counted in `discovery.announce_interval_seconds` missed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LivenessConfig {
+    pub unknown_after_missed: u32,
+    /// Offline peers are not sent PDUs; they are held until the peer is
+    /// heard from again.
+    pub offline_after_missed: u32,
+    pub check_interval_seconds: u64,
+    /// Most messages held for each offline peer; the oldest are dropped
+    /// beyond this.
+    pub held_messages: usize,
+}
+
+/// A record on disk of every envelope sent and received, for debugging,
+/// served at `/admin/archive`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ArchiveConfig {
+    pub enabled: bool,
+    /// Directory the archive files are written to.
+    pub path: String,
+    /// Leave payloads out, keeping only the envelope and payload size.
+    pub redact_payloads: bool,
+    /// Size of each archive file before the next is started.
+    pub segment_mb: u64,
+    /// Total size kept; the oldest files are deleted beyond it.
+    pub max_mb: u64,
+    /// Age after which archive files are deleted.
+    pub max_age_hours: u64,
+    /// Most messages returned by one query.
+    pub max_results: usize,
+}
+
+/// What happens to traffic with a peer over its bandwidth quota.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum QuotaAction {
+    /// Hold outbound sends until the peer is back under quota, for up to
+    /// `max_delay_seconds`, and answer inbound messages with `over_quota`
+    /// so the sender backs off.
+    #[default]
+    Throttle,
+    /// Fail outbound sends, and drop inbound messages without an answer.
+    Drop,
+}
+
+/// Hourly quotas for one server, overriding the defaults.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BandwidthQuota {
+    pub server_name: String,
+    #[serde(default)]
+    pub sent_mb_per_hour: u64,
+    #[serde(default)]
+    pub received_mb_per_hour: u64,
+}
+
+/// Bytes exchanged with each peer per hour, served at `/admin/bandwidth`,
+/// with optional quotas for metered links.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BandwidthConfig {
+    /// Enforce the quotas; usage is counted either way.
+    pub enabled: bool,
+    /// Quotas per peer, in MB per hour; 0 is unlimited.
+    pub sent_mb_per_hour: u64,
+    pub received_mb_per_hour: u64,
+    pub action: QuotaAction,
+    pub max_delay_seconds: u64,
+    pub servers: Vec<BandwidthQuota>,
+}
+
+/// Where the user counts in announcements come from.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CapacityProviderKind {
+    /// Synapse's admin API, with `homeserver.admin_token`.
+    #[default]
+    Synapse,
+    /// `current_users` and `monthly_active_users` as configured.
+    Static,
+    /// The JSON printed by `command`.
+    Command,
+}
+
+/// How the user counts announced to other servers are measured.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CapacityConfig {
+    pub provider: CapacityProviderKind,
+    pub current_users: u32,
+    pub monthly_active_users: Option<u32>,
+    /// Program and arguments, run without a shell.
+    pub command: Vec<String>,
+    /// How long the command may take.
+    pub timeout_seconds: u64,
+}
+
+/// Capabilities announced beyond those of the enabled features, which
+/// register their own.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CapabilitiesConfig {
+    pub advertise: Vec<String>,
+    /// Never announced, even by the feature providing them, so peers don't
+    /// use them with this bridge.
+    pub withhold: Vec<String>,
+}
+
+/// Where the federation identity key is used from.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SignerConfig {
+    /// External signing service holding the key, as an `http(s)://` URL or
+    /// `unix:` followed by a socket path. Unset signs with the keypair at
+    /// `signing_key_path`.
+    pub url: Option<String>,
+    pub timeout_ms: u64,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ClusterMode {
+    /// Every instance takes sends; only the leader polls and announces.
+    #[default]
+    ActiveActive,
+    /// The leader does all the work. Followers are hot standbys that refuse
+    /// requests until they take over, and a leader that loses mycelium
+    /// steps down for one that has it.
+    ActivePassive,
+}
+
+/// Inbound token bucket applied per remote server.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RateLimitConfig {
+    pub enabled: bool,
+    /// Messages a server may send at once before being throttled.
+    pub burst: u32,
+    /// Sustained messages per second per server.
+    pub per_second: f64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BatchingConfig {
+    pub enabled: bool,
+    pub max_events: usize,
+    pub max_delay_ms: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CompressionConfig {
+    pub enabled: bool,
+    pub threshold_bytes: usize,
+    pub level: i32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EncryptionConfig {
+    pub enabled: bool,
+    /// Refuse to exchange plaintext payloads with peers that lack support.
+    pub require: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct QueryConfig {
+    /// How long to wait for a remote bridge to answer a federation query.
+    pub timeout_seconds: u64,
+    /// Relay the knock handshake for rooms with knocking enabled.
+    pub knock_enabled: bool,
+    /// Answer and fan out federated user directory searches.
+    pub user_search_enabled: bool,
+    /// Overall deadline for collecting user search results from peers.
+    pub user_search_timeout_ms: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MediaConfig {
+    pub enabled: bool,
+    pub cache_dir: String,
+    pub max_cache_bytes: u64,
+    /// Largest file that will be transferred over the overlay.
+    pub max_media_bytes: usize,
+    pub chunk_size: usize,
+    pub timeout_seconds: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EduConfig {
+    /// How often the low-latency EDU topic is polled.
+    pub poll_interval_ms: u64,
+    /// Presence federation is opt-in, as it is by far the noisiest EDU.
+    pub presence_enabled: bool,
+    /// At most one presence EDU is sent per destination in this interval.
+    pub presence_interval_ms: u64,
+    /// Receipts for the same room and user within this window are coalesced.
+    pub receipt_delay_ms: u64,
+    /// Typing and presence EDUs are dropped by the sender and receiver
+    /// after this long, as they are worthless once stale. 0 never expires.
+    pub typing_ttl_seconds: u64,
+    pub presence_ttl_seconds: u64,
+}
+
+/// OpenTelemetry tracing. Trace context travels inside each
+/// `MyceliumMessage`, so one trace follows an event from the sending
+/// homeserver to the receiving one.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TelemetryConfig {
+    pub enabled: bool,
+    /// OTLP/HTTP traces endpoint of the collector.
+    pub otlp_endpoint: String,
+    pub service_name: String,
+    /// Fraction of new traces sampled. Traces started by a remote bridge or
+    /// the homeserver follow the caller's sampling decision.
+    pub sample_ratio: f64,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    /// One JSON object per line, with the fields of the enclosing spans.
+    Json,
+}
+
+/// How often the log file is rotated regardless of its size.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogRotation {
+    Never,
+    Hourly,
+    #[default]
+    Daily,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LoggingConfig {
+    pub format: LogFormat,
+    /// `tracing` filter directive, e.g. `info` or `matrix_mycelium_bridge=debug`.
+    pub level: String,
+    /// Levels for single modules on top of `level`, e.g.
+    /// `"matrix_mycelium_bridge::discovery" = "debug"`.
+    pub modules: BTreeMap<String, String>,
+    pub stdout: bool,
+    /// Log to the systemd journal, with the level as its priority.
+    pub journald: bool,
+    /// Also log to this file, rotated by size and age.
+    pub file_path: Option<String>,
+    /// Rotate the file once it grows past this size; 0 only rotates by age.
+    pub file_max_size_mb: u64,
+    pub file_rotation: LogRotation,
+    /// Rotated files kept besides the current one; older ones are deleted.
+    pub file_max_files: usize,
+}
+
+impl LoggingConfig {
+    /// `level` and the module levels as a single filter directive.
+    pub fn filter(&self) -> String {
+        self.modules
+            .iter()
+            .fold(self.level.clone(), |filter, (module, level)| format!("{},{}={}", filter, module, level))
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AdminConfig {
+    /// Bearer token for the `/admin` routes, alongside `auth.tokens` with
+    /// the admin scope. The admin API is disabled without any.
+    pub token: Option<String>,
+    /// How many sent messages are kept for manual resend.
+    pub recent_messages: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ShutdownConfig {
+    /// How long to wait for in-flight requests and queued messages on
+    /// SIGTERM/SIGINT before exiting anyway.
+    pub deadline_seconds: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PersistenceConfig {
+    /// Where the server directory is saved, and loaded from on startup.
+    /// Not persisted if unset.
+    pub directory_path: Option<String>,
+    pub save_interval_seconds: u64,
+    /// Saved servers not seen for this long are dropped on load.
+    pub max_age_hours: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DiscoveryConfig {
+    /// How often this server re-announces itself on the discovery topic.
+    pub announce_interval_seconds: u64,
+    /// Each interval is lengthened or shortened at random by up to this
+    /// much, so bridges started together don't all announce at once.
+    pub announce_jitter_seconds: u64,
+    /// Run without a discovery service: the directory is built from
+    /// announcements and gossip alone, and served at `/servers` for clients
+    /// to bootstrap from. Turns gossip on.
+    pub decentralized: bool,
+    /// HTTP discovery service to register with, in addition to announcing
+    /// on mycelium.
+    pub service_url: Option<String>,
+    /// Key the discovery service's operator issued, for services that only
+    /// accept registrations with one.
+    pub api_key: Option<String>,
+    /// How often a heartbeat keeps the registration alive. The discovery
+    /// service drops the server after three intervals without one.
+    pub heartbeat_interval_seconds: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GossipConfig {
+    /// Exchange directory digests with other bridges and pull the entries
+    /// this bridge is missing.
+    pub enabled: bool,
+    /// How often a digest is sent.
+    pub interval_seconds: u64,
+    /// Number of random peers each digest goes to.
+    pub fanout: usize,
+    /// How often the gossip topic is checked for messages.
+    pub poll_interval_seconds: u64,
+}
+
+/// Prefix of the environment variables that override config fields:
+/// `BRIDGE_SERVER_NAME`, or `BRIDGE_RATE_LIMIT__BURST` for a field in a
+/// section.
+pub const ENV_PREFIX: &str = "BRIDGE";
+
+/// Fields given as comma-separated lists in environment variables.
+const ENV_LIST_KEYS: &[&str] = &[
+    "mycelium_api_url",
+    "cors_origins",
+    "mycelium.announce_peers",
+    "http.no_proxy",
+    "federation.allowed_servers",
+    "federation.blocked_servers",
+    "federation.allowed_rooms",
+    "federation.blocked_rooms",
+    "validation.room_versions",
+    "capabilities.advertise",
+    "capabilities.withhold",
+    "capacity.command",
+];
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TlsConfig {
+    /// Serve HTTPS on `bind_address` instead of plain HTTP.
+    pub enabled: bool,
+    /// PEM certificate chain.
+    pub cert_path: String,
+    /// PEM private key.
+    pub key_path: String,
+    /// How often to check the certificate and key for renewal. 0 disables
+    /// reloading.
+    pub reload_interval_seconds: u64,
+}
+
+/// Bearer tokens for the bridge's own HTTP endpoints. The Matrix API routes
+/// keep the authentication the homeserver uses for them.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AuthConfig {
+    /// With no token granting `send`, the `/federation` endpoints refuse
+    /// every request, as do `/metrics` and `/stats` with none granting
+    /// `metrics`.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tokens: Vec<ApiToken>,
+    /// Serve the `/federation` endpoints without a token while none grants
+    /// `send`, and `/metrics` and `/stats` while none grants `metrics`. Only
+    /// allowed with a loopback `bind_address`.
+    pub allow_unauthenticated: bool,
+}
+
+/// A statically configured peer. It stays in the directory whether or not
+/// it announces, and its announcements must be signed with `public_key`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PeerConfig {
+    pub server_name: String,
+    pub mycelium_address: String,
+    /// Base64 Ed25519 key the peer signs its messages with.
+    pub public_key: String,
+    #[serde(default)]
+    pub capabilities: Vec<String>,
+}
+
+/// Comments written by `to_commented_toml` above a key (`section.key`) or
+/// a section header (`[section]`). After a section's first line come its
+/// optional settings that are unset by default, shown commented out below
+/// the header.
+const CONFIG_COMMENTS: &[(&str, &str)] = &[
+    ("server_name", "Matrix server name of the homeserver this bridge serves"),
+    ("bind_address", "Address the bridge's HTTP API listens on"),
+    ("matrix_homeserver_url", "URL the bridge reaches the local homeserver at"),
+    (
+        "mycelium_api_url",
+        "Mycelium API URL or unix:///path socket, or several in order of preference to fail over between",
+    ),
+    ("signing_key_path", "Ed25519 key signing bridge messages; generated if missing"),
+    ("max_users", "User capacity announced to other servers"),
+    (
+        "cors_origins",
+        "Origins browsers may call the bridge from, e.g. \"https://chat.example\"; \"*\" allows any",
+    ),
+    (
+        "[homeserver]",
+        "The local homeserver; flavor is \"synapse\", \"conduit\" or \"dendrite\"\n\
+         server_name = \"homeserver.example\"\n\
+         admin_token = \"syt_...\"  # Synapse admin API, used to report capacity\n\
+         buffer_path = \"inbound-buffer.json\"  # keep undelivered events across restarts",
+    ),
+    (
+        "[appservice]",
+        "Run as an application service instead of feeding the federation API",
+    ),
+    ("[mycelium]", "Connection to the local mycelium node"),
+    ("mycelium.legacy_api", "Use the pre-0.5 mycelium message API"),
+    ("mycelium.announce_peers", "Mycelium addresses or keys to announce this server to"),
+    (
+        "[http]",
+        "Requests to the homeserver, discovery service and signer\n\
+         proxy = \"socks5h://127.0.0.1:1080\"  # or an http(s):// proxy; no_proxy hosts bypass it",
+    ),
+    ("[batching]", "Coalesce outbound events per destination into transactions"),
+    ("[compression]", "zstd compression of large payloads, for peers that support it"),
+    ("[encryption]", "End-to-end encryption of payloads between bridges"),
+    ("encryption.require", "Refuse to send to peers that can't decrypt"),
+    ("[queries]", "Federation queries relayed to remote bridges"),
+    ("[edu]", "Ephemeral events: typing, presence and receipts"),
+    ("[media]", "Chunked media transfer between bridges"),
+    (
+        "[federation]",
+        "Servers and rooms to federate; an empty allow list allows everything not blocked\n\
+         blocked_rooms = [\"#*-internal:example.org\", \"!private:example.org\"]",
+    ),
+    ("[rate_limit]", "Inbound token bucket per remote server"),
+    ("[validation]", "Structural checks on inbound events; rejected events are reported to the sender"),
+    ("[idempotency]", "Deduplicate /federation/send retries by Idempotency-Key header or event ID"),
+    ("[send_queue]", "Queue for /federation/send requests with \"Prefer: respond-async\""),
+    ("[fanout]", "Sends to many servers at once: /federation/broadcast, announcements and gossip"),
+    ("[priority]", "Weighted scheduling of sends to each destination: interactive, normal, bulk"),
+    ("[stream]", "WebSocket stream of verified inbound events at /federation/stream"),
+    ("[metrics]", "Per-peer traffic and latency at /metrics (metrics token) and /admin/peers (admin token)"),
+    (
+        "[cluster]",
+        "Bridges sharing one homeserver through Redis; mode is \"active-active\" or \"active-passive\"\n\
+         node_id = \"bridge-1\"  # random if unset",
+    ),
+    ("[directory]", "Most servers kept in the directory; the least recently seen are evicted"),
+    ("directory.new_servers_per_hour", "New servers taken in per hour through each overlay node"),
+    (
+        "[liveness]",
+        "Peers silent for this many announcement intervals become unknown, then offline",
+    ),
+    ("[archive]", "Keep every message sent and received on disk, for GET /admin/archive"),
+    (
+        "[bandwidth]",
+        "Hourly traffic quotas per peer; action is \"throttle\" or \"drop\", 0 MB is unlimited",
+    ),
+    (
+        "[capacity]",
+        "Source of the user counts in announcements: \"synapse\", \"static\" or \"command\"\n\
+         current_users = 120  # with provider = \"static\"\n\
+         command = [\"/usr/local/bin/count-users\"]  # prints {\"current_users\": 120}",
+    ),
+    (
+        "[capabilities]",
+        "Announced capabilities; enabled features add theirs, and withhold hides any from peers",
+    ),
+    (
+        "[signer]",
+        "Sign through an external service holding the key instead of signing_key_path\n\
+         url = \"unix:/run/bridge-signer.sock\"  # or an http(s):// URL",
+    ),
+    ("[telemetry]", "OpenTelemetry tracing exported over OTLP/HTTP"),
+    (
+        "[logging]",
+        "Log format (\"text\" or \"json\"), levels and sinks; RUST_LOG overrides the levels\n\
+         file_path = \"/var/log/matrix-mycelium-bridge/bridge.log\"  # rotated by size and file_rotation",
+    ),
+    ("logging.file_rotation", "\"never\", \"hourly\" or \"daily\""),
+    (
+        "[logging.modules]",
+        "Per-module levels\n\
+         \"matrix_mycelium_bridge::discovery\" = \"debug\"",
+    ),
+    (
+        "[admin]",
+        "Admin API under /admin, enabled by setting a bearer token\n\
+         token = \"change-me\"",
+    ),
+    ("[shutdown]", "Time allowed to drain queued work on SIGTERM/SIGINT"),
+    ("[persistence]", "Where the server directory is saved across restarts"),
+    (
+        "[discovery]",
+        "Server announcements on the mycelium discovery topic; decentralized serves /servers locally\n\
+         service_url = \"https://discovery.example\"  # also register with a discovery service\n\
+         api_key = \"...\"  # for an invite-only discovery service",
+    ),
+    ("[gossip]", "Directory exchange with other bridges, independent of the discovery service"),
+    (
+        "[auth]",
+        "Bearer tokens for /federation (scope \"send\"), /admin (\"admin\"), /metrics and /stats (\"metrics\")\n\
+         tokens = [{ name = \"synapse\", token = \"change-me\", scopes = [\"send\"] }]",
+    ),
+    (
+        "auth.allow_unauthenticated",
+        "Serve /federation, /metrics and /stats without a token while none has their scope; loopback only",
+    ),
+    ("[tls]", "Serve HTTPS directly with these PEM files, reloaded when they change"),
+];
+
+const CONFIG_HEADER: &str = "\
+# Matrix-Mycelium bridge configuration
+#
+# Every field can be overridden with a BRIDGE_* environment variable, e.g.
+# BRIDGE_SERVER_NAME or BRIDGE_RATE_LIMIT__BURST for a field in a section.
+# Send SIGHUP to reload max_users, [federation], [rate_limit] and [discovery].
+
+";
+
+const PEERS_EXAMPLE: &str = "
+# Peers to federate with even if they never announce
+# [[peers]]
+# server_name = \"peer.example\"
+# mycelium_address = \"400::1\"
+# public_key = \"base64 Ed25519 key\"
+";
+
+/// Placeholder for secrets in printed configs.
+const REDACTED: &str = "<redacted>";
+
 impl BridgeConfig {
+    /// Load the config file, with `BRIDGE_*` environment variables taking
+    /// precedence over it.
     pub fn from_file(path: &str) -> Result<Self> {
-        let content = fs::read_to_string(path)?;
-        let config: BridgeConfig = toml::from_str(&content)?;
+        let mut environment = ::config::Environment::with_prefix(ENV_PREFIX)
+            .prefix_separator("_")
+            .separator("__")
+            .try_parsing(true)
+            .list_separator(",");
+        for key in ENV_LIST_KEYS {
+            environment = environment.with_list_parse_key(key);
+        }
+
+        let config = ::config::Config::builder()
+            .add_source(::config::File::new(path, ::config::FileFormat::Toml))
+            .add_source(environment)
+            .build()?
+            .try_deserialize()?;
         Ok(config)
     }
-    
-    pub fn default() -> Self {
+
+    /// The config as TOML, with comments describing the settings.
+    pub fn to_commented_toml(&self) -> Result<String> {
+        let plain = toml::to_string_pretty(self)?;
+        let mut commented = String::from(CONFIG_HEADER);
+        let mut section = String::new();
+
+        for line in plain.lines() {
+            let key = if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                section = name.to_string();
+                line.to_string()
+            } else if let Some((key, _)) = line.split_once(" = ") {
+                if section.is_empty() {
+                    key.to_string()
+                } else {
+                    format!("{}.{}", section, key)
+                }
+            } else {
+                String::new()
+            };
+
+            let comment = CONFIG_COMMENTS
+                .iter()
+                .find(|(name, _)| *name == key)
+                .map(|(_, comment)| comment.lines().map(str::trim_start).collect::<Vec<_>>())
+                .unwrap_or_default();
+            let (above, below) = if line.starts_with('[') && !comment.is_empty() {
+                comment.split_at(1)
+            } else {
+                (comment.as_slice(), &[][..])
+            };
+
+            for comment_line in above {
+                commented.push_str(&format!("# {}\n", comment_line));
+            }
+            commented.push_str(line);
+            commented.push('\n');
+            for comment_line in below {
+                commented.push_str(&format!("# {}\n", comment_line));
+            }
+        }
+
+        if self.peers.is_empty() {
+            commented.push_str(PEERS_EXAMPLE);
+        }
+
+        Ok(commented)
+    }
+
+    /// Gossip is always on in decentralized mode, as it is then the only way
+    /// to learn about servers that don't announce to this bridge directly.
+    pub fn gossip_enabled(&self) -> bool {
+        self.gossip.enabled || self.discovery.decentralized
+    }
+
+    /// A copy safe to print, with tokens replaced by a placeholder.
+    pub fn redacted(&self) -> Self {
+        let mut config = self.clone();
+        for secret in [
+            &mut config.homeserver.admin_token,
+            &mut config.admin.token,
+            &mut config.discovery.api_key,
+        ] {
+            if secret.is_some() {
+                *secret = Some(REDACTED.to_string());
+            }
+        }
+        for token in &mut config.auth.tokens {
+            token.token = REDACTED.to_string();
+        }
+        if let Some(proxy) = &mut config.http.proxy {
+            let url = reqwest::Url::parse(proxy).ok();
+            if let Some(password) = url.as_ref().and_then(|url| url.password()) {
+                *proxy = proxy.replacen(&format!(":{}@", password), &format!(":{}@", REDACTED), 1);
+            }
+        }
+        config
+    }
+
+    /// Check the settings that would otherwise only fail once the bridge is
+    /// running, reporting every problem at once.
+    pub fn validate(&self) -> Result<()> {
+        let mut problems = Vec::new();
+
+        if self.server_name.is_empty() || self.server_name.contains("://") {
+            problems.push(format!(
+                "server_name {:?} must be a bare server name such as \"example.org\"",
+                self.server_name
+            ));
+        }
+        if let Err(e) = self.bind_address.parse::<std::net::SocketAddr>() {
+            problems.push(format!(
+                "bind_address {:?} is not an address and port such as \"127.0.0.1:8080\": {}",
+                self.bind_address, e
+            ));
+        }
+        check_http_url(&mut problems, "matrix_homeserver_url", &self.matrix_homeserver_url);
+        for origin in &self.cors_origins {
+            if origin != "*" && axum::http::HeaderValue::from_str(origin).is_err() {
+                problems.push(format!("cors_origins entry {:?} is not a valid origin", origin));
+            }
+        }
+        if self.mycelium_api_url.is_empty() {
+            problems.push("mycelium_api_url must list at least one URL".to_string());
+        }
+        for url in &self.mycelium_api_url {
+            if !url.starts_with(UNIX_SCHEME) {
+                check_http_url(&mut problems, "mycelium_api_url", url);
+            }
+        }
+        if self.mycelium.inbound_concurrency == 0 || self.mycelium.inbound_max_pending == 0 {
+            problems.push(
+                "mycelium.inbound_concurrency and inbound_max_pending must be positive".to_string(),
+            );
+        }
+        let http = &self.http;
+        if http.connect_timeout_seconds == 0 || http.request_timeout_seconds == 0 {
+            problems.push(
+                "http.connect_timeout_seconds and request_timeout_seconds must be positive".to_string(),
+            );
+        }
+        if let Some(proxy) = &http.proxy {
+            let schemes = ["http", "https", "socks5", "socks5h"];
+            match reqwest::Url::parse(proxy) {
+                Ok(url) if schemes.contains(&url.scheme()) && url.has_host() => {}
+                _ => problems.push(format!(
+                    "http.proxy {:?} must be an http://, https://, socks5:// or socks5h:// URL",
+                    proxy
+                )),
+            }
+        }
+        if self.appservice.enabled {
+            check_http_url(&mut problems, "appservice.url", &self.appservice.url);
+        }
+        if self.telemetry.enabled {
+            check_http_url(&mut problems, "telemetry.otlp_endpoint", &self.telemetry.otlp_endpoint);
+        }
+        if !(0.0..=1.0).contains(&self.telemetry.sample_ratio) {
+            problems.push("telemetry.sample_ratio must be between 0 and 1".to_string());
+        }
+        if let Err(e) = tracing_subscriber::EnvFilter::try_new(&self.logging.level) {
+            problems.push(format!("logging.level {:?} is not a valid filter: {}", self.logging.level, e));
+        }
+        for (module, level) in &self.logging.modules {
+            if let Err(e) = tracing_subscriber::EnvFilter::try_new(format!("{}={}", module, level)) {
+                problems.push(format!("logging.modules.{} {:?} is not a valid level: {}", module, level, e));
+            }
+        }
+        if !self.logging.stdout && !self.logging.journald && self.logging.file_path.is_none() {
+            problems.push("logging needs stdout, journald or a file_path to log to".to_string());
+        }
+        if self.logging.file_path.is_some() && self.logging.file_max_files == 0 {
+            problems.push("logging.file_max_files must be positive".to_string());
+        }
+        if self.persistence.directory_path.is_some() && self.persistence.save_interval_seconds == 0 {
+            problems.push("persistence.save_interval_seconds must be positive".to_string());
+        }
+        if self.discovery.announce_interval_seconds == 0 {
+            problems.push("discovery.announce_interval_seconds must be positive".to_string());
+        }
+        if self.discovery.announce_jitter_seconds >= self.discovery.announce_interval_seconds {
+            problems.push(
+                "discovery.announce_jitter_seconds must be below announce_interval_seconds".to_string(),
+            );
+        }
+        if let Some(url) = &self.discovery.service_url {
+            check_http_url(&mut problems, "discovery.service_url", url);
+            if self.discovery.decentralized {
+                problems.push("discovery.service_url can't be set in decentralized mode".to_string());
+            }
+            if self.discovery.heartbeat_interval_seconds == 0 {
+                problems.push("discovery.heartbeat_interval_seconds must be positive".to_string());
+            }
+        }
+        if self.discovery.api_key.as_deref() == Some("") {
+            problems.push("discovery.api_key must not be empty; remove it to go without".to_string());
+        }
+        let gossip = &self.gossip;
+        if self.gossip_enabled() && (gossip.interval_seconds == 0 || gossip.poll_interval_seconds == 0) {
+            problems.push("gossip intervals must be positive".to_string());
+        }
+        if self.rate_limit.enabled && (self.rate_limit.burst == 0 || self.rate_limit.per_second <= 0.0) {
+            problems.push("rate_limit.burst and rate_limit.per_second must be positive".to_string());
+        }
+        if self.validation.enabled
+            && (self.validation.max_event_bytes == 0 || self.validation.max_transaction_pdus == 0)
+        {
+            problems.push("validation.max_event_bytes and max_transaction_pdus must be positive".to_string());
+        }
+        if self.idempotency.enabled && (self.idempotency.ttl_seconds == 0 || self.idempotency.max_keys == 0) {
+            problems.push("idempotency.ttl_seconds and idempotency.max_keys must be positive".to_string());
+        }
+        let homeserver = &self.homeserver;
+        if homeserver.delivery_attempts == 0 || homeserver.buffer_max_events == 0 {
+            problems.push("homeserver.delivery_attempts and buffer_max_events must be positive".to_string());
+        }
+        let send_queue = &self.send_queue;
+        if send_queue.capacity == 0 || send_queue.concurrency == 0 || send_queue.tracked_messages == 0 {
+            problems.push("send_queue sizes must be positive".to_string());
+        }
+        if self.fanout.concurrency == 0 || self.fanout.max_destinations == 0 {
+            problems.push("fanout.concurrency and max_destinations must be positive".to_string());
+        }
+        let priority = &self.priority;
+        let weights = [priority.interactive_weight, priority.normal_weight, priority.bulk_weight];
+        if priority.max_in_flight_per_destination == 0 || weights.contains(&0) {
+            problems.push("priority.max_in_flight_per_destination and weights must be positive".to_string());
+        }
+        if self.stream.enabled && self.stream.buffer == 0 {
+            problems.push("stream.buffer must be positive".to_string());
+        }
+        if self.stream.exclusive && !self.stream.enabled {
+            problems.push("stream.exclusive needs stream.enabled".to_string());
+        }
+        if self.cluster.enabled {
+            let cluster = &self.cluster;
+            if let Err(e) = redis::Client::open(cluster.redis_url.as_str()) {
+                problems.push(format!("cluster.redis_url {:?} is not a Redis URL: {}", cluster.redis_url, e));
+            }
+            if cluster.lease_seconds < 3 || cluster.sync_interval_seconds == 0 {
+                problems.push(
+                    "cluster.lease_seconds must be at least 3 and sync_interval_seconds positive".to_string(),
+                );
+            }
+        }
+        if self.directory.max_servers <= self.peers.len() {
+            problems.push("directory.max_servers must leave room beyond the static peers".to_string());
+        }
+        if self.directory.new_servers_per_hour == 0 {
+            problems.push("directory.new_servers_per_hour must be positive".to_string());
+        }
+        let liveness = &self.liveness;
+        let (unknown, offline) = (liveness.unknown_after_missed, liveness.offline_after_missed);
+        if unknown == 0 || offline <= unknown {
+            problems.push(
+                "liveness.unknown_after_missed must be positive and below offline_after_missed".to_string(),
+            );
+        }
+        if liveness.check_interval_seconds == 0 || liveness.held_messages == 0 {
+            problems.push("liveness.check_interval_seconds and held_messages must be positive".to_string());
+        }
+        let archive = &self.archive;
+        if archive.enabled {
+            if archive.segment_mb == 0 || archive.max_age_hours == 0 || archive.max_results == 0 {
+                problems.push("archive sizes, max_age_hours and max_results must be positive".to_string());
+            }
+            if archive.max_mb < archive.segment_mb {
+                problems.push("archive.max_mb must be at least archive.segment_mb".to_string());
+            }
+        }
+        let bandwidth = &self.bandwidth;
+        if bandwidth.action == QuotaAction::Throttle && bandwidth.max_delay_seconds == 0 {
+            problems.push("bandwidth.max_delay_seconds must be positive".to_string());
+        }
+        let capacity = &self.capacity;
+        if capacity.provider == CapacityProviderKind::Command && capacity.command.is_empty() {
+            problems.push("capacity.command must be set with the command provider".to_string());
+        }
+        if capacity.timeout_seconds == 0 {
+            problems.push("capacity.timeout_seconds must be positive".to_string());
+        }
+        let capabilities = self.capabilities.advertise.iter().chain(&self.capabilities.withhold);
+        if capabilities.into_iter().any(|capability| capability.trim().is_empty()) {
+            problems.push("capabilities entries must not be empty".to_string());
+        }
+        if let Some(url) = &self.signer.url {
+            if !url.starts_with(UNIX_SCHEME) {
+                check_http_url(&mut problems, "signer.url", url);
+            }
+            if self.signer.timeout_ms == 0 {
+                problems.push("signer.timeout_ms must be positive".to_string());
+            }
+            if self.encryption.enabled {
+                problems.push("encryption needs the local signing key, not signer.url".to_string());
+            }
+        }
+        if self.tls.enabled {
+            let files = [("tls.cert_path", &self.tls.cert_path), ("tls.key_path", &self.tls.key_path)];
+            for (field, path) in files {
+                if !std::path::Path::new(path).is_file() {
+                    problems.push(format!("{} {:?} does not exist", field, path));
+                }
+            }
+        }
+        for token in &self.auth.tokens {
+            if token.token.is_empty() || token.scopes.is_empty() {
+                problems.push(format!("auth token {:?} needs a non-empty token and scopes", token.name));
+            }
+        }
+        let loopback = self
+            .bind_address
+            .parse::<std::net::SocketAddr>()
+            .is_ok_and(|address| address.ip().is_loopback());
+        if self.auth.allow_unauthenticated && !loopback {
+            problems.push(format!(
+                "auth.allow_unauthenticated needs a loopback bind_address, not {:?}",
+                self.bind_address
+            ));
+        }
+        let mut peer_names = std::collections::HashSet::new();
+        for peer in &self.peers {
+            if peer.server_name.is_empty() || peer.mycelium_address.is_empty() {
+                problems.push("peers need a server_name and mycelium_address".to_string());
+            }
+            if peer.server_name == self.server_name {
+                problems.push(format!("peers must not include this server, {:?}", peer.server_name));
+            }
+            if !peer_names.insert(&peer.server_name) {
+                problems.push(format!("peer {:?} is configured more than once", peer.server_name));
+            }
+            let key = base64::engine::general_purpose::STANDARD.decode(&peer.public_key);
+            if !key.is_ok_and(|key| key.len() == 32) {
+                problems.push(format!("peer {:?} public_key is not a base64 Ed25519 key", peer.server_name));
+            }
+        }
+        if self.admin.token.as_deref() == Some("") {
+            problems.push("admin.token must not be empty; remove it to disable the admin API".to_string());
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(anyhow::anyhow!("Invalid configuration:\n  - {}", problems.join("\n  - ")))
+        }
+    }
+}
+
+fn check_http_url(problems: &mut Vec<String>, field: &str, url: &str) {
+    match reqwest::Url::parse(url) {
+        Ok(parsed) if matches!(parsed.scheme(), "http" | "https") && parsed.has_host() => {}
+        Ok(_) => problems.push(format!("{} {:?} must be an http:// or https:// URL", field, url)),
+        Err(e) => problems.push(format!(
+            "{} {:?} is not a valid URL (include the scheme, e.g. \"http://{}\"): {}",
+            field, url, url, e
+        )),
+    }
+}
+
+impl Default for BridgeConfig {
+    fn default() -> Self {
         Self {
             server_name: "matrix.localhost".to_string(),
             bind_address: "127.0.0.1:8080".to_string(),
             matrix_homeserver_url: "http://localhost:8008".to_string(),
-            mycelium_api_url: "http://localhost:8989".to_string(),
+            mycelium_api_url: vec!["http://localhost:8989".to_string()],
             signing_key_path: "./data/signing.key".to_string(),
             max_users: 1000,
+            cors_origins: Vec::new(),
+            homeserver: HomeserverConfig::default(),
+            appservice: AppserviceConfig::default(),
+            mycelium: MyceliumConfig::default(),
+            http: HttpConfig::default(),
+            batching: BatchingConfig::default(),
+            compression: CompressionConfig::default(),
+            encryption: EncryptionConfig::default(),
+            queries: QueryConfig::default(),
+            edu: EduConfig::default(),
+            media: MediaConfig::default(),
+            federation: FederationConfig::default(),
+            rate_limit: RateLimitConfig::default(),
+            validation: ValidationConfig::default(),
+            idempotency: IdempotencyConfig::default(),
+            send_queue: SendQueueConfig::default(),
+            fanout: FanoutConfig::default(),
+            priority: PriorityConfig::default(),
+            stream: StreamConfig::default(),
+            metrics: MetricsConfig::default(),
+            cluster: ClusterConfig::default(),
+            signer: SignerConfig::default(),
+            capabilities: CapabilitiesConfig::default(),
+            capacity: CapacityConfig::default(),
+            bandwidth: BandwidthConfig::default(),
+            archive: ArchiveConfig::default(),
+            liveness: LivenessConfig::default(),
+            directory: DirectoryConfig::default(),
+            telemetry: TelemetryConfig::default(),
+            logging: LoggingConfig::default(),
+            admin: AdminConfig::default(),
+            shutdown: ShutdownConfig::default(),
+            persistence: PersistenceConfig::default(),
+            discovery: DiscoveryConfig::default(),
+            gossip: GossipConfig::default(),
+            tls: TlsConfig::default(),
+            auth: AuthConfig::default(),
+            peers: Vec::new(),
         }
     }
 }
 
-impl Default for BridgeConfig {
+impl Default for MyceliumConfig {
+    fn default() -> Self {
+        Self {
+            long_poll: true,
+            poll_timeout_seconds: 30,
+            poll_interval_ms: 5000,
+            legacy_api: false,
+            announce_peers: Vec::new(),
+            connect_timeout_seconds: 5,
+            health_check_interval_seconds: 30,
+            request_timeout_seconds: 10,
+            max_retries: 3,
+            retry_base_delay_ms: 200,
+            pool_max_idle_per_host: 16,
+            inbound_concurrency: 8,
+            inbound_max_pending: 1000,
+        }
+    }
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        Self {
+            connect_timeout_seconds: 10,
+            request_timeout_seconds: 60,
+            proxy: None,
+            no_proxy: Vec::new(),
+            tcp_keepalive_seconds: 60,
+            pool_idle_timeout_seconds: 90,
+            max_retries: 2,
+            retry_base_delay_ms: 250,
+        }
+    }
+}
+
+impl Default for BatchingConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            max_events: 50,
+            max_delay_ms: 100,
+        }
+    }
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            threshold_bytes: 1024,
+            level: 3,
+        }
+    }
+}
+
+impl Default for EncryptionConfig {
     fn default() -> Self {
-        Self::default()
+        Self {
+            enabled: true,
+            require: false,
+        }
+    }
+}
+
+impl Default for QueryConfig {
+    fn default() -> Self {
+        Self {
+            timeout_seconds: 30,
+            knock_enabled: true,
+            user_search_enabled: true,
+            user_search_timeout_ms: 5000,
+        }
+    }
+}
+
+impl Default for EduConfig {
+    fn default() -> Self {
+        Self {
+            poll_interval_ms: 500,
+            presence_enabled: false,
+            presence_interval_ms: 10_000,
+            receipt_delay_ms: 1000,
+            typing_ttl_seconds: 30,
+            presence_ttl_seconds: 120,
+        }
+    }
+}
+
+impl Default for MediaConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            cache_dir: "./data/media".to_string(),
+            max_cache_bytes: 512 * 1024 * 1024,
+            max_media_bytes: 50 * 1024 * 1024,
+            chunk_size: 32 * 1024,
+            timeout_seconds: 120,
+        }
+    }
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            burst: 200,
+            per_second: 50.0,
+        }
+    }
+}
+
+impl Default for AppserviceConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            id: "mycelium-bridge".to_string(),
+            url: "http://127.0.0.1:8080".to_string(),
+            registration_path: "./data/appservice-registration.yaml".to_string(),
+            sender_localpart: "mycelium-bridge".to_string(),
+            user_prefix: "_mycelium_".to_string(),
+        }
+    }
+}
+
+impl Default for HomeserverConfig {
+    fn default() -> Self {
+        Self {
+            flavor: HomeserverFlavor::default(),
+            sign_requests: false,
+            server_name: None,
+            admin_token: None,
+            capacity_cache_seconds: 300,
+            delivery_attempts: 3,
+            retry_delay_ms: 500,
+            max_retry_delay_seconds: 60,
+            buffer_path: None,
+            buffer_max_events: 10000,
+        }
+    }
+}
+
+impl Default for ValidationConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            max_event_bytes: 65536,
+            max_transaction_pdus: 50,
+            room_versions: Vec::new(),
+        }
+    }
+}
+
+impl Default for IdempotencyConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            ttl_seconds: 600,
+            max_keys: 10000,
+        }
+    }
+}
+
+impl Default for SendQueueConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 1000,
+            concurrency: 16,
+            tracked_messages: 10000,
+        }
+    }
+}
+
+impl Default for FanoutConfig {
+    fn default() -> Self {
+        Self {
+            concurrency: 8,
+            max_destinations: 1000,
+        }
+    }
+}
+
+impl Default for PriorityConfig {
+    fn default() -> Self {
+        Self {
+            max_in_flight_per_destination: 4,
+            interactive_weight: 8,
+            normal_weight: 4,
+            bulk_weight: 1,
+        }
+    }
+}
+
+impl Default for StreamConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            buffer: 1024,
+            exclusive: false,
+        }
+    }
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        Self {
+            latency_warning_ms: 5000,
+        }
+    }
+}
+
+impl Default for ClusterConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            mode: ClusterMode::ActiveActive,
+            redis_url: "redis://127.0.0.1:6379".to_string(),
+            node_id: None,
+            key_prefix: "matrix-mycelium-bridge".to_string(),
+            lease_seconds: 15,
+            sync_interval_seconds: 10,
+        }
+    }
+}
+
+impl Default for DirectoryConfig {
+    fn default() -> Self {
+        Self {
+            max_servers: 10_000,
+            new_servers_per_hour: 100,
+        }
+    }
+}
+
+impl Default for LivenessConfig {
+    fn default() -> Self {
+        Self {
+            unknown_after_missed: 2,
+            offline_after_missed: 4,
+            check_interval_seconds: 30,
+            held_messages: 1000,
+        }
+    }
+}
+
+impl Default for ArchiveConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            path: "archive".to_string(),
+            redact_payloads: false,
+            segment_mb: 10,
+            max_mb: 100,
+            max_age_hours: 72,
+            max_results: 1000,
+        }
+    }
+}
+
+impl Default for BandwidthConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            sent_mb_per_hour: 0,
+            received_mb_per_hour: 0,
+            action: QuotaAction::default(),
+            max_delay_seconds: 30,
+            servers: Vec::new(),
+        }
+    }
+}
+
+impl Default for CapacityConfig {
+    fn default() -> Self {
+        Self {
+            provider: CapacityProviderKind::default(),
+            current_users: 0,
+            monthly_active_users: None,
+            command: Vec::new(),
+            timeout_seconds: 10,
+        }
+    }
+}
+
+impl Default for CapabilitiesConfig {
+    fn default() -> Self {
+        Self {
+            advertise: BASE_CAPABILITIES.iter().map(|capability| capability.to_string()).collect(),
+            withhold: Vec::new(),
+        }
+    }
+}
+
+impl Default for SignerConfig {
+    fn default() -> Self {
+        Self {
+            url: None,
+            timeout_ms: 2000,
+        }
+    }
+}
+
+impl Default for TelemetryConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            otlp_endpoint: "http://localhost:4318/v1/traces".to_string(),
+            service_name: "matrix-mycelium-bridge".to_string(),
+            sample_ratio: 1.0,
+        }
+    }
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        Self {
+            format: LogFormat::default(),
+            level: "info".to_string(),
+            modules: BTreeMap::new(),
+            stdout: true,
+            journald: false,
+            file_path: None,
+            file_max_size_mb: 100,
+            file_rotation: LogRotation::default(),
+            file_max_files: 7,
+        }
+    }
+}
+
+impl Default for AdminConfig {
+    fn default() -> Self {
+        Self {
+            token: None,
+            recent_messages: 1000,
+        }
+    }
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        Self {
+            deadline_seconds: 30,
+        }
+    }
+}
+
+impl Default for PersistenceConfig {
+    fn default() -> Self {
+        Self {
+            directory_path: Some("./data/directory.json".to_string()),
+            save_interval_seconds: 60,
+            max_age_hours: 24,
+        }
+    }
+}
+
+impl Default for DiscoveryConfig {
+    fn default() -> Self {
+        Self {
+            announce_interval_seconds: 300,
+            announce_jitter_seconds: 30,
+            decentralized: false,
+            service_url: None,
+            api_key: None,
+            heartbeat_interval_seconds: 120,
+        }
+    }
+}
+
+impl Default for GossipConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            interval_seconds: 120,
+            fanout: 3,
+            poll_interval_seconds: 10,
+        }
+    }
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            cert_path: "./data/tls/cert.pem".to_string(),
+            key_path: "./data/tls/key.pem".to_string(),
+            reload_interval_seconds: 3600,
+        }
     }
 }
@@ -0,0 +1,199 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use tokio::sync::Mutex;
+
+use crate::types::FederationEvent;
+
+/// Sent back by the receiving bridge once it has handed messages to its
+/// homeserver.
+pub const DELIVERY_ACK_MESSAGE_TYPE: &str = "delivery_ack";
+
+/// Advertised by bridges that want [`DELIVERY_ACK_MESSAGE_TYPE`] messages.
+pub const DELIVERY_ACK_CAPABILITY: &str = "delivery.ack";
+
+/// `Prefer` header value asking `/federation/send` to return before sending.
+pub const RESPOND_ASYNC: &str = "respond-async";
+
+/// An event accepted by the asynchronous send API, waiting to be sent.
+#[derive(Serialize, Deserialize)]
+pub struct QueuedSend {
+    pub message_id: String,
+    pub event: FederationEvent,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeliveryAck {
+    /// Event IDs of the delivered PDUs.
+    pub event_ids: Vec<String>,
+    /// Correlation IDs of the delivered messages, when known.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub message_ids: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DeliveryState {
+    /// Waiting in the send queue.
+    Queued,
+    /// Held for its destination, which is offline, until it is back.
+    Held,
+    /// Handed to mycelium.
+    Sent,
+    /// Delivered to the destination's homeserver.
+    Acked,
+    /// Not sent, or rejected by the destination.
+    Failed,
+}
+
+/// What became of a message the bridge was asked to send.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SendOutcome {
+    /// Handed to mycelium.
+    Sent,
+    /// Held for its destination, which is offline, until it is back.
+    Held,
+}
+
+impl SendOutcome {
+    pub fn state(self) -> DeliveryState {
+        match self {
+            Self::Sent => DeliveryState::Sent,
+            Self::Held => DeliveryState::Held,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeliveryStatus {
+    pub message_id: String,
+    pub destination: String,
+    /// ID the destination acks the message by: the event ID, if it has one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub event_id: Option<String>,
+    pub state: DeliveryState,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl DeliveryStatus {
+    pub(crate) fn queued(message_id: &str, destination: &str, event_id: Option<&str>) -> Self {
+        Self {
+            message_id: message_id.to_string(),
+            destination: destination.to_string(),
+            event_id: event_id.map(str::to_string),
+            state: DeliveryState::Queued,
+            error: None,
+            updated_at: Utc::now(),
+        }
+    }
+
+    pub(crate) fn set(&mut self, state: DeliveryState, error: Option<String>) {
+        self.state = state;
+        self.error = error;
+        self.updated_at = Utc::now();
+    }
+}
+
+#[derive(Default)]
+struct Tracked {
+    statuses: HashMap<String, DeliveryStatus>,
+    /// Message IDs by destination and event ID, to match acks and errors.
+    by_event: HashMap<(String, String), String>,
+    /// Message IDs oldest first, to forget the oldest when full.
+    order: VecDeque<String>,
+}
+
+impl Tracked {
+    fn find_mut(&mut self, destination: &str, event_id: &str) -> Option<&mut DeliveryStatus> {
+        let message_id = self.by_event.get(&(destination.to_string(), event_id.to_string()))?;
+        self.statuses.get_mut(message_id)
+    }
+}
+
+/// State of the messages sent through the asynchronous send API.
+pub struct DeliveryTracker {
+    capacity: usize,
+    tracked: Mutex<Tracked>,
+}
+
+impl DeliveryTracker {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            tracked: Mutex::new(Tracked::default()),
+        }
+    }
+
+    /// Start tracking a message as queued. Returns its message ID.
+    pub async fn queue(&self, destination: &str, event_id: Option<&str>) -> String {
+        let message_id = uuid::Uuid::new_v4().to_string();
+        let mut tracked = self.tracked.lock().await;
+        if tracked.order.len() >= self.capacity {
+            if let Some(oldest) = tracked.order.pop_front() {
+                if let Some(status) = tracked.statuses.remove(&oldest) {
+                    if let Some(event_id) = status.event_id {
+                        let key = (status.destination, event_id);
+                        // Unless the event has been sent again since
+                        if tracked.by_event.get(&key) == Some(&oldest) {
+                            tracked.by_event.remove(&key);
+                        }
+                    }
+                }
+            }
+        }
+        if let Some(event_id) = event_id {
+            tracked
+                .by_event
+                .insert((destination.to_string(), event_id.to_string()), message_id.clone());
+        }
+        tracked.order.push_back(message_id.clone());
+        tracked
+            .statuses
+            .insert(message_id.clone(), DeliveryStatus::queued(&message_id, destination, event_id));
+        message_id
+    }
+
+    /// Record the outcome of handing a message to mycelium. An ack or error
+    /// that overtook the send result is kept.
+    pub async fn sent(&self, message_id: &str, result: Result<SendOutcome, String>) {
+        let mut tracked = self.tracked.lock().await;
+        let Some(status) = tracked.statuses.get_mut(message_id) else {
+            return;
+        };
+        if status.state != DeliveryState::Queued {
+            return;
+        }
+        match result {
+            Ok(outcome) => status.set(outcome.state(), None),
+            Err(e) => status.set(DeliveryState::Failed, Some(e)),
+        }
+    }
+
+    /// Mark the messages `source` acknowledged as delivered.
+    pub async fn acknowledge(&self, source: &str, event_ids: &[String]) {
+        let mut tracked = self.tracked.lock().await;
+        for event_id in event_ids {
+            if let Some(status) = tracked.find_mut(source, event_id) {
+                if status.state != DeliveryState::Failed {
+                    status.set(DeliveryState::Acked, None);
+                }
+            }
+        }
+    }
+
+    /// Mark a message `source` rejected as failed. Only the message's
+    /// destination can reject it.
+    pub async fn reject(&self, source: &str, event_id: &str, reason: &str) {
+        let mut tracked = self.tracked.lock().await;
+        let status = tracked.find_mut(source, event_id);
+        if let Some(status) = status.filter(|status| status.destination == source) {
+            status.set(DeliveryState::Failed, Some(reason.to_string()));
+        }
+    }
+
+    pub async fn get(&self, message_id: &str) -> Option<DeliveryStatus> {
+        self.tracked.lock().await.statuses.get(message_id).cloned()
+    }
+}
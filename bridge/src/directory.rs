@@ -0,0 +1,118 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::types::{ServerAnnouncement, ServerInfo, ServerStatus};
+
+/// Longest server name accepted, as for DNS names with a port.
+const MAX_SERVER_NAME_LENGTH: usize = 255;
+
+/// Mycelium addresses are IPv6 addresses or hex encoded public keys.
+const MAX_ADDRESS_LENGTH: usize = 64;
+
+const MAX_CAPABILITIES: usize = 64;
+const MAX_CAPABILITY_LENGTH: usize = 64;
+
+/// Reject announcements no real bridge would make, before they take up
+/// room in the directory.
+pub fn check_announcement(announcement: &ServerAnnouncement) -> Result<(), String> {
+    let name = &announcement.server_name;
+    if name.is_empty() || name.len() > MAX_SERVER_NAME_LENGTH {
+        return Err(format!("server name of {} bytes", name.len()));
+    }
+    let allowed = |c: char| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | ':' | '[' | ']');
+    if !name.chars().all(allowed) {
+        return Err(format!("invalid server name {:?}", name));
+    }
+    let address = &announcement.mycelium_address;
+    if address.is_empty() || address.len() > MAX_ADDRESS_LENGTH {
+        return Err(format!("mycelium address of {} bytes", address.len()));
+    }
+    if announcement.capabilities.len() > MAX_CAPABILITIES {
+        return Err(format!("{} capabilities", announcement.capabilities.len()));
+    }
+    if announcement.capabilities.iter().any(|capability| capability.len() > MAX_CAPABILITY_LENGTH) {
+        return Err("capability name too long".to_string());
+    }
+    Ok(())
+}
+
+/// The entry to drop to make room in a full directory: untrusted and
+/// offline servers before unknown ones before online ones, the least
+/// recently seen first. Entries for which `keep` is true are never picked.
+pub fn eviction_candidate(
+    directory: &HashMap<String, ServerInfo>,
+    keep: impl Fn(&ServerInfo) -> bool,
+) -> Option<String> {
+    let rank = |status: ServerStatus| match status {
+        ServerStatus::Untrusted | ServerStatus::Offline => 0,
+        ServerStatus::Unknown => 1,
+        ServerStatus::Online => 2,
+    };
+    directory
+        .values()
+        .filter(|server| !keep(server))
+        .min_by_key(|server| (rank(server.status), server.last_seen))
+        .map(|server| server.server_name.clone())
+}
+
+/// Counts of what was kept out of the directory.
+#[derive(Default)]
+pub struct DirectoryStats {
+    evicted: AtomicU64,
+    rejected: AtomicU64,
+}
+
+impl DirectoryStats {
+    pub fn record_eviction(&self) {
+        self.evicted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_rejection(&self) {
+        self.rejected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn evicted(&self) -> u64 {
+        self.evicted.load(Ordering::Relaxed)
+    }
+
+    pub fn rejected(&self) -> u64 {
+        self.rejected.load(Ordering::Relaxed)
+    }
+
+    /// Directory size and counters in the Prometheus text format.
+    pub fn render_prometheus(&self, servers: usize, max_servers: usize) -> String {
+        let mut output = String::new();
+        let gauges = [
+            ("matrix_mycelium_bridge_directory_servers", "Servers in the directory.", servers),
+            (
+                "matrix_mycelium_bridge_directory_max_servers",
+                "Most servers the directory holds.",
+                max_servers,
+            ),
+        ];
+        for (name, help, value) in gauges {
+            let _ = writeln!(output, "# HELP {} {}", name, help);
+            let _ = writeln!(output, "# TYPE {} gauge", name);
+            let _ = writeln!(output, "{} {}", name, value);
+        }
+        let counters = [
+            (
+                "matrix_mycelium_bridge_directory_evictions_total",
+                "Servers dropped to make room in the full directory.",
+                self.evicted(),
+            ),
+            (
+                "matrix_mycelium_bridge_directory_rejections_total",
+                "Announcements refused as invalid or for a full directory.",
+                self.rejected(),
+            ),
+        ];
+        for (name, help, value) in counters {
+            let _ = writeln!(output, "# HELP {} {}", name, help);
+            let _ = writeln!(output, "# TYPE {} counter", name);
+            let _ = writeln!(output, "{} {}", name, value);
+        }
+        output
+    }
+}
@@ -1,15 +1,28 @@
-use anyhow::Result;
-use serde::{Deserialize, Serialize};
+use rand::Rng;
 use std::collections::HashMap;
+use std::time::Duration;
 use tracing::{info, warn};
 
 use crate::types::{ServerAnnouncement, ServerInfo};
 
+/// `interval_seconds` give or take up to `jitter_seconds`, at random.
+pub fn jittered_interval(interval_seconds: u64, jitter_seconds: u64) -> Duration {
+    let jitter = jitter_seconds.min(interval_seconds);
+    let seconds = rand::thread_rng().gen_range(interval_seconds - jitter..=interval_seconds + jitter);
+    Duration::from_secs(seconds)
+}
+
 #[derive(Debug, Clone)]
 pub struct DiscoveryService {
     servers: HashMap<String, ServerInfo>,
 }
 
+impl Default for DiscoveryService {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl DiscoveryService {
     pub fn new() -> Self {
         Self {
@@ -18,18 +31,9 @@ impl DiscoveryService {
     }
     
     pub fn add_server(&mut self, announcement: ServerAnnouncement) {
-        let server_info = ServerInfo {
-            server_name: announcement.server_name.clone(),
-            mycelium_address: announcement.mycelium_address,
-            public_key: announcement.public_key,
-            capabilities: announcement.capabilities,
-            capacity: announcement.capacity,
-            last_seen: chrono::Utc::now(),
-            status: crate::types::ServerStatus::Online,
-        };
-        
-        self.servers.insert(announcement.server_name.clone(), server_info);
-        info!("Added server to discovery: {}", announcement.server_name);
+        let server_name = announcement.server_name.clone();
+        self.servers.insert(server_name.clone(), ServerInfo::from(announcement));
+        info!("Added server to discovery: {}", server_name);
     }
     
     pub fn get_available_servers(&self) -> Vec<&ServerInfo> {
@@ -0,0 +1,86 @@
+use anyhow::Result;
+
+use crate::http_client::HttpClient;
+use crate::telemetry;
+use crate::types::{AddressProof, Deregistration, Heartbeat, RegisterRequest};
+
+/// Client for the HTTP discovery service's registration endpoints.
+pub struct DiscoveryClient {
+    http_client: HttpClient,
+    url: String,
+    api_key: Option<String>,
+}
+
+impl DiscoveryClient {
+    pub fn new(http_client: HttpClient, url: &str, api_key: Option<String>) -> Self {
+        Self {
+            http_client,
+            url: url.trim_end_matches('/').to_string(),
+            api_key,
+        }
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Register, or refresh an existing registration, with the API key if
+    /// there is one.
+    pub async fn register(&self, registration: &RegisterRequest) -> Result<()> {
+        let mut request = self
+            .http_client
+            .post(format!("{}/servers/register", self.url))
+            .json(registration);
+        if let Some(api_key) = &self.api_key {
+            request = request.bearer_auth(api_key);
+        }
+        let response = self.http_client.send(telemetry::inject_headers(request)).await?;
+        if !response.status().is_success() {
+            return Err(anyhow::anyhow!("Discovery service returned {}", response.status()));
+        }
+        Ok(())
+    }
+
+    /// Keep an existing registration alive. False when the service doesn't
+    /// know the server, which then has to register again.
+    pub async fn heartbeat(&self, heartbeat: &Heartbeat) -> Result<bool> {
+        let request = self
+            .http_client
+            .post(format!("{}/servers/{}/heartbeat", self.url, heartbeat.server_name))
+            .json(heartbeat);
+        let response = self.http_client.send(telemetry::inject_headers(request)).await?;
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(false);
+        }
+        if !response.status().is_success() {
+            return Err(anyhow::anyhow!("Discovery service returned {}", response.status()));
+        }
+        Ok(true)
+    }
+
+    /// Remove the server from the service. False when it wasn't listed.
+    pub async fn deregister(&self, deregistration: &Deregistration) -> Result<bool> {
+        let request = self
+            .http_client
+            .delete(format!("{}/servers/{}", self.url, deregistration.server_name))
+            .json(deregistration);
+        let response = self.http_client.send(telemetry::inject_headers(request)).await?;
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(false);
+        }
+        if !response.status().is_success() {
+            return Err(anyhow::anyhow!("Discovery service returned {}", response.status()));
+        }
+        Ok(true)
+    }
+
+    /// Answer an address challenge the discovery service sent over the overlay.
+    pub async fn prove_address(&self, proof: &AddressProof) -> Result<()> {
+        let request = self.http_client.post(format!("{}/servers/verify", self.url)).json(proof);
+        let response = self.http_client.send(telemetry::inject_headers(request)).await?;
+        if !response.status().is_success() {
+            return Err(anyhow::anyhow!("Discovery service returned {}", response.status()));
+        }
+        Ok(())
+    }
+}
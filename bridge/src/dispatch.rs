@@ -0,0 +1,85 @@
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::task::JoinSet;
+
+use crate::types::MyceliumMessage;
+
+type Lanes = Arc<Mutex<HashMap<String, VecDeque<(MyceliumMessage, OwnedSemaphorePermit)>>>>;
+
+/// Processes polled messages concurrently, `concurrency` at a time, while
+/// messages from the same source are still processed one after the other,
+/// in the order they arrived.
+pub struct InboundDispatcher {
+    slots: Arc<Semaphore>,
+    /// Bounds the messages queued behind others from their source.
+    pending: Arc<Semaphore>,
+    /// Messages waiting per source. A source has a lane while a worker is
+    /// processing its messages.
+    lanes: Lanes,
+    workers: JoinSet<()>,
+}
+
+impl InboundDispatcher {
+    pub fn new(concurrency: usize, max_pending: usize) -> Self {
+        Self {
+            slots: Arc::new(Semaphore::new(concurrency.max(1))),
+            pending: Arc::new(Semaphore::new(max_pending.max(1))),
+            lanes: Arc::new(Mutex::new(HashMap::new())),
+            workers: JoinSet::new(),
+        }
+    }
+
+    /// Queue `message` behind earlier ones from its source, to be handed to
+    /// `process`. Waits while `max_pending` messages are already queued.
+    pub async fn dispatch<F, Fut>(&mut self, message: MyceliumMessage, process: F)
+    where
+        F: Fn(MyceliumMessage) -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send,
+    {
+        let Ok(pending) = self.pending.clone().acquire_owned().await else {
+            return;
+        };
+        while self.workers.try_join_next().is_some() {}
+
+        let source = message.source_server.clone();
+        {
+            let mut lanes = self.lanes.lock().unwrap();
+            if let Some(lane) = lanes.get_mut(&source) {
+                lane.push_back((message, pending));
+                return;
+            }
+            lanes.insert(source.clone(), VecDeque::from([(message, pending)]));
+        }
+
+        let lanes = self.lanes.clone();
+        let slots = self.slots.clone();
+        self.workers.spawn(async move {
+            loop {
+                let next = {
+                    let mut lanes = lanes.lock().unwrap();
+                    let next = lanes.get_mut(&source).and_then(VecDeque::pop_front);
+                    if next.is_none() {
+                        lanes.remove(&source);
+                    }
+                    next
+                };
+                let Some((message, pending)) = next else {
+                    break;
+                };
+                let Ok(slot) = slots.acquire().await else {
+                    break;
+                };
+                process(message).await;
+                drop(slot);
+                drop(pending);
+            }
+        });
+    }
+
+    /// Wait for every queued message to be processed.
+    pub async fn finish(mut self) {
+        while self.workers.join_next().await.is_some() {}
+    }
+}
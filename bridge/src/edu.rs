@@ -0,0 +1,145 @@
+use std::collections::HashMap;
+use tokio::sync::Mutex;
+
+/// Message type for ephemeral data units (typing, presence, receipts, ...).
+pub const EDU_MESSAGE_TYPE: &str = "edu";
+
+pub const TYPING_EDU_TYPE: &str = "m.typing";
+
+pub const PRESENCE_EDU_TYPE: &str = "m.presence";
+
+pub const RECEIPT_EDU_TYPE: &str = "m.receipt";
+
+pub const TO_DEVICE_EDU_TYPE: &str = "m.direct_to_device";
+
+pub const DEVICE_LIST_UPDATE_EDU_TYPE: &str = "m.device_list_update";
+
+/// Message type for EDUs that must not be dropped, such as to-device messages
+/// carrying Olm/Megolm keys. They travel on the regular federation topic.
+pub const RELIABLE_EDU_MESSAGE_TYPE: &str = "reliable_edu";
+
+/// Topic for a server's regular federation traffic.
+pub fn federation_topic(server_name: &str) -> String {
+    format!("matrix.federation.{}", server_name)
+}
+
+/// Separate topic for EDUs, so they are polled more often and never wait
+/// behind large PDU transactions.
+pub fn edu_topic(server_name: &str) -> String {
+    format!("matrix.federation.{}.edu", server_name)
+}
+
+/// EDUs that end-to-end encryption depends on, which are delivered reliably.
+pub fn is_reliable(edu_type: &str) -> bool {
+    edu_type == TO_DEVICE_EDU_TYPE || edu_type == DEVICE_LIST_UPDATE_EDU_TYPE
+}
+
+/// EDUs that are relayed on the low-latency path instead of being batched.
+pub fn is_low_latency(edu_type: &str) -> bool {
+    edu_type == TYPING_EDU_TYPE
+}
+
+/// Keeps only the latest update per key (e.g. per user) for each destination
+/// until the next flush, so bursts collapse into a single EDU.
+#[derive(Default)]
+pub struct EduCoalescer {
+    pending: Mutex<HashMap<String, HashMap<String, serde_json::Value>>>,
+}
+
+impl EduCoalescer {
+    /// Record an update. Returns `true` if it is the first one pending for
+    /// `destination`, in which case the caller schedules the flush.
+    pub async fn push(&self, destination: &str, key: String, update: serde_json::Value) -> bool {
+        let mut pending = self.pending.lock().await;
+        let updates = pending.entry(destination.to_string()).or_default();
+        let first = updates.is_empty();
+        updates.insert(key, update);
+        first
+    }
+
+    pub async fn take(&self, destination: &str) -> Vec<serde_json::Value> {
+        self.pending
+            .lock()
+            .await
+            .remove(destination)
+            .map(|updates| updates.into_values().collect())
+            .unwrap_or_default()
+    }
+}
+
+/// Split an `m.presence` EDU into per-user updates.
+pub fn presence_updates(edu: &serde_json::Value) -> Vec<(String, serde_json::Value)> {
+    edu["content"]["push"]
+        .as_array()
+        .map(|push| {
+            push.iter()
+                .filter_map(|update| {
+                    let user_id = update["user_id"].as_str()?;
+                    Some((user_id.to_string(), update.clone()))
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+pub fn presence_edu(updates: Vec<serde_json::Value>) -> serde_json::Value {
+    serde_json::json!({
+        "edu_type": PRESENCE_EDU_TYPE,
+        "content": { "push": updates }
+    })
+}
+
+/// Split an `m.receipt` EDU into one update per room, receipt type and user.
+pub fn receipt_updates(edu: &serde_json::Value) -> Vec<(String, serde_json::Value)> {
+    let mut updates = Vec::new();
+    let Some(rooms) = edu["content"].as_object() else {
+        return updates;
+    };
+
+    for (room_id, receipt_types) in rooms {
+        let Some(receipt_types) = receipt_types.as_object() else {
+            continue;
+        };
+        for (receipt_type, users) in receipt_types {
+            let Some(users) = users.as_object() else {
+                continue;
+            };
+            for (user_id, receipt) in users {
+                let key = format!("{}|{}|{}", room_id, receipt_type, user_id);
+                updates.push((
+                    key,
+                    serde_json::json!({
+                        "room_id": room_id,
+                        "receipt_type": receipt_type,
+                        "user_id": user_id,
+                        "receipt": receipt,
+                    }),
+                ));
+            }
+        }
+    }
+
+    updates
+}
+
+/// Reassemble coalesced receipt updates into a single `m.receipt` EDU.
+pub fn receipt_edu(updates: Vec<serde_json::Value>) -> serde_json::Value {
+    let mut content = serde_json::Map::new();
+    for update in updates {
+        let (Some(room_id), Some(receipt_type), Some(user_id)) = (
+            update["room_id"].as_str(),
+            update["receipt_type"].as_str(),
+            update["user_id"].as_str(),
+        ) else {
+            continue;
+        };
+        content
+            .entry(room_id)
+            .or_insert_with(|| serde_json::json!({}))[receipt_type][user_id] = update["receipt"].clone();
+    }
+
+    serde_json::json!({
+        "edu_type": RECEIPT_EDU_TYPE,
+        "content": content
+    })
+}
@@ -0,0 +1,150 @@
+use anyhow::Result;
+use base64::Engine;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use ed25519_dalek::{SigningKey, VerifyingKey};
+use serde_json::value::RawValue;
+use sha2::{Digest, Sha256};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+/// Capability advertised by bridges that accept encrypted payloads.
+pub const E2E_CAPABILITY: &str = "e2e.x25519-chacha20poly1305";
+
+/// `encryption` value for payloads encrypted with [`PayloadCipher`].
+pub const E2E_SCHEME: &str = "x25519-chacha20poly1305";
+
+const KEY_DERIVATION_CONTEXT: &[u8] = b"mycelium-chat-e2e-v1";
+
+/// Encrypts federation payloads to a destination bridge.
+///
+/// The X25519 keys are derived from the bridges' Ed25519 signing keys, so no
+/// extra key material has to be announced: the peer's announced public key is
+/// enough to compute the shared secret.
+#[derive(Clone)]
+pub struct PayloadCipher {
+    secret: StaticSecret,
+}
+
+impl PayloadCipher {
+    pub fn from_signing_key(signing_key: &SigningKey) -> Self {
+        Self {
+            secret: StaticSecret::from(signing_key.to_scalar_bytes()),
+        }
+    }
+
+    pub fn encrypt(
+        &self,
+        peer_public_key: &str,
+        associated_data: &[u8],
+        payload: &RawValue,
+    ) -> Result<Box<RawValue>> {
+        let cipher = self.cipher_for(peer_public_key)?;
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+
+        let ciphertext = cipher
+            .encrypt(&nonce, Payload { msg: payload.get().as_bytes(), aad: associated_data })
+            .map_err(|_| anyhow::anyhow!("Failed to encrypt payload"))?;
+
+        let engine = base64::engine::general_purpose::STANDARD;
+        Ok(serde_json::value::to_raw_value(&serde_json::json!({
+            "nonce": engine.encode(nonce),
+            "ciphertext": engine.encode(ciphertext),
+        }))?)
+    }
+
+    pub fn decrypt(
+        &self,
+        peer_public_key: &str,
+        associated_data: &[u8],
+        payload: &RawValue,
+    ) -> Result<Box<RawValue>> {
+        let payload: serde_json::Value = serde_json::from_str(payload.get())?;
+        let engine = base64::engine::general_purpose::STANDARD;
+        let field = |name: &str| -> Result<Vec<u8>> {
+            let value = payload[name]
+                .as_str()
+                .ok_or_else(|| anyhow::anyhow!("Encrypted payload is missing {}", name))?;
+            Ok(engine.decode(value)?)
+        };
+
+        let nonce = field("nonce")?;
+        if nonce.len() != 12 {
+            return Err(anyhow::anyhow!("Invalid nonce length"));
+        }
+        let ciphertext = field("ciphertext")?;
+
+        let cipher = self.cipher_for(peer_public_key)?;
+        let plaintext = cipher
+            .decrypt(Nonce::from_slice(&nonce), Payload { msg: &ciphertext, aad: associated_data })
+            .map_err(|_| anyhow::anyhow!("Failed to decrypt payload"))?;
+
+        Ok(RawValue::from_string(String::from_utf8(plaintext)?)?)
+    }
+
+    fn cipher_for(&self, peer_public_key: &str) -> Result<ChaCha20Poly1305> {
+        let peer = x25519_public_key(peer_public_key)?;
+        let shared = self.secret.diffie_hellman(&peer);
+
+        let mut hasher = Sha256::new();
+        hasher.update(KEY_DERIVATION_CONTEXT);
+        hasher.update(shared.as_bytes());
+        let key = hasher.finalize();
+
+        Ok(ChaCha20Poly1305::new(Key::from_slice(&key)))
+    }
+}
+
+/// Convert a base64 Ed25519 public key, as found in announcements, into the
+/// corresponding X25519 public key.
+fn x25519_public_key(ed25519_public_key: &str) -> Result<PublicKey> {
+    let bytes = base64::engine::general_purpose::STANDARD.decode(ed25519_public_key)?;
+    let bytes: [u8; 32] = bytes
+        .try_into()
+        .map_err(|_| anyhow::anyhow!("Invalid public key length"))?;
+    let verifying_key = VerifyingKey::from_bytes(&bytes)?;
+
+    Ok(PublicKey::from(verifying_key.to_montgomery().to_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::signer;
+
+    fn payload() -> Box<RawValue> {
+        serde_json::value::to_raw_value(&serde_json::json!({ "body": "hello" })).unwrap()
+    }
+
+    #[test]
+    fn only_the_peer_can_decrypt() {
+        let (a_key, b_key) = (signer::generate_keypair(), signer::generate_keypair());
+        let (a, b) = (PayloadCipher::from_signing_key(&a_key), PayloadCipher::from_signing_key(&b_key));
+        let a_public = signer::encode_public_key(&a_key.verifying_key());
+        let b_public = signer::encode_public_key(&b_key.verifying_key());
+
+        let encrypted = a.encrypt(&b_public, b"envelope", &payload()).unwrap();
+        assert!(!encrypted.get().contains("hello"));
+        assert_eq!(b.decrypt(&a_public, b"envelope", &encrypted).unwrap().get(), payload().get());
+
+        let c = PayloadCipher::from_signing_key(&signer::generate_keypair());
+        assert!(c.decrypt(&a_public, b"envelope", &encrypted).is_err());
+    }
+
+    #[test]
+    fn tampering_fails_decryption() {
+        let (a_key, b_key) = (signer::generate_keypair(), signer::generate_keypair());
+        let (a, b) = (PayloadCipher::from_signing_key(&a_key), PayloadCipher::from_signing_key(&b_key));
+        let a_public = signer::encode_public_key(&a_key.verifying_key());
+        let b_public = signer::encode_public_key(&b_key.verifying_key());
+        let encrypted = a.encrypt(&b_public, b"envelope", &payload()).unwrap();
+
+        assert!(b.decrypt(&a_public, b"other envelope", &encrypted).is_err());
+        let mut fields: serde_json::Value = serde_json::from_str(encrypted.get()).unwrap();
+        let engine = base64::engine::general_purpose::STANDARD;
+        let mut ciphertext = engine.decode(fields["ciphertext"].as_str().unwrap()).unwrap();
+        ciphertext[0] ^= 1;
+        fields["ciphertext"] = engine.encode(ciphertext).into();
+        let tampered = serde_json::value::to_raw_value(&fields).unwrap();
+        assert!(b.decrypt(&a_public, b"envelope", &tampered).is_err());
+    }
+}
@@ -0,0 +1,83 @@
+use axum::http::StatusCode;
+use thiserror::Error;
+
+/// Errors from the public API of the bridge, by what went wrong, so callers
+/// can tell which are worth retrying and the HTTP API can answer with the
+/// right status.
+#[derive(Debug, Error)]
+pub enum BridgeError {
+    /// The bridge can't run as configured.
+    #[error("configuration error: {0}")]
+    Config(#[source] anyhow::Error),
+    /// The mycelium node couldn't be reached or didn't take the message.
+    #[error("mycelium error: {0}")]
+    Mycelium(#[source] anyhow::Error),
+    /// The homeserver couldn't be reached or failed the request.
+    #[error("homeserver error: {0}")]
+    Homeserver(#[source] anyhow::Error),
+    /// Signing or encryption failed.
+    #[error("crypto error: {0}")]
+    Crypto(#[source] anyhow::Error),
+    /// The request is invalid, or not allowed by the configuration or the
+    /// destination.
+    #[error("{0}")]
+    Validation(String),
+    /// The bridge is too busy to take the request right now.
+    #[error("{0}")]
+    Unavailable(String),
+    /// A remote bridge didn't answer in time.
+    #[error("{0}")]
+    Timeout(String),
+    /// Anything else, such as a task that panicked.
+    #[error(transparent)]
+    Other(anyhow::Error),
+}
+
+impl BridgeError {
+    /// Whether the same call may succeed if tried again later.
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            BridgeError::Mycelium(_)
+                | BridgeError::Homeserver(_)
+                | BridgeError::Unavailable(_)
+                | BridgeError::Timeout(_)
+        )
+    }
+
+    /// The status the HTTP API answers with when a request fails with this.
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            BridgeError::Mycelium(_) | BridgeError::Homeserver(_) => StatusCode::BAD_GATEWAY,
+            BridgeError::Validation(_) => StatusCode::BAD_REQUEST,
+            BridgeError::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            BridgeError::Timeout(_) => StatusCode::GATEWAY_TIMEOUT,
+            BridgeError::Config(_) | BridgeError::Crypto(_) | BridgeError::Other(_) => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        }
+    }
+}
+
+/// Internally errors travel as `anyhow::Error`; one that started out as a
+/// `BridgeError` comes back out as itself.
+impl From<anyhow::Error> for BridgeError {
+    fn from(error: anyhow::Error) -> Self {
+        match error.downcast::<BridgeError>() {
+            Ok(error) => error,
+            Err(error) => BridgeError::Other(error),
+        }
+    }
+}
+
+impl From<serde_json::Error> for BridgeError {
+    fn from(error: serde_json::Error) -> Self {
+        BridgeError::Other(error.into())
+    }
+}
+
+impl From<tokio::task::JoinError> for BridgeError {
+    fn from(error: tokio::task::JoinError) -> Self {
+        BridgeError::Other(error.into())
+    }
+}
@@ -0,0 +1,77 @@
+use serde::{Deserialize, Serialize};
+
+use crate::config::RateLimitConfig;
+
+/// Message type sent back to a bridge whose message was rejected.
+pub const FEDERATION_ERROR_MESSAGE_TYPE: &str = "federation_error";
+
+/// Federation errors sent to each server. The claimed sender of a message
+/// isn't authenticated until its signature is checked, so unlimited errors
+/// would let forged messages aim them at a third party.
+pub const REPLY_LIMITS: RateLimitConfig = RateLimitConfig {
+    enabled: true,
+    burst: 10,
+    per_second: 1.0,
+};
+
+/// Why a message was rejected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// An event in the message failed validation.
+    InvalidEvent,
+    /// The signature doesn't match the sender's announced key.
+    BadSignature,
+    /// The receiver has no directory entry for the sender yet.
+    UnknownServer,
+    /// The sender is over its rate limit.
+    OverQuota,
+    /// The envelope version isn't supported by the receiver.
+    UnsupportedVersion,
+    /// A code this bridge doesn't know yet.
+    #[serde(other)]
+    Unknown,
+}
+
+impl ErrorCode {
+    /// Whether sending the same message again is pointless. Unknown codes
+    /// are classified by the `permanent` flag sent with them.
+    pub fn is_permanent(&self) -> Option<bool> {
+        match self {
+            ErrorCode::InvalidEvent | ErrorCode::BadSignature | ErrorCode::UnsupportedVersion => Some(true),
+            ErrorCode::UnknownServer | ErrorCode::OverQuota => Some(false),
+            ErrorCode::Unknown => None,
+        }
+    }
+}
+
+/// Payload of a `federation_error` message.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FederationError {
+    pub code: ErrorCode,
+    /// Whether the message will be rejected again if resent as is.
+    #[serde(default)]
+    pub permanent: bool,
+    /// Correlation ID of the rejected message.
+    pub message_id: Option<String>,
+    pub message_type: String,
+    pub reason: String,
+}
+
+impl FederationError {
+    pub fn new(code: ErrorCode, message_id: Option<String>, message_type: &str, reason: String) -> Self {
+        Self {
+            code,
+            permanent: code.is_permanent().unwrap_or(true),
+            message_id,
+            message_type: message_type.to_string(),
+            reason,
+        }
+    }
+
+    /// As classified by this bridge, falling back to the sender's flag for
+    /// codes it doesn't know.
+    pub fn is_permanent(&self) -> bool {
+        self.code.is_permanent().unwrap_or(self.permanent)
+    }
+}
@@ -0,0 +1,157 @@
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use tokio::sync::RwLock;
+
+use crate::types::ServerAnnouncement;
+
+/// Mycelium topic carrying directory digests between bridges.
+pub const GOSSIP_TOPIC: &str = "matrix.discovery.gossip";
+
+/// How far ahead of this clock a peer's timestamp may be. Anything later
+/// would outrank every real announcement until then.
+pub const MAX_CLOCK_SKEW_SECONDS: i64 = 300;
+
+/// A directory entry as summarized in a digest.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DigestEntry {
+    pub server_name: String,
+    pub public_key: String,
+    /// Timestamp of the newest announcement held for the server.
+    pub timestamp: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "message_type")]
+pub enum GossipBody {
+    /// What the sender's directory holds.
+    #[serde(rename = "gossip_digest")]
+    Digest { entries: Vec<DigestEntry> },
+    /// Servers the sender wants the announcements of, after seeing a digest.
+    #[serde(rename = "gossip_request")]
+    Request { server_names: Vec<String> },
+    /// The requested announcements, as signed by the servers themselves.
+    #[serde(rename = "gossip_entries")]
+    Entries { announcements: Vec<ServerAnnouncement> },
+}
+
+/// A gossip message, signed by the sending bridge over the message with an
+/// empty `signature`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GossipMessage {
+    pub source_server: String,
+    pub timestamp: String,
+    #[serde(flatten)]
+    pub body: GossipBody,
+    pub signature: String,
+}
+
+impl GossipMessage {
+    pub fn new(source_server: &str, body: GossipBody) -> Self {
+        Self {
+            source_server: source_server.to_string(),
+            timestamp: Utc::now().to_rfc3339(),
+            body,
+            signature: String::new(),
+        }
+    }
+
+    /// The bytes the signature is made over.
+    pub fn signing_payload(&self) -> serde_json::Result<String> {
+        let mut unsigned = self.clone();
+        unsigned.signature = String::new();
+        serde_json::to_string(&unsigned)
+    }
+}
+
+/// The newest signed announcement seen from each server, kept so they can be
+/// handed on to peers that are missing them.
+#[derive(Default)]
+pub struct AnnouncementStore {
+    latest: RwLock<HashMap<String, ServerAnnouncement>>,
+}
+
+impl AnnouncementStore {
+    /// Keep `announcement` unless an announcement at least as new is already
+    /// held for the server. Returns whether it was kept.
+    pub async fn record(&self, announcement: &ServerAnnouncement) -> bool {
+        let Some(timestamp) = parse_peer_timestamp(&announcement.timestamp) else {
+            return false;
+        };
+        let mut latest = self.latest.write().await;
+        let newer = latest
+            .get(&announcement.server_name)
+            .and_then(|held| parse_timestamp(&held.timestamp))
+            .is_none_or(|held| timestamp > held);
+        if newer {
+            latest.insert(announcement.server_name.clone(), announcement.clone());
+        }
+        newer
+    }
+
+    pub async fn digest(&self) -> Vec<DigestEntry> {
+        self.latest
+            .read()
+            .await
+            .values()
+            .map(|announcement| DigestEntry {
+                server_name: announcement.server_name.clone(),
+                public_key: announcement.public_key.clone(),
+                timestamp: announcement.timestamp.clone(),
+            })
+            .collect()
+    }
+
+    /// Servers in a peer's digest that this store has nothing for, or only
+    /// an older announcement.
+    pub async fn missing(&self, digest: &[DigestEntry]) -> Vec<String> {
+        let latest = self.latest.read().await;
+        digest
+            .iter()
+            .filter(|entry| {
+                let Some(theirs) = parse_peer_timestamp(&entry.timestamp) else {
+                    return false;
+                };
+                latest
+                    .get(&entry.server_name)
+                    .and_then(|held| parse_timestamp(&held.timestamp))
+                    .is_none_or(|ours| theirs > ours)
+            })
+            .map(|entry| entry.server_name.clone())
+            .collect()
+    }
+
+    pub async fn get(&self, server_names: &[String]) -> Vec<ServerAnnouncement> {
+        let latest = self.latest.read().await;
+        server_names.iter().filter_map(|name| latest.get(name).cloned()).collect()
+    }
+
+    pub async fn remove(&self, server_name: &str) {
+        self.latest.write().await.remove(server_name);
+    }
+
+    /// Forget announcements made with a revoked key.
+    pub async fn remove_key(&self, public_key: &str) {
+        self.latest
+            .write()
+            .await
+            .retain(|_, announcement| announcement.public_key != public_key);
+    }
+}
+
+pub fn parse_timestamp(timestamp: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(timestamp)
+        .ok()
+        .map(|timestamp| timestamp.with_timezone(&Utc))
+}
+
+/// Parse a timestamp from a peer, refusing one too far in the future to
+/// have been made yet.
+pub fn parse_peer_timestamp(timestamp: &str) -> Option<DateTime<Utc>> {
+    parse_timestamp(timestamp).filter(|timestamp| !is_ahead(*timestamp))
+}
+
+/// Whether `timestamp` is further ahead of this clock than clocks drift.
+pub fn is_ahead(timestamp: DateTime<Utc>) -> bool {
+    timestamp > Utc::now() + Duration::seconds(MAX_CLOCK_SKEW_SECONDS)
+}
@@ -0,0 +1,66 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::time::Instant;
+use tokio::sync::RwLock;
+
+pub const MYCELIUM_COMPONENT: &str = "mycelium";
+pub const HOMESERVER_COMPONENT: &str = "homeserver";
+
+/// Last known state of a component the bridge depends on.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ComponentHealth {
+    pub healthy: bool,
+    pub last_success: Option<DateTime<Utc>>,
+    pub last_failure: Option<DateTime<Utc>>,
+    pub last_error: Option<String>,
+}
+
+/// Tracks process uptime and the outcome of component probes.
+pub struct HealthTracker {
+    started: Instant,
+    components: RwLock<BTreeMap<&'static str, ComponentHealth>>,
+}
+
+impl Default for HealthTracker {
+    fn default() -> Self {
+        Self {
+            started: Instant::now(),
+            components: RwLock::new(BTreeMap::new()),
+        }
+    }
+}
+
+impl HealthTracker {
+    pub fn uptime_seconds(&self) -> u64 {
+        self.started.elapsed().as_secs()
+    }
+
+    pub async fn record(&self, component: &'static str, result: Result<(), String>) {
+        let mut components = self.components.write().await;
+        let health = components.entry(component).or_default();
+        match result {
+            Ok(()) => {
+                health.healthy = true;
+                health.last_success = Some(Utc::now());
+            }
+            Err(e) => {
+                health.healthy = false;
+                health.last_failure = Some(Utc::now());
+                health.last_error = Some(e);
+            }
+        }
+    }
+
+    pub async fn is_healthy(&self, component: &'static str) -> bool {
+        self.components
+            .read()
+            .await
+            .get(component)
+            .is_some_and(|health| health.healthy)
+    }
+
+    pub async fn snapshot(&self) -> BTreeMap<&'static str, ComponentHealth> {
+        self.components.read().await.clone()
+    }
+}
@@ -0,0 +1,284 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use ed25519_dalek::VerifyingKey;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+use tracing::{error, info};
+
+use crate::config::{BridgeConfig, HomeserverFlavor};
+use crate::http_client::HttpClient;
+use crate::matrix_keys;
+use crate::signer::Signer;
+use crate::telemetry;
+use crate::x_matrix;
+
+/// How often an unknown key ID may make the homeserver's keys be fetched
+/// again, so forged `X-Matrix` headers can't flood it with key requests.
+const KEY_REFETCH_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// The homeserver's verify keys and when they were last fetched.
+#[derive(Default)]
+struct FetchedKeys {
+    fetched_at: Option<std::time::Instant>,
+    keys: HashMap<String, VerifyingKey>,
+}
+
+/// HTTP access to the homeserver shared by every backend.
+pub struct HomeserverClient {
+    http_client: HttpClient,
+    url: String,
+    /// Destination in `X-Matrix` headers: the homeserver's server name.
+    destination: String,
+    signer: Arc<dyn Signer>,
+    sign_requests: bool,
+    /// For checking the `X-Matrix` headers on requests from the homeserver.
+    keys: RwLock<FetchedKeys>,
+}
+
+impl HomeserverClient {
+    /// Whether requests relayed from remote servers are signed as them.
+    pub fn signs_requests(&self) -> bool {
+        self.sign_requests
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    pub fn http_client(&self) -> &HttpClient {
+        &self.http_client
+    }
+
+    /// Send a request of the bridge's own to the homeserver.
+    pub async fn request(
+        &self,
+        method: &str,
+        path: &str,
+        body: Option<&serde_json::Value>,
+    ) -> Result<(u16, serde_json::Value)> {
+        self.send(None, method, path, body).await
+    }
+
+    /// Send a request relayed from the remote server `origin`. With request
+    /// signing on, it is signed as `origin` in an `X-Matrix` header, with the
+    /// bridge's key, which the bridge lists among `origin`'s keys.
+    pub async fn request_from(
+        &self,
+        origin: &str,
+        method: &str,
+        path: &str,
+        body: Option<&serde_json::Value>,
+    ) -> Result<(u16, serde_json::Value)> {
+        self.send(Some(origin), method, path, body).await
+    }
+
+    async fn send(
+        &self,
+        origin: Option<&str>,
+        method: &str,
+        path: &str,
+        body: Option<&serde_json::Value>,
+    ) -> Result<(u16, serde_json::Value)> {
+        let method = reqwest::Method::from_bytes(method.as_bytes())?;
+        let mut request = self.http_client.request(method.clone(), format!("{}{}", self.url, path));
+        if let Some(body) = body {
+            request = request.json(body);
+        }
+        if let Some(origin) = origin.filter(|_| self.sign_requests) {
+            let authorization = x_matrix::sign_request(
+                self.signer.as_ref(),
+                origin,
+                &self.destination,
+                method.as_str(),
+                path,
+                body,
+            )
+            .await?;
+            request = request.header(reqwest::header::AUTHORIZATION, authorization);
+        }
+        request = telemetry::inject_headers(request);
+
+        let response = self.http_client.send(request).await?;
+        let status = response.status().as_u16();
+        let body = response.json().await.unwrap_or(serde_json::Value::Null);
+
+        Ok((status, body))
+    }
+
+    /// The homeserver's current verify key `key_id`, fetched from its key
+    /// endpoint and cached.
+    pub async fn verify_key(&self, key_id: &str) -> Result<Option<VerifyingKey>> {
+        let keys = self.keys.read().await;
+        if let Some(key) = keys.keys.get(key_id) {
+            return Ok(Some(*key));
+        }
+        if keys.fetched_at.is_some_and(|fetched_at| fetched_at.elapsed() < KEY_REFETCH_INTERVAL) {
+            return Ok(None);
+        }
+        drop(keys);
+
+        let (status, response) = self.request("GET", "/_matrix/key/v2/server", None).await?;
+        if !(200..300).contains(&status) {
+            return Err(anyhow::anyhow!("Homeserver returned {} for its keys", status));
+        }
+        let mut keys = self.keys.write().await;
+        *keys = FetchedKeys {
+            fetched_at: Some(std::time::Instant::now()),
+            keys: matrix_keys::verify_keys(&response),
+        };
+        Ok(keys.keys.get(key_id).copied())
+    }
+
+    /// Deliver a PDU or EDU from `origin` as a standard federation
+    /// transaction.
+    async fn send_transaction(&self, origin: &str, payload: &serde_json::Value) -> Result<serde_json::Value> {
+        let (pdus, edus) = if payload.get("edu_type").is_some() {
+            (vec![], vec![payload.clone()])
+        } else {
+            (vec![payload.clone()], vec![])
+        };
+        let transaction = serde_json::json!({
+            "origin": origin,
+            "origin_server_ts": chrono::Utc::now().timestamp_millis(),
+            "pdus": pdus,
+            "edus": edus,
+        });
+        let path = format!("/_matrix/federation/v1/send/{}", uuid::Uuid::new_v4());
+
+        let (status, body) = self.request_from(origin, "PUT", &path, Some(&transaction)).await?;
+        check_delivery(status, body)
+    }
+}
+
+/// Fail deliveries worth retrying: the homeserver is down, overloaded or
+/// rate limiting. Other errors are logged and returned as the body.
+fn check_delivery(status: u16, body: serde_json::Value) -> Result<serde_json::Value> {
+    if (200..300).contains(&status) {
+        info!("Federation message forwarded to Matrix homeserver");
+    } else if status >= 500 || status == 408 || status == 429 {
+        return Err(anyhow::anyhow!("Homeserver returned {}", status));
+    } else {
+        error!("Failed to forward message to Matrix: {}", status);
+    }
+    Ok(body)
+}
+
+/// The parts of the bridge that depend on which homeserver implementation it
+/// sits in front of.
+#[async_trait]
+pub trait HomeserverBackend: Send + Sync {
+    fn flavor(&self) -> HomeserverFlavor;
+
+    fn client(&self) -> &HomeserverClient;
+
+    /// Hand an inbound PDU or EDU from `origin` to the homeserver. The
+    /// returned body may list `missing_prev_events` for the bridge to fetch.
+    /// Fails if the homeserver is unreachable or asks for the delivery to be
+    /// retried.
+    async fn deliver(&self, origin: &str, payload: &serde_json::Value) -> Result<serde_json::Value>;
+
+    /// Check that the homeserver is up and answering.
+    async fn health_check(&self) -> Result<()> {
+        let (status, _) = self.client().request("GET", "/_matrix/client/versions", None).await?;
+        if (200..300).contains(&status) {
+            Ok(())
+        } else {
+            Err(anyhow::anyhow!("Homeserver returned {}", status))
+        }
+    }
+
+    /// Replay a federation API request from the remote server `origin`.
+    async fn federation_request(
+        &self,
+        origin: &str,
+        method: &str,
+        path: &str,
+        body: Option<&serde_json::Value>,
+    ) -> Result<(u16, serde_json::Value)> {
+        self.client().request_from(origin, method, path, body).await
+    }
+}
+
+/// Synapse with the bridge's `/federation/receive` endpoint.
+pub struct SynapseBackend(HomeserverClient);
+
+#[async_trait]
+impl HomeserverBackend for SynapseBackend {
+    fn flavor(&self) -> HomeserverFlavor {
+        HomeserverFlavor::Synapse
+    }
+
+    fn client(&self) -> &HomeserverClient {
+        &self.0
+    }
+
+    async fn deliver(&self, origin: &str, payload: &serde_json::Value) -> Result<serde_json::Value> {
+        let (status, body) = self.0.request_from(origin, "POST", "/federation/receive", Some(payload)).await?;
+        check_delivery(status, body)
+    }
+}
+
+/// Conduit, fed through its standard federation API.
+pub struct ConduitBackend(HomeserverClient);
+
+#[async_trait]
+impl HomeserverBackend for ConduitBackend {
+    fn flavor(&self) -> HomeserverFlavor {
+        HomeserverFlavor::Conduit
+    }
+
+    fn client(&self) -> &HomeserverClient {
+        &self.0
+    }
+
+    async fn deliver(&self, origin: &str, payload: &serde_json::Value) -> Result<serde_json::Value> {
+        self.0.send_transaction(origin, payload).await
+    }
+}
+
+/// Dendrite, fed through its standard federation API.
+pub struct DendriteBackend(HomeserverClient);
+
+#[async_trait]
+impl HomeserverBackend for DendriteBackend {
+    fn flavor(&self) -> HomeserverFlavor {
+        HomeserverFlavor::Dendrite
+    }
+
+    fn client(&self) -> &HomeserverClient {
+        &self.0
+    }
+
+    async fn deliver(&self, origin: &str, payload: &serde_json::Value) -> Result<serde_json::Value> {
+        self.0.send_transaction(origin, payload).await
+    }
+}
+
+/// Build the backend selected by `homeserver.flavor`.
+pub fn backend_for(
+    config: &BridgeConfig,
+    http_client: HttpClient,
+    signer: Arc<dyn Signer>,
+) -> Arc<dyn HomeserverBackend> {
+    let flavor = config.homeserver.flavor;
+    let client = HomeserverClient {
+        http_client,
+        url: config.matrix_homeserver_url.clone(),
+        destination: config
+            .homeserver
+            .server_name
+            .clone()
+            .unwrap_or_else(|| config.server_name.clone()),
+        signer,
+        // The standard federation API rejects unsigned requests
+        sign_requests: config.homeserver.sign_requests || flavor != HomeserverFlavor::Synapse,
+        keys: RwLock::default(),
+    };
+
+    match flavor {
+        HomeserverFlavor::Synapse => Arc::new(SynapseBackend(client)),
+        HomeserverFlavor::Conduit => Arc::new(ConduitBackend(client)),
+        HomeserverFlavor::Dendrite => Arc::new(DendriteBackend(client)),
+    }
+}
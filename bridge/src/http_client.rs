@@ -0,0 +1,107 @@
+use anyhow::Result;
+use rand::Rng;
+use reqwest::{RequestBuilder, Response, StatusCode};
+use std::time::Duration;
+use tracing::warn;
+
+use crate::config::HttpConfig;
+
+/// When and how often a failed request is tried again: after connection
+/// failures, timeouts, 429s and 5xx responses, with exponential backoff and
+/// jitter.
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    pub base_delay: Duration,
+}
+
+impl RetryPolicy {
+    pub fn new(max_retries: u32, base_delay: Duration) -> Self {
+        Self {
+            max_retries,
+            base_delay,
+        }
+    }
+
+    /// Whether a response with this status is worth trying again.
+    pub fn is_retryable_status(status: StatusCode) -> bool {
+        status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS
+    }
+
+    /// How long to wait before retry number `attempt`, counting from 1.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let backoff = self.base_delay * 2u32.saturating_pow(attempt.saturating_sub(1));
+        let jitter = rand::thread_rng().gen_range(0..=self.base_delay.as_millis() as u64);
+        backoff + Duration::from_millis(jitter)
+    }
+}
+
+/// The HTTP client for every outbound call except to mycelium, which has its
+/// own settings: the homeserver, the discovery service and the signer. It
+/// applies the `[http]` timeouts and proxy, and retries with the policy they
+/// share.
+#[derive(Debug, Clone)]
+pub struct HttpClient {
+    client: reqwest::Client,
+    retry: RetryPolicy,
+}
+
+impl HttpClient {
+    pub fn new(config: &HttpConfig) -> Result<Self> {
+        let mut builder = reqwest::Client::builder()
+            .connect_timeout(Duration::from_secs(config.connect_timeout_seconds))
+            .timeout(Duration::from_secs(config.request_timeout_seconds))
+            .pool_idle_timeout(Duration::from_secs(config.pool_idle_timeout_seconds))
+            .tcp_keepalive((config.tcp_keepalive_seconds > 0).then(|| {
+                Duration::from_secs(config.tcp_keepalive_seconds)
+            }));
+        if let Some(proxy) = &config.proxy {
+            let no_proxy = reqwest::NoProxy::from_string(&config.no_proxy.join(","));
+            builder = builder.proxy(reqwest::Proxy::all(proxy)?.no_proxy(no_proxy));
+        }
+
+        Ok(Self {
+            client: builder.build()?,
+            retry: RetryPolicy::new(config.max_retries, Duration::from_millis(config.retry_base_delay_ms)),
+        })
+    }
+
+    pub fn get(&self, url: impl reqwest::IntoUrl) -> RequestBuilder {
+        self.client.get(url)
+    }
+
+    pub fn post(&self, url: impl reqwest::IntoUrl) -> RequestBuilder {
+        self.client.post(url)
+    }
+
+    pub fn delete(&self, url: impl reqwest::IntoUrl) -> RequestBuilder {
+        self.client.delete(url)
+    }
+
+    pub fn request(&self, method: reqwest::Method, url: impl reqwest::IntoUrl) -> RequestBuilder {
+        self.client.request(method, url)
+    }
+
+    /// Send `request`, retrying it by the retry policy. A request whose body
+    /// is a stream can't be repeated, and is sent once.
+    pub async fn send(&self, request: RequestBuilder) -> reqwest::Result<Response> {
+        let mut attempt = 0;
+        loop {
+            let Some(retry) = request.try_clone().filter(|_| attempt < self.retry.max_retries) else {
+                return request.send().await;
+            };
+            let retryable = match retry.send().await {
+                Ok(response) if RetryPolicy::is_retryable_status(response.status()) => {
+                    format!("status {}", response.status())
+                }
+                Err(e) if e.is_connect() || e.is_timeout() => e.to_string(),
+                result => return result,
+            };
+
+            attempt += 1;
+            let delay = self.retry.delay(attempt);
+            warn!("HTTP request failed ({}), retry {} in {:?}", retryable, attempt, delay);
+            tokio::time::sleep(delay).await;
+        }
+    }
+}
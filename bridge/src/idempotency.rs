@@ -0,0 +1,85 @@
+use serde_json::Value;
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{Mutex, OnceCell};
+
+use crate::config::IdempotencyConfig;
+
+/// Header a caller can set on `POST /federation/send` to make retries safe.
+pub const IDEMPOTENCY_KEY_HEADER: &str = "idempotency-key";
+
+/// Set on responses replayed for a key that was already used.
+pub const IDEMPOTENT_REPLAYED_HEADER: &str = "idempotent-replayed";
+
+/// Responses to recent sends by idempotency key, so a homeserver retrying a
+/// send gets the original response instead of the event going out again.
+pub struct IdempotencyCache {
+    ttl: Duration,
+    max_keys: usize,
+    entries: Mutex<Entries>,
+}
+
+#[derive(Default)]
+struct Entries {
+    responses: HashMap<String, Arc<OnceCell<Value>>>,
+    /// Keys oldest first, with when they were first used.
+    order: VecDeque<(Instant, String)>,
+}
+
+impl IdempotencyCache {
+    pub fn new(config: &IdempotencyConfig) -> Self {
+        Self {
+            ttl: Duration::from_secs(config.ttl_seconds),
+            max_keys: config.max_keys,
+            entries: Mutex::new(Entries::default()),
+        }
+    }
+
+    /// Run `send` unless a send with `key` already succeeded, or is in
+    /// flight, in which case its response is waited for. Returns the
+    /// response and whether it was replayed. Failed sends aren't remembered,
+    /// so retrying them sends again.
+    pub async fn run<F, Fut, E>(&self, key: &str, send: F) -> Result<(Value, bool), E>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<Value, E>>,
+    {
+        let response = self.slot(key).await;
+        let mut sent = false;
+        let value = response
+            .get_or_try_init(|| {
+                sent = true;
+                send()
+            })
+            .await?;
+        Ok((value.clone(), !sent))
+    }
+
+    async fn slot(&self, key: &str) -> Arc<OnceCell<Value>> {
+        let mut entries = self.entries.lock().await;
+        let now = Instant::now();
+        while let Some((used, _)) = entries.order.front() {
+            if now.duration_since(*used) < self.ttl {
+                break;
+            }
+            if let Some((_, expired)) = entries.order.pop_front() {
+                entries.responses.remove(&expired);
+            }
+        }
+
+        if let Some(response) = entries.responses.get(key) {
+            return response.clone();
+        }
+        if entries.order.len() >= self.max_keys {
+            if let Some((_, oldest)) = entries.order.pop_front() {
+                entries.responses.remove(&oldest);
+            }
+        }
+        let response = Arc::new(OnceCell::new());
+        entries.responses.insert(key.to_string(), response.clone());
+        entries.order.push_back((now, key.to_string()));
+        response
+    }
+}
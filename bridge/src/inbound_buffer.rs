@@ -0,0 +1,106 @@
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+use tokio::sync::Mutex;
+use tracing::{error, info, warn};
+
+/// An inbound event waiting for the homeserver to come back.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BufferedEvent {
+    pub origin: String,
+    pub payload: serde_json::Value,
+    pub buffered_at: DateTime<Utc>,
+}
+
+/// Inbound events held in arrival order while the homeserver is
+/// unreachable. With a path, the buffer is saved after every change so it
+/// survives a restart.
+pub struct InboundBuffer {
+    path: Option<PathBuf>,
+    max_events: usize,
+    events: Mutex<VecDeque<BufferedEvent>>,
+}
+
+impl InboundBuffer {
+    /// Open the buffer, picking up events saved at `path` by a previous run.
+    pub async fn load(path: Option<&str>, max_events: usize) -> Self {
+        let path = path.map(PathBuf::from);
+        let events = match &path {
+            Some(path) if path.exists() => match read_events(path).await {
+                Ok(events) => {
+                    if !events.is_empty() {
+                        info!("Loaded {} buffered inbound events from {}", events.len(), path.display());
+                    }
+                    events
+                }
+                Err(e) => {
+                    error!("Failed to load buffered inbound events from {}: {}", path.display(), e);
+                    VecDeque::new()
+                }
+            },
+            _ => VecDeque::new(),
+        };
+        Self {
+            path,
+            max_events,
+            events: Mutex::new(events),
+        }
+    }
+
+    pub async fn len(&self) -> usize {
+        self.events.lock().await.len()
+    }
+
+    pub async fn is_empty(&self) -> bool {
+        self.events.lock().await.is_empty()
+    }
+
+    /// Append an event, dropping the oldest once `max_events` are held.
+    pub async fn push(&self, origin: &str, payload: &serde_json::Value) -> Result<()> {
+        let mut events = self.events.lock().await;
+        if events.len() >= self.max_events {
+            events.pop_front();
+            warn!("Inbound buffer is full, dropped the oldest event");
+        }
+        events.push_back(BufferedEvent {
+            origin: origin.to_string(),
+            payload: payload.clone(),
+            buffered_at: Utc::now(),
+        });
+        self.save(&events).await
+    }
+
+    pub async fn front(&self) -> Option<BufferedEvent> {
+        self.events.lock().await.front().cloned()
+    }
+
+    /// Remove the oldest event once it has been delivered.
+    pub async fn pop_front(&self) -> Result<()> {
+        let mut events = self.events.lock().await;
+        events.pop_front();
+        self.save(&events).await
+    }
+
+    /// Write the buffer through a temporary file, like the directory
+    /// snapshot, so a crash mid-write keeps the previous copy.
+    async fn save(&self, events: &VecDeque<BufferedEvent>) -> Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        let temp_path = path.with_extension("tmp");
+        fs::write(&temp_path, serde_json::to_vec(events)?).await?;
+        fs::rename(&temp_path, path).await?;
+        Ok(())
+    }
+}
+
+async fn read_events(path: &Path) -> Result<VecDeque<BufferedEvent>> {
+    let content = fs::read(path).await?;
+    Ok(serde_json::from_slice(&content)?)
+}
@@ -1,105 +1,987 @@
 use anyhow::Result;
 use axum::{
-    extract::State,
-    http::StatusCode,
-    response::Json,
-    routing::{get, post},
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        Path, Query, Request, State,
+    },
+    http::{
+        header::{AUTHORIZATION, CONTENT_TYPE, HOST},
+        HeaderMap, HeaderValue, Method, StatusCode, Uri,
+    },
+    middleware::Next,
+    response::{IntoResponse, Json, Response},
+    routing::{delete, get, post, put},
     Router,
 };
-use ed25519_dalek::{Keypair, Signature, Signer, Verifier};
-use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use admin::AdminStats;
+use appservice::Appservice;
+use auth::{ApiToken, TokenScope};
+use base64::Engine;
+use batching::{BatchAction, PendingEvent, SendDone, TRANSACTION_MESSAGE_TYPE};
+use cluster::Cluster;
+use archive::{ArchiveDirection, ArchiveQuery, MessageArchive};
+use bandwidth::{BandwidthTracker, Direction};
+use capabilities::CapabilityRegistry;
+use liveness::OfflineQueue;
+use ping::{Ping, PingQuery, PingTracker, PING_MESSAGE_TYPE, PONG_MESSAGE_TYPE};
+use priority::{OutboundScheduler, Priority};
+use capacity::CapacityProvider;
+use compression::{ZSTD_CAPABILITY, ZSTD_ENCODING};
+use delivery::{
+    DeliveryAck, DeliveryTracker, QueuedSend, SendOutcome, DELIVERY_ACK_CAPABILITY, DELIVERY_ACK_MESSAGE_TYPE,
+};
+use directory::DirectoryStats;
+use dispatch::InboundDispatcher;
+use discovery_client::DiscoveryClient;
+use edu::EduCoalescer;
+use federation_error::{ErrorCode, FederationError, FEDERATION_ERROR_MESSAGE_TYPE};
+use gossip::{AnnouncementStore, GossipBody, GossipMessage, GOSSIP_TOPIC};
+use health::HealthTracker;
+use homeserver::HomeserverBackend;
+use http_client::HttpClient;
+use inbound_buffer::InboundBuffer;
+use idempotency::{IdempotencyCache, IDEMPOTENCY_KEY_HEADER, IDEMPOTENT_REPLAYED_HEADER};
+use encryption::{PayloadCipher, E2E_CAPABILITY, E2E_SCHEME};
+use peer_metrics::PeerMetricsTracker;
+use mycelium::{Destination, FailoverMyceliumClient, FederationTransport, MyceliumClient, MyceliumInfo};
+use protocol::ProtocolVersion;
+use media::{MediaAssembler, MediaCache, MediaChunk, MediaFile, MediaRequest};
+use queries::{QueryKind, QueryRequest, QueryResponse, QueryTracker};
+use rate_limit::RateLimiter;
+use reload::ReloadableSettings;
+use room_filter::RoomAliases;
+use server_acl::AclStore;
+use signer::Signer;
+use stream::{EventKind, EventStream, InboundEvent};
+use systemd::LoopHeartbeats;
+use ed25519_dalek::{Signature, SigningKey, Verifier, VerifyingKey};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::sync::Arc;
-use tokio::sync::RwLock;
-use tower_http::cors::CorsLayer;
-use tracing::{error, info, warn};
-use uuid::Uuid;
+use tokio::sync::{broadcast, mpsc, watch, RwLock};
+use tokio::task::{JoinHandle, JoinSet};
+use tower_http::cors::{AllowOrigin, Any, CorsLayer};
+use tracing::{debug, error, info, warn, Instrument};
 
+pub mod admin;
+pub mod appservice;
+pub mod archive;
+pub mod auth;
+pub mod bandwidth;
+pub mod batching;
+pub mod capabilities;
+pub mod capacity;
+pub mod cluster;
+pub mod compression;
 pub mod config;
+pub mod delivery;
+pub mod directory;
+pub mod dispatch;
+pub mod encryption;
+pub mod error;
+pub mod federation_error;
+pub mod gossip;
+pub mod matrix_keys;
+pub mod media;
+pub mod queries;
+pub mod rate_limit;
+pub mod reload;
+pub mod room_filter;
+pub mod server_acl;
+pub mod signer;
+pub mod stream;
+pub mod systemd;
+pub mod synapse_admin;
+pub mod telemetry;
+pub mod tls;
 pub mod discovery;
+pub mod discovery_client;
+pub mod edu;
+pub mod health;
+pub mod homeserver;
+pub mod http_client;
+pub mod idempotency;
+pub mod liveness;
+pub mod memory_transport;
+pub mod inbound_buffer;
 pub mod mycelium;
+pub mod peer_metrics;
+pub mod persistence;
+pub mod ping;
+pub mod priority;
+pub mod protocol;
 pub mod types;
+pub mod validation;
+pub mod x_matrix;
 
+pub use batching::TransactionBatcher;
 pub use config::BridgeConfig;
+pub use error::BridgeError;
 pub use types::*;
 
+/// A running bridge. Clones are cheap and share all of its state, so one is
+/// handed to every task and request handler.
 #[derive(Clone)]
 pub struct MatrixMyceliumBridge {
+    inner: Arc<BridgeInner>,
+}
+
+impl std::ops::Deref for MatrixMyceliumBridge {
+    type Target = BridgeInner;
+    
+    fn deref(&self) -> &BridgeInner {
+        &self.inner
+    }
+}
+
+/// A started bridge, returned by [`MatrixMyceliumBridge::start`].
+pub struct BridgeHandle {
+    bridge: MatrixMyceliumBridge,
+    local_addr: std::net::SocketAddr,
+    task: JoinHandle<Result<()>>,
+}
+
+impl BridgeHandle {
+    /// Address the HTTP API listens on, with the actual port when bound to
+    /// port 0.
+    pub fn local_addr(&self) -> std::net::SocketAddr {
+        self.local_addr
+    }
+    
+    pub fn bridge(&self) -> &MatrixMyceliumBridge {
+        &self.bridge
+    }
+    
+    /// Begin a graceful shutdown; [`join`](Self::join) waits for it.
+    pub fn shutdown(&self) {
+        self.bridge.request_shutdown();
+    }
+    
+    /// Wait until the bridge has stopped, after draining on shutdown. Fails
+    /// if the HTTP server did.
+    pub async fn join(self) -> Result<(), BridgeError> {
+        Ok(self.task.await??)
+    }
+}
+
+/// The state behind a [`MatrixMyceliumBridge`], shared by all its clones.
+/// Subsystems that change while running keep their own interior locks.
+pub struct BridgeInner {
     config: BridgeConfig,
-    server_directory: Arc<RwLock<HashMap<String, ServerInfo>>>,
-    mycelium_client: reqwest::Client,
-    signing_keypair: Keypair,
+    server_directory: RwLock<HashMap<String, ServerInfo>>,
+    revoked_keys: RwLock<HashSet<String>>,
+    /// Signed announcements behind the directory, handed on through gossip.
+    announcements: AnnouncementStore,
+    homeserver: Arc<dyn HomeserverBackend>,
+    /// Inbound events waiting for the homeserver to come back.
+    inbound_buffer: InboundBuffer,
+    /// Wakes the replay of `inbound_buffer` when an event is buffered.
+    buffer_replay: tokio::sync::Notify,
+    mycelium: Arc<dyn FederationTransport>,
+    /// Signs with the federation identity key.
+    signer: Arc<dyn Signer>,
+    /// Key rotated away from, countersigning announcements until its file
+    /// is removed.
+    previous_signing_key: Option<SigningKey>,
+    batcher: TransactionBatcher,
+    /// Payload encryption, unless the signing key is held externally.
+    cipher: Option<PayloadCipher>,
+    /// What this bridge announces it supports.
+    capabilities: CapabilityRegistry,
+    queries: QueryTracker,
+    pings: PingTracker,
+    presence: EduCoalescer,
+    receipts: EduCoalescer,
+    media_assembler: MediaAssembler,
+    media_cache: MediaCache,
+    server_acls: AclStore,
+    room_aliases: RoomAliases,
+    rate_limiter: RateLimiter,
+    /// Limits the federation errors sent to each server.
+    error_reply_limiter: RateLimiter,
+    /// Limits the servers new to the directory heard of through each
+    /// overlay node.
+    new_server_limiter: RateLimiter,
+    /// Mycelium address in the last announcement, to re-announce on change.
+    announced_address: RwLock<Option<String>>,
+    /// Whether the last announcement said there was room for more users.
+    announced_available: RwLock<Option<bool>>,
+    /// Wakes the announcement loop to announce straight away.
+    reannounce: tokio::sync::Notify,
+    /// Wakes pollers backing off after an error once the mycelium node is
+    /// back after a restart.
+    mycelium_restarted: tokio::sync::Notify,
+    /// Checked by the systemd watchdog.
+    loop_heartbeats: LoopHeartbeats,
+    appservice: Option<Arc<Appservice>>,
+    discovery_client: Option<Arc<DiscoveryClient>>,
+    /// State of messages sent through the asynchronous send API.
+    deliveries: DeliveryTracker,
+    send_queue: mpsc::Sender<QueuedSend>,
+    /// Messages for peers gone offline, sent when they are back.
+    offline_queue: OfflineQueue,
+    outbound: Arc<OutboundScheduler>,
+    directory_stats: DirectoryStats,
+    /// Taken by the send queue worker on start.
+    send_queue_receiver: std::sync::Mutex<Option<mpsc::Receiver<QueuedSend>>>,
+    /// The other bridges serving this homeserver, if clustered.
+    cluster: Option<Arc<Cluster>>,
+    /// Consumers of `/federation/stream`, if enabled.
+    event_stream: Option<Arc<EventStream>>,
+    /// Responses to recent `/federation/send` requests, if deduplicating.
+    idempotency: Option<IdempotencyCache>,
+    /// Counts the local users for announcements.
+    capacity_provider: Arc<dyn CapacityProvider>,
+    /// Last capacity measurement and when it was taken.
+    capacity_cache: RwLock<Option<(std::time::Instant, ServerCapacity)>>,
+    health: HealthTracker,
+    admin_stats: AdminStats,
+    peer_metrics: PeerMetricsTracker,
+    bandwidth: BandwidthTracker,
+    /// Every envelope sent and received, if archiving.
+    archive: Option<MessageArchive>,
+    /// Flips to `true` once shutdown starts.
+    shutdown: watch::Sender<bool>,
+    /// Inbound message pollers, the send queue workers and the cluster lease,
+    /// awaited on shutdown so in-flight messages finish processing.
+    message_tasks: std::sync::Mutex<Vec<JoinHandle<()>>>,
+    /// Periodic tasks, cancelled once shutdown has drained.
+    background: std::sync::Mutex<JoinSet<()>>,
+    /// The parts of `config` that can be reloaded while running.
+    settings: std::sync::RwLock<Arc<ReloadableSettings>>,
+    /// File the config was loaded from, re-read on SIGHUP.
+    config_path: std::sync::RwLock<Option<std::path::PathBuf>>,
+    /// `auth.tokens`, plus `admin.token` with the admin scope.
+    api_tokens: Vec<ApiToken>,
 }
 
 impl MatrixMyceliumBridge {
-    pub async fn new(config: BridgeConfig) -> Result<Self> {
-        let mycelium_client = reqwest::Client::new();
+    pub async fn new(config: BridgeConfig) -> Result<Self, BridgeError> {
+        let mycelium: Result<Arc<dyn FederationTransport>> = match config.mycelium_api_url.as_slice() {
+            [url] => MyceliumClient::new(url.clone(), &config.mycelium).map(|client| Arc::new(client) as _),
+            urls => FailoverMyceliumClient::new(urls, &config.mycelium).map(|client| Arc::new(client) as _),
+        };
+        Self::with_mycelium(config, mycelium.map_err(BridgeError::Config)?).await
+    }
+    
+    /// Create a bridge that talks to mycelium through `mycelium` instead of
+    /// the HTTP client.
+    pub async fn with_mycelium(
+        config: BridgeConfig,
+        mycelium: Arc<dyn FederationTransport>,
+    ) -> Result<Self, BridgeError> {
+        let http_client = HttpClient::new(&config.http).map_err(BridgeError::Config)?;
+        let appservice = if config.appservice.enabled {
+            let appservice = Appservice::new(
+                &config.appservice,
+                &config.server_name,
+                &config.matrix_homeserver_url,
+                http_client.clone(),
+            )
+            .map_err(BridgeError::Config)?;
+            Some(Arc::new(appservice))
+        } else {
+            None
+        };
+        
+        let discovery_client = config.discovery.service_url.as_deref().map(|url| {
+            Arc::new(DiscoveryClient::new(http_client.clone(), url, config.discovery.api_key.clone()))
+        });
+        let idempotency = config
+            .idempotency
+            .enabled
+            .then(|| IdempotencyCache::new(&config.idempotency));
+        let cluster = match config.cluster.enabled {
+            true => Some(Arc::new(Cluster::connect(&config.cluster, &config.server_name).await?)),
+            false => None,
+        };
+        // In a cluster the queue is shared in Redis, and the local one only
+        // holds the sends this instance is about to make
+        let local_capacity = match cluster {
+            Some(_) => config.send_queue.concurrency,
+            None => config.send_queue.capacity,
+        };
+        let (send_queue, send_queue_receiver) = mpsc::channel(local_capacity.max(1));
+        let deliveries = DeliveryTracker::new(config.send_queue.tracked_messages);
+        let event_stream = config
+            .stream
+            .enabled
+            .then(|| Arc::new(EventStream::new(config.stream.buffer)));
+        
+        let signer = signer::from_config(&config, http_client.clone())
+            .await
+            .map_err(BridgeError::Config)?;
+        let previous_key_path = signer::previous_key_path(&config.signing_key_path);
+        let previous_signing_key = match config.signer.url {
+            None if std::path::Path::new(&previous_key_path).exists() => {
+                let previous = signer::read_keypair(&previous_key_path)?;
+                info!("Countersigning announcements with the previous key in {}", previous_key_path);
+                Some(previous)
+            }
+            _ => None,
+        };
         
-        // Load or generate signing keypair
-        let signing_keypair = Self::load_or_generate_keypair(&config.signing_key_path)?;
+        let homeserver = homeserver::backend_for(&config, http_client.clone(), signer.clone());
+        let inbound_buffer = InboundBuffer::load(
+            config.homeserver.buffer_path.as_deref(),
+            config.homeserver.buffer_max_events,
+        )
+        .await;
+        let batcher = TransactionBatcher::new(config.batching.clone());
+        let capacity_provider = capacity::from_config(&config, http_client.clone());
+        let cipher = signer.signing_key().map(PayloadCipher::from_signing_key);
+        let capabilities = CapabilityRegistry::new(&config.capabilities);
+        register_capabilities(&capabilities, &config, cipher.is_some());
+        let media_assembler = MediaAssembler::new(config.media.max_media_bytes);
+        let media_cache = MediaCache::new(&config.media);
+        let rate_limiter = RateLimiter::new(config.rate_limit.clone());
+        let new_server_limiter = RateLimiter::new(config.directory.new_server_limits());
+        let admin_stats = AdminStats::new(config.admin.recent_messages);
+        let peer_metrics = PeerMetricsTracker::new(config.metrics.latency_warning_ms);
+        let bandwidth = BandwidthTracker::new(config.bandwidth.clone());
+        let offline_queue = OfflineQueue::new(config.liveness.held_messages);
+        let outbound = Arc::new(OutboundScheduler::new(&config.priority));
+        let archive = match config.archive.enabled {
+            true => Some(MessageArchive::open(&config.archive).await?),
+            false => None,
+        };
+        let settings = Arc::new(ReloadableSettings::from_config(&config));
+        let mut directory = match &config.persistence.directory_path {
+            Some(path) => {
+                let max_age = chrono::Duration::hours(config.persistence.max_age_hours);
+                persistence::load_directory(std::path::Path::new(path), max_age).await
+            }
+            None => persistence::LoadedDirectory::default(),
+        };
+        for peer in &config.peers {
+            let revoked = directory.revoked_keys.contains(&peer.public_key);
+            if revoked {
+                warn!("Static peer {} is configured with a revoked key", peer.server_name);
+            }
+            directory.servers.insert(peer.server_name.clone(), static_peer_info(peer, revoked));
+        }
+        while directory.servers.len() > config.directory.max_servers {
+            let is_static =
+                |server: &ServerInfo| config.peers.iter().any(|peer| peer.server_name == server.server_name);
+            let Some(evicted) = directory::eviction_candidate(&directory.servers, is_static) else {
+                break;
+            };
+            directory.servers.remove(&evicted);
+        }
+        let mut api_tokens = config.auth.tokens.clone();
+        if let Some(token) = &config.admin.token {
+            api_tokens.push(ApiToken {
+                name: "admin.token".to_string(),
+                token: token.clone(),
+                scopes: vec![TokenScope::Admin],
+            });
+        }
+        if !api_tokens.iter().any(|token| token.scopes.contains(&TokenScope::Send)) {
+            if config.auth.allow_unauthenticated {
+                warn!(
+                    "No auth token has the send scope and auth.allow_unauthenticated is set, so the \
+                     /federation endpoints are open to anyone reaching {}",
+                    config.bind_address
+                );
+            } else {
+                warn!("No auth token has the send scope, so the /federation endpoints refuse every request");
+            }
+        }
+        let metrics_scope = api_tokens.iter().any(|token| token.scopes.contains(&TokenScope::Metrics));
+        if !metrics_scope && !config.auth.allow_unauthenticated {
+            warn!("No auth token has the metrics scope, so /metrics and /stats refuse every request");
+        }
         
         Ok(Self {
-            config,
-            server_directory: Arc::new(RwLock::new(HashMap::new())),
-            mycelium_client,
-            signing_keypair,
+            inner: Arc::new(BridgeInner {
+                config,
+                server_directory: RwLock::new(directory.servers),
+                revoked_keys: RwLock::new(directory.revoked_keys),
+                announcements: AnnouncementStore::default(),
+                homeserver,
+                inbound_buffer,
+                buffer_replay: tokio::sync::Notify::new(),
+                mycelium,
+                signer,
+                previous_signing_key,
+                batcher,
+                cipher,
+                capabilities,
+                queries: QueryTracker::default(),
+                pings: PingTracker::default(),
+                presence: EduCoalescer::default(),
+                receipts: EduCoalescer::default(),
+                media_assembler,
+                media_cache,
+                server_acls: AclStore::default(),
+                room_aliases: RoomAliases::default(),
+                rate_limiter,
+                error_reply_limiter: RateLimiter::new(federation_error::REPLY_LIMITS),
+                new_server_limiter,
+                announced_address: RwLock::new(None),
+                announced_available: RwLock::new(None),
+                reannounce: tokio::sync::Notify::new(),
+                mycelium_restarted: tokio::sync::Notify::new(),
+                loop_heartbeats: LoopHeartbeats::default(),
+                appservice,
+                discovery_client,
+                deliveries,
+                send_queue,
+                offline_queue,
+                outbound,
+                directory_stats: DirectoryStats::default(),
+                send_queue_receiver: std::sync::Mutex::new(Some(send_queue_receiver)),
+                cluster,
+                event_stream,
+                idempotency,
+                capacity_provider,
+                capacity_cache: RwLock::new(None),
+                health: HealthTracker::default(),
+                admin_stats,
+                peer_metrics,
+                bandwidth,
+                archive,
+                shutdown: watch::channel(false).0,
+                message_tasks: std::sync::Mutex::new(Vec::new()),
+                background: std::sync::Mutex::new(JoinSet::new()),
+                settings: std::sync::RwLock::new(settings),
+                config_path: std::sync::RwLock::new(None),
+                api_tokens,
+            }),
         })
     }
     
-    pub async fn start(&mut self) -> Result<()> {
+    /// Start the background tasks and the HTTP API, returning once the API
+    /// is listening. The bridge runs until shut down through the handle.
+    pub async fn start(&self) -> Result<BridgeHandle, BridgeError> {
+        self.start_cluster().await;
+        
         // Start discovery service
         self.start_discovery_service().await?;
         
+        self.resolve_room_aliases().await;
+        
         // Start message processing
         self.start_message_processor().await?;
+        self.start_send_queue();
+        self.start_cluster_feed();
+        self.start_buffer_replay();
+        
+        self.start_mycelium_monitor();
+        self.start_liveness_checks();
+        self.start_directory_persistence();
+        self.start_gossip();
+        
+        #[cfg(unix)]
+        if self.config_path.read().unwrap().is_some() {
+            self.spawn_reload_on_sighup();
+        }
+        
+        // Start HTTP API server; it stops accepting connections on shutdown
+        let (local_addr, server) = self.spawn_http_server().await?;
+        let bridge = self.clone();
+        let task = tokio::spawn(async move { bridge.run(server).await });
         
-        // Start HTTP API server
-        self.start_http_server().await?;
+        Ok(BridgeHandle {
+            bridge: self.clone(),
+            local_addr,
+            task,
+        })
+    }
+    
+    /// Serve until shutdown is requested, then drain. A failing HTTP server
+    /// stops the bridge without draining.
+    async fn run(&self, mut server: JoinHandle<Result<()>>) -> Result<()> {
+        tokio::select! {
+            result = &mut server => {
+                self.request_shutdown();
+                self.stop_background_tasks().await;
+                return result?;
+            }
+            _ = self.shutdown_requested() => {}
+        }
         
+        self.drain(server).await;
+        self.stop_background_tasks().await;
         Ok(())
     }
     
-    async fn start_http_server(&self) -> Result<()> {
-        let app = Router::new()
-            .route("/health", get(health_check))
+    /// Run `task` in the background until the bridge stops.
+    fn spawn_background(&self, task: impl std::future::Future<Output = ()> + Send + 'static) {
+        self.background.lock().unwrap().spawn(task);
+    }
+    
+    async fn stop_background_tasks(&self) {
+        let mut tasks = std::mem::take(&mut *self.background.lock().unwrap());
+        tasks.shutdown().await;
+    }
+    
+    /// Remember the file the config came from so it can be reloaded.
+    pub fn set_config_path(&self, path: impl Into<std::path::PathBuf>) {
+        *self.config_path.write().unwrap() = Some(path.into());
+    }
+    
+    fn settings(&self) -> Arc<ReloadableSettings> {
+        self.settings.read().unwrap().clone()
+    }
+    
+    /// Re-read the config file and apply the settings that can change while
+    /// running. An invalid config is rejected as a whole, leaving the current
+    /// settings in place. Returns the changes applied.
+    pub async fn reload_config(&self) -> Result<Vec<String>, BridgeError> {
+        let path = self
+            .config_path
+            .read()
+            .unwrap()
+            .clone()
+            .ok_or_else(|| anyhow::anyhow!("The bridge was not started from a config file"))
+            .map_err(BridgeError::Config)?;
+        let config = BridgeConfig::from_file(&path.to_string_lossy()).map_err(BridgeError::Config)?;
+        config.validate().map_err(BridgeError::Config)?;
+        let new_settings = ReloadableSettings::from_config(&config);
+        
+        for section in reload::restart_required(&self.config, &config) {
+            warn!("Config {} changed, but only takes effect after a restart", section);
+        }
+        
+        let changes = self.settings().diff(&new_settings);
+        if changes.is_empty() {
+            info!("Config reloaded, no reloadable settings changed");
+            return Ok(changes);
+        }
+        
+        self.rate_limiter.set_config(new_settings.rate_limit.clone());
+        let max_users_changed = self.settings().max_users != new_settings.max_users;
+        *self.settings.write().unwrap() = Arc::new(new_settings);
+        if max_users_changed {
+            *self.capacity_cache.write().await = None;
+        }
+        self.resolve_room_aliases().await;
+        for change in &changes {
+            info!("Config reloaded: {}", change);
+        }
+        
+        Ok(changes)
+    }
+    
+    #[cfg(unix)]
+    fn spawn_reload_on_sighup(&self) {
+        use tokio::signal::unix::{signal, SignalKind};
+        
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(hangup) => hangup,
+            Err(e) => {
+                error!("Failed to listen for SIGHUP: {}", e);
+                return;
+            }
+        };
+        let bridge = self.clone();
+        self.spawn_background(async move {
+            while hangup.recv().await.is_some() {
+                info!("Received SIGHUP, reloading config");
+                if let Err(e) = bridge.reload_config().await {
+                    error!("Rejected config reload: {}", e);
+                }
+            }
+        });
+    }
+    
+    /// Begin a graceful shutdown: stop taking requests and polling for
+    /// messages, then drain. [`BridgeHandle::join`] returns once done.
+    pub fn request_shutdown(&self) {
+        self.shutdown.send_replace(true);
+    }
+    
+    fn is_shutting_down(&self) -> bool {
+        *self.shutdown.borrow()
+    }
+    
+    pub(crate) async fn shutdown_requested(&self) {
+        let _ = self.shutdown.subscribe().wait_for(|stopping| *stopping).await;
+    }
+    
+    /// Sleep for `duration`, waking early if shutdown starts.
+    async fn idle(&self, duration: std::time::Duration) {
+        tokio::select! {
+            _ = tokio::time::sleep(duration) => {}
+            _ = self.shutdown_requested() => {}
+        }
+    }
+    
+    /// Finish in-flight requests and messages, send whatever is still
+    /// queued, then save the directory. Gives up on the in-flight work after
+    /// `shutdown.deadline_seconds`.
+    async fn drain(&self, server: JoinHandle<Result<()>>) {
+        let deadline = std::time::Duration::from_secs(self.config.shutdown.deadline_seconds);
+        info!("Shutting down, draining for up to {:?}", deadline);
+        
+        let pollers = std::mem::take(&mut *self.message_tasks.lock().unwrap());
+        let drained = tokio::time::timeout(deadline, async {
+            for poller in pollers {
+                let _ = poller.await;
+            }
+            if let Ok(Err(e)) = server.await {
+                error!("HTTP server failed during shutdown: {}", e);
+            }
+            self.flush_all_batches().await;
+        })
+        .await;
+        if drained.is_err() {
+            warn!("Shutdown deadline reached with work still in flight");
+        }
+        
+        if let Err(e) = self.save_directory().await {
+            error!("Failed to save server directory: {}", e);
+        }
+        info!("Bridge stopped");
+    }
+    
+    /// Send every queued batch now instead of after its batching delay.
+    async fn flush_all_batches(&self) {
+        for destination in self.batcher.queue_depths().await.into_keys() {
+            let events = self.batcher.take(&destination).await;
+            if !events.is_empty() {
+                self.flush_transaction(&destination, events).await;
+            }
+        }
+    }
+    
+    async fn save_directory(&self) -> Result<()> {
+        let Some(path) = &self.config.persistence.directory_path else {
+            return Ok(());
+        };
+        let servers = self.server_directory.read().await.clone();
+        let revoked_keys = self.revoked_keys.read().await.clone();
+        persistence::save_directory(std::path::Path::new(path), &servers, &revoked_keys).await?;
+        debug!("Saved {} servers to {}", servers.len(), path);
+        Ok(())
+    }
+    
+    /// The directory, plus this server as last announced, as the discovery
+    /// service would list them.
+    async fn known_servers(&self) -> Vec<ServerInfo> {
+        let mut servers: Vec<ServerInfo> = self.server_directory.read().await.values().cloned().collect();
+        if !servers.iter().any(|server| server.server_name == self.config.server_name) {
+            let own = self.announcements.get(std::slice::from_ref(&self.config.server_name)).await;
+            servers.extend(own.into_iter().map(ServerInfo::from));
+        }
+        servers
+    }
+    
+    /// Whether this instance polls mycelium and announces the server: always,
+    /// unless it is a cluster follower.
+    fn is_leader(&self) -> bool {
+        self.cluster.as_ref().is_none_or(|cluster| cluster.is_leader())
+    }
+    
+    /// Whether this instance is a hot standby, waiting to take over.
+    fn is_standby(&self) -> bool {
+        self.config.cluster.mode == config::ClusterMode::ActivePassive && !self.is_leader()
+    }
+    
+    /// Take or keep the leader lease. In active-passive mode only an
+    /// instance that reaches mycelium contends, so a leader that loses it
+    /// steps down for a standby. Returns true when this instance just
+    /// became the leader.
+    async fn contend_for_lease(&self, cluster: &Cluster) -> bool {
+        if self.config.cluster.mode == config::ClusterMode::ActivePassive {
+            let probe = tokio::time::timeout(cluster.lease() / 3, self.mycelium.get_info()).await;
+            if !probe.is_ok_and(|info| info.is_ok()) {
+                if cluster.is_leader() {
+                    warn!("Mycelium is unreachable, stepping down as cluster leader");
+                }
+                cluster.release_lease().await;
+                return false;
+            }
+        }
+        cluster.renew_lease().await
+    }
+    
+    /// Join the cluster: contend for the leader lease, and keep the shared
+    /// directory in step. The leader announces itself as soon as it takes
+    /// over.
+    async fn start_cluster(&self) {
+        let Some(cluster) = self.cluster.clone() else {
+            return;
+        };
+        self.contend_for_lease(&cluster).await;
+        cluster.keep_alive().await;
+        if let Err(e) = cluster.requeue_abandoned_sends(true).await {
+            error!("Failed to requeue unfinished sends: {}", e);
+        }
+        
+        let bridge = self.clone();
+        let lease_cluster = cluster.clone();
+        let lease = tokio::spawn(async move {
+            let mut interval = tokio::time::interval(lease_cluster.lease() / 3);
+            interval.tick().await;
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => {}
+                    _ = bridge.shutdown_requested() => break,
+                }
+                lease_cluster.keep_alive().await;
+                if bridge.contend_for_lease(&lease_cluster).await {
+                    if let Err(e) = bridge.announce_server().await {
+                        error!("Failed to announce server: {}", e);
+                    }
+                }
+                // The leader hands on the sends of nodes that died mid-send
+                if lease_cluster.is_leader() {
+                    if let Err(e) = lease_cluster.requeue_abandoned_sends(false).await {
+                        error!("Failed to requeue unfinished sends: {}", e);
+                    }
+                }
+            }
+            // Hand the leadership over now rather than when the lease runs out
+            lease_cluster.release_lease().await;
+        });
+        self.message_tasks.lock().unwrap().push(lease);
+        
+        let bridge = self.clone();
+        let period = std::time::Duration::from_secs(self.config.cluster.sync_interval_seconds);
+        self.spawn_background(async move {
+            let mut interval = tokio::time::interval(period);
+            loop {
+                interval.tick().await;
+                if let Err(e) = bridge.sync_directory(&cluster).await {
+                    error!("Failed to sync the shared directory: {}", e);
+                }
+            }
+        });
+    }
+    
+    /// Share the directory if leading, or take the leader's if following.
+    async fn sync_directory(&self, cluster: &Cluster) -> Result<()> {
+        if cluster.is_leader() {
+            let directory = persistence::LoadedDirectory {
+                servers: self.server_directory.read().await.clone(),
+                revoked_keys: self.revoked_keys.read().await.clone(),
+            };
+            return cluster.share_directory(&directory).await;
+        }
+        if let Some(directory) = cluster.shared_directory().await? {
+            *self.server_directory.write().await = directory.servers;
+            *self.revoked_keys.write().await = directory.revoked_keys;
+        }
+        Ok(())
+    }
+    
+    fn start_directory_persistence(&self) {
+        if self.config.persistence.directory_path.is_none() {
+            return;
+        }
+        let bridge = self.clone();
+        let period = std::time::Duration::from_secs(self.config.persistence.save_interval_seconds);
+        self.spawn_background(async move {
+            let mut interval = tokio::time::interval(period);
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                if let Err(e) = bridge.save_directory().await {
+                    error!("Failed to save server directory: {}", e);
+                }
+            }
+        });
+    }
+    
+    /// Bind the HTTP API and serve it until shutdown, returning the address
+    /// it listens on.
+    async fn spawn_http_server(&self) -> Result<(std::net::SocketAddr, JoinHandle<Result<()>>)> {
+        let admin = Router::new()
+            .route("/admin/queues", get(queue_stats))
+            .route("/admin/servers", get(dump_directory))
+            .route("/admin/servers/:server_name", delete(drop_server))
+            .route("/admin/verification_failures", get(verification_failures))
+            .route("/admin/rejections", get(rejections))
+            .route("/admin/peers", get(peer_stats))
+            .route("/admin/capabilities", get(capability_registrations))
+            .route("/admin/bandwidth", get(bandwidth_stats))
+            .route("/admin/archive", get(query_archive))
+            .route("/admin/messages/:correlation_id/resend", post(resend_message))
+            .route("/admin/announce", post(force_announce))
+            .route("/admin/ping/:server_name", post(ping_server))
+            .route("/admin/reload", post(reload_config))
+            .route("/admin/server_acls", get(list_server_acls))
+            .route("/admin/rate_limits", get(rate_limit_stats))
+            .route("/admin/appservice/rooms", get(appservice_rooms))
+            .route_layer(axum::middleware::from_fn_with_state(self.clone(), require_admin_scope));
+        
+        let send = Router::new()
             .route("/federation/send", post(send_federation_event))
+            .route("/federation/broadcast", post(broadcast_federation_event))
+            .route("/federation/status/:message_id", get(delivery_status))
+            .route("/federation/stream", get(stream_events))
             .route("/federation/servers", get(list_servers))
-            .layer(CorsLayer::permissive())
+            .route("/federation/servers/:server_name", get(get_server))
+            .route("/federation/user_search", post(search_users))
+            .route_layer(axum::middleware::from_fn_with_state(self.clone(), require_send_scope));
+        
+        let stats = Router::new()
+            .route("/stats", get(bridge_stats))
+            .route("/metrics", get(metrics))
+            .route_layer(axum::middleware::from_fn_with_state(self.clone(), require_metrics_scope));
+        
+        // Only the local homeserver may send through these
+        let federation = Router::new()
+            .route("/_matrix/federation/v1/send/:txn_id", put(receive_matrix_transaction))
+            .route("/_matrix/federation/v1/backfill/:room_id", get(backfill))
+            .route("/_matrix/federation/v1/get_missing_events/:room_id", post(get_missing_events))
+            .route("/_matrix/federation/v1/make_leave/:room_id/:user_id", get(make_leave))
+            .route("/_matrix/federation/v2/send_leave/:room_id/:event_id", put(send_leave))
+            .route("/_matrix/federation/v2/invite/:room_id/:event_id", put(invite))
+            .route("/_matrix/federation/v1/make_knock/:room_id/:user_id", get(make_knock))
+            .route("/_matrix/federation/v1/send_knock/:room_id/:event_id", put(send_knock))
+            .route("/_matrix/federation/v1/user/keys/claim", post(claim_keys))
+            .route("/_matrix/federation/v1/user/keys/query", post(query_keys))
+            .route("/_matrix/federation/v1/user/devices/:user_id", get(user_devices))
+            .route("/_matrix/federation/v1/query/profile", get(query_profile))
+            .route("/_matrix/media/:version/download/:server_name/:media_id", get(download_media))
+            .route(
+                "/_matrix/media/:version/download/:server_name/:media_id/:file_name",
+                get(download_media_with_name),
+            )
+            .route_layer(axum::middleware::from_fn_with_state(self.clone(), require_homeserver_signature));
+        
+        let mut app = Router::new()
+            .route("/health", get(health_check))
+            .route("/_matrix/app/v1/transactions/:txn_id", put(appservice_transaction))
+            .route("/_matrix/app/v1/users/:user_id", get(appservice_user_query))
+            .route("/_matrix/key/v2/server", get(matrix_server_keys))
+            .merge(federation)
+            .merge(send)
+            .merge(stats)
+            .merge(admin);
+        if self.config.discovery.decentralized {
+            // Same endpoints as the discovery service, as public as there
+            app = app
+                .route("/servers", get(directory_servers))
+                .route("/servers/select", get(select_directory_server))
+                .route("/servers/:server_name", get(directory_server));
+        }
+        let app = app
+            .layer(axum::middleware::from_fn_with_state(self.clone(), refuse_on_standby))
+            .layer(axum::middleware::from_fn(trace_request))
+            .layer(cors_layer(&self.config.cors_origins))
             .with_state(self.clone());
         
+        if self.config.tls.enabled {
+            let rustls = tls::load(&self.config.tls).await?;
+            self.spawn_background(tls::reload_on_change(self.config.tls.clone(), rustls.clone()));
+            
+            let handle = axum_server::Handle::new();
+            let shutdown = handle.clone();
+            let bridge = self.clone();
+            self.spawn_background(async move {
+                bridge.shutdown_requested().await;
+                shutdown.graceful_shutdown(None);
+            });
+            
+            let address: std::net::SocketAddr = self.config.bind_address.parse()?;
+            let listening = handle.clone();
+            let mut server = tokio::spawn(async move {
+                axum_server::bind_rustls(address, rustls)
+                    .handle(handle)
+                    .serve(app.into_make_service())
+                    .await?;
+                Ok(())
+            });
+            // Resolves to `None` when binding failed, leaving the error in `server`
+            let local_addr = tokio::select! {
+                Some(local_addr) = listening.listening() => local_addr,
+                result = &mut server => {
+                    result??;
+                    return Err(anyhow::anyhow!("HTTPS server stopped before listening"));
+                }
+            };
+            info!("Bridge HTTPS server listening on {}", local_addr);
+            return Ok((local_addr, server));
+        }
+        
         let listener = tokio::net::TcpListener::bind(&self.config.bind_address).await?;
-        info!("Bridge HTTP server listening on {}", self.config.bind_address);
+        let local_addr = listener.local_addr()?;
+        info!("Bridge HTTP server listening on {}", local_addr);
         
-        axum::serve(listener, app).await?;
-        Ok(())
+        let bridge = self.clone();
+        let server = tokio::spawn(async move {
+            axum::serve(listener, app)
+                .with_graceful_shutdown(async move { bridge.shutdown_requested().await })
+                .await?;
+            Ok(())
+        });
+        Ok((local_addr, server))
     }
     
-    async fn start_discovery_service(&mut self) -> Result<()> {
+    async fn start_discovery_service(&self) -> Result<()> {
         info!("Starting discovery service");
         
         // Announce this server
         self.announce_server().await?;
         
-        // Start periodic announcements
+        // Start periodic announcements, re-reading the interval each time as
+        // it can be reloaded
         let bridge = self.clone();
-        tokio::spawn(async move {
-            let mut interval = tokio::time::interval(std::time::Duration::from_secs(300));
+        self.spawn_background(async move {
             loop {
-                interval.tick().await;
+                let settings = bridge.settings();
+                let interval = discovery::jittered_interval(
+                    settings.announce_interval_seconds,
+                    settings.announce_jitter_seconds,
+                );
+                tokio::select! {
+                    _ = tokio::time::sleep(interval) => {}
+                    _ = bridge.reannounce.notified() => {}
+                }
                 if let Err(e) = bridge.announce_server().await {
                     error!("Failed to announce server: {}", e);
                 }
             }
         });
+        self.start_capacity_monitor();
+        
+        if let Some(client) = self.discovery_client.clone() {
+            let bridge = self.clone();
+            let period = std::time::Duration::from_secs(self.config.discovery.heartbeat_interval_seconds);
+            self.spawn_background(async move {
+                let mut interval = tokio::time::interval(period);
+                let mut registered_address = None;
+                loop {
+                    interval.tick().await;
+                    if !bridge.is_leader() {
+                        registered_address = None;
+                        continue;
+                    }
+                    if let Err(e) = bridge.refresh_registration(&client, &mut registered_address).await {
+                        error!("Failed to register with discovery service {}: {}", client.url(), e);
+                    }
+                }
+            });
+        }
         
         // Start listening for announcements
         let bridge = self.clone();
-        tokio::spawn(async move {
+        self.spawn_background(async move {
             loop {
+                if !bridge.is_leader() {
+                    tokio::time::sleep(std::time::Duration::from_secs(60)).await;
+                    continue;
+                }
                 match bridge.poll_discovery_messages().await {
-                    Ok(announcements) => {
-                        for announcement in announcements {
-                            bridge.process_server_announcement(announcement).await;
+                    Ok(messages) => {
+                        for message in messages {
+                            match message {
+                                DiscoveryMessage::Announcement(announcement) => {
+                                    bridge.process_server_announcement(announcement).await;
+                                }
+                                DiscoveryMessage::KeyRevocation(revocation) => {
+                                    bridge.process_key_revocation(revocation).await;
+                                }
+                                DiscoveryMessage::AddressChallenge(challenge) => {
+                                    if let Err(e) = bridge.answer_address_challenge(challenge).await {
+                                        error!("Failed to answer address challenge: {}", e);
+                                    }
+                                }
+                            }
                         }
                     }
                     Err(e) => {
@@ -115,318 +997,3775 @@ impl MatrixMyceliumBridge {
         Ok(())
     }
     
-    async fn start_message_processor(&mut self) -> Result<()> {
-        info!("Starting message processor");
-        
+    /// Health-check mycelium periodically and re-announce if the address we
+    /// are reachable at changed, e.g. after failing over to another node.
+    ///
+    /// A node that restarted, or was unreachable and is back, is treated
+    /// the same even at the same address, as it may have lost what it knew
+    /// of the overlay: the bridge re-announces and re-registers straight
+    /// away, and pollers waiting out an error read again at once.
+    fn start_mycelium_monitor(&self) {
         let bridge = self.clone();
-        tokio::spawn(async move {
+        let interval = std::time::Duration::from_secs(self.config.mycelium.health_check_interval_seconds);
+        self.spawn_background(async move {
+            let mut interval = tokio::time::interval(interval);
+            let mut last_seen: Option<MyceliumInfo> = None;
+            let mut unreachable = false;
             loop {
-                match bridge.poll_federation_messages().await {
-                    Ok(messages) => {
-                        for message in messages {
-                            if let Err(e) = bridge.process_federation_message(message).await {
-                                error!("Failed to process federation message: {}", e);
-                            }
-                        }
+                interval.tick().await;
+                bridge.loop_heartbeats.beat("mycelium monitor");
+                let info = match bridge.mycelium.get_info().await {
+                    Ok(info) => {
+                        bridge.health.record(health::MYCELIUM_COMPONENT, Ok(())).await;
+                        info
                     }
                     Err(e) => {
-                        error!("Failed to poll federation messages: {}", e);
-                        tokio::time::sleep(std::time::Duration::from_secs(10)).await;
+                        error!("Mycelium health check failed: {}", e);
+                        bridge.health.record(health::MYCELIUM_COMPONENT, Err(e.to_string())).await;
+                        unreachable = true;
+                        continue;
                     }
-                }
+                };
                 
-                tokio::time::sleep(std::time::Duration::from_secs(5)).await;
+                let restarted = last_seen
+                    .as_ref()
+                    .is_some_and(|last_seen| unreachable || info.restarted_since(last_seen));
+                let announced = bridge.announced_address.read().await.clone();
+                if restarted {
+                    warn!("Mycelium node restarted, at {}; re-announcing", info.address);
+                    bridge.mycelium_restarted.notify_waiters();
+                    bridge.reannounce.notify_one();
+                    if let Some(client) = bridge.discovery_client.as_deref().filter(|_| bridge.is_leader()) {
+                        if let Err(e) = bridge.register_with(client).await {
+                            error!("Failed to register with discovery service {}: {}", client.url(), e);
+                        }
+                    }
+                } else if announced.is_some_and(|announced| announced != info.address) {
+                    warn!("Mycelium address changed to {}, re-announcing", info.address);
+                    bridge.reannounce.notify_one();
+                }
+                last_seen = Some(info);
+                unreachable = false;
             }
         });
-        
-        Ok(())
-    }
-    
-    pub async fn send_federation_event(&self, event: FederationEvent) -> Result<()> {
-        // Translate Matrix event to Mycelium message
-        let mycelium_msg = self.translate_to_mycelium(event).await?;
-        
-        // Send via Mycelium
-        self.send_mycelium_message(mycelium_msg).await?;
-        
-        Ok(())
     }
     
-    async fn translate_to_mycelium(&self, event: FederationEvent) -> Result<MyceliumMessage> {
-        let payload = serde_json::to_string(&event.event_data)?;
-        let signature = self.sign_message(&payload)?;
-        
-        let msg = MyceliumMessage {
-            version: "1.0".to_string(),
-            source_server: self.config.server_name.clone(),
-            destination_server: event.destination,
-            message_type: "federation_event".to_string(),
-            timestamp: chrono::Utc::now().to_rfc3339(),
-            payload: event.event_data,
-            signature,
-        };
-        
-        Ok(msg)
+    /// Wait out `duration` after a failed poll, or less if the mycelium node
+    /// comes back from a restart meanwhile.
+    async fn back_off_polling(&self, duration: std::time::Duration) {
+        tokio::select! {
+            _ = self.idle(duration) => {}
+            _ = self.mycelium_restarted.notified() => {}
+        }
     }
     
-    async fn send_mycelium_message(&self, msg: MyceliumMessage) -> Result<()> {
-        let topic = format!("matrix.federation.{}", msg.destination_server);
-        
-        let response = self.mycelium_client
-            .post(&format!("{}/api/v1/message", self.config.mycelium_api_url))
-            .json(&serde_json::json!({
-                "topic": topic,
-                "data": serde_json::to_string(&msg)?
-            }))
-            .send()
-            .await?;
-            
-        if response.status().is_success() {
-            info!("Message sent successfully to {}", msg.destination_server);
-        } else {
-            error!("Failed to send message: {}", response.status());
-            return Err(anyhow::anyhow!("Failed to send message: {}", response.status()));
-        }
-        
-        Ok(())
+    /// Re-announce as soon as this server fills up or has room again,
+    /// rather than at the next interval.
+    fn start_capacity_monitor(&self) {
+        let bridge = self.clone();
+        let interval = std::time::Duration::from_secs(self.config.homeserver.capacity_cache_seconds);
+        self.spawn_background(async move {
+            while !bridge.is_shutting_down() {
+                bridge.idle(interval).await;
+                let available = match bridge.get_current_capacity().await {
+                    Ok(capacity) => capacity.available,
+                    Err(e) => {
+                        error!("Failed to check capacity: {}", e);
+                        continue;
+                    }
+                };
+                let announced = *bridge.announced_available.read().await;
+                if announced.is_some_and(|announced| announced != available) {
+                    let now = if available { "has room again" } else { "is full" };
+                    info!("Server {}, re-announcing", now);
+                    bridge.reannounce.notify_one();
+                }
+            }
+        });
     }
     
-    async fn announce_server(&self) -> Result<()> {
-        let announcement = ServerAnnouncement {
-            server_name: self.config.server_name.clone(),
-            mycelium_address: self.get_mycelium_address().await?,
-            public_key: base64::encode(self.signing_keypair.public.to_bytes()),
-            capabilities: vec!["matrix_federation".to_string(), "tf_connect_auth".to_string()],
-            capacity: self.get_current_capacity().await?,
-            timestamp: chrono::Utc::now().to_rfc3339(),
-            signature: String::new(), // Will be filled after signing
-        };
-        
-        let announcement_json = serde_json::to_string(&announcement)?;
-        let signature = self.sign_message(&announcement_json)?;
-        
-        let mut signed_announcement = announcement;
-        signed_announcement.signature = signature;
-        
-        self.mycelium_client
-            .post(&format!("{}/api/v1/message", self.config.mycelium_api_url))
-            .json(&serde_json::json!({
-                "topic": "matrix.discovery",
-                "data": serde_json::to_string(&signed_announcement)?
-            }))
-            .send()
-            .await?;
-            
-        info!("Server announced to discovery service");
-        Ok(())
+    /// Periodically move peers that stopped announcing to `unknown`, then
+    /// `offline`.
+    fn start_liveness_checks(&self) {
+        let bridge = self.clone();
+        let interval = std::time::Duration::from_secs(self.config.liveness.check_interval_seconds);
+        self.spawn_background(async move {
+            while !bridge.is_shutting_down() {
+                bridge.idle(interval).await;
+                bridge.update_liveness().await;
+            }
+        });
     }
     
-    async fn poll_discovery_messages(&self) -> Result<Vec<ServerAnnouncement>> {
-        let response = self.mycelium_client
-            .get(&format!("{}/api/v1/messages", self.config.mycelium_api_url))
-            .query(&[("topic", "matrix.discovery")])
-            .send()
-            .await?;
-            
-        if !response.status().is_success() {
-            return Err(anyhow::anyhow!("Failed to poll discovery messages"));
-        }
-        
-        let messages: Vec<serde_json::Value> = response.json().await?;
-        let mut announcements = Vec::new();
-        
-        for msg in messages {
-            if let Ok(announcement) = serde_json::from_value::<ServerAnnouncement>(msg) {
-                if self.verify_server_announcement(&announcement) {
-                    announcements.push(announcement);
-                } else {
-                    warn!("Invalid server announcement signature");
-                }
+    async fn update_liveness(&self) {
+        let now = chrono::Utc::now();
+        let interval = self.settings().announce_interval_seconds;
+        let mut directory = self.server_directory.write().await;
+        for server in directory.values_mut() {
+            if server.status == ServerStatus::Untrusted {
+                continue;
+            }
+            let is_static = self.static_peer(&server.server_name).is_some();
+            let status =
+                liveness::status_after(&self.config.liveness, server.last_seen, now, interval, is_static);
+            if status != server.status {
+                let silent = (now - server.last_seen).num_seconds();
+                info!("{} is now {}, silent for {}s", server.server_name, status, silent);
+                server.status = status;
             }
         }
-        
-        Ok(announcements)
     }
     
-    async fn poll_federation_messages(&self) -> Result<Vec<MyceliumMessage>> {
-        let topic = format!("matrix.federation.{}", self.config.server_name);
-        
-        let response = self.mycelium_client
-            .get(&format!("{}/api/v1/messages", self.config.mycelium_api_url))
-            .query(&[("topic", &topic)])
-            .send()
-            .await?;
-            
-        if !response.status().is_success() {
-            return Err(anyhow::anyhow!("Failed to poll federation messages"));
+    /// Record traffic from `server_name`, bringing it back online if it had
+    /// gone quiet.
+    async fn mark_seen(&self, server_name: &str) {
+        let mut directory = self.server_directory.write().await;
+        let Some(server) = directory.get_mut(server_name) else {
+            return;
+        };
+        server.last_seen = chrono::Utc::now();
+        let previous = server.status;
+        if matches!(previous, ServerStatus::Unknown | ServerStatus::Offline) {
+            info!("{} is online again", server_name);
+            server.status = ServerStatus::Online;
+        }
+        drop(directory);
+        if previous == ServerStatus::Offline {
+            self.release_held_messages(server_name);
+        }
+    }
+    
+    /// Send what was held for `server_name` while it was offline.
+    fn release_held_messages(&self, server_name: &str) {
+        let bridge = self.clone();
+        let server_name = server_name.to_string();
+        tokio::spawn(async move {
+            let held = bridge.offline_queue.take(&server_name).await;
+            if held.is_empty() {
+                return;
+            }
+            info!("{} is back online, sending {} held messages", server_name, held.len());
+            for msg in held {
+                if let Err(e) = bridge.send_mycelium_message(msg).await {
+                    error!("Failed to send a held message to {}: {}", server_name, e);
+                }
+            }
+        });
+    }
+    
+    /// Periodically send a directory digest to a few random peers, and
+    /// answer the digests, requests and entries they send back.
+    fn start_gossip(&self) {
+        if !self.config.gossip_enabled() {
+            return;
         }
+        info!("Starting directory gossip");
         
-        let messages: Vec<serde_json::Value> = response.json().await?;
-        let mut federation_messages = Vec::new();
+        let bridge = self.clone();
+        let period = std::time::Duration::from_secs(self.config.gossip.interval_seconds);
+        self.spawn_background(async move {
+            let mut interval = tokio::time::interval(period);
+            loop {
+                interval.tick().await;
+                if !bridge.is_leader() {
+                    continue;
+                }
+                if let Err(e) = bridge.send_gossip_digest().await {
+                    error!("Failed to send gossip digest: {}", e);
+                }
+            }
+        });
         
-        for msg in messages {
-            if let Ok(federation_msg) = serde_json::from_value::<MyceliumMessage>(msg) {
-                if self.verify_federation_message(&federation_msg) {
-                    federation_messages.push(federation_msg);
-                } else {
-                    warn!("Invalid federation message signature");
+        let bridge = self.clone();
+        let period = std::time::Duration::from_secs(self.config.gossip.poll_interval_seconds);
+        self.spawn_background(async move {
+            loop {
+                if !bridge.is_leader() {
+                    tokio::time::sleep(period).await;
+                    continue;
+                }
+                match bridge.poll_gossip_messages().await {
+                    Ok(messages) => {
+                        for message in messages {
+                            bridge.process_gossip_message(message).await;
+                        }
+                    }
+                    Err(e) => error!("Failed to poll gossip messages: {}", e),
+                }
+                tokio::time::sleep(period).await;
+            }
+        });
+    }
+    
+    async fn start_message_processor(&self) -> Result<()> {
+        info!("Starting message processor");
+        
+        self.spawn_message_poller(
+            edu::federation_topic(&self.config.server_name),
+            std::time::Duration::from_millis(self.config.mycelium.poll_interval_ms),
+        );
+        self.spawn_message_poller(
+            edu::edu_topic(&self.config.server_name),
+            std::time::Duration::from_millis(self.config.edu.poll_interval_ms),
+        );
+        
+        Ok(())
+    }
+    
+    fn spawn_message_poller(&self, topic: String, interval: std::time::Duration) {
+        let bridge = self.clone();
+        let config = &self.config.mycelium;
+        let mut dispatcher = InboundDispatcher::new(config.inbound_concurrency, config.inbound_max_pending);
+        let poller = tokio::spawn(async move {
+            let heartbeat = format!("{} poller", topic);
+            while !bridge.is_shutting_down() {
+                bridge.loop_heartbeats.beat(&heartbeat);
+                if !bridge.is_leader() {
+                    bridge.idle(interval.max(std::time::Duration::from_secs(1))).await;
+                    continue;
+                }
+                let polled = tokio::select! {
+                    polled = bridge.poll_federation_messages(&topic) => polled,
+                    _ = bridge.shutdown_requested() => break,
+                };
+                match polled {
+                    Ok(messages) => {
+                        for message in messages {
+                            let bridge = bridge.clone();
+                            let process = move |message| bridge.clone().process_polled_message(message);
+                            dispatcher.dispatch(message, process).await;
+                        }
+                    }
+                    Err(e) => {
+                        error!("Failed to poll federation messages: {}", e);
+                        bridge.back_off_polling(std::time::Duration::from_secs(10)).await;
+                    }
+                }
+                
+                // A long poll already waited inside the read
+                if !bridge.config.mycelium.long_poll {
+                    bridge.idle(interval).await;
                 }
             }
+            dispatcher.finish().await;
+        });
+        self.message_tasks.lock().unwrap().push(poller);
+    }
+    
+    async fn process_polled_message(self, message: MyceliumMessage) {
+        let span = tracing::info_span!(
+            "federation_message",
+            source = %message.source_server,
+            message_type = %message.message_type,
+            correlation_id = %message
+                .correlation_id
+                .clone()
+                .unwrap_or_else(|| uuid::Uuid::new_v4().to_string()),
+        );
+        if let Some(carrier) = &message.trace_context {
+            telemetry::set_remote_parent(&span, carrier);
+        }
+        if let Err(e) = self.process_federation_message(message).instrument(span).await {
+            error!("Failed to process federation message: {}", e);
+        }
+    }
+    
+    /// Queue `event` to be sent in the background. Returns the message ID its
+    /// status is tracked by, or an error if the queue is full.
+    pub async fn queue_federation_event(&self, event: FederationEvent) -> Result<String, BridgeError> {
+        let event_id = event.event_data["event_id"].as_str();
+        if let Some(cluster) = &self.cluster {
+            let message_id = cluster.queue_delivery(&event.destination, event_id).await?;
+            let queued = QueuedSend {
+                message_id: message_id.clone(),
+                event,
+            };
+            cluster.push_send(&queued, self.config.send_queue.capacity).await?;
+            return Ok(message_id);
         }
         
-        Ok(federation_messages)
+        let permit = self
+            .send_queue
+            .try_reserve()
+            .map_err(|e| BridgeError::Unavailable(format!("Send queue unavailable: {}", e)))?;
+        let message_id = self.deliveries.queue(&event.destination, event_id).await;
+        permit.send(QueuedSend {
+            message_id: message_id.clone(),
+            event,
+        });
+        Ok(message_id)
     }
     
-    async fn process_server_announcement(&self, announcement: ServerAnnouncement) {
-        let server_info = ServerInfo {
-            server_name: announcement.server_name.clone(),
-            mycelium_address: announcement.mycelium_address,
-            public_key: announcement.public_key,
-            capabilities: announcement.capabilities,
-            capacity: announcement.capacity,
-            last_seen: chrono::Utc::now(),
-            status: ServerStatus::Online,
+    pub async fn delivery_status(&self, message_id: &str) -> Option<delivery::DeliveryStatus> {
+        let Some(cluster) = &self.cluster else {
+            return self.deliveries.get(message_id).await;
         };
+        cluster.delivery(message_id).await.unwrap_or_else(|e| {
+            error!("Failed to look up message {}: {}", message_id, e);
+            None
+        })
+    }
+    
+    /// The servers in the directory, in no particular order.
+    pub async fn directory(&self) -> Vec<ServerInfo> {
+        self.server_directory.read().await.values().cloned().collect()
+    }
+    
+    /// Verified inbound events as they arrive, as pushed to
+    /// `/federation/stream` consumers. `None` unless `[stream] enabled`.
+    pub fn subscribe_events(&self) -> Option<broadcast::Receiver<Arc<InboundEvent>>> {
+        self.event_stream.as_ref().map(|stream| stream.subscribe())
+    }
+    
+    /// Send queued events, `send_queue.concurrency` at a time. On shutdown,
+    /// events already queued are still sent.
+    fn start_send_queue(&self) {
+        let Some(mut queue) = self.send_queue_receiver.lock().unwrap().take() else {
+            return;
+        };
+        let bridge = self.clone();
+        let worker = tokio::spawn(async move {
+            let slots = Arc::new(tokio::sync::Semaphore::new(bridge.config.send_queue.concurrency.max(1)));
+            let mut sends = tokio::task::JoinSet::new();
+            loop {
+                let queued = tokio::select! {
+                    queued = queue.recv() => queued,
+                    _ = bridge.shutdown_requested() => None,
+                };
+                let Some(queued) = queued else {
+                    break;
+                };
+                let Ok(slot) = slots.clone().acquire_owned().await else {
+                    break;
+                };
+                let sender = bridge.clone();
+                sends.spawn(async move {
+                    sender.send_queued(queued).await;
+                    drop(slot);
+                });
+                while sends.try_join_next().is_some() {}
+            }
+            
+            queue.close();
+            while let Some(queued) = queue.recv().await {
+                let sender = bridge.clone();
+                sends.spawn(async move { sender.send_queued(queued).await });
+            }
+            while sends.join_next().await.is_some() {}
+        });
+        self.message_tasks.lock().unwrap().push(worker);
+    }
+    
+    async fn send_queued(&self, queued: QueuedSend) {
+        let destination = queued.event.destination.clone();
+        let result = self.send_federation_event(queued.event.clone()).await.map_err(|e| {
+            error!("Failed to send queued message {} to {}: {}", queued.message_id, destination, e);
+            e.to_string()
+        });
+        match &self.cluster {
+            Some(cluster) => {
+                cluster.delivery_sent(&queued.message_id, result).await;
+                cluster.finish_send(&queued).await;
+            }
+            None => self.deliveries.sent(&queued.message_id, result).await,
+        }
+    }
+    
+    /// Move sends from the shared queue to the local one as it has room, so
+    /// each instance takes its share.
+    fn start_cluster_feed(&self) {
+        let Some(cluster) = self.cluster.clone() else {
+            return;
+        };
+        let bridge = self.clone();
+        let feed = tokio::spawn(async move {
+            let mut connection = None;
+            while !bridge.is_shutting_down() {
+                if bridge.is_standby() {
+                    bridge.idle(std::time::Duration::from_secs(1)).await;
+                    continue;
+                }
+                let Ok(permit) = bridge.send_queue.reserve().await else {
+                    break;
+                };
+                let popped = match &mut connection {
+                    Some(connection) => cluster.pop_send(connection, std::time::Duration::from_secs(1)).await,
+                    None => cluster.queue_connection().await.map(|opened| {
+                        connection = Some(opened);
+                        None
+                    }),
+                };
+                match popped {
+                    Ok(Some(queued)) if bridge.is_shutting_down() => {
+                        if let Err(e) = cluster.return_send(&queued).await {
+                            error!("Failed to return message {} to the queue: {}", queued.message_id, e);
+                        }
+                    }
+                    Ok(Some(queued)) => permit.send(queued),
+                    Ok(None) => {}
+                    Err(e) => {
+                        error!("Failed to read the shared send queue: {}", e);
+                        connection = None;
+                        bridge.idle(std::time::Duration::from_secs(5)).await;
+                    }
+                }
+            }
+        });
+        self.message_tasks.lock().unwrap().push(feed);
+    }
+    
+    /// Whether `federation.allowed_rooms` and `blocked_rooms` let events in
+    /// the room out.
+    async fn is_room_federated(&self, room_id: &str) -> bool {
+        let aliases = self.room_aliases.aliases(room_id).await;
+        self.settings().federation.is_room_allowed(room_id, &aliases)
+    }
+    
+    /// Look up the rooms behind the aliases named in the room filters, so
+    /// they apply before the room's canonical alias event has been seen.
+    async fn resolve_room_aliases(&self) {
+        let federation = self.settings().federation.clone();
+        let aliases = federation
+            .allowed_rooms
+            .iter()
+            .chain(&federation.blocked_rooms)
+            .filter(|pattern| pattern.starts_with('#') && !pattern.contains(['*', '?']));
+        for alias in aliases {
+            let path = format!("/_matrix/client/v3/directory/room/{}", appservice::encode(alias));
+            match self.homeserver.client().request("GET", &path, None).await {
+                Ok((200, body)) => match body["room_id"].as_str() {
+                    Some(room_id) => self.room_aliases.insert(room_id, alias).await,
+                    None => warn!("Homeserver returned no room for {}", alias),
+                },
+                Ok((status, _)) => warn!("Failed to resolve room alias {}: {}", alias, status),
+                Err(e) => warn!("Failed to resolve room alias {}: {}", alias, e),
+            }
+        }
+    }
+    
+    /// Send an EDU straight to the destination's EDU topic, bypassing the
+    /// batcher so it is never delayed behind PDUs.
+    pub async fn send_edu(&self, destination: &str, edu: serde_json::Value) -> Result<(), BridgeError> {
+        if let Some(room_id) = edu["content"]["room_id"].as_str() {
+            if !self.is_room_federated(room_id).await {
+                return Err(BridgeError::Validation(format!("{} is not federated over mycelium", room_id)));
+            }
+            if !self.server_acls.is_allowed(room_id, destination).await {
+                return Err(BridgeError::Validation(format!(
+                    "{} is denied by the server ACL of {}",
+                    destination, room_id
+                )));
+            }
+        }
         
-        let mut directory = self.server_directory.write().await;
-        directory.insert(announcement.server_name, server_info);
-        
-        info!("Updated server directory with {}", announcement.server_name);
+        let ttl_seconds = match edu["edu_type"].as_str() {
+            Some(edu::TYPING_EDU_TYPE) => self.config.edu.typing_ttl_seconds,
+            Some(edu::PRESENCE_EDU_TYPE) => self.config.edu.presence_ttl_seconds,
+            _ => 0,
+        };
+        let mut msg = self.build_message(destination, edu::EDU_MESSAGE_TYPE, &edu).await?;
+        if ttl_seconds > 0 {
+            let expires_at = chrono::Utc::now() + chrono::Duration::seconds(ttl_seconds as i64);
+            msg.expires_at = Some(expires_at.to_rfc3339());
+        }
+        Ok(self.send_mycelium_message_on(&edu::edu_topic(destination), msg).await?)
     }
     
-    async fn process_federation_message(&self, message: MyceliumMessage) -> Result<()> {
-        info!("Processing federation message from {}", message.source_server);
+    /// Coalesce presence updates per user and send at most one presence EDU
+    /// per destination every `presence_interval_ms`.
+    async fn queue_presence(&self, destination: &str, edu: &serde_json::Value) {
+        if !self.config.edu.presence_enabled {
+            return;
+        }
         
-        // Forward to Matrix homeserver
-        let response = self.mycelium_client
-            .post(&format!("{}/federation/receive", self.config.matrix_homeserver_url))
-            .json(&message.payload)
-            .send()
-            .await?;
-            
-        if response.status().is_success() {
-            info!("Federation message forwarded to Matrix homeserver");
-        } else {
-            error!("Failed to forward message to Matrix: {}", response.status());
+        let mut schedule_flush = false;
+        for (user_id, update) in edu::presence_updates(edu) {
+            schedule_flush |= self.presence.push(destination, user_id, update).await;
+        }
+        if !schedule_flush {
+            return;
         }
         
+        let bridge = self.clone();
+        let destination = destination.to_string();
+        tokio::spawn(async move {
+            let interval = std::time::Duration::from_millis(bridge.config.edu.presence_interval_ms);
+            tokio::time::sleep(interval).await;
+            
+            let updates = bridge.presence.take(&destination).await;
+            if updates.is_empty() {
+                return;
+            }
+            let count = updates.len();
+            if let Err(e) = bridge.send_edu(&destination, edu::presence_edu(updates)).await {
+                error!("Failed to send presence to {}: {}", destination, e);
+            } else {
+                info!("Sent {} coalesced presence updates to {}", count, destination);
+            }
+        });
+    }
+    
+    /// Send an EDU on the regular federation topic, unbatched and never
+    /// coalesced, for EDUs whose loss breaks end-to-end encryption.
+    pub async fn send_reliable_edu(
+        &self,
+        destination: &str,
+        edu: serde_json::Value,
+    ) -> Result<(), BridgeError> {
+        let msg = self.build_message(destination, edu::RELIABLE_EDU_MESSAGE_TYPE, &edu).await?;
+        self.send_mycelium_message(msg).await?;
         Ok(())
     }
     
-    async fn get_mycelium_address(&self) -> Result<String> {
-        let response = self.mycelium_client
-            .get(&format!("{}/api/v1/info", self.config.mycelium_api_url))
-            .send()
-            .await?;
-            
-        let info: serde_json::Value = response.json().await?;
-        let address = info["address"].as_str()
-            .ok_or_else(|| anyhow::anyhow!("Failed to get Mycelium address"))?;
+    /// Coalesce read receipts per room and user before sending them on.
+    async fn queue_receipts(&self, destination: &str, edu: &serde_json::Value) {
+        let mut schedule_flush = false;
+        for (key, update) in edu::receipt_updates(edu) {
+            schedule_flush |= self.receipts.push(destination, key, update).await;
+        }
+        if !schedule_flush {
+            return;
+        }
+        
+        let bridge = self.clone();
+        let destination = destination.to_string();
+        tokio::spawn(async move {
+            let delay = std::time::Duration::from_millis(bridge.config.edu.receipt_delay_ms);
+            tokio::time::sleep(delay).await;
             
-        Ok(address.to_string())
+            let updates = bridge.receipts.take(&destination).await;
+            if updates.is_empty() {
+                return;
+            }
+            if let Err(e) = bridge.send_edu(&destination, edu::receipt_edu(updates)).await {
+                error!("Failed to send receipts to {}: {}", destination, e);
+            }
+        });
     }
     
-    async fn get_current_capacity(&self) -> Result<ServerCapacity> {
-        // Query Matrix homeserver for current user count
-        let response = self.mycelium_client
-            .get(&format!("{}/admin/users", self.config.matrix_homeserver_url))
-            .send()
-            .await;
-            
-        let current_users = match response {
-            Ok(resp) => {
-                let users: serde_json::Value = resp.json().await.unwrap_or_default();
-                users["total"].as_u64().unwrap_or(0) as u32
+    #[tracing::instrument(skip_all, fields(
+        destination = %event.destination,
+        correlation_id = %telemetry::correlation_id(&event.event_data),
+    ))]
+    pub async fn send_federation_event(&self, event: FederationEvent) -> Result<SendOutcome, BridgeError> {
+        let done = self.start_federation_event(event).await?;
+        let outcome = done
+            .await
+            .map_err(|_| anyhow::anyhow!("Transaction batch was dropped"))?
+            .map_err(|e| anyhow::anyhow!(e))?;
+        Ok(outcome)
+    }
+    
+    /// Check `event` and start sending it, so events started one after the
+    /// other go out in that order. The receiver gets the outcome once it was
+    /// sent.
+    async fn start_federation_event(&self, event: FederationEvent) -> Result<SendDone, BridgeError> {
+        if types::is_expired(event.expires_at.as_deref()) {
+            let expired = format!("Event for {} expired before it was sent", event.destination);
+            return Err(BridgeError::Validation(expired));
+        }
+        self.room_aliases.observe(&event.event_data).await;
+        if let Some(room_id) = event.event_data["room_id"].as_str() {
+            if !self.is_room_federated(room_id).await {
+                return Err(BridgeError::Validation(format!("{} is not federated over mycelium", room_id)));
+            }
+            if !self.server_acls.is_allowed(room_id, &event.destination).await {
+                return Err(BridgeError::Validation(format!(
+                    "{} is denied by the server ACL of {}",
+                    event.destination, room_id
+                )));
+            }
+        }
+        
+        // A transaction has one expiry for all its events, so events with
+        // their own are sent alone
+        if self.config.batching.enabled && event.expires_at.is_none() {
+            return Ok(self.queue_batched(event).await);
+        }
+        
+        // Translate Matrix event to Mycelium message
+        let mycelium_msg = self.translate_to_mycelium(event).await?;
+        
+        // Send via Mycelium
+        let outcome = self.send_mycelium_message(mycelium_msg).await?;
+        let (done, receiver) = tokio::sync::oneshot::channel();
+        let _ = done.send(Ok(outcome));
+        Ok(receiver)
+    }
+    
+    /// Send a copy of `event` to each of its destinations,
+    /// `fanout.concurrency` at a time. Returns the outcome per destination.
+    pub async fn broadcast_federation_event(
+        &self,
+        event: BroadcastEvent,
+    ) -> Result<BTreeMap<String, Result<(), String>>, BridgeError> {
+        let destinations = self.broadcast_destinations(&event).await;
+        if destinations.len() > self.config.fanout.max_destinations {
+            return Err(BridgeError::Validation(format!(
+                "{} destinations is over the limit of {}",
+                destinations.len(),
+                self.config.fanout.max_destinations
+            )));
+        }
+        info!("Broadcasting {} to {} servers", event.event_type, destinations.len());
+        
+        let slots = Arc::new(tokio::sync::Semaphore::new(self.config.fanout.concurrency.max(1)));
+        let mut sends = tokio::task::JoinSet::new();
+        for destination in destinations {
+            let slot = slots.clone().acquire_owned().await.map_err(anyhow::Error::from)?;
+            let bridge = self.clone();
+            let copy = FederationEvent {
+                destination: destination.clone(),
+                event_type: event.event_type.clone(),
+                event_data: event.event_data.clone(),
+                expires_at: event.expires_at.clone(),
+            };
+            sends.spawn(async move {
+                let result = bridge.send_federation_event(copy).await.map(drop).map_err(|e| e.to_string());
+                drop(slot);
+                (destination, result)
+            });
+        }
+        
+        let mut results = BTreeMap::new();
+        while let Some(joined) = sends.join_next().await {
+            let (destination, result) = joined?;
+            if let Err(e) = &result {
+                warn!("Broadcast of {} to {} failed: {}", event.event_type, destination, e);
             }
-            Err(_) => 0,
+            results.insert(destination, result);
+        }
+        Ok(results)
+    }
+    
+    /// The servers a broadcast goes to, never including this one. Without
+    /// a list, that is every trusted server in the directory.
+    async fn broadcast_destinations(&self, event: &BroadcastEvent) -> Vec<String> {
+        let directory = self.server_directory.read().await;
+        let candidates: Vec<String> = match &event.destinations {
+            Some(destinations) => destinations.clone(),
+            None => directory
+                .values()
+                .filter(|server| server.status != ServerStatus::Untrusted)
+                .map(|server| server.server_name.clone())
+                .collect(),
+        };
+        let supported = |name: &String| {
+            event.capability.as_ref().is_none_or(|capability| {
+                directory.get(name).is_some_and(|server| server.supports(capability))
+            })
         };
+        let mut destinations: Vec<String> = candidates
+            .into_iter()
+            .filter(|name| *name != self.config.server_name && supported(name))
+            .collect();
+        destinations.sort();
+        destinations.dedup();
+        destinations
+    }
+    
+    /// Add `event` to its destination's batch, flushing the batch when full.
+    async fn queue_batched(&self, event: FederationEvent) -> SendDone {
+        let destination = event.destination.clone();
+        let (done, action) = self.batcher.push(&destination, event.event_data).await;
         
-        Ok(ServerCapacity {
-            max_users: self.config.max_users,
-            current_users,
-            available: current_users < self.config.max_users,
-        })
+        match action {
+            BatchAction::Flush(events) => {
+                self.flush_transaction(&destination, events).await;
+            }
+            BatchAction::ScheduleFlush => {
+                let bridge = self.clone();
+                tokio::spawn(async move {
+                    tokio::time::sleep(bridge.batcher.max_delay()).await;
+                    let events = bridge.batcher.take(&destination).await;
+                    bridge.flush_transaction(&destination, events).await;
+                });
+            }
+            BatchAction::Wait => {}
+        }
+        
+        done
     }
     
-    fn sign_message(&self, message: &str) -> Result<String> {
-        let signature = self.signing_keypair.sign(message.as_bytes());
-        Ok(base64::encode(signature.to_bytes()))
+    /// Relay a Matrix federation transaction received from the local homeserver
+    /// to `destination`. Returns the per-PDU results in the shape expected by
+    /// `PUT /_matrix/federation/v1/send/{txnId}`, or `Unavailable` if the
+    /// overlay failed to send a PDU.
+    pub async fn relay_matrix_transaction(
+        &self,
+        txn_id: &str,
+        destination: &str,
+        transaction: serde_json::Value,
+    ) -> Result<serde_json::Value, BridgeError> {
+        let pdus = transaction["pdus"].as_array().cloned().unwrap_or_default();
+        let edus = transaction["edus"].as_array().cloned().unwrap_or_default();
+        info!(
+            "Relaying transaction {} to {} ({} PDUs, {} EDUs)",
+            txn_id, destination, pdus.len(), edus.len()
+        );
+        
+        // PDUs are started in order, so the batcher coalesces them into one
+        // message without reordering them. A PDU that can't go to the
+        // destination fails alone; one the overlay failed to send fails the
+        // transaction, so the homeserver retries it.
+        let mut results = serde_json::Map::new();
+        let mut started = Vec::new();
+        for pdu in pdus {
+            // The homeserver signed the request, so the ACLs in its events apply
+            self.server_acls.observe(&pdu).await;
+            let event = FederationEvent {
+                destination: destination.to_string(),
+                event_type: pdu["type"].as_str().unwrap_or_default().to_string(),
+                event_data: pdu,
+                expires_at: None,
+            };
+            let event_id = event.event_data["event_id"].as_str().map(str::to_string);
+            match self.start_federation_event(event).await {
+                Ok(done) => started.push((event_id, done)),
+                Err(BridgeError::Validation(reason)) => {
+                    error!("Failed to relay event to {}: {}", destination, reason);
+                    if let Some(event_id) = event_id {
+                        results.insert(event_id, serde_json::json!({ "error": reason }));
+                    }
+                }
+                Err(e) => return Err(relay_failed(txn_id, destination, e.to_string())),
+            }
+        }
+        
+        // EDUs don't fail the transaction
+        let mut sends = tokio::task::JoinSet::new();
+        for edu in edus {
+            let edu_type = edu["edu_type"].as_str().unwrap_or_default();
+            if edu_type == edu::PRESENCE_EDU_TYPE {
+                self.queue_presence(destination, &edu).await;
+                continue;
+            }
+            if edu_type == edu::RECEIPT_EDU_TYPE {
+                self.queue_receipts(destination, &edu).await;
+                continue;
+            }
+            if edu::is_reliable(edu_type) {
+                let bridge = self.clone();
+                let destination = destination.to_string();
+                let send = async move { bridge.send_reliable_edu(&destination, edu).await };
+                sends.spawn(send.in_current_span());
+                continue;
+            }
+            if edu::is_low_latency(edu_type) {
+                let bridge = self.clone();
+                let destination = destination.to_string();
+                let send = async move { bridge.send_edu(&destination, edu).await };
+                sends.spawn(send.in_current_span());
+                continue;
+            }
+            
+            let event = FederationEvent {
+                destination: destination.to_string(),
+                event_type: edu["edu_type"].as_str().unwrap_or_default().to_string(),
+                event_data: edu,
+                expires_at: None,
+            };
+            let bridge = self.clone();
+            let send = async move { bridge.send_federation_event(event).await.map(drop) };
+            sends.spawn(send.in_current_span());
+        }
+        
+        for (event_id, done) in started {
+            let sent = done.await.unwrap_or_else(|_| Err("Transaction batch was dropped".to_string()));
+            if let Err(e) = sent {
+                return Err(relay_failed(txn_id, destination, e));
+            }
+            if let Some(event_id) = event_id {
+                results.insert(event_id, serde_json::json!({}));
+            }
+        }
+        while let Some(joined) = sends.join_next().await {
+            if let Err(e) = joined? {
+                error!("Failed to relay EDU to {}: {}", destination, e);
+            }
+        }
+        
+        Ok(serde_json::json!({ "pdus": results }))
     }
     
-    fn verify_federation_message(&self, message: &MyceliumMessage) -> bool {
-        // Get public key for source server
-        // For now, we'll implement basic verification
-        // In production, this should verify against known server keys
-        !message.signature.is_empty()
+    /// Relay events the homeserver pushed to the appservice to the remote
+    /// servers linked to their rooms.
+    pub async fn relay_appservice_transaction(
+        &self,
+        txn_id: &str,
+        events: &[serde_json::Value],
+    ) -> Result<(), BridgeError> {
+        let Some(appservice) = &self.appservice else {
+            return Err(BridgeError::Validation("Appservice mode is disabled".to_string()));
+        };
+        if !appservice.is_new_transaction(txn_id).await {
+            return Ok(());
+        }
+        
+        let mut sends = tokio::task::JoinSet::new();
+        for (destination, pdu) in appservice.outbound_events(events).await {
+            let event = FederationEvent {
+                destination,
+                event_type: pdu["type"].as_str().unwrap_or_default().to_string(),
+                event_data: pdu,
+                expires_at: None,
+            };
+            let bridge = self.clone();
+            sends.spawn(async move { bridge.send_federation_event(event).await });
+        }
+        while let Some(joined) = sends.join_next().await {
+            if let Err(e) = joined? {
+                error!("Failed to relay appservice event: {}", e);
+            }
+        }
+        
+        Ok(())
     }
     
-    fn verify_server_announcement(&self, announcement: &ServerAnnouncement) -> bool {
-        // Verify announcement signature
-        // For now, basic verification
-        !announcement.signature.is_empty()
+    /// Proxy a federation API call to the bridge serving `destination` and wait
+    /// for its answer.
+    pub async fn federation_query(
+        &self,
+        kind: &QueryKind,
+        destination: &str,
+        path: String,
+        body: Option<serde_json::Value>,
+    ) -> Result<QueryResponse, BridgeError> {
+        if let Some(capability) = kind.capability {
+            if !self.capabilities.supports(capability) {
+                let disabled = format!("{} queries are disabled on this bridge", kind.name);
+                return Err(BridgeError::Validation(disabled));
+            }
+            let supported = self
+                .server_directory
+                .read()
+                .await
+                .get(destination)
+                .is_some_and(|server| server.supports(capability));
+            if !supported {
+                let unsupported = format!("{} does not support {} queries", destination, kind.name);
+                return Err(BridgeError::Validation(unsupported));
+            }
+        }
+        
+        let request = QueryRequest {
+            request_id: uuid::Uuid::new_v4().to_string(),
+            path,
+            body,
+        };
+        let request_id = request.request_id.clone();
+        let receiver = self.queries.register(&request_id, destination).await;
+        
+        let sent = match self
+            .build_message(destination, &kind.request_type(), &request)
+            .await
+        {
+            Ok(msg) => self.send_mycelium_message(msg).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = sent {
+            self.queries.cancel(&request_id).await;
+            return Err(e.into());
+        }
+        
+        let timeout = std::time::Duration::from_secs(self.config.queries.timeout_seconds);
+        match tokio::time::timeout(timeout, receiver).await {
+            Ok(Ok(response)) => Ok(response),
+            Ok(Err(_)) => Err(BridgeError::Unavailable(format!(
+                "{} query to {} was dropped",
+                kind.name, destination
+            ))),
+            Err(_) => {
+                self.queries.cancel(&request_id).await;
+                Err(BridgeError::Timeout(format!("{} query to {} timed out", kind.name, destination)))
+            }
+        }
     }
     
-    fn load_or_generate_keypair(path: &str) -> Result<Keypair> {
-        use std::fs;
+    /// Ping the bridge serving `destination` through the overlay. Returns the
+    /// round trip time.
+    pub async fn ping(
+        &self,
+        destination: &str,
+        timeout: std::time::Duration,
+    ) -> Result<std::time::Duration, BridgeError> {
+        let ping = Ping {
+            ping_id: uuid::Uuid::new_v4().to_string(),
+        };
+        let receiver = self.pings.register(&ping.ping_id, destination).await;
+        let sent = match self
+            .build_message(destination, PING_MESSAGE_TYPE, &ping)
+            .await
+        {
+            Ok(msg) => self.send_mycelium_message(msg).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = sent {
+            self.pings.cancel(&ping.ping_id).await;
+            return Err(e.into());
+        }
         
-        if let Ok(key_data) = fs::read(path) {
-            if key_data.len() == 64 {
-                let keypair = Keypair::from_bytes(&key_data)?;
-                info!("Loaded existing signing keypair from {}", path);
-                return Ok(keypair);
+        match tokio::time::timeout(timeout, receiver).await {
+            Ok(Ok(rtt)) => Ok(rtt),
+            Ok(Err(_)) => Err(BridgeError::Unavailable(format!("Ping to {} was dropped", destination))),
+            Err(_) => {
+                self.pings.cancel(&ping.ping_id).await;
+                Err(BridgeError::Timeout(format!("No answer from {} within {:?}", destination, timeout)))
             }
         }
+    }
+    
+    /// Send an invite to a user on `destination` and check that the event
+    /// handed back is the same invite, countersigned with the destination's
+    /// key from the directory.
+    pub async fn federated_invite(
+        &self,
+        destination: &str,
+        path: String,
+        body: serde_json::Value,
+    ) -> Result<QueryResponse, BridgeError> {
+        let sent_event = body["event"].clone();
+        let room_version = body["room_version"].as_str().unwrap_or("1").to_string();
+        let response = self
+            .federation_query(&queries::INVITE, destination, path, Some(body))
+            .await?;
         
-        // Generate new keypair
-        let mut csprng = rand::rngs::OsRng;
-        let keypair = Keypair::generate(&mut csprng);
+        if response.status != StatusCode::OK.as_u16() {
+            return Ok(response);
+        }
         
-        // Save to file
-        if let Some(parent) = std::path::Path::new(path).parent() {
-            fs::create_dir_all(parent)?;
+        let returned_event = &response.body["event"];
+        for field in ["room_id", "sender", "state_key", "type"] {
+            if returned_event[field] != sent_event[field] {
+                let altered = anyhow::anyhow!("Invite response from {} altered {}", destination, field);
+                return Err(BridgeError::Homeserver(altered));
+            }
+        }
+        let key = self
+            .server_directory
+            .read()
+            .await
+            .get(destination)
+            .and_then(|server| signer::decode_public_key(&server.public_key));
+        let countersigned =
+            key.is_some_and(|key| matrix_keys::verify_event(returned_event, &room_version, destination, &key));
+        if !countersigned {
+            let unsigned = anyhow::anyhow!("Invite response is not signed by {}", destination);
+            return Err(BridgeError::Homeserver(unsigned));
         }
-        fs::write(path, keypair.to_bytes())?;
         
-        info!("Generated new signing keypair and saved to {}", path);
-        Ok(keypair)
+        Ok(response)
     }
-}
-
-// HTTP handlers
-async fn health_check(State(bridge): State<MatrixMyceliumBridge>) -> Json<serde_json::Value> {
-    let health = serde_json::json!({
-        "status": "healthy",
-        "server_name": bridge.config.server_name,
-        "mycelium_connected": true, // TODO: actual health check
-        "matrix_connected": true,   // TODO: actual health check
-        "federation_active": true,
-        "uptime": 0 // TODO: track actual uptime
-    });
     
-    Json(health)
-}
-
-async fn send_federation_event(
-    State(bridge): State<MatrixMyceliumBridge>,
-    Json(event): Json<FederationEvent>,
-) -> Result<Json<serde_json::Value>, StatusCode> {
-    match bridge.send_federation_event(event).await {
-        Ok(()) => Ok(Json(serde_json::json!({
-            "success": true,
-            "message": "Federation event sent successfully"
-        }))),
-        Err(e) => {
-            error!("Failed to send federation event: {}", e);
-            Err(StatusCode::INTERNAL_SERVER_ERROR)
+    /// Search the user directories of `servers` (or every peer advertising
+    /// support) and merge whatever arrives before the search deadline.
+    pub async fn search_users(
+        &self,
+        search_term: &str,
+        limit: usize,
+        servers: Option<Vec<String>>,
+    ) -> Result<serde_json::Value, BridgeError> {
+        if !self.config.queries.user_search_enabled {
+            return Err(BridgeError::Validation("Federated user search is disabled".to_string()));
+        }
+        
+        let targets: Vec<String> = {
+            let directory = self.server_directory.read().await;
+            let supports_search = |name: &String| {
+                directory
+                    .get(name)
+                    .is_some_and(|server| server.supports(queries::USER_SEARCH_CAPABILITY))
+            };
+            match servers {
+                Some(servers) => servers.into_iter().filter(|name| supports_search(name)).collect(),
+                None => directory.keys().filter(|name| supports_search(name)).cloned().collect(),
+            }
+        };
+        
+        let body = serde_json::json!({ "search_term": search_term, "limit": limit });
+        let mut searches = tokio::task::JoinSet::new();
+        for server in targets {
+            let bridge = self.clone();
+            let body = body.clone();
+            searches.spawn(async move {
+                let path = queries::USER_SEARCH.path_prefix.to_string();
+                let result = bridge.federation_query(&queries::USER_SEARCH, &server, path, Some(body)).await;
+                (server, result)
+            });
+        }
+        
+        let deadline = tokio::time::Instant::now()
+            + std::time::Duration::from_millis(self.config.queries.user_search_timeout_ms);
+        let mut responses = Vec::new();
+        let mut timed_out = false;
+        loop {
+            match tokio::time::timeout_at(deadline, searches.join_next()).await {
+                Ok(Some(joined)) => match joined? {
+                    (_, Ok(response)) if response.status == StatusCode::OK.as_u16() => {
+                        responses.push(response.body)
+                    }
+                    (server, Ok(response)) => {
+                        warn!("User search on {} returned {}", server, response.status)
+                    }
+                    (server, Err(e)) => warn!("User search on {} failed: {}", server, e),
+                },
+                Ok(None) => break,
+                Err(_) => {
+                    warn!("User search timed out with {} servers outstanding", searches.len());
+                    searches.abort_all();
+                    timed_out = true;
+                    break;
+                }
+            }
         }
+        
+        let (results, limited) = queries::merge_user_search_results(responses, limit);
+        Ok(serde_json::json!({
+            "results": results,
+            "limited": limited || timed_out
+        }))
     }
-}
-
-async fn list_servers(State(bridge): State<MatrixMyceliumBridge>) -> Json<serde_json::Value> {
-    let directory = bridge.server_directory.read().await;
-    let servers: Vec<&ServerInfo> = directory.values().collect();
     
-    Json(serde_json::json!({
-        "servers": servers
-    }))
+    /// Fetch media hosted on `server_name`, from the local cache or as a
+    /// chunked transfer from that server's bridge.
+    pub async fn fetch_remote_media(
+        &self,
+        server_name: &str,
+        media_id: &str,
+    ) -> Result<MediaFile, BridgeError> {
+        if !self.config.media.enabled {
+            return Err(BridgeError::Validation("Media federation is disabled".to_string()));
+        }
+        if let Some(file) = self.media_cache.get(server_name, media_id).await {
+            return Ok(file);
+        }
+        
+        let supported = self
+            .server_directory
+            .read()
+            .await
+            .get(server_name)
+            .is_some_and(|server| server.supports(media::MEDIA_CAPABILITY));
+        if !supported {
+            return Err(BridgeError::Validation(format!("{} does not support media transfers", server_name)));
+        }
+        
+        let request = MediaRequest {
+            request_id: uuid::Uuid::new_v4().to_string(),
+            server_name: server_name.to_string(),
+            media_id: media_id.to_string(),
+        };
+        let receiver = self.media_assembler.register(&request.request_id, server_name).await;
+        
+        let sent = match self
+            .build_message(server_name, media::MEDIA_REQUEST_MESSAGE_TYPE, &request)
+            .await
+        {
+            Ok(msg) => self.send_mycelium_message(msg).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = sent {
+            self.media_assembler.cancel(&request.request_id).await;
+            return Err(e.into());
+        }
+        
+        let timeout = std::time::Duration::from_secs(self.config.media.timeout_seconds);
+        let file = match tokio::time::timeout(timeout, receiver).await {
+            Ok(Ok(Ok(file))) => file,
+            Ok(Ok(Err(e))) => {
+                let failed = anyhow::anyhow!("Media transfer from {} failed: {}", server_name, e);
+                return Err(BridgeError::Homeserver(failed));
+            }
+            Ok(Err(_)) => {
+                let dropped = format!("Media transfer from {} was dropped", server_name);
+                return Err(BridgeError::Unavailable(dropped));
+            }
+            Err(_) => {
+                self.media_assembler.cancel(&request.request_id).await;
+                return Err(BridgeError::Timeout(format!("Media transfer from {} timed out", server_name)));
+            }
+        };
+        
+        if let Err(e) = self.media_cache.put(server_name, media_id, &file).await {
+            warn!("Failed to cache media {}/{}: {}", server_name, media_id, e);
+        }
+        
+        Ok(file)
+    }
+    
+    /// Stream local media to the requesting bridge as `media_chunk` messages.
+    async fn serve_media_request(&self, source: &str, request: MediaRequest) -> Result<()> {
+        let chunks = match self.load_local_media(&request).await {
+            Ok(file) => media::split_into_chunks(&request.request_id, &file, self.config.media.chunk_size),
+            Err(e) => {
+                warn!("Cannot serve media {} to {}: {}", request.media_id, source, e);
+                vec![MediaChunk {
+                    request_id: request.request_id.clone(),
+                    index: 0,
+                    total: 0,
+                    content_type: None,
+                    sha256: String::new(),
+                    file_sha256: String::new(),
+                    data: String::new(),
+                    error: Some(e.to_string()),
+                }]
+            }
+        };
+        
+        info!("Sending media {} to {} in {} chunks", request.media_id, source, chunks.len());
+        for chunk in chunks {
+            let msg = self
+                .build_message(source, media::MEDIA_CHUNK_MESSAGE_TYPE, &chunk)
+                .await?;
+            self.send_mycelium_message(msg).await?;
+        }
+        
+        Ok(())
+    }
+    
+    async fn load_local_media(&self, request: &MediaRequest) -> Result<MediaFile> {
+        if !self.config.media.enabled {
+            return Err(anyhow::anyhow!("Media federation is disabled"));
+        }
+        if request.server_name != self.config.server_name {
+            return Err(anyhow::anyhow!("Media is not hosted on this server"));
+        }
+        
+        let http_client = self.homeserver.client().http_client();
+        let request = http_client
+            .get(format!(
+                "{}/_matrix/media/v3/download/{}/{}",
+                self.config.matrix_homeserver_url, request.server_name, request.media_id
+            ))
+            .query(&[("allow_remote", "false")]);
+        let response = http_client.send(request).await?;
+        
+        if !response.status().is_success() {
+            return Err(anyhow::anyhow!("Homeserver returned {}", response.status()));
+        }
+        if response
+            .content_length()
+            .is_some_and(|length| length as usize > self.config.media.max_media_bytes)
+        {
+            return Err(anyhow::anyhow!("Media exceeds the maximum allowed size"));
+        }
+        
+        let content_type = response
+            .headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string);
+        let data = response.bytes().await?.to_vec();
+        if data.len() > self.config.media.max_media_bytes {
+            return Err(anyhow::anyhow!("Media exceeds the maximum allowed size"));
+        }
+        
+        Ok(MediaFile { content_type, data })
+    }
+    
+    /// Answer a query from a remote bridge by replaying it against the local
+    /// homeserver.
+    async fn answer_federation_query(&self, kind: &QueryKind, message: &MyceliumMessage) -> Result<()> {
+        let request: QueryRequest = message.payload()?;
+        
+        let enabled = kind
+            .capability
+            .is_none_or(|capability| self.capabilities.supports(capability));
+        
+        let response = if !enabled {
+            QueryResponse {
+                request_id: request.request_id,
+                status: StatusCode::NOT_FOUND.as_u16(),
+                body: serde_json::json!({
+                    "errcode": "M_UNRECOGNIZED",
+                    "error": format!("{} is not supported by this server", kind.name)
+                }),
+            }
+        } else if !kind.allows_path(&request.path) {
+            warn!("Rejecting {} query from {} for {}", kind.name, message.source_server, request.path);
+            QueryResponse {
+                request_id: request.request_id,
+                status: StatusCode::FORBIDDEN.as_u16(),
+                body: serde_json::json!({
+                    "errcode": "M_FORBIDDEN",
+                    "error": "Path not allowed for this query type"
+                }),
+            }
+        } else {
+            let (status, body) = self
+                .query_homeserver(&message.source_server, kind.method, &request.path, request.body.as_ref())
+                .await?;
+            QueryResponse {
+                request_id: request.request_id,
+                status,
+                body,
+            }
+        };
+        
+        let reply = self
+            .build_message(&message.source_server, &kind.response_type(), &response)
+            .await?;
+        self.send_mycelium_message(reply).await?;
+        Ok(())
+    }
+    
+    async fn query_homeserver(
+        &self,
+        origin: &str,
+        method: &str,
+        path: &str,
+        body: Option<&serde_json::Value>,
+    ) -> Result<(u16, serde_json::Value)> {
+        Ok(self
+            .homeserver
+            .federation_request(origin, method, path, body)
+            .await
+            .map_err(BridgeError::Homeserver)?)
+    }
+    
+    async fn flush_transaction(&self, destination: &str, events: Vec<PendingEvent>) {
+        if events.is_empty() {
+            return;
+        }
+        
+        let payload = batching::build_transaction(&events);
+        let outcome = match self.build_message(destination, TRANSACTION_MESSAGE_TYPE, &payload).await {
+            Ok(msg) => self.send_mycelium_message(msg).await.map_err(|e| e.to_string()),
+            Err(e) => Err(e.to_string()),
+        };
+        
+        match &outcome {
+            Ok(SendOutcome::Sent) => info!("Sent transaction with {} events to {}", events.len(), destination),
+            Ok(SendOutcome::Held) => info!("Holding transaction with {} events for {}", events.len(), destination),
+            Err(e) => error!("Failed to send transaction to {}: {}", destination, e),
+        }
+        
+        for event in events {
+            let _ = event.done.send(outcome.clone());
+        }
+    }
+    
+    async fn translate_to_mycelium(&self, event: FederationEvent) -> Result<MyceliumMessage> {
+        let mut msg = self.build_message(&event.destination, "federation_event", &event.event_data).await?;
+        msg.expires_at = event.expires_at;
+        Ok(msg)
+    }
+    
+    async fn build_message<P: Serialize + ?Sized>(
+        &self,
+        destination: &str,
+        message_type: &str,
+        payload: &P,
+    ) -> Result<MyceliumMessage> {
+        if !self.settings().federation.is_allowed(destination) {
+            let refused = format!("Federation with {} is not allowed", destination);
+            return Err(BridgeError::Validation(refused).into());
+        }
+        // Serialized once: compression, encryption and the signature all
+        // work on these bytes, which go out as they are
+        let payload = serde_json::value::to_raw_value(payload)?;
+        let correlation_id = telemetry::raw_correlation_id(&payload);
+        let version = match self.server_directory.read().await.get(destination) {
+            Some(server) if matches!(server.status, ServerStatus::Untrusted) => {
+                return Err(BridgeError::Validation(format!("{} is using a revoked key", destination)).into());
+            }
+            Some(server) => ProtocolVersion::negotiate(&server.capabilities)
+                .ok_or_else(|| {
+                    BridgeError::Validation(format!("No protocol version in common with {}", destination))
+                })?,
+            None => ProtocolVersion::CURRENT,
+        };
+        
+        let (payload, content_encoding) = if self.should_compress(destination, payload.get().len()).await {
+            let compressed = compression::compress_payload(&payload, self.config.compression.level)?;
+            (compressed, Some(ZSTD_ENCODING.to_string()))
+        } else {
+            (payload, None)
+        };
+        
+        let (payload, encryption) = match self.encryption_key_for(destination).await? {
+            Some(peer_key) => {
+                let aad = Self::encryption_aad(&self.config.server_name, destination);
+                let encrypted = self
+                    .cipher()?
+                    .encrypt(&peer_key, aad.as_bytes(), &payload)
+                    .map_err(BridgeError::Crypto)?;
+                (encrypted, Some(E2E_SCHEME.to_string()))
+            }
+            None => (payload, None),
+        };
+        
+        let mut msg = MyceliumMessage {
+            version: ProtocolVersion::CURRENT.as_str().to_string(),
+            source_server: self.config.server_name.clone(),
+            destination_server: destination.to_string(),
+            message_type: message_type.to_string(),
+            timestamp: chrono::Utc::now().to_rfc3339(),
+            payload,
+            signature: String::new(), // Will be filled after translating
+            content_encoding,
+            encryption,
+            trace_context: telemetry::current_context(),
+            correlation_id: Some(correlation_id),
+            expires_at: None,
+        };
+        protocol::downgrade(&mut msg, version);
+        msg.signature = self.sign_message(&msg.signing_payload()?).await?;
+        
+        Ok(msg)
+    }
+    
+    /// Compress only when enabled, worthwhile, and the destination has
+    /// advertised support for it.
+    async fn should_compress(&self, destination: &str, payload_size: usize) -> bool {
+        if !self.config.compression.enabled || payload_size < self.config.compression.threshold_bytes {
+            return false;
+        }
+        
+        let directory = self.server_directory.read().await;
+        directory
+            .get(destination)
+            .map(|server| server.supports(ZSTD_CAPABILITY))
+            .unwrap_or(false)
+    }
+    
+    /// Returns the destination's public key when the payload should be
+    /// encrypted to it, or `None` to fall back to plaintext for old peers.
+    async fn encryption_key_for(&self, destination: &str) -> Result<Option<String>> {
+        if !self.config.encryption.enabled {
+            return Ok(None);
+        }
+        
+        let directory = self.server_directory.read().await;
+        let peer_key = directory
+            .get(destination)
+            .filter(|server| server.supports(E2E_CAPABILITY))
+            .map(|server| server.public_key.clone());
+        
+        if peer_key.is_none() && self.config.encryption.require {
+            let unsupported = format!("{} does not support payload encryption", destination);
+            return Err(BridgeError::Validation(unsupported).into());
+        }
+        
+        Ok(peer_key)
+    }
+    
+    fn cipher(&self) -> Result<&PayloadCipher> {
+        self.cipher
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("Payload encryption needs the local signing key"))
+            .map_err(|e| BridgeError::Config(e).into())
+    }
+    
+    fn encryption_aad(source: &str, destination: &str) -> String {
+        format!("{}|{}", source, destination)
+    }
+    
+    async fn decrypt_payload(&self, message: &mut MyceliumMessage) -> Result<()> {
+        match message.encryption.as_deref() {
+            None if self.config.encryption.require => {
+                Err(anyhow::anyhow!("Rejecting unencrypted message from {}", message.source_server))
+            }
+            None => Ok(()),
+            Some(E2E_SCHEME) => {
+                let peer_key = self
+                    .server_directory
+                    .read()
+                    .await
+                    .get(&message.source_server)
+                    .map(|server| server.public_key.clone())
+                    .ok_or_else(|| anyhow::anyhow!("No known key for {}", message.source_server))?;
+                let aad = Self::encryption_aad(&message.source_server, &message.destination_server);
+                message.payload = self.cipher()?.decrypt(&peer_key, aad.as_bytes(), &message.payload)?;
+                Ok(())
+            }
+            Some(other) => Err(anyhow::anyhow!("Unsupported payload encryption: {}", other)),
+        }
+    }
+    
+    /// Advertise `capability` in announcements from now on, for features
+    /// provided outside the bridge. `subsystem` is shown in the admin API.
+    pub fn register_capability(&self, capability: &str, subsystem: &str) {
+        self.capabilities.register(capability, subsystem);
+    }
+    
+    /// Send `msg`, or hold it if it must not be lost and its destination is
+    /// offline.
+    async fn send_mycelium_message(&self, msg: MyceliumMessage) -> Result<SendOutcome> {
+        if liveness::HELD_MESSAGE_TYPES.contains(&msg.message_type.as_str()) {
+            let status = self
+                .server_directory
+                .read()
+                .await
+                .get(&msg.destination_server)
+                .map(|server| server.status);
+            if status == Some(ServerStatus::Offline) {
+                debug!("Holding {} for {} until it is back online", msg.message_type, msg.destination_server);
+                self.offline_queue.hold(msg).await;
+                return Ok(SendOutcome::Held);
+            }
+        }
+        let topic = edu::federation_topic(&msg.destination_server);
+        self.send_mycelium_message_on(&topic, msg).await?;
+        Ok(SendOutcome::Sent)
+    }
+    
+    async fn send_mycelium_message_on(&self, topic: &str, msg: MyceliumMessage) -> Result<()> {
+        self.await_send_quota(&msg.destination_server).await?;
+        // Checked after any wait for the quota, which may have outlasted it
+        if msg.is_expired() {
+            debug!("Dropping expired {} for {}", msg.message_type, msg.destination_server);
+            let expired = format!("Message for {} expired before it was sent", msg.destination_server);
+            return Err(BridgeError::Validation(expired).into());
+        }
+        let data = serde_json::to_vec(&msg)?;
+        // Waits behind sends of higher priority to the same destination
+        let slot = self
+            .outbound
+            .acquire(&msg.destination_server, Priority::of(&msg.message_type))
+            .await;
+        let sent = async {
+            if self.mycelium.is_legacy() {
+                // The legacy API routes by topic alone
+                self.mycelium.publish(topic, &data).await.map_err(BridgeError::Mycelium)?;
+            } else {
+                let destination = self.mycelium_destination(&msg.destination_server).await?;
+                self.mycelium
+                    .send_message(&destination, topic, &data)
+                    .await
+                    .map_err(BridgeError::Mycelium)?;
+            }
+            Ok::<_, anyhow::Error>(())
+        };
+        let sent = sent.await;
+        drop(slot);
+        if let Err(e) = sent {
+            self.peer_metrics.record_failure(&msg.destination_server).await;
+            return Err(e);
+        }
+        info!("Message sent successfully to {}", msg.destination_server);
+        // Only PDUs are acked, so only their latency can be measured
+        let acked = msg.message_type == "federation_event" || msg.message_type == TRANSACTION_MESSAGE_TYPE;
+        let correlation_id = msg.correlation_id.as_deref().filter(|_| acked);
+        self.peer_metrics
+            .record_sent(&msg.destination_server, correlation_id, data.len())
+            .await;
+        self.bandwidth
+            .record(&msg.destination_server, Direction::Sent, data.len())
+            .await;
+        self.admin_stats.record_sent(topic, &msg).await;
+        if let Some(archive) = &self.archive {
+            archive.record(ArchiveDirection::Outbound, &msg).await;
+        }
+        
+        Ok(())
+    }
+    
+    /// Hold a send to `destination` while it is over its outbound quota,
+    /// as `bandwidth.action` says. Fails if it stays over quota.
+    async fn await_send_quota(&self, destination: &str) -> Result<()> {
+        let Err(wait) = self.bandwidth.check(destination, Direction::Sent).await else {
+            return Ok(());
+        };
+        let max_delay = std::time::Duration::from_secs(self.config.bandwidth.max_delay_seconds);
+        if self.config.bandwidth.action == config::QuotaAction::Throttle && wait <= max_delay {
+            self.idle(wait).await;
+            if self.bandwidth.check(destination, Direction::Sent).await.is_ok() {
+                return Ok(());
+            }
+        }
+        Err(BridgeError::Unavailable(format!("{} is over its outbound bandwidth quota", destination)).into())
+    }
+    
+    /// Send a recently sent message again, as it was. Returns `false` if no
+    /// message with `correlation_id` is remembered.
+    pub async fn resend_message(&self, correlation_id: &str) -> Result<bool, BridgeError> {
+        let Some(sent) = self.admin_stats.find_sent(correlation_id).await else {
+            return Ok(false);
+        };
+        info!("Resending message {} to {}", correlation_id, sent.message.destination_server);
+        self.send_mycelium_message_on(&sent.topic, sent.message).await?;
+        Ok(true)
+    }
+    
+    async fn mycelium_destination(&self, server_name: &str) -> Result<Destination> {
+        self.server_directory
+            .read()
+            .await
+            .get(server_name)
+            .map(|server| Destination::parse(&server.mycelium_address))
+            .ok_or_else(|| anyhow::anyhow!("No known mycelium address for {}", server_name))
+    }
+    
+    /// Send a message on the discovery topic. Mycelium has no broadcast, so
+    /// outside legacy mode it goes to every known peer and the configured
+    /// announce peers individually.
+    async fn broadcast_discovery(&self, data: &[u8]) -> Result<()> {
+        if self.mycelium.is_legacy() {
+            return self.mycelium.publish(DISCOVERY_TOPIC, data).await;
+        }
+        
+        let mut destinations: HashSet<String> = self.config.mycelium.announce_peers.iter().cloned().collect();
+        destinations.extend(
+            self.server_directory
+                .read()
+                .await
+                .values()
+                .map(|server| server.mycelium_address.clone()),
+        );
+        
+        let results = self.send_to_many(destinations, DISCOVERY_TOPIC, data.to_vec()).await;
+        let mut sent = 0;
+        for (address, result) in &results {
+            match result {
+                Ok(()) => sent += 1,
+                Err(e) => warn!("Failed to send discovery message to {}: {}", address, e),
+            }
+        }
+        // Partial failures are expected with peers coming and going
+        if sent == 0 && !results.is_empty() {
+            let peers = results.len();
+            return Err(anyhow::anyhow!("Discovery message could not be sent to any of {} peers", peers));
+        }
+        if sent < results.len() {
+            warn!("Discovery message sent to {} of {} peers", sent, results.len());
+        }
+        
+        Ok(())
+    }
+    
+    /// Send `data` on `topic` to each of `addresses`, `[fanout] concurrency`
+    /// at a time, with the outcome per address.
+    async fn send_to_many(
+        &self,
+        addresses: impl IntoIterator<Item = String>,
+        topic: &'static str,
+        data: Vec<u8>,
+    ) -> BTreeMap<String, Result<(), String>> {
+        let data: Arc<[u8]> = data.into();
+        let slots = Arc::new(tokio::sync::Semaphore::new(self.config.fanout.concurrency.max(1)));
+        let mut sends = tokio::task::JoinSet::new();
+        for address in addresses {
+            let Ok(slot) = slots.clone().acquire_owned().await else {
+                break;
+            };
+            let mycelium = self.mycelium.clone();
+            let data = data.clone();
+            sends.spawn(async move {
+                let destination = Destination::parse(&address);
+                let result = mycelium.send_message(&destination, topic, &data).await;
+                drop(slot);
+                (address, result.map_err(|e| e.to_string()))
+            });
+        }
+        
+        let mut results = BTreeMap::new();
+        while let Some(joined) = sends.join_next().await {
+            match joined {
+                Ok((address, result)) => {
+                    results.insert(address, result);
+                }
+                Err(e) => error!("Fan-out send task failed: {}", e),
+            }
+        }
+        results
+    }
+    
+    /// Broadcast a signed announcement of this server. After a key rotation,
+    /// it is countersigned with the previous key.
+    pub async fn announce_server(&self) -> Result<(), BridgeError> {
+        // Only the leader is reachable at the announced address
+        if !self.is_leader() {
+            debug!("Not announcing, another cluster node is the leader");
+            return Ok(());
+        }
+        let mycelium_address = self.get_mycelium_address().await?;
+        let capacity = self.get_current_capacity().await?;
+        let available = capacity.available;
+        let announcement = ServerAnnouncement {
+            server_name: self.config.server_name.clone(),
+            mycelium_address: mycelium_address.clone(),
+            public_key: base64::engine::general_purpose::STANDARD
+                .encode(self.signer.verifying_key().to_bytes()),
+            capabilities: self.capabilities.advertised(),
+            capacity,
+            timestamp: chrono::Utc::now().to_rfc3339(),
+            signature: String::new(), // Will be filled after signing
+            previous_key: None,
+            previous_signature: None,
+        };
+        
+        let announcement_json = serde_json::to_string(&announcement)?;
+        let signature = self.sign_message(&announcement_json).await?;
+        
+        let mut signed_announcement = announcement;
+        signed_announcement.signature = signature;
+        if let Some(previous) = &self.previous_signing_key {
+            let previous_signature = ed25519_dalek::Signer::sign(previous, announcement_json.as_bytes());
+            signed_announcement.previous_key = Some(signer::encode_public_key(&previous.verifying_key()));
+            signed_announcement.previous_signature = Some(
+                base64::engine::general_purpose::STANDARD.encode(previous_signature.to_bytes()),
+            );
+        }
+        
+        self.broadcast_discovery(&serde_json::to_vec(&signed_announcement)?).await?;
+        self.announcements.record(&signed_announcement).await;
+        *self.announced_address.write().await = Some(mycelium_address);
+        *self.announced_available.write().await = Some(available);
+            
+        info!("Server announced to discovery service");
+        Ok(())
+    }
+    
+    /// Register with the HTTP discovery service. Repeated registrations
+    /// refresh the entry there.
+    async fn register_with(&self, client: &DiscoveryClient) -> Result<()> {
+        let registration = RegisterRequest {
+            server_name: self.config.server_name.clone(),
+            mycelium_address: self.get_mycelium_address().await?,
+            public_key: base64::engine::general_purpose::STANDARD
+                .encode(self.signer.verifying_key().to_bytes()),
+            capabilities: self.capabilities.advertised(),
+            capacity: self.get_current_capacity().await?,
+            metadata: None,
+            timestamp: chrono::Utc::now().to_rfc3339(),
+            signature: String::new(), // Will be filled after signing
+        };
+        
+        let signature = self.sign_message(&registration.signing_payload()?).await?;
+        let mut signed_registration = registration;
+        signed_registration.signature = signature;
+        
+        client.register(&signed_registration).await?;
+        debug!("Registered with discovery service {}", client.url());
+        Ok(())
+    }
+    
+    /// Remove this server from the discovery service at
+    /// `[discovery] service_url`, when decommissioning it. False when the
+    /// service didn't list it. A running bridge would register again.
+    pub async fn deregister_from_discovery(&self) -> Result<bool, BridgeError> {
+        let Some(client) = &self.discovery_client else {
+            return Err(BridgeError::Config(anyhow::anyhow!("discovery.service_url is not set")));
+        };
+        let deregistration = Deregistration {
+            server_name: self.config.server_name.clone(),
+            timestamp: chrono::Utc::now().to_rfc3339(),
+            signature: String::new(), // Will be filled after signing
+        };
+        
+        let signature = self.sign_message(&deregistration.signing_payload()?).await?;
+        let mut signed_deregistration = deregistration;
+        signed_deregistration.signature = signature;
+        
+        Ok(client.deregister(&signed_deregistration).await?)
+    }
+    
+    /// Keep this server listed by the discovery service: heartbeats while the
+    /// address it was registered at is current, and a full registration when
+    /// it isn't, or when the service no longer knows the server.
+    async fn refresh_registration(
+        &self,
+        client: &DiscoveryClient,
+        registered_address: &mut Option<String>,
+    ) -> Result<()> {
+        let address = self.get_mycelium_address().await?;
+        if registered_address.as_deref() == Some(address.as_str()) {
+            if self.send_heartbeat(client).await? {
+                return Ok(());
+            }
+            info!("Discovery service {} no longer lists this server, registering again", client.url());
+        }
+        
+        *registered_address = None;
+        self.register_with(client).await?;
+        *registered_address = Some(address);
+        Ok(())
+    }
+    
+    /// Send a heartbeat with the current capacity. False when the discovery
+    /// service doesn't know this server.
+    async fn send_heartbeat(&self, client: &DiscoveryClient) -> Result<bool> {
+        let heartbeat = Heartbeat {
+            server_name: self.config.server_name.clone(),
+            capacity: self.get_current_capacity().await?,
+            // Outlives two missed heartbeats
+            ttl_seconds: 3 * self.config.discovery.heartbeat_interval_seconds,
+            timestamp: chrono::Utc::now().to_rfc3339(),
+            signature: String::new(), // Will be filled after signing
+        };
+        
+        let signature = self.sign_message(&heartbeat.signing_payload()?).await?;
+        let mut signed_heartbeat = heartbeat;
+        signed_heartbeat.signature = signature;
+        
+        let known = client.heartbeat(&signed_heartbeat).await?;
+        if known {
+            debug!("Sent heartbeat to discovery service {}", client.url());
+        }
+        Ok(known)
+    }
+    
+    /// Prove to the discovery service that this bridge owns the address it
+    /// registered, by handing back the nonce it sent there, signed.
+    async fn answer_address_challenge(&self, challenge: AddressChallenge) -> Result<()> {
+        let Some(client) = &self.discovery_client else {
+            return Ok(());
+        };
+        if challenge.server_name != self.config.server_name {
+            warn!("Ignoring address challenge for {}", challenge.server_name);
+            return Ok(());
+        }
+        
+        let proof = AddressProof {
+            server_name: challenge.server_name,
+            nonce: challenge.nonce,
+            signature: String::new(), // Will be filled after signing
+        };
+        let signature = self.sign_message(&proof.signing_payload()?).await?;
+        let mut signed_proof = proof;
+        signed_proof.signature = signature;
+        
+        client.prove_address(&signed_proof).await?;
+        info!("Proved ownership of our mycelium address to {}", client.url());
+        Ok(())
+    }
+    
+    /// Broadcast a revocation of this bridge's current signing key. Peers and
+    /// the discovery service stop trusting the key until a new one is announced.
+    pub async fn broadcast_key_revocation(&self, reason: Option<String>) -> Result<(), BridgeError> {
+        let revocation = KeyRevocation {
+            message_type: KEY_REVOCATION_MESSAGE_TYPE.to_string(),
+            server_name: self.config.server_name.clone(),
+            revoked_key: base64::engine::general_purpose::STANDARD
+                .encode(self.signer.verifying_key().to_bytes()),
+            reason,
+            timestamp: chrono::Utc::now().to_rfc3339(),
+            signature: String::new(), // Will be filled after signing
+        };
+        
+        let signature = self.sign_message(&serde_json::to_string(&revocation)?).await?;
+        let mut signed_revocation = revocation;
+        signed_revocation.signature = signature;
+        
+        self.broadcast_discovery(&serde_json::to_vec(&signed_revocation)?).await?;
+        
+        warn!("Broadcast revocation of signing key for {}", self.config.server_name);
+        Ok(())
+    }
+    
+    async fn poll_discovery_messages(&self) -> Result<Vec<DiscoveryMessage>> {
+        let messages = self.mycelium.receive_messages(DISCOVERY_TOPIC, 0).await?;
+        let mut discovery_messages = Vec::new();
+        
+        for inbound in messages {
+            let Ok(msg) = serde_json::from_slice::<serde_json::Value>(&inbound.payload) else {
+                continue;
+            };
+            if msg["message_type"] == KEY_REVOCATION_MESSAGE_TYPE {
+                if let Ok(revocation) = serde_json::from_value::<KeyRevocation>(msg) {
+                    if Self::verify_key_revocation(&revocation) {
+                        discovery_messages.push(DiscoveryMessage::KeyRevocation(revocation));
+                    } else {
+                        warn!("Invalid key revocation signature");
+                        self.admin_stats
+                            .record_verification_failure("key_revocation", &revocation.server_name)
+                            .await;
+                    }
+                }
+            } else if msg["message_type"] == ADDRESS_CHALLENGE_MESSAGE_TYPE {
+                if let Ok(challenge) = serde_json::from_value::<AddressChallenge>(msg) {
+                    discovery_messages.push(DiscoveryMessage::AddressChallenge(challenge));
+                }
+            } else if let Ok(announcement) = serde_json::from_value::<ServerAnnouncement>(msg) {
+                if !inbound.sent_from(&announcement.mycelium_address) {
+                    warn!(
+                        "Ignoring announcement for {} at {}, sent from another node",
+                        announcement.server_name, announcement.mycelium_address
+                    );
+                    self.directory_stats.record_rejection();
+                } else if Self::verify_server_announcement(&announcement) {
+                    discovery_messages.push(DiscoveryMessage::Announcement(announcement));
+                } else {
+                    warn!("Invalid server announcement signature");
+                    self.admin_stats
+                        .record_verification_failure("announcement", &announcement.server_name)
+                        .await;
+                }
+            }
+        }
+        
+        Ok(discovery_messages)
+    }
+    
+    async fn send_gossip_digest(&self) -> Result<()> {
+        let entries = self.announcements.digest().await;
+        if self.mycelium.is_legacy() {
+            return self.send_gossip(None, GossipBody::Digest { entries }).await;
+        }
+        
+        let peers: Vec<String> = {
+            use rand::seq::IteratorRandom;
+            
+            let directory = self.server_directory.read().await;
+            let candidates = directory.values().filter(|server| {
+                server.server_name != self.config.server_name
+                    && !matches!(server.status, ServerStatus::Untrusted)
+            });
+            candidates
+                .map(|server| server.mycelium_address.clone())
+                .choose_multiple(&mut rand::thread_rng(), self.config.gossip.fanout)
+        };
+        // The digest is the same for every peer, so it is signed once
+        let data = self.gossip_payload(GossipBody::Digest { entries }).await?;
+        for (address, result) in self.send_to_many(peers, GOSSIP_TOPIC, data).await {
+            if let Err(e) = result {
+                warn!("Failed to send gossip digest to {}: {}", address, e);
+            }
+        }
+        
+        Ok(())
+    }
+    
+    /// A signed gossip message, serialized for sending.
+    async fn gossip_payload(&self, body: GossipBody) -> Result<Vec<u8>> {
+        let mut message = GossipMessage::new(&self.config.server_name, body);
+        message.signature = self.sign_message(&message.signing_payload()?).await?;
+        Ok(serde_json::to_vec(&message)?)
+    }
+    
+    /// Sign and send a gossip message to the peer at `address`, or publish it
+    /// on the gossip topic with the legacy API.
+    async fn send_gossip(&self, address: Option<&str>, body: GossipBody) -> Result<()> {
+        let data = self.gossip_payload(body).await?;
+        
+        match address {
+            Some(address) if !self.mycelium.is_legacy() => {
+                let destination = Destination::parse(address);
+                self.mycelium.send_message(&destination, GOSSIP_TOPIC, &data).await
+            }
+            _ => self.mycelium.publish(GOSSIP_TOPIC, &data).await,
+        }
+    }
+    
+    /// Gossip messages from known servers, with valid signatures.
+    async fn poll_gossip_messages(&self) -> Result<Vec<GossipMessage>> {
+        let messages = self.mycelium.receive_messages(GOSSIP_TOPIC, 0).await?;
+        let mut gossip_messages = Vec::new();
+        
+        for inbound in messages {
+            let Ok(message) = serde_json::from_slice::<GossipMessage>(&inbound.payload) else {
+                continue;
+            };
+            if message.source_server == self.config.server_name {
+                continue;
+            }
+            let public_key = self
+                .server_directory
+                .read()
+                .await
+                .get(&message.source_server)
+                .map(|server| server.public_key.clone());
+            let Some(public_key) = public_key else {
+                debug!("Ignoring gossip from unknown server {}", message.source_server);
+                continue;
+            };
+            
+            let valid = message
+                .signing_payload()
+                .is_ok_and(|payload| verify_signature(&public_key, &payload, &message.signature));
+            if valid {
+                self.mark_seen(&message.source_server).await;
+                gossip_messages.push(message);
+            } else {
+                warn!("Invalid gossip signature from {}", message.source_server);
+                self.admin_stats
+                    .record_verification_failure("gossip", &message.source_server)
+                    .await;
+            }
+        }
+        
+        Ok(gossip_messages)
+    }
+    
+    async fn process_gossip_message(&self, message: GossipMessage) {
+        let source = message.source_server;
+        let address = self
+            .server_directory
+            .read()
+            .await
+            .get(&source)
+            .map(|server| server.mycelium_address.clone());
+        
+        let reply = match message.body {
+            GossipBody::Digest { entries } => {
+                let settings = self.settings();
+                let mut server_names = self.announcements.missing(&entries).await;
+                server_names.retain(|name| {
+                    *name != self.config.server_name && settings.federation.is_allowed(name)
+                });
+                if server_names.is_empty() {
+                    return;
+                }
+                debug!("Requesting {} directory entries from {}", server_names.len(), source);
+                GossipBody::Request { server_names }
+            }
+            GossipBody::Request { server_names } => {
+                let announcements = self.announcements.get(&server_names).await;
+                if announcements.is_empty() {
+                    return;
+                }
+                GossipBody::Entries { announcements }
+            }
+            GossipBody::Entries { announcements } => {
+                for announcement in announcements {
+                    if !Self::verify_server_announcement(&announcement) {
+                        warn!("Invalid announcement for {} relayed by {}", announcement.server_name, source);
+                        self.admin_stats
+                            .record_verification_failure("announcement", &announcement.server_name)
+                            .await;
+                        continue;
+                    }
+                    // Last seen when it announced, not when the copy arrived
+                    let announced = gossip::parse_timestamp(&announcement.timestamp)
+                        .map_or_else(chrono::Utc::now, |timestamp| timestamp.min(chrono::Utc::now()));
+                    let relayed_by = address.as_deref().unwrap_or(&source);
+                    self.admit_server_announcement(announcement, announced, relayed_by).await;
+                }
+                return;
+            }
+        };
+        
+        if let Err(e) = self.send_gossip(address.as_deref(), reply).await {
+            warn!("Failed to answer gossip from {}: {}", source, e);
+        }
+    }
+    
+    async fn poll_federation_messages(&self, topic: &str) -> Result<Vec<MyceliumMessage>> {
+        let wait = if self.config.mycelium.long_poll {
+            self.config.mycelium.poll_timeout_seconds
+        } else {
+            0
+        };
+        let messages = self.mycelium.receive_messages(topic, wait).await?;
+        let mut federation_messages = Vec::new();
+        
+        for inbound in messages {
+            if let Ok(federation_msg) = serde_json::from_slice::<MyceliumMessage>(&inbound.payload) {
+                if let Some(archive) = &self.archive {
+                    archive.record(ArchiveDirection::Inbound, &federation_msg).await;
+                }
+                // Only charged to a source that signed it; the rest is
+                // dropped unanswered when processed
+                if !self.verify_message_signature(&federation_msg).await {
+                    federation_messages.push(federation_msg);
+                    continue;
+                }
+                let source = federation_msg.source_server.clone();
+                let over_quota = self.bandwidth.check(&source, Direction::Received).await.is_err();
+                self.bandwidth
+                    .record(&source, Direction::Received, inbound.payload.len())
+                    .await;
+                self.peer_metrics.record_received(&source, inbound.payload.len()).await;
+                if !over_quota {
+                    federation_messages.push(federation_msg);
+                } else if self.config.bandwidth.action == config::QuotaAction::Throttle {
+                    let reason = "bandwidth quota exceeded".to_string();
+                    self.reject_message(&federation_msg, ErrorCode::OverQuota, reason).await;
+                }
+            }
+        }
+        
+        Ok(federation_messages)
+    }
+    
+    async fn process_server_announcement(&self, announcement: ServerAnnouncement) {
+        // Announcements are only taken from the node at the address they name
+        let source = announcement.mycelium_address.clone();
+        self.admit_server_announcement(announcement, chrono::Utc::now(), &source).await;
+    }
+    
+    /// Add a verified announcement to the directory, unless it is older than
+    /// the one already held for the server. `source` is the overlay node it
+    /// came through, which can only bring in so many new servers.
+    async fn admit_server_announcement(
+        &self,
+        announcement: ServerAnnouncement,
+        last_seen: chrono::DateTime<chrono::Utc>,
+        source: &str,
+    ) {
+        if !self.settings().federation.is_allowed(&announcement.server_name) {
+            return;
+        }
+        if let Err(e) = directory::check_announcement(&announcement) {
+            warn!("Ignoring invalid announcement: {}", e);
+            self.directory_stats.record_rejection();
+            return;
+        }
+        if gossip::parse_timestamp(&announcement.timestamp).is_some_and(gossip::is_ahead) {
+            warn!("Ignoring announcement from {} dated in the future", announcement.server_name);
+            self.directory_stats.record_rejection();
+            return;
+        }
+        if self.revoked_keys.read().await.contains(&announcement.public_key) {
+            warn!("Ignoring announcement from {} signed with a revoked key", announcement.server_name);
+            return;
+        }
+        // A rotation countersigned with the configured key is let through
+        let peer = self.static_peer(&announcement.server_name);
+        let previous_key = announcement.previous_key.clone();
+        let rotated_from = |key: &String| previous_key.as_ref() == Some(key);
+        let pinned_elsewhere = |peer: &config::PeerConfig| {
+            peer.public_key != announcement.public_key && !rotated_from(&peer.public_key)
+        };
+        if peer.is_some_and(pinned_elsewhere) {
+            warn!("Ignoring announcement from static peer {} with another key", announcement.server_name);
+            return;
+        }
+        // A new key must be vouched for by the held one, whose
+        // countersignature was checked along with the announcement, unless
+        // the held key was revoked
+        let held_key = self
+            .server_directory
+            .read()
+            .await
+            .get(&announcement.server_name)
+            .filter(|server| server.status != ServerStatus::Untrusted)
+            .map(|server| server.public_key.clone());
+        if held_key.is_some_and(|held| held != announcement.public_key && !rotated_from(&held)) {
+            warn!("Ignoring announcement from {} with a key it did not rotate to", announcement.server_name);
+            self.directory_stats.record_rejection();
+            return;
+        }
+        let known = self.server_directory.read().await.contains_key(&announcement.server_name);
+        if !known && self.new_server_limiter.check(source).await.is_err() {
+            debug!("Ignoring {}, {} brought in too many new servers", announcement.server_name, source);
+            self.directory_stats.record_rejection();
+            return;
+        }
+        if !self.announcements.record(&announcement).await {
+            debug!("Ignoring outdated announcement from {}", announcement.server_name);
+            return;
+        }
+        
+        let server_name = announcement.server_name.clone();
+        let mut server_info = ServerInfo::from(announcement);
+        server_info.last_seen = last_seen;
+        if let Some(peer) = peer {
+            server_info.mycelium_address = peer.mycelium_address.clone();
+            server_info.metadata = static_peer_info(peer, false).metadata;
+        }
+        let mut directory = self.server_directory.write().await;
+        if !directory.contains_key(&server_name) && directory.len() >= self.config.directory.max_servers {
+            // A new name can't push out a peer that is online or verified
+            let keep = |server: &ServerInfo| {
+                server.server_name == self.config.server_name
+                    || self.static_peer(&server.server_name).is_some()
+                    || server.status == ServerStatus::Online
+                    || server.verified
+            };
+            let Some(evicted) = directory::eviction_candidate(&directory, keep) else {
+                drop(directory);
+                warn!("Server directory is full, ignoring {}", server_name);
+                self.directory_stats.record_rejection();
+                self.announcements.remove(&server_name).await;
+                return;
+            };
+            directory.remove(&evicted);
+            self.directory_stats.record_eviction();
+            info!("Server directory is full, evicted {} for {}", evicted, server_name);
+            self.announcements.remove(&evicted).await;
+        }
+        if let Some(held) = directory.get(&server_name) {
+            // Traffic since the announcement was made counts too
+            server_info.last_seen = server_info.last_seen.max(held.last_seen);
+        }
+        let held_key = directory.get(&server_name).map(|server| server.public_key.clone());
+        if held_key.is_some_and(|held| held != server_info.public_key) {
+            info!("{} rotated its signing key to {}", server_name, server_info.public_key);
+        }
+        let previous = directory.insert(server_name.clone(), server_info);
+        drop(directory);
+        
+        info!("Updated server directory with {}", server_name);
+        if previous.is_some_and(|previous| previous.status == ServerStatus::Offline) {
+            self.release_held_messages(&server_name);
+        }
+    }
+    
+    fn static_peer(&self, server_name: &str) -> Option<&config::PeerConfig> {
+        self.config.peers.iter().find(|peer| peer.server_name == server_name)
+    }
+    
+    async fn process_key_revocation(&self, revocation: KeyRevocation) {
+        self.revoked_keys.write().await.insert(revocation.revoked_key.clone());
+        self.announcements.remove_key(&revocation.revoked_key).await;
+        
+        let mut directory = self.server_directory.write().await;
+        for server in directory.values_mut() {
+            if server.public_key == revocation.revoked_key {
+                server.status = ServerStatus::Untrusted;
+                warn!("Marked {} as untrusted: signing key revoked", server.server_name);
+            }
+        }
+    }
+    
+    async fn process_federation_message(&self, mut message: MyceliumMessage) -> Result<()> {
+        if !self.settings().federation.is_allowed(&message.source_server) {
+            warn!("Dropping message from {}, federation is not allowed", message.source_server);
+            return Ok(());
+        }
+        if message.destination_server != self.config.server_name {
+            warn!(
+                "Dropping {} from {} meant for {}",
+                message.message_type, message.source_server, message.destination_server
+            );
+            return Ok(());
+        }
+        if message.is_expired() {
+            debug!("Discarding expired {} from {}", message.message_type, message.source_server);
+            return Ok(());
+        }
+        
+        // Checked before decryption, as the signature covers the wire payload:
+        // nothing is decrypted, decompressed, counted or answered for a source
+        // that didn't sign it
+        if !self.verify_message_signature(&message).await {
+            warn!("Dropping {} not signed by {}", message.message_type, message.source_server);
+            self.admin_stats
+                .record_verification_failure("federation_message", &message.source_server)
+                .await;
+            return Ok(());
+        }
+        self.mark_seen(&message.source_server).await;
+        
+        if let Err(throttled) = self.rate_limiter.check(&message.source_server).await {
+            warn!(
+                "Rate limiting {}: dropped {} message (total throttled: {})",
+                message.source_server, message.message_type, throttled
+            );
+            self.reject_message(&message, ErrorCode::OverQuota, "rate limit exceeded".to_string())
+                .await;
+            return Ok(());
+        }
+        
+        let version = match ProtocolVersion::for_inbound(&message.version) {
+            Ok(version) => version,
+            Err(e) => {
+                self.reject_message(&message, ErrorCode::UnsupportedVersion, e.to_string()).await;
+                return Ok(());
+            }
+        };
+        
+        info!("Processing federation message from {}", message.source_server);
+        
+        self.decrypt_payload(&mut message).await?;
+        
+        match message.content_encoding.as_deref() {
+            None => {}
+            Some(ZSTD_ENCODING) => {
+                message.payload = compression::decompress_payload(&message.payload)?;
+            }
+            Some(other) => {
+                return Err(anyhow::anyhow!("Unsupported content encoding: {}", other));
+            }
+        }
+        protocol::upgrade(&mut message, version);
+        
+        if message.message_type == FEDERATION_ERROR_MESSAGE_TYPE {
+            let error: FederationError = message.payload()?;
+            if let Some(message_id) = &error.message_id {
+                let source = &message.source_server;
+                match &self.cluster {
+                    Some(cluster) => cluster.reject_delivery(source, message_id, &error.reason).await,
+                    None => self.deliveries.reject(source, message_id, &error.reason).await,
+                }
+            }
+            let message_id = error.message_id.as_deref().unwrap_or("(no ID)");
+            if error.is_permanent() {
+                warn!(
+                    "{} rejected {} {} for good: {:?}, {}",
+                    message.source_server, error.message_type, message_id, error.code, error.reason
+                );
+            } else {
+                info!(
+                    "{} rejected {} {} for now: {:?}, {}",
+                    message.source_server, error.message_type, message_id, error.code, error.reason
+                );
+            }
+            self.admin_stats.record_rejection(&message.source_server, error).await;
+            return Ok(());
+        }
+        
+        if message.message_type == DELIVERY_ACK_MESSAGE_TYPE {
+            let ack: DeliveryAck = message.payload()?;
+            match &self.cluster {
+                Some(cluster) => cluster.acknowledge_deliveries(&message.source_server, &ack.event_ids).await,
+                None => self.deliveries.acknowledge(&message.source_server, &ack.event_ids).await,
+            }
+            let acked: Vec<String> = ack.message_ids.into_iter().chain(ack.event_ids).collect();
+            self.peer_metrics.record_acks(&message.source_server, &acked).await;
+            return Ok(());
+        }
+        
+        if message.message_type == PING_MESSAGE_TYPE || message.message_type == PONG_MESSAGE_TYPE {
+            let ping: Ping = message.payload()?;
+            if message.message_type == PONG_MESSAGE_TYPE {
+                if !self.pings.complete(&message.source_server, &ping.ping_id).await {
+                    warn!("Dropping unexpected pong from {}", message.source_server);
+                }
+                return Ok(());
+            }
+            let pong = self
+                .build_message(&message.source_server, PONG_MESSAGE_TYPE, &ping)
+                .await?;
+            self.send_mycelium_message(pong).await?;
+            return Ok(());
+        }
+        
+        if let Some(kind) = QueryKind::from_request_type(&message.message_type) {
+            return self.answer_federation_query(kind, &message).await;
+        }
+        
+        if QueryKind::from_response_type(&message.message_type).is_some() {
+            let response: QueryResponse = message.payload()?;
+            if !self.queries.complete(&message.source_server, response).await {
+                warn!("Dropping unexpected {} from {}", message.message_type, message.source_server);
+            }
+            return Ok(());
+        }
+        
+        if message.message_type == media::MEDIA_REQUEST_MESSAGE_TYPE {
+            let request: MediaRequest = message.payload()?;
+            let bridge = self.clone();
+            tokio::spawn(async move {
+                if let Err(e) = bridge.serve_media_request(&message.source_server, request).await {
+                    error!("Failed to serve media to {}: {}", message.source_server, e);
+                }
+            });
+            return Ok(());
+        }
+        
+        if message.message_type == media::MEDIA_CHUNK_MESSAGE_TYPE {
+            let chunk: MediaChunk = message.payload()?;
+            self.media_assembler.add_chunk(&message.source_server, chunk).await;
+            return Ok(());
+        }
+        
+        // Everything left is a PDU or EDU for the homeserver
+        let payload: serde_json::Value = message.payload()?;
+        if message.message_type == edu::RELIABLE_EDU_MESSAGE_TYPE {
+            if let Err(reason) = self.validate(&payload, validation::validate_edu) {
+                self.reject_message(&message, ErrorCode::InvalidEvent, reason).await;
+                return Ok(());
+            }
+            self.stream_event(&message.source_server, EventKind::Edu, &payload);
+            self.deliver_reliably(&message.source_server, &payload).await?;
+            return Ok(());
+        }
+        
+        if message.message_type == edu::EDU_MESSAGE_TYPE {
+            if let Err(reason) = self.validate(&payload, validation::validate_edu) {
+                self.reject_message(&message, ErrorCode::InvalidEvent, reason).await;
+                return Ok(());
+            }
+            if payload["edu_type"] == edu::PRESENCE_EDU_TYPE && !self.config.edu.presence_enabled {
+                return Ok(());
+            }
+            if let Some(room_id) = payload["content"]["room_id"].as_str() {
+                if !self.server_acls.is_allowed(room_id, &message.source_server).await {
+                    warn!(
+                        "Dropping EDU from {} denied by the server ACL of {}",
+                        message.source_server, room_id
+                    );
+                    return Ok(());
+                }
+            }
+            self.stream_event(&message.source_server, EventKind::Edu, &payload);
+            // Ephemeral: a failed delivery is not worth retrying
+            self.forward_to_homeserver(&message.source_server, &payload).await?;
+            return Ok(());
+        }
+        
+        if message.message_type == TRANSACTION_MESSAGE_TYPE {
+            if let Err(reason) = self.validate(&payload, validation::validate_transaction) {
+                self.reject_message(&message, ErrorCode::InvalidEvent, reason).await;
+                return Ok(());
+            }
+            let pdus = batching::unpack_transaction(payload);
+            info!("Unpacking transaction with {} events from {}", pdus.len(), message.source_server);
+            let mut invalid = Vec::new();
+            let mut delivered = Vec::new();
+            for (index, pdu) in pdus.iter().enumerate() {
+                match self.validate(pdu, validation::validate_pdu) {
+                    Ok(()) => {
+                        if self.forward_pdu(&message.source_server, pdu).await? {
+                            delivered.extend(pdu["event_id"].as_str().map(str::to_string));
+                        }
+                    }
+                    Err(reason) => invalid.push(format!("PDU {}: {}", index, reason)),
+                }
+            }
+            if !invalid.is_empty() {
+                self.reject_message(&message, ErrorCode::InvalidEvent, invalid.join("; ")).await;
+            }
+            if !delivered.is_empty() {
+                let ack = DeliveryAck {
+                    event_ids: delivered,
+                    message_ids: message.correlation_id.clone().into_iter().collect(),
+                };
+                self.acknowledge_delivery(&message.source_server, ack).await;
+            }
+            return Ok(());
+        }
+        
+        if let Err(reason) = self.validate(&payload, validation::validate_pdu) {
+            self.reject_message(&message, ErrorCode::InvalidEvent, reason).await;
+            return Ok(());
+        }
+        if self.forward_pdu(&message.source_server, &payload).await? {
+            let ack = DeliveryAck {
+                event_ids: payload["event_id"].as_str().map(str::to_string).into_iter().collect(),
+                message_ids: message.correlation_id.clone().into_iter().collect(),
+            };
+            self.acknowledge_delivery(&message.source_server, ack).await;
+        }
+        Ok(())
+    }
+    
+    /// Tell a sender that wants acks which of its PDUs reached the homeserver.
+    async fn acknowledge_delivery(&self, source_server: &str, ack: DeliveryAck) {
+        if ack.event_ids.is_empty() && ack.message_ids.is_empty() {
+            return;
+        }
+        let wants_acks = self
+            .server_directory
+            .read()
+            .await
+            .get(source_server)
+            .is_some_and(|server| server.supports(DELIVERY_ACK_CAPABILITY));
+        if !wants_acks {
+            return;
+        }
+        
+        let sent = async {
+            let ack = self.build_message(source_server, DELIVERY_ACK_MESSAGE_TYPE, &ack).await?;
+            self.send_mycelium_message(ack).await
+        };
+        if let Err(e) = sent.await {
+            error!("Failed to send delivery ack to {}: {}", source_server, e);
+        }
+    }
+    
+    fn validate(
+        &self,
+        payload: &serde_json::Value,
+        check: fn(&serde_json::Value, &config::ValidationConfig) -> Result<(), String>,
+    ) -> Result<(), String> {
+        if !self.config.validation.enabled {
+            return Ok(());
+        }
+        check(payload, &self.config.validation)
+    }
+    
+    /// Tell the sender its message was rejected and whether it is worth
+    /// retrying, so it doesn't have to guess from silence.
+    async fn reject_message(&self, message: &MyceliumMessage, code: ErrorCode, reason: String) {
+        warn!("Rejecting {} from {}: {}", message.message_type, message.source_server, reason);
+        // Never answer an error with an error
+        if message.message_type == FEDERATION_ERROR_MESSAGE_TYPE {
+            return;
+        }
+        if self.error_reply_limiter.check(&message.source_server).await.is_err() {
+            debug!("Not sending more federation errors to {} for now", message.source_server);
+            return;
+        }
+        
+        let error = FederationError::new(code, message.correlation_id.clone(), &message.message_type, reason);
+        let sent = async {
+            let reply = self
+                .build_message(&message.source_server, FEDERATION_ERROR_MESSAGE_TYPE, &error)
+                .await?;
+            self.send_mycelium_message(reply).await
+        };
+        if let Err(e) = sent.await {
+            error!("Failed to send federation error to {}: {}", message.source_server, e);
+        }
+    }
+    
+    /// Forward a PDU to the homeserver, filling any gap it reports in the
+    /// event graph by asking the origin bridge for the missing events.
+    /// Returns `false` if the PDU was dropped or buffered for later.
+    async fn forward_pdu(&self, origin: &str, pdu: &serde_json::Value) -> Result<bool> {
+        let Some(result) = self.deliver_pdu(origin, pdu).await? else {
+            return Ok(false);
+        };
+        
+        if result["missing_prev_events"].as_array().is_some_and(|m| !m.is_empty()) {
+            // The answer arrives through the message processor, so the query
+            // must not block it
+            let bridge = self.clone();
+            let origin = origin.to_string();
+            let pdu = pdu.clone();
+            tokio::spawn(async move {
+                if let Err(e) = bridge.fill_event_gap(&origin, &pdu, &result).await {
+                    error!("Failed to fetch missing events from {}: {}", origin, e);
+                }
+            });
+        }
+        
+        Ok(true)
+    }
+    
+    /// Deliver a PDU the server ACL of its room allows, to stream consumers
+    /// and the homeserver. Returns the homeserver's answer, or `None` if the
+    /// PDU was dropped or buffered for later.
+    async fn deliver_pdu(&self, origin: &str, pdu: &serde_json::Value) -> Result<Option<serde_json::Value>> {
+        if let Some(room_id) = pdu["room_id"].as_str() {
+            if !self.server_acls.is_allowed(room_id, origin).await {
+                warn!("Dropping PDU from {} denied by the server ACL of {}", origin, room_id);
+                return Ok(None);
+            }
+        }
+        
+        self.stream_event(origin, EventKind::Pdu, pdu);
+        if self.config.stream.exclusive {
+            return Ok(Some(serde_json::json!({})));
+        }
+        if let Some(appservice) = &self.appservice {
+            appservice.inject(origin, pdu).await?;
+            return Ok(Some(serde_json::json!({})));
+        }
+        
+        let Some(result) = self.deliver_reliably(origin, pdu).await? else {
+            return Ok(None);
+        };
+        // Only trust an ACL change once the homeserver has accepted the event
+        if result.get("errcode").is_none() {
+            self.server_acls.observe(pdu).await;
+        }
+        Ok(Some(result))
+    }
+    
+    /// Deliver to the homeserver, retrying with backoff, and buffer the
+    /// event if it stays unreachable. Returns `None` if the event was
+    /// buffered. Nothing overtakes events already buffered.
+    async fn deliver_reliably(
+        &self,
+        origin: &str,
+        payload: &serde_json::Value,
+    ) -> Result<Option<serde_json::Value>> {
+        if !self.inbound_buffer.is_empty().await {
+            self.inbound_buffer.push(origin, payload).await?;
+            self.buffer_replay.notify_one();
+            return Ok(None);
+        }
+        
+        let attempts = self.config.homeserver.delivery_attempts.max(1);
+        let mut delay = std::time::Duration::from_millis(self.config.homeserver.retry_delay_ms);
+        for attempt in 1..=attempts {
+            match self.forward_to_homeserver(origin, payload).await {
+                Ok(result) => return Ok(Some(result)),
+                Err(e) if attempt < attempts => {
+                    warn!("Delivery to the homeserver failed (attempt {}): {}", attempt, e);
+                    self.idle(delay).await;
+                    delay *= 2;
+                }
+                Err(e) => warn!("Homeserver unreachable, buffering event from {}: {}", origin, e),
+            }
+        }
+        
+        self.inbound_buffer.push(origin, payload).await?;
+        self.buffer_replay.notify_one();
+        Ok(None)
+    }
+    
+    /// Replay buffered events in order once the homeserver answers again,
+    /// backing off up to `homeserver.max_retry_delay_seconds` meanwhile.
+    fn start_buffer_replay(&self) {
+        let bridge = self.clone();
+        self.spawn_background(async move {
+            let base_delay = std::time::Duration::from_millis(bridge.config.homeserver.retry_delay_ms);
+            let max_delay = std::time::Duration::from_secs(bridge.config.homeserver.max_retry_delay_seconds);
+            let mut delay = base_delay;
+            while !bridge.is_shutting_down() {
+                let Some(event) = bridge.inbound_buffer.front().await else {
+                    tokio::select! {
+                        _ = bridge.buffer_replay.notified() => {}
+                        _ = bridge.shutdown_requested() => {}
+                    }
+                    continue;
+                };
+                
+                match bridge.forward_to_homeserver(&event.origin, &event.payload).await {
+                    Ok(result) => {
+                        delay = base_delay;
+                        if let Err(e) = bridge.inbound_buffer.pop_front().await {
+                            error!("Failed to save the inbound buffer: {}", e);
+                        }
+                        if event.payload.get("edu_type").is_none() {
+                            if result.get("errcode").is_none() {
+                                bridge.server_acls.observe(&event.payload).await;
+                            }
+                            let event_id = event.payload["event_id"].as_str().map(str::to_string);
+                            let ack = DeliveryAck {
+                                event_ids: event_id.into_iter().collect(),
+                                message_ids: Vec::new(),
+                            };
+                            bridge.acknowledge_delivery(&event.origin, ack).await;
+                        }
+                        if bridge.inbound_buffer.is_empty().await {
+                            info!("Replayed every buffered event to the homeserver");
+                        }
+                    }
+                    Err(e) => {
+                        let buffered = bridge.inbound_buffer.len().await;
+                        debug!("Homeserver still unreachable, {} events buffered: {}", buffered, e);
+                        bridge.idle(delay).await;
+                        delay = (delay * 2).min(max_delay);
+                    }
+                }
+            }
+        });
+    }
+    
+    async fn fill_event_gap(
+        &self,
+        origin: &str,
+        pdu: &serde_json::Value,
+        result: &serde_json::Value,
+    ) -> Result<()> {
+        let missing = result["missing_prev_events"].as_array().map(Vec::len).unwrap_or(0);
+        let (Some(room_id), Some(event_id)) = (pdu["room_id"].as_str(), pdu["event_id"].as_str()) else {
+            warn!("Homeserver reported missing events for a PDU without room or event ID");
+            return Ok(());
+        };
+        
+        info!("Homeserver is missing {} prev_events in {}, asking {}", missing, room_id, origin);
+        let body = serde_json::json!({
+            "earliest_events": result.get("earliest_events").cloned().unwrap_or_default(),
+            "latest_events": [event_id],
+            "limit": missing.max(10),
+            "min_depth": 0
+        });
+        let path = format!("{}{}", queries::GET_MISSING_EVENTS.path_prefix, room_id);
+        let response = self
+            .federation_query(&queries::GET_MISSING_EVENTS, origin, path, Some(body))
+            .await?;
+        
+        let events = response.body["events"].as_array().cloned().unwrap_or_default();
+        info!("Received {} missing events for {} from {}", events.len(), room_id, origin);
+        // Checked and delivered like live PDUs, but without filling gaps of
+        // their own, so a gap that can't be filled isn't asked about forever
+        for event in &events {
+            if let Err(reason) = self.validate(event, validation::validate_pdu) {
+                warn!("Dropping missing event from {}: {}", origin, reason);
+                continue;
+            }
+            self.deliver_pdu(origin, event).await?;
+        }
+        // Re-deliver the original event now that its ancestors are known
+        self.deliver_reliably(origin, pdu).await?;
+        
+        Ok(())
+    }
+    
+    async fn forward_to_homeserver(
+        &self,
+        origin: &str,
+        payload: &serde_json::Value,
+    ) -> Result<serde_json::Value> {
+        // Unmodified homeservers have no endpoint for raw federation
+        // traffic, so only PDUs are bridged in appservice mode
+        if self.appservice.is_some() || self.config.stream.exclusive {
+            return Ok(serde_json::json!({}));
+        }
+        
+        Ok(self.homeserver.deliver(origin, payload).await.map_err(BridgeError::Homeserver)?)
+    }
+    
+    /// Push a verified inbound event to `/federation/stream` consumers.
+    fn stream_event(&self, origin: &str, kind: EventKind, event: &serde_json::Value) {
+        let Some(stream) = &self.event_stream else {
+            return;
+        };
+        if !stream.publish(origin, kind, event) && self.config.stream.exclusive {
+            warn!("Dropping event from {}, no stream consumer is connected", origin);
+        }
+    }
+    
+    /// Main loops, such as the message pollers, that haven't gone round
+    /// within `timeout`.
+    pub fn stalled_loops(&self, timeout: std::time::Duration) -> Vec<String> {
+        self.loop_heartbeats.stalled(timeout)
+    }
+    
+    /// Probe mycelium and the homeserver and record the outcome. Returns
+    /// whether every critical component is up.
+    pub async fn check_health(&self) -> bool {
+        let timeout = std::time::Duration::from_secs(5);
+        let probe_timed_out = |_| anyhow::anyhow!("Timed out after {:?}", timeout);
+        let (mycelium, homeserver) = tokio::join!(
+            tokio::time::timeout(timeout, self.mycelium.get_info()),
+            tokio::time::timeout(timeout, self.homeserver.health_check()),
+        );
+        let mycelium = mycelium.map_err(probe_timed_out).and_then(|info| info.map(|_| ()));
+        let homeserver = homeserver.map_err(probe_timed_out).and_then(|result| result);
+        
+        self.health
+            .record(health::MYCELIUM_COMPONENT, mycelium.map_err(|e| e.to_string()))
+            .await;
+        self.health
+            .record(health::HOMESERVER_COMPONENT, homeserver.map_err(|e| e.to_string()))
+            .await;
+        
+        self.health.is_healthy(health::MYCELIUM_COMPONENT).await
+            && self.health.is_healthy(health::HOMESERVER_COMPONENT).await
+    }
+    
+    async fn get_mycelium_address(&self) -> Result<String> {
+        Ok(self.mycelium.get_info().await.map_err(BridgeError::Mycelium)?.address)
+    }
+    
+    async fn get_current_capacity(&self) -> Result<ServerCapacity> {
+        let max_age = std::time::Duration::from_secs(self.config.homeserver.capacity_cache_seconds);
+        if let Some((measured, capacity)) = self.capacity_cache.read().await.as_ref() {
+            if measured.elapsed() < max_age {
+                return Ok(capacity.clone());
+            }
+        }
+        
+        let settings = self.settings();
+        let unknown = ServerCapacity {
+            max_users: settings.max_users,
+            current_users: 0,
+            available: settings.max_users > 0,
+            monthly_active_users: None,
+        };
+        let capacity = match self.capacity_provider.user_counts().await {
+            Ok(None) => {
+                let provider = self.capacity_provider.name();
+                warn!("No user counts from the {} capacity provider, reporting zero users", provider);
+                return Ok(unknown);
+            }
+            Ok(Some(counts)) => ServerCapacity {
+                max_users: settings.max_users,
+                current_users: counts.total,
+                available: counts.total < settings.max_users,
+                monthly_active_users: counts.monthly_active,
+            },
+            Err(e) => {
+                // Keep announcing the last known numbers rather than zero
+                error!("Failed to count homeserver users: {}", e);
+                let cached = self.capacity_cache.read().await.as_ref().map(|(_, c)| c.clone());
+                return Ok(cached.unwrap_or(unknown));
+            }
+        };
+        
+        *self.capacity_cache.write().await = Some((std::time::Instant::now(), capacity.clone()));
+        Ok(capacity)
+    }
+    
+    async fn sign_message(&self, message: &str) -> Result<String> {
+        let signature = self.signer.sign(message.as_bytes()).await.map_err(BridgeError::Crypto)?;
+        Ok(base64::engine::general_purpose::STANDARD.encode(signature.to_bytes()))
+    }
+    
+    /// Check the envelope signature against the key the source server announced.
+    async fn verify_message_signature(&self, message: &MyceliumMessage) -> bool {
+        let directory = self.server_directory.read().await;
+        let Some(server) = directory.get(&message.source_server) else {
+            return false;
+        };
+        
+        message
+            .signing_payload()
+            .is_ok_and(|payload| verify_signature(&server.public_key, &payload, &message.signature))
+    }
+    
+    /// Announcements are relayed by other bridges through gossip, so check
+    /// the signature against the key the announcement carries, and the
+    /// countersignature of a key rotation against the previous key.
+    pub fn verify_server_announcement(announcement: &ServerAnnouncement) -> bool {
+        let mut unsigned = announcement.clone();
+        unsigned.signature = String::new();
+        unsigned.previous_key = None;
+        unsigned.previous_signature = None;
+        
+        let Ok(message) = serde_json::to_string(&unsigned) else {
+            return false;
+        };
+        if !verify_signature(&announcement.public_key, &message, &announcement.signature) {
+            return false;
+        }
+        match (&announcement.previous_key, &announcement.previous_signature) {
+            (None, None) => true,
+            (Some(previous_key), Some(previous_signature)) => {
+                verify_signature(previous_key, &message, previous_signature)
+            }
+            _ => false,
+        }
+    }
+    
+    /// Check a revocation against the key it revokes, which signed it.
+    pub fn verify_key_revocation(revocation: &KeyRevocation) -> bool {
+        let mut unsigned = revocation.clone();
+        unsigned.signature = String::new();
+        
+        match serde_json::to_string(&unsigned) {
+            Ok(message) => verify_signature(&revocation.revoked_key, &message, &revocation.signature),
+            Err(_) => false,
+        }
+    }
+}
+
+/// Have each enabled subsystem advertise what it supports. Encryption is
+/// only advertised when `encrypts`, as it needs the local signing key.
+fn register_capabilities(registry: &CapabilityRegistry, config: &BridgeConfig, encrypts: bool) {
+    if config.compression.enabled {
+        registry.register(ZSTD_CAPABILITY, "compression");
+    }
+    if config.encryption.enabled && encrypts {
+        registry.register(E2E_CAPABILITY, "encryption");
+    }
+    if config.media.enabled {
+        registry.register(media::MEDIA_CAPABILITY, "media");
+    }
+    if config.queries.knock_enabled {
+        registry.register(queries::KNOCK_CAPABILITY, "queries");
+    }
+    if config.queries.user_search_enabled {
+        registry.register(queries::USER_SEARCH_CAPABILITY, "queries");
+    }
+    registry.register(DELIVERY_ACK_CAPABILITY, "delivery");
+    for version in ProtocolVersion::SUPPORTED {
+        registry.register(&version.capability(), "protocol");
+    }
+}
+
+/// Directory entry for a static peer, before it has announced anything.
+fn static_peer_info(peer: &config::PeerConfig, revoked: bool) -> ServerInfo {
+    ServerInfo {
+        server_name: peer.server_name.clone(),
+        mycelium_address: peer.mycelium_address.clone(),
+        public_key: peer.public_key.clone(),
+        capabilities: peer.capabilities.clone(),
+        capacity: ServerCapacity {
+            max_users: 0,
+            current_users: 0,
+            available: false,
+            monthly_active_users: None,
+        },
+        last_seen: chrono::Utc::now(),
+        status: if revoked { ServerStatus::Untrusted } else { ServerStatus::Online },
+        metadata: Some(serde_json::json!({ "static": true })),
+        verified: false,
+        heartbeat_ttl_seconds: None,
+    }
+}
+
+/// Verify a base64 Ed25519 signature over `message` with a base64 public key.
+pub fn verify_signature(public_key: &str, message: &str, signature: &str) -> bool {
+    let engine = base64::engine::general_purpose::STANDARD;
+    let Ok(key_bytes) = engine.decode(public_key) else {
+        return false;
+    };
+    let Ok(signature_bytes) = engine.decode(signature) else {
+        return false;
+    };
+    let Ok(key_bytes) = <[u8; 32]>::try_from(key_bytes.as_slice()) else {
+        return false;
+    };
+    let Ok(signature) = Signature::from_slice(&signature_bytes) else {
+        return false;
+    };
+    
+    VerifyingKey::from_bytes(&key_bytes)
+        .map(|key| key.verify(message.as_bytes(), &signature).is_ok())
+        .unwrap_or(false)
+}
+
+// HTTP handlers
+/// Returns 503 when mycelium or the homeserver is down, so load balancers
+/// and service managers can react.
+async fn health_check(State(bridge): State<MatrixMyceliumBridge>) -> (StatusCode, Json<serde_json::Value>) {
+    let healthy = bridge.check_health().await;
+    let standby = bridge.is_standby();
+    let components = bridge.health.snapshot().await;
+    let known_servers = bridge.server_directory.read().await.len();
+    
+    let mut health = serde_json::json!({
+        "status": match (standby, healthy) {
+            (true, _) => "standby",
+            (false, true) => "healthy",
+            (false, false) => "unhealthy",
+        },
+        "server_name": bridge.config.server_name,
+        "mycelium_connected": components.get(health::MYCELIUM_COMPONENT).is_some_and(|c| c.healthy),
+        "matrix_connected": components.get(health::HOMESERVER_COMPONENT).is_some_and(|c| c.healthy),
+        "federation_active": known_servers > 0,
+        "known_servers": known_servers,
+        "uptime": bridge.health.uptime_seconds(),
+        "components": components
+    });
+    if let Some(cluster) = &bridge.cluster {
+        health["cluster"] = serde_json::json!({
+            "node_id": cluster.node_id(),
+            "mode": bridge.config.cluster.mode,
+            "leader": cluster.is_leader()
+        });
+    }
+    
+    let status = if healthy && !standby { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+    (status, Json(health))
+}
+
+/// CORS for the configured origins, `*` allowing any. Without origins no
+/// cross-origin requests are allowed.
+fn cors_layer(origins: &[String]) -> CorsLayer {
+    let layer = CorsLayer::new()
+        .allow_methods([Method::GET, Method::POST, Method::PUT, Method::DELETE])
+        .allow_headers([AUTHORIZATION, CONTENT_TYPE])
+        .max_age(std::time::Duration::from_secs(3600));
+    
+    if origins.iter().any(|origin| origin == "*") {
+        layer.allow_origin(Any)
+    } else {
+        let origins: Vec<HeaderValue> = origins.iter().filter_map(|origin| origin.parse().ok()).collect();
+        layer.allow_origin(AllowOrigin::list(origins))
+    }
+}
+
+/// Continue the caller's trace, if it sent one, for the whole request, and
+/// tag its log lines with the caller's `X-Correlation-ID` or a fresh one.
+async fn trace_request(request: Request, next: Next) -> Response {
+    let correlation_id = request
+        .headers()
+        .get("x-correlation-id")
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string)
+        .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+    let span = tracing::info_span!(
+        "http_request",
+        method = %request.method(),
+        path = %request.uri().path(),
+        correlation_id = %correlation_id,
+    );
+    telemetry::set_remote_parent(&span, &telemetry::HeaderExtractor(request.headers()));
+    next.run(request).instrument(span).await
+}
+
+async fn send_federation_event(
+    State(bridge): State<MatrixMyceliumBridge>,
+    headers: HeaderMap,
+    Json(event): Json<FederationEvent>,
+) -> Result<(StatusCode, HeaderMap, Json<serde_json::Value>), StatusCode> {
+    let respond_async = headers
+        .get_all("prefer")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|preference| preference.trim().eq_ignore_ascii_case(delivery::RESPOND_ASYNC));
+    let send = || async {
+        if respond_async {
+            return match bridge.queue_federation_event(event.clone()).await {
+                Ok(message_id) => Ok(serde_json::json!({
+                    "success": true,
+                    "message_id": message_id,
+                    "state": delivery::DeliveryState::Queued
+                })),
+                Err(e) => {
+                    warn!("Refusing federation event: {}", e);
+                    Err(e.status_code())
+                }
+            };
+        }
+        match bridge.send_federation_event(event.clone()).await {
+            Ok(SendOutcome::Sent) => Ok(serde_json::json!({
+                "success": true,
+                "message": "Federation event sent successfully",
+                "state": delivery::DeliveryState::Sent
+            })),
+            Ok(SendOutcome::Held) => Ok(serde_json::json!({
+                "success": true,
+                "message": "Federation event held until its destination is back online",
+                "state": delivery::DeliveryState::Held
+            })),
+            Err(e) => {
+                error!("Failed to send federation event: {}", e);
+                Err(e.status_code())
+            }
+        }
+    };
+    
+    // The same event may go to several destinations, so keys are per destination
+    let key = headers
+        .get(IDEMPOTENCY_KEY_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .or_else(|| event.event_data["event_id"].as_str())
+        .map(|key| format!("{} {}", event.destination, key));
+    let (response, replayed) = match (&bridge.idempotency, key) {
+        (Some(cache), Some(key)) => cache.run(&key, send).await?,
+        _ => (send().await?, false),
+    };
+    
+    let mut response_headers = HeaderMap::new();
+    if replayed {
+        debug!("Replaying the response to a repeated send to {}", event.destination);
+        response_headers.insert(IDEMPOTENT_REPLAYED_HEADER, HeaderValue::from_static("true"));
+    }
+    let status = if respond_async { StatusCode::ACCEPTED } else { StatusCode::OK };
+    Ok((status, response_headers, Json(response)))
+}
+
+async fn broadcast_federation_event(
+    State(bridge): State<MatrixMyceliumBridge>,
+    Json(event): Json<BroadcastEvent>,
+) -> Result<Json<serde_json::Value>, StatusCode> {
+    let results = match bridge.broadcast_federation_event(event).await {
+        Ok(results) => results,
+        Err(e) => {
+            warn!("Refusing broadcast: {}", e);
+            return Err(e.status_code());
+        }
+    };
+    let failed = results.values().filter(|result| result.is_err()).count();
+    let results: BTreeMap<&String, serde_json::Value> = results
+        .iter()
+        .map(|(destination, result)| {
+            let outcome = match result {
+                Ok(()) => serde_json::json!({ "success": true }),
+                Err(e) => serde_json::json!({ "success": false, "error": e }),
+            };
+            (destination, outcome)
+        })
+        .collect();
+    
+    Ok(Json(serde_json::json!({
+        "success": failed == 0,
+        "sent": results.len() - failed,
+        "failed": failed,
+        "results": results
+    })))
+}
+
+async fn delivery_status(
+    State(bridge): State<MatrixMyceliumBridge>,
+    Path(message_id): Path<String>,
+) -> Result<Json<delivery::DeliveryStatus>, StatusCode> {
+    bridge
+        .delivery_status(&message_id)
+        .await
+        .map(Json)
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+async fn stream_events(
+    State(bridge): State<MatrixMyceliumBridge>,
+    ws: WebSocketUpgrade,
+) -> Result<Response, StatusCode> {
+    let stream = bridge.event_stream.clone().ok_or(StatusCode::NOT_FOUND)?;
+    let events = stream.subscribe();
+    Ok(ws.on_upgrade(move |socket| stream_to_consumer(bridge, events, socket)))
+}
+
+/// Push inbound events to one consumer as JSON text frames until it
+/// disconnects or the bridge shuts down.
+async fn stream_to_consumer(
+    bridge: MatrixMyceliumBridge,
+    mut events: broadcast::Receiver<Arc<InboundEvent>>,
+    mut socket: WebSocket,
+) {
+    info!("Stream consumer connected");
+    loop {
+        let frame = tokio::select! {
+            event = events.recv() => match event {
+                Ok(event) => match serde_json::to_string(&*event) {
+                    Ok(frame) => frame,
+                    Err(e) => {
+                        error!("Failed to serialize streamed event: {}", e);
+                        continue;
+                    }
+                },
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    warn!("Stream consumer fell behind and missed {} events", missed);
+                    serde_json::json!({ "missed": missed }).to_string()
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+            // Consumers only listen; pings are answered while reading
+            incoming = socket.recv() => match incoming {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => continue,
+            },
+            _ = bridge.shutdown_requested() => break,
+        };
+        if socket.send(Message::Text(frame)).await.is_err() {
+            break;
+        }
+    }
+    info!("Stream consumer disconnected");
+}
+
+async fn list_servers(
+    State(bridge): State<MatrixMyceliumBridge>,
+    Query(query): Query<ServerQuery>,
+) -> Json<serde_json::Value> {
+    let directory = bridge.server_directory.read().await;
+    let servers: Vec<&ServerInfo> = directory.values().filter(|server| query.matches(server)).collect();
+    
+    Json(serde_json::json!({
+        "servers": servers
+    }))
+}
+
+async fn get_server(
+    State(bridge): State<MatrixMyceliumBridge>,
+    Path(server_name): Path<String>,
+) -> Result<Json<serde_json::Value>, StatusCode> {
+    let server = bridge.server_directory.read().await.get(&server_name).cloned();
+    let Some(server) = server else {
+        return Err(StatusCode::NOT_FOUND);
+    };
+    let peer = bridge.peer_metrics.snapshot().await.remove(&server_name);
+    let held = bridge.offline_queue.counts().await.remove(&server_name).unwrap_or(0);
+    
+    Ok(Json(serde_json::json!({
+        "server": server,
+        "found": true,
+        "age_seconds": (chrono::Utc::now() - server.last_seen).num_seconds(),
+        "metrics": peer,
+        "held_for_offline": held
+    })))
+}
+
+#[derive(serde::Deserialize)]
+struct UserSearchRequest {
+    search_term: String,
+    #[serde(default = "default_search_limit")]
+    limit: usize,
+    /// Servers to search; defaults to every peer that supports it.
+    #[serde(default)]
+    servers: Option<Vec<String>>,
+}
+
+fn default_search_limit() -> usize {
+    10
+}
+
+async fn search_users(
+    State(bridge): State<MatrixMyceliumBridge>,
+    Json(request): Json<UserSearchRequest>,
+) -> Result<Json<serde_json::Value>, StatusCode> {
+    bridge
+        .search_users(&request.search_term, request.limit, request.servers)
+        .await
+        .map(Json)
+        .map_err(|e| {
+            error!("Federated user search failed: {}", e);
+            e.status_code()
+        })
+}
+
+/// Check the request's bearer token for `scope`. Without any token granting
+/// it, the send endpoints refuse every request unless
+/// `auth.allow_unauthenticated` is set, and the admin API is disabled.
+fn check_scope(
+    bridge: &MatrixMyceliumBridge,
+    headers: &HeaderMap,
+    scope: TokenScope,
+) -> Result<(), StatusCode> {
+    if !bridge.api_tokens.iter().any(|token| token.scopes.contains(&scope)) {
+        return match scope {
+            TokenScope::Send | TokenScope::Metrics if bridge.config.auth.allow_unauthenticated => Ok(()),
+            TokenScope::Send | TokenScope::Metrics => Err(StatusCode::UNAUTHORIZED),
+            TokenScope::Admin => Err(StatusCode::NOT_FOUND),
+        };
+    }
+    
+    let given = headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .ok_or(StatusCode::UNAUTHORIZED)?;
+    let token = auth::authenticate(&bridge.api_tokens, given).ok_or(StatusCode::UNAUTHORIZED)?;
+    if !token.scopes.contains(&scope) {
+        warn!("Token {} lacks the {:?} scope", token.name, scope);
+        return Err(StatusCode::FORBIDDEN);
+    }
+    Ok(())
+}
+
+async fn require_send_scope(
+    State(bridge): State<MatrixMyceliumBridge>,
+    headers: HeaderMap,
+    request: Request,
+    next: Next,
+) -> Result<Response, StatusCode> {
+    check_scope(&bridge, &headers, TokenScope::Send)?;
+    Ok(next.run(request).await)
+}
+
+async fn require_metrics_scope(
+    State(bridge): State<MatrixMyceliumBridge>,
+    headers: HeaderMap,
+    request: Request,
+    next: Next,
+) -> Result<Response, StatusCode> {
+    check_scope(&bridge, &headers, TokenScope::Metrics)?;
+    Ok(next.run(request).await)
+}
+
+async fn require_admin_scope(
+    State(bridge): State<MatrixMyceliumBridge>,
+    headers: HeaderMap,
+    request: Request,
+    next: Next,
+) -> Result<Response, StatusCode> {
+    check_scope(&bridge, &headers, TokenScope::Admin)?;
+    Ok(next.run(request).await)
+}
+
+/// Turn requests away from a hot standby, so a load balancer checking
+/// `/health` sends them to the leader. Admin requests and stats are still
+/// served.
+async fn refuse_on_standby(
+    State(bridge): State<MatrixMyceliumBridge>,
+    request: Request,
+    next: Next,
+) -> Result<Response, StatusCode> {
+    let path = request.uri().path();
+    let always_served =
+        path == "/health" || path == "/metrics" || path == "/stats" || path.starts_with("/admin/");
+    if bridge.is_standby() && !always_served {
+        return Err(StatusCode::SERVICE_UNAVAILABLE);
+    }
+    Ok(next.run(request).await)
+}
+
+async fn queue_stats(State(bridge): State<MatrixMyceliumBridge>) -> Json<serde_json::Value> {
+    let depths = bridge.batcher.queue_depths().await;
+    let total: usize = depths.values().sum();
+    Json(serde_json::json!({
+        "destinations": depths,
+        "total": total,
+        "inbound_buffered": bridge.inbound_buffer.len().await,
+        "held_for_offline": bridge.offline_queue.counts().await,
+        "outbound_waiting": bridge.outbound.waiting()
+    }))
+}
+
+/// Directory counts, message totals and queue depths at a glance.
+async fn bridge_stats(State(bridge): State<MatrixMyceliumBridge>) -> Json<serde_json::Value> {
+    let directory = bridge.server_directory.read().await;
+    let mut by_status: BTreeMap<String, usize> = BTreeMap::new();
+    for server in directory.values() {
+        *by_status.entry(server.status.to_string()).or_default() += 1;
+    }
+    let available_servers = directory.values().filter(|server| server.capacity.available).count();
+    let total_servers = directory.len();
+    drop(directory);
+    
+    let peers = bridge.peer_metrics.snapshot().await;
+    let total = |count: fn(&peer_metrics::PeerMetrics) -> u64| peers.values().map(count).sum::<u64>();
+    let batched: usize = bridge.batcher.queue_depths().await.values().sum();
+    let held: usize = bridge.offline_queue.counts().await.values().sum();
+    let send_queue = bridge.send_queue.max_capacity() - bridge.send_queue.capacity();
+    
+    Json(serde_json::json!({
+        "total_servers": total_servers,
+        "servers_by_status": by_status,
+        "available_servers": available_servers,
+        "messages": {
+            "sent": total(|peer| peer.messages_sent),
+            "received": total(|peer| peer.messages_received),
+            "send_failures": total(|peer| peer.send_failures),
+            "bytes_sent": total(|peer| peer.bytes_sent),
+            "bytes_received": total(|peer| peer.bytes_received)
+        },
+        "queues": {
+            "send": send_queue,
+            "batched": batched,
+            "inbound_buffered": bridge.inbound_buffer.len().await,
+            "held_for_offline": held
+        },
+        "timestamp": chrono::Utc::now()
+    }))
+}
+
+async fn dump_directory(State(bridge): State<MatrixMyceliumBridge>) -> Json<serde_json::Value> {
+    let now = chrono::Utc::now();
+    let directory = bridge.server_directory.read().await;
+    let servers: Vec<serde_json::Value> = directory
+        .values()
+        .map(|server| {
+            serde_json::json!({
+                "server": server,
+                "age_seconds": (now - server.last_seen).num_seconds()
+            })
+        })
+        .collect();
+    
+    Json(serde_json::json!({
+        "servers": servers,
+        "revoked_keys": *bridge.revoked_keys.read().await
+    }))
+}
+
+async fn drop_server(
+    State(bridge): State<MatrixMyceliumBridge>,
+    Path(server_name): Path<String>,
+) -> StatusCode {
+    if bridge.static_peer(&server_name).is_some() {
+        warn!("Not dropping {}, it is a static peer", server_name);
+        return StatusCode::CONFLICT;
+    }
+    let removed = bridge.server_directory.write().await.remove(&server_name);
+    bridge.announcements.remove(&server_name).await;
+    match removed {
+        Some(_) => {
+            info!("Dropped {} from the server directory", server_name);
+            StatusCode::NO_CONTENT
+        }
+        None => StatusCode::NOT_FOUND,
+    }
+}
+
+async fn directory_servers(
+    State(bridge): State<MatrixMyceliumBridge>,
+    Query(query): Query<ServerQuery>,
+) -> Json<serde_json::Value> {
+    let mut servers = bridge.known_servers().await;
+    servers.retain(|server| query.matches(server));
+    
+    Json(serde_json::json!({
+        "servers": servers,
+        "total": servers.len(),
+        "timestamp": chrono::Utc::now()
+    }))
+}
+
+async fn select_directory_server(
+    State(bridge): State<MatrixMyceliumBridge>,
+    Query(query): Query<ServerQuery>,
+) -> Json<serde_json::Value> {
+    let servers = bridge.known_servers().await;
+    match query.select(&servers) {
+        Some(selected_server) => Json(serde_json::json!({
+            "server": selected_server,
+            "message": "Server selected successfully",
+            "selection_method": "lowest_load"
+        })),
+        None => Json(serde_json::json!({
+            "server": null,
+            "message": "No available servers matching criteria",
+            "total_servers": servers.len()
+        })),
+    }
+}
+
+async fn directory_server(
+    State(bridge): State<MatrixMyceliumBridge>,
+    Path(server_name): Path<String>,
+) -> Result<Json<serde_json::Value>, StatusCode> {
+    let servers = bridge.known_servers().await;
+    match servers.into_iter().find(|server| server.server_name == server_name) {
+        Some(server) => Ok(Json(serde_json::json!({
+            "server": server,
+            "found": true
+        }))),
+        None => Err(StatusCode::NOT_FOUND),
+    }
+}
+
+async fn verification_failures(State(bridge): State<MatrixMyceliumBridge>) -> Json<serde_json::Value> {
+    Json(serde_json::json!(bridge.admin_stats.verification_failures().await))
+}
+
+async fn peer_stats(State(bridge): State<MatrixMyceliumBridge>) -> Json<serde_json::Value> {
+    Json(serde_json::json!({
+        "peers": bridge.peer_metrics.snapshot().await
+    }))
+}
+
+async fn capability_registrations(State(bridge): State<MatrixMyceliumBridge>) -> Json<serde_json::Value> {
+    Json(serde_json::json!({
+        "capabilities": bridge.capabilities.registrations(),
+        "withheld": bridge.capabilities.withheld(),
+    }))
+}
+
+async fn metrics(State(bridge): State<MatrixMyceliumBridge>) -> impl IntoResponse {
+    let servers = bridge.server_directory.read().await.len();
+    let directory = bridge.directory_stats.render_prometheus(servers, bridge.config.directory.max_servers);
+    let peers = bridge.peer_metrics.render_prometheus().await;
+    let bandwidth = bridge.bandwidth.render_prometheus().await;
+    ([(CONTENT_TYPE, "text/plain; version=0.0.4")], peers + &bandwidth + &directory)
+}
+
+async fn bandwidth_stats(State(bridge): State<MatrixMyceliumBridge>) -> Json<serde_json::Value> {
+    Json(serde_json::json!({
+        "peers": bridge.bandwidth.snapshot().await
+    }))
+}
+
+async fn query_archive(
+    State(bridge): State<MatrixMyceliumBridge>,
+    Query(query): Query<ArchiveQuery>,
+) -> Result<Json<serde_json::Value>, StatusCode> {
+    let archive = bridge.archive.as_ref().ok_or(StatusCode::NOT_FOUND)?;
+    let messages = archive.query(&query).await.map_err(|e| {
+        error!("Failed to query the message archive: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    Ok(Json(serde_json::json!({ "messages": messages })))
+}
+
+async fn rejections(State(bridge): State<MatrixMyceliumBridge>) -> Json<serde_json::Value> {
+    Json(serde_json::json!({
+        "rejections": bridge.admin_stats.rejections().await
+    }))
+}
+
+async fn resend_message(
+    State(bridge): State<MatrixMyceliumBridge>,
+    Path(correlation_id): Path<String>,
+) -> StatusCode {
+    match bridge.resend_message(&correlation_id).await {
+        Ok(true) => StatusCode::ACCEPTED,
+        Ok(false) => StatusCode::NOT_FOUND,
+        Err(e) => {
+            error!("Failed to resend message {}: {}", correlation_id, e);
+            e.status_code()
+        }
+    }
+}
+
+async fn ping_server(
+    State(bridge): State<MatrixMyceliumBridge>,
+    Path(server_name): Path<String>,
+    Query(query): Query<PingQuery>,
+) -> (StatusCode, Json<serde_json::Value>) {
+    let timeout_seconds = query.timeout_seconds.unwrap_or(ping::DEFAULT_TIMEOUT_SECONDS);
+    let timeout = std::time::Duration::from_secs(timeout_seconds);
+    match bridge.ping(&server_name, timeout).await {
+        Ok(rtt) => (
+            StatusCode::OK,
+            Json(serde_json::json!({
+                "server_name": server_name,
+                "rtt_ms": rtt.as_secs_f64() * 1000.0,
+            })),
+        ),
+        Err(e) => {
+            warn!("Ping to {} failed: {}", server_name, e);
+            (e.status_code(), Json(serde_json::json!({ "error": e.to_string() })))
+        }
+    }
+}
+
+async fn force_announce(State(bridge): State<MatrixMyceliumBridge>) -> StatusCode {
+    match bridge.announce_server().await {
+        Ok(()) => StatusCode::ACCEPTED,
+        Err(e) => {
+            error!("Failed to re-announce: {}", e);
+            e.status_code()
+        }
+    }
+}
+
+async fn reload_config(
+    State(bridge): State<MatrixMyceliumBridge>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    match bridge.reload_config().await {
+        Ok(changes) => Ok(Json(serde_json::json!({ "changes": changes }))),
+        Err(e) => {
+            error!("Rejected config reload: {}", e);
+            Err((StatusCode::UNPROCESSABLE_ENTITY, e.to_string()))
+        }
+    }
+}
+
+async fn list_server_acls(State(bridge): State<MatrixMyceliumBridge>) -> Json<serde_json::Value> {
+    Json(serde_json::json!({
+        "rooms": bridge.server_acls.snapshot().await
+    }))
+}
+
+async fn rate_limit_stats(State(bridge): State<MatrixMyceliumBridge>) -> Json<serde_json::Value> {
+    Json(serde_json::json!({
+        "throttled_messages": bridge.rate_limiter.throttled_counts().await
+    }))
+}
+
+/// The bridge's key. The homeserver reaches remote servers through the
+/// bridge, so asked by a trusted remote server's name in `Host` it serves
+/// that server's key from the directory, signed by the bridge as a notary.
+async fn matrix_server_keys(
+    State(bridge): State<MatrixMyceliumBridge>,
+    headers: HeaderMap,
+) -> Result<Json<serde_json::Value>, StatusCode> {
+    let host = headers.get(HOST).and_then(|value| value.to_str().ok()).unwrap_or_default();
+    let relayed = {
+        let directory = bridge.server_directory.read().await;
+        // The server name may or may not carry the port
+        [host, host.split(':').next().unwrap_or_default()]
+            .into_iter()
+            .filter(|name| *name != bridge.config.server_name)
+            .find_map(|name| {
+                let server = directory.get(name).filter(|server| server.status != ServerStatus::Untrusted)?;
+                Some((name.to_string(), signer::decode_public_key(&server.public_key)?))
+            })
+    };
+    let response = match relayed {
+        // The remote's own key, vouched for by the bridge, along with the
+        // bridge's while it signs the requests it relays as that server
+        Some((server_name, key)) => {
+            let mut keys = vec![key];
+            if bridge.homeserver.client().signs_requests() {
+                keys.push(bridge.signer.verifying_key());
+            }
+            let notary = &bridge.config.server_name;
+            matrix_keys::notarized_keys_response(&server_name, &keys, notary, bridge.signer.as_ref()).await
+        }
+        None => matrix_keys::server_keys_response(&bridge.config.server_name, bridge.signer.as_ref()).await,
+    };
+    response.map(Json).map_err(|e| {
+        error!("Failed to build server keys response: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })
+}
+
+/// Destination server of a homeserver federation request, taken from its
+/// X-Matrix authorization header.
+fn x_matrix_destination(headers: &HeaderMap) -> Result<String, StatusCode> {
+    headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(x_matrix::parse_authorization)
+        .and_then(|auth| auth.destination)
+        .ok_or(StatusCode::BAD_REQUEST)
+}
+
+/// Check that a federation request comes from the local homeserver, signed
+/// in its `X-Matrix` header with one of the homeserver's keys.
+async fn authenticate_federation_request(
+    bridge: &MatrixMyceliumBridge,
+    headers: &HeaderMap,
+    method: &str,
+    uri: &Uri,
+    content: Option<&serde_json::Value>,
+) -> Result<(), StatusCode> {
+    let auth = headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(x_matrix::parse_authorization)
+        .ok_or(StatusCode::UNAUTHORIZED)?;
+    let origin = auth.origin.as_deref().unwrap_or_default();
+    if origin != bridge.config.server_name {
+        warn!("Refusing federation request with origin {:?}, not the homeserver", origin);
+        return Err(StatusCode::FORBIDDEN);
+    }
+    let key_id = auth.key.as_deref().ok_or(StatusCode::UNAUTHORIZED)?;
+    let key = bridge
+        .homeserver
+        .client()
+        .verify_key(key_id)
+        .await
+        .map_err(|e| {
+            error!("Failed to fetch the homeserver's keys: {}", e);
+            StatusCode::BAD_GATEWAY
+        })?;
+    let uri = uri.path_and_query().map_or(uri.path(), |path| path.as_str());
+    match key {
+        Some(key) if x_matrix::verify_request(&auth, &key, method, uri, content) => Ok(()),
+        _ => {
+            bridge.admin_stats.record_verification_failure("x_matrix", origin).await;
+            Err(StatusCode::UNAUTHORIZED)
+        }
+    }
+}
+
+/// Largest `/_matrix/federation` request body read to check its signature,
+/// axum's default limit for JSON bodies.
+const MAX_FEDERATION_BODY_BYTES: usize = 2 * 1024 * 1024;
+
+async fn require_homeserver_signature(
+    State(bridge): State<MatrixMyceliumBridge>,
+    request: Request,
+    next: Next,
+) -> Result<Response, StatusCode> {
+    let (parts, body) = request.into_parts();
+    let bytes = axum::body::to_bytes(body, MAX_FEDERATION_BODY_BYTES)
+        .await
+        .map_err(|_| StatusCode::PAYLOAD_TOO_LARGE)?;
+    let content: Option<serde_json::Value> = match bytes.is_empty() {
+        true => None,
+        false => Some(serde_json::from_slice(&bytes).map_err(|_| StatusCode::BAD_REQUEST)?),
+    };
+    let method = parts.method.as_str();
+    authenticate_federation_request(&bridge, &parts.headers, method, &parts.uri, content.as_ref()).await?;
+    Ok(next.run(Request::from_parts(parts, axum::body::Body::from(bytes))).await)
+}
+
+async fn receive_matrix_transaction(
+    State(bridge): State<MatrixMyceliumBridge>,
+    Path(txn_id): Path<String>,
+    headers: HeaderMap,
+    Json(transaction): Json<serde_json::Value>,
+) -> Result<Json<serde_json::Value>, StatusCode> {
+    let destination = x_matrix_destination(&headers)?;
+    
+    match bridge.relay_matrix_transaction(&txn_id, &destination, transaction).await {
+        Ok(results) => Ok(Json(results)),
+        Err(e) => {
+            error!("Failed to relay transaction {}: {}", txn_id, e);
+            Err(e.status_code())
+        }
+    }
+}
+
+/// A transaction the overlay failed to send, which the homeserver should
+/// retry as a whole.
+fn relay_failed(txn_id: &str, destination: &str, reason: String) -> BridgeError {
+    BridgeError::Unavailable(format!("Failed to relay transaction {} to {}: {}", txn_id, destination, reason))
+}
+
+/// The homeserver's token from an appservice request, sent either as a
+/// bearer token or as the legacy `access_token` query parameter.
+fn appservice_token(headers: &HeaderMap, uri: &Uri) -> Option<String> {
+    let bearer = headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::to_string);
+    bearer.or_else(|| {
+        uri.query()?
+            .split('&')
+            .find_map(|pair| pair.strip_prefix("access_token="))
+            .map(str::to_string)
+    })
+}
+
+fn check_appservice_token(
+    bridge: &MatrixMyceliumBridge,
+    headers: &HeaderMap,
+    uri: &Uri,
+) -> Result<Arc<Appservice>, StatusCode> {
+    let appservice = bridge.appservice.clone().ok_or(StatusCode::NOT_FOUND)?;
+    match appservice_token(headers, uri) {
+        Some(token) if appservice.check_hs_token(&token) => Ok(appservice),
+        Some(_) => Err(StatusCode::FORBIDDEN),
+        None => Err(StatusCode::UNAUTHORIZED),
+    }
+}
+
+async fn appservice_transaction(
+    State(bridge): State<MatrixMyceliumBridge>,
+    Path(txn_id): Path<String>,
+    uri: Uri,
+    headers: HeaderMap,
+    Json(transaction): Json<serde_json::Value>,
+) -> Result<Json<serde_json::Value>, StatusCode> {
+    check_appservice_token(&bridge, &headers, &uri)?;
+    
+    let events = transaction["events"].as_array().cloned().unwrap_or_default();
+    match bridge.relay_appservice_transaction(&txn_id, &events).await {
+        Ok(()) => Ok(Json(serde_json::json!({}))),
+        Err(e) => {
+            error!("Failed to relay appservice transaction {}: {}", txn_id, e);
+            Err(e.status_code())
+        }
+    }
+}
+
+/// Puppets are created on demand, so every user in the namespace exists.
+async fn appservice_user_query(
+    State(bridge): State<MatrixMyceliumBridge>,
+    Path(user_id): Path<String>,
+    uri: Uri,
+    headers: HeaderMap,
+) -> Result<Json<serde_json::Value>, StatusCode> {
+    let appservice = check_appservice_token(&bridge, &headers, &uri)?;
+    if appservice.remote_user_id(&user_id).is_some() {
+        Ok(Json(serde_json::json!({})))
+    } else {
+        Err(StatusCode::NOT_FOUND)
+    }
+}
+
+async fn appservice_rooms(
+    State(bridge): State<MatrixMyceliumBridge>,
+) -> Result<Json<serde_json::Value>, StatusCode> {
+    let appservice = bridge.appservice.as_ref().ok_or(StatusCode::NOT_FOUND)?;
+    Ok(Json(serde_json::json!({
+        "rooms": appservice.room_links().await
+    })))
+}
+
+/// Forward a federation API call from the local homeserver to the remote
+/// bridge and relay its answer verbatim.
+async fn proxy_federation_query(
+    bridge: &MatrixMyceliumBridge,
+    kind: &QueryKind,
+    uri: &Uri,
+    headers: &HeaderMap,
+    body: Option<serde_json::Value>,
+) -> Result<(StatusCode, Json<serde_json::Value>), StatusCode> {
+    let destination = x_matrix_destination(headers)?;
+    let path = uri
+        .path_and_query()
+        .map(|p| p.as_str().to_string())
+        .unwrap_or_else(|| uri.path().to_string());
+    
+    match bridge.federation_query(kind, &destination, path, body).await {
+        Ok(response) => {
+            let status = StatusCode::from_u16(response.status).unwrap_or(StatusCode::BAD_GATEWAY);
+            Ok((status, Json(response.body)))
+        }
+        Err(e) => {
+            error!("Federation {} query to {} failed: {}", kind.name, destination, e);
+            Err(e.status_code())
+        }
+    }
+}
+
+async fn backfill(
+    State(bridge): State<MatrixMyceliumBridge>,
+    uri: Uri,
+    headers: HeaderMap,
+) -> Result<(StatusCode, Json<serde_json::Value>), StatusCode> {
+    proxy_federation_query(&bridge, &queries::BACKFILL, &uri, &headers, None).await
+}
+
+async fn get_missing_events(
+    State(bridge): State<MatrixMyceliumBridge>,
+    uri: Uri,
+    headers: HeaderMap,
+    Json(body): Json<serde_json::Value>,
+) -> Result<(StatusCode, Json<serde_json::Value>), StatusCode> {
+    proxy_federation_query(&bridge, &queries::GET_MISSING_EVENTS, &uri, &headers, Some(body)).await
+}
+
+async fn make_leave(
+    State(bridge): State<MatrixMyceliumBridge>,
+    uri: Uri,
+    headers: HeaderMap,
+) -> Result<(StatusCode, Json<serde_json::Value>), StatusCode> {
+    proxy_federation_query(&bridge, &queries::MAKE_LEAVE, &uri, &headers, None).await
+}
+
+async fn send_leave(
+    State(bridge): State<MatrixMyceliumBridge>,
+    uri: Uri,
+    headers: HeaderMap,
+    Json(body): Json<serde_json::Value>,
+) -> Result<(StatusCode, Json<serde_json::Value>), StatusCode> {
+    proxy_federation_query(&bridge, &queries::SEND_LEAVE, &uri, &headers, Some(body)).await
+}
+
+async fn invite(
+    State(bridge): State<MatrixMyceliumBridge>,
+    uri: Uri,
+    headers: HeaderMap,
+    Json(body): Json<serde_json::Value>,
+) -> Result<(StatusCode, Json<serde_json::Value>), StatusCode> {
+    let destination = x_matrix_destination(&headers)?;
+    
+    match bridge.federated_invite(&destination, uri.to_string(), body).await {
+        Ok(response) => {
+            let status = StatusCode::from_u16(response.status).unwrap_or(StatusCode::BAD_GATEWAY);
+            Ok((status, Json(response.body)))
+        }
+        Err(e) => {
+            error!("Federated invite to {} failed: {}", destination, e);
+            Err(e.status_code())
+        }
+    }
+}
+
+async fn make_knock(
+    State(bridge): State<MatrixMyceliumBridge>,
+    uri: Uri,
+    headers: HeaderMap,
+) -> Result<(StatusCode, Json<serde_json::Value>), StatusCode> {
+    proxy_federation_query(&bridge, &queries::MAKE_KNOCK, &uri, &headers, None).await
+}
+
+async fn send_knock(
+    State(bridge): State<MatrixMyceliumBridge>,
+    uri: Uri,
+    headers: HeaderMap,
+    Json(body): Json<serde_json::Value>,
+) -> Result<(StatusCode, Json<serde_json::Value>), StatusCode> {
+    proxy_federation_query(&bridge, &queries::SEND_KNOCK, &uri, &headers, Some(body)).await
+}
+
+async fn claim_keys(
+    State(bridge): State<MatrixMyceliumBridge>,
+    uri: Uri,
+    headers: HeaderMap,
+    Json(body): Json<serde_json::Value>,
+) -> Result<(StatusCode, Json<serde_json::Value>), StatusCode> {
+    proxy_federation_query(&bridge, &queries::CLAIM_KEYS, &uri, &headers, Some(body)).await
+}
+
+async fn query_keys(
+    State(bridge): State<MatrixMyceliumBridge>,
+    uri: Uri,
+    headers: HeaderMap,
+    Json(body): Json<serde_json::Value>,
+) -> Result<(StatusCode, Json<serde_json::Value>), StatusCode> {
+    proxy_federation_query(&bridge, &queries::QUERY_KEYS, &uri, &headers, Some(body)).await
+}
+
+async fn user_devices(
+    State(bridge): State<MatrixMyceliumBridge>,
+    uri: Uri,
+    headers: HeaderMap,
+) -> Result<(StatusCode, Json<serde_json::Value>), StatusCode> {
+    proxy_federation_query(&bridge, &queries::DEVICE_LIST_RESYNC, &uri, &headers, None).await
+}
+
+async fn query_profile(
+    State(bridge): State<MatrixMyceliumBridge>,
+    uri: Uri,
+    headers: HeaderMap,
+) -> Result<(StatusCode, Json<serde_json::Value>), StatusCode> {
+    proxy_federation_query(&bridge, &queries::PROFILE_QUERY, &uri, &headers, None).await
+}
+
+async fn download_media(
+    State(bridge): State<MatrixMyceliumBridge>,
+    Path((_version, server_name, media_id)): Path<(String, String, String)>,
+) -> Response {
+    serve_remote_media(&bridge, &server_name, &media_id).await
+}
+
+async fn download_media_with_name(
+    State(bridge): State<MatrixMyceliumBridge>,
+    Path((_version, server_name, media_id, _file_name)): Path<(String, String, String, String)>,
+) -> Response {
+    serve_remote_media(&bridge, &server_name, &media_id).await
+}
+
+async fn serve_remote_media(bridge: &MatrixMyceliumBridge, server_name: &str, media_id: &str) -> Response {
+    match bridge.fetch_remote_media(server_name, media_id).await {
+        Ok(file) => {
+            let content_type = file
+                .content_type
+                .unwrap_or_else(|| "application/octet-stream".to_string());
+            ([(CONTENT_TYPE, content_type)], file.data).into_response()
+        }
+        Err(e) => {
+            error!("Failed to fetch media {}/{}: {}", server_name, media_id, e);
+            (
+                e.status_code(),
+                Json(serde_json::json!({
+                    "errcode": "M_UNKNOWN",
+                    "error": "Failed to fetch remote media"
+                })),
+            )
+                .into_response()
+        }
+    }
 }
@@ -0,0 +1,73 @@
+use chrono::{DateTime, Utc};
+use std::collections::{HashMap, VecDeque};
+use tokio::sync::Mutex;
+use tracing::warn;
+
+use crate::batching::TRANSACTION_MESSAGE_TYPE;
+use crate::config::LivenessConfig;
+use crate::edu::RELIABLE_EDU_MESSAGE_TYPE;
+use crate::types::{MyceliumMessage, ServerStatus};
+
+/// Message types held for an offline peer rather than sent into the void:
+/// the ones carrying PDUs or EDUs that must not be lost.
+pub const HELD_MESSAGE_TYPES: [&str; 3] =
+    ["federation_event", TRANSACTION_MESSAGE_TYPE, RELIABLE_EDU_MESSAGE_TYPE];
+
+/// The status of a peer last heard from at `last_seen`, given that peers
+/// announce every `interval_seconds`. Static peers are never taken offline,
+/// as they are federated with whether or not they announce.
+pub fn status_after(
+    config: &LivenessConfig,
+    last_seen: DateTime<Utc>,
+    now: DateTime<Utc>,
+    interval_seconds: u64,
+    is_static: bool,
+) -> ServerStatus {
+    let missed = (now - last_seen).num_seconds().max(0) as u64 / interval_seconds.max(1);
+    if missed >= config.offline_after_missed as u64 && !is_static {
+        ServerStatus::Offline
+    } else if missed >= config.unknown_after_missed as u64 {
+        ServerStatus::Unknown
+    } else {
+        ServerStatus::Online
+    }
+}
+
+/// Messages for offline peers, sent once they are back online.
+pub struct OfflineQueue {
+    max_per_peer: usize,
+    held: Mutex<HashMap<String, VecDeque<MyceliumMessage>>>,
+}
+
+impl OfflineQueue {
+    pub fn new(max_per_peer: usize) -> Self {
+        Self {
+            max_per_peer,
+            held: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Hold `message` for its destination, dropping the oldest held for it
+    /// once full.
+    pub async fn hold(&self, message: MyceliumMessage) {
+        let mut held = self.held.lock().await;
+        let queue = held.entry(message.destination_server.clone()).or_default();
+        if queue.len() >= self.max_per_peer {
+            queue.pop_front();
+            warn!("Too many messages held for {}, dropped the oldest", message.destination_server);
+        }
+        queue.push_back(message);
+    }
+
+    /// Everything held for `server_name` that hasn't expired, oldest first.
+    pub async fn take(&self, server_name: &str) -> Vec<MyceliumMessage> {
+        let held = self.held.lock().await.remove(server_name).unwrap_or_default();
+        held.into_iter().filter(|message| !message.is_expired()).collect()
+    }
+
+    /// Number of messages held per peer.
+    pub async fn counts(&self) -> HashMap<String, usize> {
+        let held = self.held.lock().await;
+        held.iter().map(|(server_name, queue)| (server_name.clone(), queue.len())).collect()
+    }
+}
@@ -1,38 +1,298 @@
 use anyhow::Result;
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use matrix_mycelium_bridge::{BridgeConfig, MatrixMyceliumBridge};
-use tracing::{info, Level};
-use tracing_subscriber;
+use matrix_mycelium_bridge::auth::TokenScope;
+use matrix_mycelium_bridge::http_client::HttpClient;
+use matrix_mycelium_bridge::{matrix_keys, signer, systemd, telemetry};
+use tracing::{error, info};
 
 #[derive(Parser)]
 #[command(name = "matrix-mycelium-bridge")]
 #[command(about = "Bridge between Matrix homeserver and Mycelium P2P network")]
 struct Cli {
-    #[arg(short, long, default_value = "config.toml")]
+    #[arg(short, long, default_value = "config.toml", global = true)]
     config: String,
+    
+    /// Write a commented default config to the --config path and exit
+    #[arg(long)]
+    generate_config: bool,
+    
+    /// Validate the config and print the effective settings, secrets
+    /// redacted, without starting the bridge
+    #[arg(long)]
+    check_config: bool,
+    
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Manage the signing key at signing_key_path
+    Key {
+        #[command(subcommand)]
+        command: KeyCommand,
+    },
+    /// Ping a remote bridge through the overlay, by way of the running
+    /// bridge's admin API, and print the round trip times
+    Ping {
+        server_name: String,
+        /// Pings to send
+        #[arg(short = 'n', long, default_value_t = 4)]
+        count: u32,
+        /// Seconds to wait for each answer
+        #[arg(long, default_value_t = 10)]
+        timeout: u64,
+        /// URL of the running bridge; defaults to one on bind_address
+        #[arg(long)]
+        url: Option<String>,
+    },
+    /// Remove this server from the discovery service at
+    /// discovery.service_url when decommissioning it. Stop the bridge
+    /// first, or it registers again
+    Deregister,
+}
+
+#[derive(Subcommand)]
+enum KeyCommand {
+    /// Generate a new keypair
+    Generate {
+        /// Replace an existing key
+        #[arg(long)]
+        force: bool,
+    },
+    /// Print the public key, Matrix key ID and fingerprint
+    Inspect,
+    /// Print the public key as a discovery service trusted_keys entry
+    Export,
+    /// Replace the key, keeping the old one to countersign announcements.
+    /// The bridge announces the new key when restarted
+    Rotate {
+        /// Replace the previous key kept from an earlier rotation
+        #[arg(long)]
+        force: bool,
+    },
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    // Initialize tracing
-    tracing_subscriber::fmt()
-        .with_max_level(Level::INFO)
-        .init();
-
     let cli = Cli::parse();
     
-    info!("Starting Matrix-Mycelium Bridge");
+    // Generate config file if requested
+    if cli.generate_config {
+        if std::path::Path::new(&cli.config).exists() {
+            anyhow::bail!("{} already exists, not overwriting it", cli.config);
+        }
+        std::fs::write(&cli.config, BridgeConfig::default().to_commented_toml()?)?;
+        println!("Generated default configuration file: {}", cli.config);
+        return Ok(());
+    }
     
     // Load configuration
     let config = BridgeConfig::from_file(&cli.config)?;
+    config.validate()?;
+    
+    match cli.command {
+        Some(Command::Key { command }) => return manage_key(config, command).await,
+        Some(Command::Ping {
+            server_name,
+            count,
+            timeout,
+            url,
+        }) => return ping(&config, &server_name, count, timeout, url).await,
+        Some(Command::Deregister) => return deregister(config).await,
+        None => {}
+    }
+    
+    if cli.check_config {
+        print!("{}", toml::to_string_pretty(&config.redacted())?);
+        // A TOML comment, so the output still parses as a config
+        println!("# Configuration in {} is valid", cli.config);
+        return Ok(());
+    }
+    
+    // Initialize tracing, keeping the guard alive to flush spans on exit
+    let _telemetry = telemetry::init(&config)?;
+    
+    info!("Starting Matrix-Mycelium Bridge");
     
     // Create and start bridge
-    let mut bridge = MatrixMyceliumBridge::new(config).await?;
+    let bridge = MatrixMyceliumBridge::new(config).await?;
+    bridge.set_config_path(&cli.config);
     
     info!("Bridge initialized, starting services...");
     
-    // Start the bridge (this will run indefinitely)
-    bridge.start().await?;
+    // Start the bridge, running until a termination signal
+    let handle = bridge.start().await?;
+    tokio::spawn(systemd::supervise(bridge.clone()));
+    tokio::spawn(async move {
+        termination_signal().await;
+        info!("Received shutdown signal");
+        bridge.request_shutdown();
+    });
+    handle.join().await?;
+    
+    Ok(())
+}
+
+/// Resolves on SIGINT, or SIGTERM on Unix.
+async fn termination_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = terminate.recv() => {}
+                }
+                return;
+            }
+            Err(e) => error!("Failed to listen for SIGTERM: {}", e),
+        }
+    }
+    if let Err(e) = tokio::signal::ctrl_c().await {
+        error!("Failed to listen for SIGINT: {}", e);
+        std::future::pending::<()>().await;
+    }
+}
+
+async fn manage_key(config: BridgeConfig, command: KeyCommand) -> Result<()> {
+    if config.signer.url.is_some() && !matches!(command, KeyCommand::Inspect | KeyCommand::Export) {
+        anyhow::bail!("The key is held by the external signer at signer.url");
+    }
+    let path = config.signing_key_path.clone();
+    
+    match command {
+        KeyCommand::Generate { force } => {
+            if std::path::Path::new(&path).exists() && !force {
+                anyhow::bail!("{} already exists; use --force to replace it, or rotate it", path);
+            }
+            let key = signer::generate_keypair();
+            signer::write_keypair(&path, &key)?;
+            println!("Generated a new signing key in {}", path);
+            print_key(&key.verifying_key(), &config.server_name);
+        }
+        KeyCommand::Inspect => print_key(&current_key(&config).await?, &config.server_name),
+        KeyCommand::Export => {
+            let key = current_key(&config).await?;
+            println!("# {}", config.server_name);
+            println!("trusted_keys = [\"{}\"]", signer::encode_public_key(&key));
+        }
+        KeyCommand::Rotate { force } => {
+            let previous_path = signer::previous_key_path(&path);
+            if std::path::Path::new(&previous_path).exists() && !force {
+                anyhow::bail!(
+                    "{} is still kept from the last rotation; remove it once every peer has the current \
+                     key, or use --force to replace it",
+                    previous_path
+                );
+            }
+            let previous = signer::read_keypair(&path)?;
+            signer::write_keypair(&previous_path, &previous)?;
+            let key = signer::generate_keypair();
+            signer::write_keypair(&path, &key)?;
+            println!("Rotated the signing key in {}, keeping the previous one in {}", path, previous_path);
+            print_key(&key.verifying_key(), &config.server_name);
+            
+            // Announced by the bridge, so peers never get a key it doesn't sign with yet
+            println!("Restart the bridge to sign with the new key; the bridge will announce it on restart.");
+            println!("Update trusted_keys and static peers, and remove the previous key file once every");
+            println!("peer has the new key.");
+        }
+    }
+    Ok(())
+}
+
+async fn deregister(config: BridgeConfig) -> Result<()> {
+    let bridge = MatrixMyceliumBridge::new(config).await?;
+    if bridge.deregister_from_discovery().await? {
+        println!("Removed this server from the discovery service");
+    } else {
+        println!("The discovery service did not list this server");
+    }
+    Ok(())
+}
+
+/// Ask the running bridge to ping `server_name` `count` times.
+async fn ping(
+    config: &BridgeConfig,
+    server_name: &str,
+    count: u32,
+    timeout: u64,
+    url: Option<String>,
+) -> Result<()> {
+    let admin_token = config.admin.token.clone().or_else(|| {
+        let tokens = config.auth.tokens.iter();
+        tokens
+            .filter(|token| token.scopes.contains(&TokenScope::Admin))
+            .map(|token| token.token.clone())
+            .next()
+    });
+    let Some(admin_token) = admin_token else {
+        anyhow::bail!("Pinging needs the admin API; set admin.token");
+    };
+    let url = url.unwrap_or_else(|| {
+        let scheme = if config.tls.enabled { "https" } else { "http" };
+        // Reach a wildcard listener on the loopback address
+        let address = config
+            .bind_address
+            .replace("0.0.0.0:", "127.0.0.1:")
+            .replace("[::]:", "[::1]:");
+        format!("{}://{}", scheme, address)
+    });
+    
+    let client = reqwest::Client::new();
+    let mut rtts = Vec::new();
+    for sequence in 1..=count {
+        if sequence > 1 {
+            tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+        }
+        let response = client
+            .post(format!("{}/admin/ping/{}", url.trim_end_matches('/'), server_name))
+            .query(&[("timeout_seconds", timeout)])
+            .bearer_auth(&admin_token)
+            .timeout(std::time::Duration::from_secs(timeout + 5))
+            .send()
+            .await?;
+        let status = response.status();
+        let body: serde_json::Value = response.json().await.unwrap_or_default();
+        match body["rtt_ms"].as_f64() {
+            Some(rtt) if status.is_success() => {
+                println!("Reply from {}: seq={} time={:.1} ms", server_name, sequence, rtt);
+                rtts.push(rtt);
+            }
+            _ => {
+                let error = body["error"].as_str().map_or_else(|| status.to_string(), str::to_string);
+                println!("No reply from {}: seq={} {}", server_name, sequence, error);
+            }
+        }
+    }
     
+    let lost = count as usize - rtts.len();
+    println!("--- {} ping statistics ---", server_name);
+    println!("{} sent, {} received, {} lost", count, rtts.len(), lost);
+    if rtts.is_empty() {
+        anyhow::bail!("{} is unreachable", server_name);
+    }
+    let min = rtts.iter().copied().fold(f64::INFINITY, f64::min);
+    let max = rtts.iter().copied().fold(0.0, f64::max);
+    let average = rtts.iter().sum::<f64>() / rtts.len() as f64;
+    println!("rtt min/avg/max = {:.1}/{:.1}/{:.1} ms", min, average, max);
     Ok(())
 }
+
+/// The public key in use, from the external signer if there is one.
+async fn current_key(config: &BridgeConfig) -> Result<ed25519_dalek::VerifyingKey> {
+    match &config.signer.url {
+        Some(_) => Ok(signer::from_config(config, HttpClient::new(&config.http)?).await?.verifying_key()),
+        None => Ok(signer::read_keypair(&config.signing_key_path)?.verifying_key()),
+    }
+}
+
+fn print_key(key: &ed25519_dalek::VerifyingKey, server_name: &str) {
+    println!("server_name: {}", server_name);
+    println!("public_key: {}", signer::encode_public_key(key));
+    println!("key_id: {}", matrix_keys::key_id(key));
+    println!("fingerprint: {}", signer::fingerprint(key));
+}
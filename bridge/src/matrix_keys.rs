@@ -0,0 +1,188 @@
+use anyhow::Result;
+use base64::Engine;
+use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+use std::collections::HashMap;
+
+use crate::signer::Signer;
+
+/// How long remote servers may cache the keys returned by `/_matrix/key/v2/server`.
+const KEY_VALIDITY: chrono::Duration = chrono::Duration::hours(24);
+
+/// Matrix key ID for a verifying key, e.g. `ed25519:a1b2c3d4`.
+pub fn key_id(verifying_key: &VerifyingKey) -> String {
+    let fingerprint: String = verifying_key.to_bytes()[..4]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+
+    format!("ed25519:{}", fingerprint)
+}
+
+/// Build the signed response for `GET /_matrix/key/v2/server`.
+pub async fn server_keys_response(server_name: &str, signer: &dyn Signer) -> Result<serde_json::Value> {
+    notarized_keys_response(server_name, &[signer.verifying_key()], server_name, signer).await
+}
+
+/// Build the `GET /_matrix/key/v2/server` response for `server_name`, a
+/// remote server reached through the bridge, listing `keys`. The bridge
+/// can't sign as that server, so it signs as `notary`, its own server name.
+pub async fn notarized_keys_response(
+    server_name: &str,
+    keys: &[VerifyingKey],
+    notary: &str,
+    signer: &dyn Signer,
+) -> Result<serde_json::Value> {
+    let engine = base64::engine::general_purpose::STANDARD_NO_PAD;
+    let valid_until_ts = (chrono::Utc::now() + KEY_VALIDITY).timestamp_millis();
+    let verify_keys: serde_json::Map<String, serde_json::Value> = keys
+        .iter()
+        .map(|key| (key_id(key), serde_json::json!({ "key": engine.encode(key.to_bytes()) })))
+        .collect();
+
+    let mut response = serde_json::json!({
+        "server_name": server_name,
+        "valid_until_ts": valid_until_ts,
+        "verify_keys": verify_keys,
+        "old_verify_keys": {}
+    });
+
+    // serde_json objects are sorted and `to_string` is compact, which matches
+    // Matrix canonical JSON for this structure.
+    let canonical = serde_json::to_string(&response)?;
+    let signature = signer.sign(canonical.as_bytes()).await?;
+
+    response["signatures"] = serde_json::json!({
+        notary: { key_id(&signer.verifying_key()): engine.encode(signature.to_bytes()) }
+    });
+
+    Ok(response)
+}
+
+/// The current keys in a `/_matrix/key/v2/server` response, by key ID.
+/// Keys that don't decode are left out.
+pub fn verify_keys(response: &serde_json::Value) -> HashMap<String, VerifyingKey> {
+    let engine = base64::engine::general_purpose::STANDARD_NO_PAD;
+    let Some(keys) = response["verify_keys"].as_object() else {
+        return HashMap::new();
+    };
+    keys.iter()
+        .filter_map(|(key_id, key)| {
+            let bytes = engine.decode(key["key"].as_str()?.trim_end_matches('=')).ok()?;
+            let key = VerifyingKey::from_bytes(&bytes.try_into().ok()?).ok()?;
+            Some((key_id.clone(), key))
+        })
+        .collect()
+}
+
+/// Top-level event keys kept by redaction in every room version.
+const REDACTION_KEPT_KEYS: &[&str] = &[
+    "event_id",
+    "type",
+    "room_id",
+    "sender",
+    "state_key",
+    "content",
+    "hashes",
+    "signatures",
+    "depth",
+    "prev_events",
+    "auth_events",
+    "origin_server_ts",
+];
+
+/// Top-level keys redaction only keeps before room version 11.
+const PRE_V11_KEPT_KEYS: &[&str] = &["origin", "membership", "prev_state"];
+
+/// `event` as redacted in `room_version`, which is what its signatures are
+/// made over. Versions that aren't numbers are redacted as the latest.
+pub fn redact(event: &serde_json::Value, room_version: &str) -> serde_json::Value {
+    let version: u32 = room_version.parse().unwrap_or(u32::MAX);
+    let Some(fields) = event.as_object() else {
+        return event.clone();
+    };
+    let keeps_key = |key: &str| {
+        REDACTION_KEPT_KEYS.contains(&key) || (version < 11 && PRE_V11_KEPT_KEYS.contains(&key))
+    };
+    let mut redacted: serde_json::Map<String, serde_json::Value> = fields
+        .iter()
+        .filter(|(key, _)| keeps_key(key))
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect();
+
+    let content = &event["content"];
+    let kept_content: &[&str] = match event["type"].as_str().unwrap_or_default() {
+        "m.room.member" if version >= 11 => {
+            &["membership", "join_authorised_via_users_server", "third_party_invite"]
+        }
+        "m.room.member" if version >= 9 => &["membership", "join_authorised_via_users_server"],
+        "m.room.member" => &["membership"],
+        "m.room.create" if version >= 11 => return serde_json::Value::Object(redacted),
+        "m.room.create" => &["creator"],
+        "m.room.join_rules" if version >= 8 => &["join_rule", "allow"],
+        "m.room.join_rules" => &["join_rule"],
+        "m.room.power_levels" => &[
+            "ban",
+            "events",
+            "events_default",
+            "invite",
+            "kick",
+            "redact",
+            "state_default",
+            "users",
+            "users_default",
+        ],
+        "m.room.history_visibility" => &["history_visibility"],
+        "m.room.aliases" if version < 6 => &["aliases"],
+        "m.room.redaction" if version >= 11 => &["redacts"],
+        _ => &[],
+    };
+    let mut kept: serde_json::Map<String, serde_json::Value> = kept_content
+        .iter()
+        .filter_map(|key| Some((key.to_string(), content.get(*key)?.clone())))
+        .collect();
+    // Power levels only keep `invite` from version 11
+    if event["type"] == "m.room.power_levels" && version < 11 {
+        kept.remove("invite");
+    }
+    // Of a third party invite, only what was signed is kept
+    if let Some(invite) = kept.get_mut("third_party_invite") {
+        *invite = match invite.get("signed") {
+            Some(signed) => serde_json::json!({ "signed": signed }),
+            None => serde_json::json!({}),
+        };
+    }
+    redacted.insert("content".to_string(), serde_json::Value::Object(kept));
+    serde_json::Value::Object(redacted)
+}
+
+/// Whether `event` carries a valid signature by `server_name` made with
+/// `key`, over its redacted form in `room_version`.
+pub fn verify_event(
+    event: &serde_json::Value,
+    room_version: &str,
+    server_name: &str,
+    key: &VerifyingKey,
+) -> bool {
+    let engine = base64::engine::general_purpose::STANDARD_NO_PAD;
+    let Some(signature) = event["signatures"][server_name][key_id(key)].as_str() else {
+        return false;
+    };
+    let Ok(signature) = engine.decode(signature.trim_end_matches('=')) else {
+        return false;
+    };
+    let Ok(signature) = Signature::from_slice(&signature) else {
+        return false;
+    };
+
+    let mut signed = redact(event, room_version);
+    if let Some(fields) = signed.as_object_mut() {
+        fields.remove("signatures");
+        fields.remove("unsigned");
+    }
+    // serde_json sorts object keys and `to_string` is compact, which gives
+    // Matrix canonical JSON
+    let Ok(canonical) = serde_json::to_string(&signed) else {
+        return false;
+    };
+    key.verify(canonical.as_bytes(), &signature).is_ok()
+}
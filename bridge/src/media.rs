@@ -0,0 +1,314 @@
+use anyhow::Result;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+use tokio::sync::{oneshot, Mutex};
+use tracing::{info, warn};
+
+use crate::config::MediaConfig;
+
+/// Capability advertised by bridges that serve chunked media transfers.
+pub const MEDIA_CAPABILITY: &str = "media.chunked";
+
+pub const MEDIA_REQUEST_MESSAGE_TYPE: &str = "media_fetch_request";
+pub const MEDIA_CHUNK_MESSAGE_TYPE: &str = "media_chunk";
+
+/// Payload of a `media_fetch_request` message.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MediaRequest {
+    pub request_id: String,
+    pub server_name: String,
+    pub media_id: String,
+}
+
+/// Payload of a `media_chunk` message. A transfer that fails on the origin
+/// side is answered with a single chunk carrying `error`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MediaChunk {
+    pub request_id: String,
+    pub index: u32,
+    pub total: u32,
+    pub content_type: Option<String>,
+    /// SHA-256 of this chunk's data.
+    pub sha256: String,
+    /// SHA-256 of the complete file.
+    pub file_sha256: String,
+    pub data: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct MediaFile {
+    pub content_type: Option<String>,
+    pub data: Vec<u8>,
+}
+
+pub fn sha256_hex(data: &[u8]) -> String {
+    Sha256::digest(data)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Split a file into `media_chunk` payloads of at most `chunk_size` bytes.
+pub fn split_into_chunks(request_id: &str, file: &MediaFile, chunk_size: usize) -> Vec<MediaChunk> {
+    let engine = base64::engine::general_purpose::STANDARD;
+    let file_sha256 = sha256_hex(&file.data);
+    let pieces: Vec<&[u8]> = if file.data.is_empty() {
+        vec![&[]]
+    } else {
+        file.data.chunks(chunk_size.max(1)).collect()
+    };
+    let total = pieces.len() as u32;
+
+    pieces
+        .into_iter()
+        .enumerate()
+        .map(|(index, piece)| MediaChunk {
+            request_id: request_id.to_string(),
+            index: index as u32,
+            total,
+            content_type: file.content_type.clone(),
+            sha256: sha256_hex(piece),
+            file_sha256: file_sha256.clone(),
+            data: engine.encode(piece),
+            error: None,
+        })
+        .collect()
+}
+
+struct PendingTransfer {
+    origin: String,
+    content_type: Option<String>,
+    /// Chunk count, taken from the first chunk. Zero until then.
+    total: u32,
+    /// Chunks received so far, by index. Not allocated up front, as the
+    /// count comes from the origin.
+    chunks: BTreeMap<u32, Vec<u8>>,
+    received_bytes: usize,
+    done: oneshot::Sender<Result<MediaFile, String>>,
+}
+
+/// Reassembles incoming chunks into files, verifying each chunk and the
+/// complete file against their hashes.
+pub struct MediaAssembler {
+    max_media_bytes: usize,
+    pending: Mutex<HashMap<String, PendingTransfer>>,
+}
+
+impl MediaAssembler {
+    pub fn new(max_media_bytes: usize) -> Self {
+        Self {
+            max_media_bytes,
+            pending: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub async fn register(
+        &self,
+        request_id: &str,
+        origin: &str,
+    ) -> oneshot::Receiver<Result<MediaFile, String>> {
+        let (done, receiver) = oneshot::channel();
+        let transfer = PendingTransfer {
+            origin: origin.to_string(),
+            content_type: None,
+            total: 0,
+            chunks: BTreeMap::new(),
+            received_bytes: 0,
+            done,
+        };
+        self.pending.lock().await.insert(request_id.to_string(), transfer);
+        receiver
+    }
+
+    pub async fn cancel(&self, request_id: &str) {
+        self.pending.lock().await.remove(request_id);
+    }
+
+    /// Add a chunk from `source`. Once the last chunk arrives (or the transfer
+    /// fails) the waiting request is completed.
+    pub async fn add_chunk(&self, source: &str, chunk: MediaChunk) {
+        let mut pending = self.pending.lock().await;
+        let Some(transfer) = pending.get_mut(&chunk.request_id) else {
+            return;
+        };
+        if transfer.origin != source {
+            warn!("Ignoring media chunk for {} from {}", chunk.request_id, source);
+            return;
+        }
+
+        let outcome = match Self::accept(transfer, &chunk, self.max_media_bytes) {
+            Ok(false) => return,
+            Ok(true) => Self::assemble(transfer, &chunk.file_sha256),
+            Err(e) => Err(e),
+        };
+
+        if let Some(transfer) = pending.remove(&chunk.request_id) {
+            let _ = transfer.done.send(outcome);
+        }
+    }
+
+    /// Store a chunk. Returns `true` once every chunk has been received.
+    fn accept(transfer: &mut PendingTransfer, chunk: &MediaChunk, max_bytes: usize) -> Result<bool, String> {
+        if let Some(error) = &chunk.error {
+            return Err(error.clone());
+        }
+        if chunk.total == 0 || chunk.index >= chunk.total {
+            return Err("Invalid chunk index".to_string());
+        }
+        // Every chunk of a file split in several carries at least a byte
+        if chunk.total as usize > max_bytes.max(1) {
+            return Err("Media exceeds the maximum allowed size".to_string());
+        }
+        if transfer.total == 0 {
+            transfer.total = chunk.total;
+            transfer.content_type = chunk.content_type.clone();
+        } else if transfer.total != chunk.total {
+            return Err("Inconsistent chunk count".to_string());
+        }
+
+        let data = base64::engine::general_purpose::STANDARD
+            .decode(&chunk.data)
+            .map_err(|e| e.to_string())?;
+        if sha256_hex(&data) != chunk.sha256 {
+            return Err(format!("Chunk {} failed its integrity check", chunk.index));
+        }
+        if data.is_empty() && chunk.total > 1 {
+            return Err(format!("Chunk {} is empty", chunk.index));
+        }
+
+        if !transfer.chunks.contains_key(&chunk.index) {
+            transfer.received_bytes += data.len();
+            if transfer.received_bytes > max_bytes {
+                return Err("Media exceeds the maximum allowed size".to_string());
+            }
+            transfer.chunks.insert(chunk.index, data);
+        }
+
+        Ok(transfer.chunks.len() == transfer.total as usize)
+    }
+
+    fn assemble(transfer: &mut PendingTransfer, file_sha256: &str) -> Result<MediaFile, String> {
+        let data: Vec<u8> = std::mem::take(&mut transfer.chunks).into_values().flatten().collect();
+        if sha256_hex(&data) != file_sha256 {
+            return Err("Media failed its integrity check".to_string());
+        }
+
+        Ok(MediaFile {
+            content_type: transfer.content_type.take(),
+            data,
+        })
+    }
+}
+
+/// On-disk cache of remote media, evicting the least recently written files
+/// once `max_cache_bytes` is exceeded.
+pub struct MediaCache {
+    dir: PathBuf,
+    max_bytes: u64,
+    /// Held while files are written and evicted, so eviction never sees a
+    /// file half written.
+    writes: Mutex<()>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CachedMeta {
+    content_type: Option<String>,
+}
+
+impl MediaCache {
+    pub fn new(config: &MediaConfig) -> Self {
+        Self {
+            dir: PathBuf::from(&config.cache_dir),
+            max_bytes: config.max_cache_bytes,
+            writes: Mutex::new(()),
+        }
+    }
+
+    fn paths(&self, server_name: &str, media_id: &str) -> (PathBuf, PathBuf) {
+        let key = sha256_hex(format!("{}/{}", server_name, media_id).as_bytes());
+        (self.dir.join(&key), self.dir.join(format!("{}.meta", key)))
+    }
+
+    pub async fn get(&self, server_name: &str, media_id: &str) -> Option<MediaFile> {
+        let (data_path, meta_path) = self.paths(server_name, media_id);
+        let data = tokio::fs::read(&data_path).await.ok()?;
+        let meta: CachedMeta = tokio::fs::read(&meta_path)
+            .await
+            .ok()
+            .and_then(|raw| serde_json::from_slice(&raw).ok())
+            .unwrap_or(CachedMeta { content_type: None });
+
+        Some(MediaFile {
+            content_type: meta.content_type,
+            data,
+        })
+    }
+
+    pub async fn put(&self, server_name: &str, media_id: &str, file: &MediaFile) -> Result<()> {
+        if file.data.len() as u64 > self.max_bytes {
+            return Ok(());
+        }
+
+        tokio::fs::create_dir_all(&self.dir).await?;
+        let (data_path, meta_path) = self.paths(server_name, media_id);
+        let meta = CachedMeta {
+            content_type: file.content_type.clone(),
+        };
+        let _writes = self.writes.lock().await;
+        // Both are written aside and renamed into place, the data last, so
+        // a cached file always has its content type next to it
+        let data_temp = data_path.with_extension("tmp");
+        let meta_temp = data_path.with_extension("meta.tmp");
+        tokio::fs::write(&meta_temp, serde_json::to_vec(&meta)?).await?;
+        tokio::fs::write(&data_temp, &file.data).await?;
+        tokio::fs::rename(&meta_temp, &meta_path).await?;
+        tokio::fs::rename(&data_temp, &data_path).await?;
+
+        self.evict().await
+    }
+
+    /// Remove the oldest files until the cache fits, along with whatever a
+    /// crash mid-write left behind. Called with `writes` held.
+    async fn evict(&self) -> Result<()> {
+        let mut entries = Vec::new();
+        let mut total: u64 = 0;
+        let mut dir = tokio::fs::read_dir(&self.dir).await?;
+        while let Some(entry) = dir.next_entry().await? {
+            let path = entry.path();
+            match path.extension().and_then(|ext| ext.to_str()) {
+                Some("tmp") => {
+                    tokio::fs::remove_file(&path).await?;
+                    continue;
+                }
+                Some("meta") => {
+                    if !tokio::fs::try_exists(path.with_extension("")).await? {
+                        tokio::fs::remove_file(&path).await?;
+                    }
+                    continue;
+                }
+                _ => {}
+            }
+            let metadata = entry.metadata().await?;
+            total += metadata.len();
+            entries.push((metadata.modified()?, metadata.len(), path));
+        }
+
+        entries.sort_by_key(|(modified, _, _)| *modified);
+        for (_, size, path) in entries {
+            if total <= self.max_bytes {
+                break;
+            }
+            tokio::fs::remove_file(&path).await?;
+            let _ = tokio::fs::remove_file(path.with_extension("meta")).await;
+            total -= size;
+            info!("Evicted {} from the media cache", path.display());
+        }
+
+        Ok(())
+    }
+}
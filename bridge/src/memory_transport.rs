@@ -0,0 +1,285 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::Notify;
+
+use crate::mycelium::{Destination, FederationTransport, InboundMessage, MyceliumInfo};
+
+/// Upper bound on messages popped by a single `receive_messages` call, as
+/// for the mycelium client.
+const MAX_MESSAGES_PER_READ: usize = 100;
+
+/// An overlay held in memory. Transports joined to the same network reach
+/// each other by address, without a mycelium node, so several bridges can
+/// federate inside one test process.
+///
+/// Links can be given latency, loss and partitions. Losses are drawn from
+/// a seeded generator, so with tokio's paused clock a run is reproducible.
+#[derive(Clone, Default)]
+pub struct MemoryNetwork {
+    nodes: Arc<Mutex<HashMap<String, Arc<Inbox>>>>,
+    conditions: Arc<Mutex<Conditions>>,
+    delivered: Arc<AtomicU64>,
+    dropped: Arc<AtomicU64>,
+}
+
+/// How the links between nodes behave.
+struct Conditions {
+    latency: Duration,
+    /// Added to the latency of each message, uniformly from zero up to this,
+    /// so messages can overtake each other.
+    jitter: Duration,
+    /// Share of messages lost, from 0 to 1.
+    loss: f64,
+    /// Pairs of nodes that can't reach each other, both ways round.
+    cut: HashSet<(String, String)>,
+    rng: StdRng,
+}
+
+impl Default for Conditions {
+    fn default() -> Self {
+        Self {
+            latency: Duration::ZERO,
+            jitter: Duration::ZERO,
+            loss: 0.0,
+            cut: HashSet::new(),
+            rng: StdRng::seed_from_u64(0),
+        }
+    }
+}
+
+impl Conditions {
+    /// Whether a message from `source` to `destination` arrives, and after
+    /// how long.
+    fn route(&mut self, source: &str, destination: &str) -> Option<Duration> {
+        if self.cut.contains(&(source.to_string(), destination.to_string())) {
+            return None;
+        }
+        if self.loss > 0.0 && self.rng.gen_bool(self.loss.min(1.0)) {
+            return None;
+        }
+        let jitter = match self.jitter.is_zero() {
+            true => Duration::ZERO,
+            false => self.jitter.mul_f64(self.rng.gen::<f64>()),
+        };
+        Some(self.latency + jitter)
+    }
+}
+
+/// Messages waiting for one node, per topic.
+#[derive(Default)]
+struct Inbox {
+    topics: Mutex<HashMap<String, VecDeque<InboundMessage>>>,
+    /// Who sent each message not yet replied to, by message ID.
+    senders: Mutex<HashMap<String, String>>,
+    arrived: Notify,
+    /// Times the node restarted, reported in place of a start time.
+    restarts: AtomicU64,
+}
+
+impl Inbox {
+    fn push(&self, message: InboundMessage) {
+        self.topics
+            .lock()
+            .unwrap()
+            .entry(message.topic.clone())
+            .or_default()
+            .push_back(message);
+        self.arrived.notify_waiters();
+    }
+
+    fn pop(&self, topic: &str) -> Vec<InboundMessage> {
+        let mut topics = self.topics.lock().unwrap();
+        let Some(queue) = topics.get_mut(topic) else {
+            return Vec::new();
+        };
+        let count = queue.len().min(MAX_MESSAGES_PER_READ);
+        queue.drain(..count).collect()
+    }
+}
+
+impl MemoryNetwork {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Seed the generator losses and jitter are drawn from. The default seed
+    /// is 0.
+    pub fn seed(&self, seed: u64) {
+        self.conditions.lock().unwrap().rng = StdRng::seed_from_u64(seed);
+    }
+
+    /// Delay every message by `latency`, plus up to `jitter` more.
+    pub fn set_latency(&self, latency: Duration, jitter: Duration) {
+        let mut conditions = self.conditions.lock().unwrap();
+        conditions.latency = latency;
+        conditions.jitter = jitter;
+    }
+
+    /// Lose this share of messages, from 0 to 1.
+    pub fn set_loss(&self, loss: f64) {
+        self.conditions.lock().unwrap().loss = loss;
+    }
+
+    /// Cut every link between a node in `side` and one in `other_side`.
+    /// Messages across the cut are lost, as they would be by mycelium.
+    pub fn partition(&self, side: &[&str], other_side: &[&str]) {
+        let mut conditions = self.conditions.lock().unwrap();
+        for a in side {
+            for b in other_side {
+                conditions.cut.insert((a.to_string(), b.to_string()));
+                conditions.cut.insert((b.to_string(), a.to_string()));
+            }
+        }
+    }
+
+    /// Restart the node at `address`, as if its mycelium daemon restarted:
+    /// its queued messages are lost and its info reports a new run. The
+    /// address stays the same.
+    pub fn restart(&self, address: &str) {
+        if let Some(inbox) = self.nodes.lock().unwrap().get(address) {
+            inbox.topics.lock().unwrap().clear();
+            inbox.senders.lock().unwrap().clear();
+            inbox.restarts.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Restore every link cut by [`partition`](Self::partition).
+    pub fn heal(&self) {
+        self.conditions.lock().unwrap().cut.clear();
+    }
+
+    /// Messages that reached their destination's inbox so far.
+    pub fn delivered(&self) -> u64 {
+        self.delivered.load(Ordering::Relaxed)
+    }
+
+    /// Messages lost to loss or partitions so far.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Join the network as `address`, which is both the node's public key
+    /// and what other nodes send to. Joining again with the same address
+    /// replaces the earlier node and drops its queued messages.
+    pub fn transport(&self, address: &str) -> MemoryTransport {
+        let inbox = Arc::new(Inbox::default());
+        self.nodes.lock().unwrap().insert(address.to_string(), inbox.clone());
+        MemoryTransport {
+            network: self.clone(),
+            address: address.to_string(),
+            inbox,
+        }
+    }
+
+    fn deliver(&self, source: &str, destination: &str, topic: &str, data: &[u8]) -> Result<()> {
+        let inbox = self
+            .nodes
+            .lock()
+            .unwrap()
+            .get(destination)
+            .cloned()
+            .ok_or_else(|| anyhow::anyhow!("No node {} on the network", destination))?;
+        let Some(delay) = self.conditions.lock().unwrap().route(source, destination) else {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            return Ok(());
+        };
+        let id = uuid::Uuid::new_v4().to_string();
+        inbox.senders.lock().unwrap().insert(id.clone(), source.to_string());
+        let message = InboundMessage {
+            id: Some(id),
+            source_ip: None,
+            source_public_key: Some(source.to_string()),
+            topic: topic.to_string(),
+            payload: data.to_vec(),
+        };
+        if delay.is_zero() {
+            inbox.push(message);
+            self.delivered.fetch_add(1, Ordering::Relaxed);
+            return Ok(());
+        }
+        let delivered = self.delivered.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(delay).await;
+            inbox.push(message);
+            delivered.fetch_add(1, Ordering::Relaxed);
+        });
+        Ok(())
+    }
+}
+
+/// One node of a [`MemoryNetwork`], speaking the native (non-legacy) API.
+pub struct MemoryTransport {
+    network: MemoryNetwork,
+    address: String,
+    inbox: Arc<Inbox>,
+}
+
+impl MemoryTransport {
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+}
+
+#[async_trait]
+impl FederationTransport for MemoryTransport {
+    fn is_legacy(&self) -> bool {
+        false
+    }
+
+    async fn send_message(&self, destination: &Destination, topic: &str, data: &[u8]) -> Result<()> {
+        let (Destination::Ip(address) | Destination::PublicKey(address)) = destination;
+        self.network.deliver(&self.address, address, topic, data)
+    }
+
+    /// Like mycelium itself, the network has no broadcast.
+    async fn publish(&self, _topic: &str, _data: &[u8]) -> Result<()> {
+        Err(anyhow::anyhow!("Mycelium does not support broadcast messages"))
+    }
+
+    /// Nobody waits on the original send, so the reply arrives as a message
+    /// on `topic`.
+    async fn reply(&self, message_id: &str, topic: &str, data: &[u8]) -> Result<()> {
+        let source = self
+            .inbox
+            .senders
+            .lock()
+            .unwrap()
+            .remove(message_id)
+            .ok_or_else(|| anyhow::anyhow!("No message {} to reply to", message_id))?;
+        self.network.deliver(&self.address, &source, topic, data)
+    }
+
+    async fn receive_messages(&self, topic: &str, timeout_seconds: u64) -> Result<Vec<InboundMessage>> {
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(timeout_seconds);
+        loop {
+            // Registered before looking, so a message arriving in between
+            // still wakes this read
+            let arrived = self.inbox.arrived.notified();
+            tokio::pin!(arrived);
+            arrived.as_mut().enable();
+
+            let messages = self.inbox.pop(topic);
+            if !messages.is_empty() || timeout_seconds == 0 {
+                return Ok(messages);
+            }
+            if tokio::time::timeout_at(deadline, arrived).await.is_err() {
+                return Ok(Vec::new());
+            }
+        }
+    }
+
+    async fn get_info(&self) -> Result<MyceliumInfo> {
+        Ok(MyceliumInfo {
+            address: self.address.clone(),
+            public_key: self.address.clone(),
+            subnet: None,
+            started_at: Some(self.inbox.restarts.load(Ordering::Relaxed).to_string()),
+        })
+    }
+}
@@ -1,96 +1,724 @@
 use anyhow::Result;
-use reqwest::Client;
+use async_trait::async_trait;
+use base64::Engine;
+use bytes::Bytes;
+use http_body_util::Full;
+use reqwest::{Client, Method, StatusCode, Url};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use tracing::{error, info};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use tracing::{error, info, warn};
 
+use crate::config::MyceliumConfig;
+use crate::http_client::RetryPolicy;
+use crate::signer::UNIX_SCHEME;
+
+/// Upper bound on messages popped by a single `receive_messages` call.
+const MAX_MESSAGES_PER_READ: usize = 100;
+
+/// Requests over a Unix socket are built against this URL. Only their path
+/// and query are sent.
+const UNIX_BASE_URL: &str = "http://localhost";
+
+/// Access to the overlay, normally through a mycelium node. The bridge only
+/// sends and receives through this trait, so tests can substitute their own
+/// transport, such as [`MemoryTransport`](crate::memory_transport::MemoryTransport).
+#[async_trait]
+pub trait FederationTransport: Send + Sync {
+    /// Whether this speaks the legacy topic-routed API.
+    fn is_legacy(&self) -> bool;
+
+    /// Send `data` to `destination` on `topic`.
+    async fn send_message(&self, destination: &Destination, topic: &str, data: &[u8]) -> Result<()>;
+
+    /// Publish on a topic without a specific destination.
+    async fn publish(&self, topic: &str, data: &[u8]) -> Result<()>;
+
+    /// Answer a received message.
+    async fn reply(&self, message_id: &str, topic: &str, data: &[u8]) -> Result<()>;
+
+    /// Pop messages on `topic`, waiting up to `timeout_seconds` for the first
+    /// one to arrive (0 returns immediately).
+    async fn receive_messages(&self, topic: &str, timeout_seconds: u64) -> Result<Vec<InboundMessage>>;
+
+    async fn get_info(&self) -> Result<MyceliumInfo>;
+
+    async fn health_check(&self) -> bool {
+        self.get_info().await.is_ok()
+    }
+}
+
+/// HTTP client for the mycelium daemon's message API.
+///
+/// By default this speaks mycelium's real API: messages are addressed to a
+/// node (IP or public key), topics and payloads are base64 encoded, and reads
+/// pop one message at a time. With `legacy` set it falls back to the old
+/// `topic`/`data` JSON API that only the development mocks implement.
+///
+/// The API is reached over TCP, or over a Unix socket for an `api_url` like
+/// `unix:///run/mycelium/api.sock`.
 #[derive(Debug, Clone)]
 pub struct MyceliumClient {
-    client: Client,
+    http: Http,
     api_url: String,
+    /// What request paths are appended to: `api_url`, or a placeholder over
+    /// a Unix socket.
+    base_url: String,
+    legacy: bool,
+    retry: RetryPolicy,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct MyceliumMessage {
+/// Where a message is sent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Destination {
+    Ip(String),
+    PublicKey(String),
+}
+
+impl Destination {
+    /// Parse an announced mycelium address, which is either an overlay IP or
+    /// a hex-encoded node public key.
+    pub fn parse(address: &str) -> Self {
+        if address.parse::<std::net::IpAddr>().is_ok() {
+            Destination::Ip(address.to_string())
+        } else {
+            Destination::PublicKey(address.to_string())
+        }
+    }
+
+    fn to_json(&self) -> Value {
+        match self {
+            Destination::Ip(ip) => serde_json::json!({ "ip": ip }),
+            Destination::PublicKey(pk) => serde_json::json!({ "pk": pk }),
+        }
+    }
+}
+
+/// A message popped from the local mycelium inbox.
+#[derive(Debug, Clone)]
+pub struct InboundMessage {
+    /// Message ID, used to reply. Not available through the legacy API.
+    pub id: Option<String>,
+    pub source_ip: Option<String>,
+    pub source_public_key: Option<String>,
     pub topic: String,
-    pub data: String,
+    pub payload: Vec<u8>,
+}
+
+impl InboundMessage {
+    /// Whether mycelium says this message came from the node at `address`.
+    /// The legacy API names no source, so there any address passes.
+    pub fn sent_from(&self, address: &str) -> bool {
+        if self.source_ip.is_none() && self.source_public_key.is_none() {
+            return true;
+        }
+        let same_ip = match (address.parse::<std::net::IpAddr>(), &self.source_ip) {
+            (Ok(address), Some(source)) => source.parse() == Ok(address),
+            _ => false,
+        };
+        let same_key = self
+            .source_public_key
+            .as_ref()
+            .is_some_and(|source| source.eq_ignore_ascii_case(address));
+        same_ip || same_key
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct NativeMessage {
+    id: String,
+    src_ip: Option<String>,
+    src_pk: Option<String>,
+    #[serde(default)]
+    topic: String,
+    payload: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct LegacyMessage {
+    topic: String,
+    data: String,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct MyceliumInfo {
+    /// Address other nodes use to reach this one: the node public key, or
+    /// the address reported by the legacy API.
     pub address: String,
     pub public_key: String,
-    pub peers: Vec<String>,
+    #[serde(default)]
+    pub subnet: Option<String>,
+    /// Identifies the node's current run, such as its start time, if the
+    /// node reports one. Nodes keep their key across restarts, so this is
+    /// what tells a restarted node from one that stayed up.
+    #[serde(default)]
+    pub started_at: Option<String>,
+}
+
+impl MyceliumInfo {
+    /// Whether this is no longer the node run `previous` described: the
+    /// node has another key, or has started again since.
+    pub fn restarted_since(&self, previous: &MyceliumInfo) -> bool {
+        self.public_key != previous.public_key
+            || (previous.started_at.is_some() && self.started_at != previous.started_at)
+    }
+}
+
+/// How requests reach the mycelium API.
+#[derive(Debug, Clone)]
+enum Http {
+    Tcp(Client),
+    /// Over a Unix socket, for nodes that serve their API only there.
+    #[cfg(unix)]
+    Unix {
+        client: Box<hyper_util::client::legacy::Client<unix::UnixConnector, Full<Bytes>>>,
+        timeout: Duration,
+    },
+}
+
+/// A mycelium API response, read in full.
+struct ApiResponse {
+    status: StatusCode,
+    body: Bytes,
+}
+
+impl ApiResponse {
+    fn json<T: DeserializeOwned>(&self) -> Result<T> {
+        Ok(serde_json::from_slice(&self.body)?)
+    }
+}
+
+impl Http {
+    #[cfg(unix)]
+    fn unix(path: &str, config: &MyceliumConfig) -> Result<Self> {
+        let connector = unix::UnixConnector::new(path, Duration::from_secs(config.connect_timeout_seconds));
+        let client = hyper_util::client::legacy::Client::builder(hyper_util::rt::TokioExecutor::new())
+            .pool_max_idle_per_host(config.pool_max_idle_per_host)
+            .build(connector);
+        Ok(Http::Unix {
+            client: Box::new(client),
+            timeout: Duration::from_secs(config.request_timeout_seconds),
+        })
+    }
+
+    #[cfg(not(unix))]
+    fn unix(_path: &str, _config: &MyceliumConfig) -> Result<Self> {
+        Err(anyhow::anyhow!("Unix sockets are not supported on this platform"))
+    }
+
+    /// Send a JSON `body` to `url`, waiting `timeout` rather than the
+    /// configured request timeout if given.
+    async fn send(
+        &self,
+        method: Method,
+        url: Url,
+        body: Option<&Value>,
+        timeout: Option<Duration>,
+    ) -> Result<ApiResponse> {
+        match self {
+            Http::Tcp(client) => {
+                let mut request = client.request(method, url);
+                if let Some(body) = body {
+                    request = request.json(body);
+                }
+                if let Some(timeout) = timeout {
+                    request = request.timeout(timeout);
+                }
+                let response = request.send().await?;
+                Ok(ApiResponse {
+                    status: response.status(),
+                    body: response.bytes().await?,
+                })
+            }
+            #[cfg(unix)]
+            Http::Unix {
+                client,
+                timeout: request_timeout,
+            } => {
+                use http_body_util::BodyExt;
+
+                let mut request = hyper::Request::builder().method(method.as_str()).uri(url.as_str());
+                let body = match body {
+                    Some(body) => {
+                        request = request.header("content-type", "application/json");
+                        Full::new(Bytes::from(serde_json::to_vec(body)?))
+                    }
+                    None => Full::default(),
+                };
+                let request = request.body(body)?;
+                let exchange = async {
+                    let response = client.request(request).await?;
+                    let status = StatusCode::from_u16(response.status().as_u16())?;
+                    let body = response.into_body().collect().await?.to_bytes();
+                    Ok::<_, anyhow::Error>(ApiResponse { status, body })
+                };
+                tokio::time::timeout(timeout.unwrap_or(*request_timeout), exchange).await?
+            }
+        }
+    }
+}
+
+/// Whether a failed request is worth retrying: the node couldn't be reached
+/// or didn't answer in time.
+fn is_transient(error: &anyhow::Error) -> bool {
+    if let Some(e) = error.downcast_ref::<reqwest::Error>() {
+        return e.is_connect() || e.is_timeout();
+    }
+    if let Some(e) = error.downcast_ref::<hyper_util::client::legacy::Error>() {
+        return e.is_connect();
+    }
+    error.is::<tokio::time::error::Elapsed>()
 }
 
 impl MyceliumClient {
-    pub fn new(api_url: String) -> Self {
-        Self {
-            client: Client::new(),
+    pub fn new(api_url: String, config: &MyceliumConfig) -> Result<Self> {
+        let (http, base_url) = match api_url.strip_prefix(UNIX_SCHEME) {
+            Some(path) => {
+                // Both unix:/path and unix:///path name the socket at /path
+                let path = path.strip_prefix("//").unwrap_or(path);
+                (Http::unix(path, config)?, UNIX_BASE_URL.to_string())
+            }
+            None => {
+                let client = Client::builder()
+                    .connect_timeout(Duration::from_secs(config.connect_timeout_seconds))
+                    .timeout(Duration::from_secs(config.request_timeout_seconds))
+                    .pool_max_idle_per_host(config.pool_max_idle_per_host)
+                    .build()?;
+                (Http::Tcp(client), api_url.clone())
+            }
+        };
+
+        Ok(Self {
+            http,
             api_url,
+            base_url,
+            legacy: config.legacy_api,
+            retry: RetryPolicy::new(config.max_retries, Duration::from_millis(config.retry_base_delay_ms)),
+        })
+    }
+
+    pub fn api_url(&self) -> &str {
+        &self.api_url
+    }
+
+    fn url(&self, path: &str, query: &[(&str, &str)]) -> Result<Url> {
+        let mut url = Url::parse(&format!("{}{}", self.base_url, path))?;
+        if !query.is_empty() {
+            url.query_pairs_mut().extend_pairs(query);
+        }
+        Ok(url)
+    }
+
+    /// Send a request, retrying connection failures, timeouts, 429s and 5xx
+    /// responses with exponential backoff and jitter.
+    async fn send_with_retry(&self, method: Method, url: Url, body: Option<&Value>) -> Result<ApiResponse> {
+        let mut attempt = 0;
+        loop {
+            let retryable = match self.http.send(method.clone(), url.clone(), body, None).await {
+                Ok(response) if RetryPolicy::is_retryable_status(response.status) => {
+                    if attempt >= self.retry.max_retries {
+                        return Ok(response);
+                    }
+                    format!("status {}", response.status)
+                }
+                Ok(response) => return Ok(response),
+                Err(e) if attempt < self.retry.max_retries && is_transient(&e) => e.to_string(),
+                Err(e) => return Err(e),
+            };
+
+            attempt += 1;
+            let delay = self.retry.delay(attempt);
+            warn!("Mycelium request failed ({}), retry {} in {:?}", retryable, attempt, delay);
+            tokio::time::sleep(delay).await;
         }
     }
-    
-    pub async fn send_message(&self, topic: &str, data: &str) -> Result<()> {
-        let message = MyceliumMessage {
+
+    async fn send_legacy(&self, topic: &str, data: &[u8]) -> Result<()> {
+        let message = LegacyMessage {
             topic: topic.to_string(),
-            data: data.to_string(),
+            data: String::from_utf8(data.to_vec())?,
         };
-        
-        let response = self.client
-            .post(&format!("{}/api/v1/message", self.api_url))
-            .json(&message)
-            .send()
+        let body = serde_json::to_value(&message)?;
+        let response = self
+            .send_with_retry(Method::POST, self.url("/api/v1/message", &[])?, Some(&body))
             .await?;
-            
-        if response.status().is_success() {
+
+        if response.status.is_success() {
             info!("Message sent to topic: {}", topic);
             Ok(())
         } else {
-            error!("Failed to send message: {}", response.status());
-            Err(anyhow::anyhow!("Failed to send message: {}", response.status()))
+            error!("Failed to send message: {}", response.status);
+            Err(anyhow::anyhow!("Failed to send message: {}", response.status))
         }
     }
-    
-    pub async fn get_messages(&self, topic: &str) -> Result<Vec<String>> {
-        let response = self.client
-            .get(&format!("{}/api/v1/messages", self.api_url))
-            .query(&[("topic", topic)])
-            .send()
+
+    async fn pop_message(&self, topic: &str, timeout_seconds: u64) -> Result<Option<InboundMessage>> {
+        let engine = base64::engine::general_purpose::STANDARD;
+        let topic = engine.encode(topic);
+        let timeout = timeout_seconds.to_string();
+        let url = self.url(
+            "/api/v1/messages",
+            &[("peek", "false"), ("topic", topic.as_str()), ("timeout", timeout.as_str())],
+        )?;
+        let response = self
+            .http
+            .send(Method::GET, url, None, Some(Duration::from_secs(timeout_seconds + 10)))
             .await?;
-            
-        if response.status().is_success() {
-            let messages: Vec<Value> = response.json().await?;
-            let data: Vec<String> = messages
-                .into_iter()
-                .filter_map(|msg| msg.get("data").and_then(|d| d.as_str().map(|s| s.to_string())))
-                .collect();
-            Ok(data)
+
+        if response.status == StatusCode::NO_CONTENT {
+            return Ok(None);
+        }
+        if !response.status.is_success() {
+            error!("Failed to get messages: {}", response.status);
+            return Err(anyhow::anyhow!("Failed to get messages: {}", response.status));
+        }
+
+        let message: NativeMessage = response.json()?;
+        let topic = engine
+            .decode(&message.topic)
+            .ok()
+            .and_then(|topic| String::from_utf8(topic).ok())
+            .unwrap_or_default();
+        Ok(Some(InboundMessage {
+            id: Some(message.id),
+            source_ip: message.src_ip,
+            source_public_key: message.src_pk,
+            topic,
+            payload: engine.decode(&message.payload)?,
+        }))
+    }
+
+    async fn receive_legacy(&self, topic: &str, timeout_seconds: u64) -> Result<Vec<InboundMessage>> {
+        let timeout = timeout_seconds.to_string();
+        let response = if timeout_seconds > 0 {
+            let url = self.url("/api/v1/messages", &[("topic", topic), ("timeout", timeout.as_str())])?;
+            let wait = Duration::from_secs(timeout_seconds + 10);
+            self.http.send(Method::GET, url, None, Some(wait)).await?
         } else {
-            error!("Failed to get messages: {}", response.status());
-            Err(anyhow::anyhow!("Failed to get messages: {}", response.status()))
+            let url = self.url("/api/v1/messages", &[("topic", topic)])?;
+            self.http.send(Method::GET, url, None, None).await?
+        };
+
+        if !response.status.is_success() {
+            error!("Failed to get messages: {}", response.status);
+            return Err(anyhow::anyhow!("Failed to get messages: {}", response.status));
         }
+
+        // The legacy API returns the messages themselves, or wraps them as data
+        let messages: Vec<Value> = response.json()?;
+        Ok(messages
+            .into_iter()
+            .map(|msg| {
+                let payload = match msg.get("data").and_then(Value::as_str) {
+                    Some(data) => data.as_bytes().to_vec(),
+                    None => msg.to_string().into_bytes(),
+                };
+                InboundMessage {
+                    id: None,
+                    source_ip: None,
+                    source_public_key: None,
+                    topic: topic.to_string(),
+                    payload,
+                }
+            })
+            .collect())
     }
-    
-    pub async fn get_info(&self) -> Result<MyceliumInfo> {
-        let response = self.client
-            .get(&format!("{}/api/v1/info", self.api_url))
-            .send()
+}
+
+#[async_trait]
+impl FederationTransport for MyceliumClient {
+    fn is_legacy(&self) -> bool {
+        self.legacy
+    }
+
+    /// The legacy API has no addressing and publishes on the topic instead.
+    async fn send_message(&self, destination: &Destination, topic: &str, data: &[u8]) -> Result<()> {
+        if self.legacy {
+            return self.send_legacy(topic, data).await;
+        }
+
+        let engine = base64::engine::general_purpose::STANDARD;
+        let body = serde_json::json!({
+            "dst": destination.to_json(),
+            "topic": engine.encode(topic),
+            "payload": engine.encode(data),
+        });
+        let response = self
+            .send_with_retry(Method::POST, self.url("/api/v1/messages", &[])?, Some(&body))
             .await?;
-            
-        if response.status().is_success() {
-            let info: MyceliumInfo = response.json().await?;
-            Ok(info)
+
+        if response.status.is_success() {
+            info!("Message sent to topic: {}", topic);
+            Ok(())
+        } else {
+            error!("Failed to send message: {}", response.status);
+            Err(anyhow::anyhow!("Failed to send message: {}", response.status))
+        }
+    }
+
+    /// Only the legacy API supports this; mycelium itself has no broadcast.
+    async fn publish(&self, topic: &str, data: &[u8]) -> Result<()> {
+        if !self.legacy {
+            return Err(anyhow::anyhow!("Mycelium does not support broadcast messages"));
+        }
+        self.send_legacy(topic, data).await
+    }
+
+    /// The reply goes to whoever is waiting on the original send.
+    async fn reply(&self, message_id: &str, topic: &str, data: &[u8]) -> Result<()> {
+        if self.legacy {
+            return Err(anyhow::anyhow!("The legacy mycelium API does not support replies"));
+        }
+
+        let engine = base64::engine::general_purpose::STANDARD;
+        let body = serde_json::json!({
+            "dst": Value::Null,
+            "topic": engine.encode(topic),
+            "payload": engine.encode(data),
+        });
+        let url = self.url(&format!("/api/v1/messages/reply/{}", message_id), &[])?;
+        let response = self.send_with_retry(Method::POST, url, Some(&body)).await?;
+
+        if response.status.is_success() {
+            Ok(())
         } else {
-            error!("Failed to get Mycelium info: {}", response.status());
-            Err(anyhow::anyhow!("Failed to get Mycelium info: {}", response.status()))
+            Err(anyhow::anyhow!("Failed to reply to {}: {}", message_id, response.status))
+        }
+    }
+
+    /// Mycelium hands out one message per read, so after the first one
+    /// whatever else is queued is drained without waiting.
+    async fn receive_messages(&self, topic: &str, timeout_seconds: u64) -> Result<Vec<InboundMessage>> {
+        if self.legacy {
+            return self.receive_legacy(topic, timeout_seconds).await;
+        }
+
+        let mut messages = Vec::new();
+        let mut wait = timeout_seconds;
+        while messages.len() < MAX_MESSAGES_PER_READ {
+            match self.pop_message(topic, wait).await? {
+                Some(message) => messages.push(message),
+                None => break,
+            }
+            wait = 0;
+        }
+
+        Ok(messages)
+    }
+
+    async fn get_info(&self) -> Result<MyceliumInfo> {
+        let path = if self.legacy { "/api/v1/info" } else { "/api/v1/admin" };
+        let response = self.send_with_retry(Method::GET, self.url(path, &[])?, None).await?;
+
+        if !response.status.is_success() {
+            error!("Failed to get Mycelium info: {}", response.status);
+            return Err(anyhow::anyhow!("Failed to get Mycelium info: {}", response.status));
+        }
+
+        let info: Value = response.json()?;
+        if self.legacy {
+            let address = info["address"]
+                .as_str()
+                .ok_or_else(|| anyhow::anyhow!("Failed to get Mycelium address"))?;
+            return Ok(MyceliumInfo {
+                address: address.to_string(),
+                public_key: info["public_key"].as_str().unwrap_or_default().to_string(),
+                subnet: None,
+                started_at: info["started_at"].as_str().map(str::to_string),
+            });
         }
+
+        let public_key = info["nodePubkey"]
+            .as_str()
+            .ok_or_else(|| anyhow::anyhow!("Failed to get Mycelium public key"))?;
+        Ok(MyceliumInfo {
+            address: public_key.to_string(),
+            public_key: public_key.to_string(),
+            subnet: info["nodeSubnet"].as_str().map(str::to_string),
+            started_at: info["startedAt"].as_str().map(str::to_string),
+        })
     }
-    
-    pub async fn health_check(&self) -> bool {
-        match self.get_info().await {
-            Ok(_) => true,
-            Err(_) => false,
+}
+
+/// Several mycelium nodes used as one: calls go to the active node and fail
+/// over to the others in order. `get_info` always probes from the primary
+/// (first) node, so the bridge moves back to it once it recovers.
+pub struct FailoverMyceliumClient {
+    endpoints: Vec<MyceliumClient>,
+    active: AtomicUsize,
+}
+
+impl FailoverMyceliumClient {
+    pub fn new(api_urls: &[String], config: &MyceliumConfig) -> Result<Self> {
+        if api_urls.is_empty() {
+            return Err(anyhow::anyhow!("At least one mycelium API URL is required"));
+        }
+        let endpoints = api_urls
+            .iter()
+            .map(|url| MyceliumClient::new(url.clone(), config))
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Self {
+            endpoints,
+            active: AtomicUsize::new(0),
+        })
+    }
+
+    /// Endpoint indices to try, starting at `first`.
+    fn order_from(&self, first: usize) -> impl Iterator<Item = usize> + '_ {
+        (0..self.endpoints.len()).map(move |offset| (first + offset) % self.endpoints.len())
+    }
+
+    fn order(&self) -> impl Iterator<Item = usize> + '_ {
+        self.order_from(self.active.load(Ordering::Relaxed))
+    }
+
+    fn succeeded(&self, index: usize) {
+        let previous = self.active.swap(index, Ordering::Relaxed);
+        if previous != index {
+            warn!(
+                "Mycelium endpoint switched from {} to {}",
+                self.endpoints[previous].api_url(),
+                self.endpoints[index].api_url()
+            );
+        }
+    }
+
+    fn failed(&self, index: usize, error: &anyhow::Error) {
+        warn!("Mycelium endpoint {} failed: {}", self.endpoints[index].api_url(), error);
+    }
+}
+
+#[async_trait]
+impl FederationTransport for FailoverMyceliumClient {
+    fn is_legacy(&self) -> bool {
+        self.endpoints[0].is_legacy()
+    }
+
+    async fn send_message(&self, destination: &Destination, topic: &str, data: &[u8]) -> Result<()> {
+        let mut last_error = None;
+        for index in self.order() {
+            match self.endpoints[index].send_message(destination, topic, data).await {
+                Ok(()) => {
+                    self.succeeded(index);
+                    return Ok(());
+                }
+                Err(e) => {
+                    self.failed(index, &e);
+                    last_error = Some(e);
+                }
+            }
+        }
+        Err(last_error.unwrap_or_else(|| anyhow::anyhow!("No mycelium endpoints")))
+    }
+
+    async fn publish(&self, topic: &str, data: &[u8]) -> Result<()> {
+        let mut last_error = None;
+        for index in self.order() {
+            match self.endpoints[index].publish(topic, data).await {
+                Ok(()) => {
+                    self.succeeded(index);
+                    return Ok(());
+                }
+                Err(e) => {
+                    self.failed(index, &e);
+                    last_error = Some(e);
+                }
+            }
+        }
+        Err(last_error.unwrap_or_else(|| anyhow::anyhow!("No mycelium endpoints")))
+    }
+
+    /// Replies must go through the node that received the message, so there
+    /// is no failover here.
+    async fn reply(&self, message_id: &str, topic: &str, data: &[u8]) -> Result<()> {
+        let index = self.active.load(Ordering::Relaxed);
+        self.endpoints[index].reply(message_id, topic, data).await
+    }
+
+    async fn receive_messages(&self, topic: &str, timeout_seconds: u64) -> Result<Vec<InboundMessage>> {
+        let mut last_error = None;
+        for index in self.order() {
+            match self.endpoints[index].receive_messages(topic, timeout_seconds).await {
+                Ok(messages) => {
+                    self.succeeded(index);
+                    return Ok(messages);
+                }
+                Err(e) => {
+                    self.failed(index, &e);
+                    last_error = Some(e);
+                }
+            }
+        }
+        Err(last_error.unwrap_or_else(|| anyhow::anyhow!("No mycelium endpoints")))
+    }
+
+    async fn get_info(&self) -> Result<MyceliumInfo> {
+        let mut last_error = None;
+        for index in self.order_from(0) {
+            match self.endpoints[index].get_info().await {
+                Ok(info) => {
+                    self.succeeded(index);
+                    return Ok(info);
+                }
+                Err(e) => {
+                    self.failed(index, &e);
+                    last_error = Some(e);
+                }
+            }
+        }
+        Err(last_error.unwrap_or_else(|| anyhow::anyhow!("No mycelium endpoints")))
+    }
+}
+
+#[cfg(unix)]
+mod unix {
+    use std::future::Future;
+    use std::path::PathBuf;
+    use std::pin::Pin;
+    use std::sync::Arc;
+    use std::task::{Context, Poll};
+    use std::time::Duration;
+
+    use hyper_util::rt::TokioIo;
+    use tokio::net::UnixStream;
+
+    /// Connects every request to one Unix socket, whatever its URL says.
+    #[derive(Debug, Clone)]
+    pub struct UnixConnector {
+        path: Arc<PathBuf>,
+        connect_timeout: Duration,
+    }
+
+    impl UnixConnector {
+        pub fn new(path: &str, connect_timeout: Duration) -> Self {
+            Self {
+                path: Arc::new(PathBuf::from(path)),
+                connect_timeout,
+            }
+        }
+    }
+
+    impl tower_service::Service<hyper::Uri> for UnixConnector {
+        type Response = TokioIo<UnixStream>;
+        type Error = std::io::Error;
+        type Future = Pin<Box<dyn Future<Output = std::io::Result<Self::Response>> + Send>>;
+
+        fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, _uri: hyper::Uri) -> Self::Future {
+            let (path, connect_timeout) = (self.path.clone(), self.connect_timeout);
+            Box::pin(async move {
+                let stream = tokio::time::timeout(connect_timeout, UnixStream::connect(&*path))
+                    .await
+                    .map_err(|_| std::io::Error::new(std::io::ErrorKind::TimedOut, "connect timed out"))??;
+                Ok(TokioIo::new(stream))
+            })
         }
     }
 }
@@ -0,0 +1,248 @@
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt::Write;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+use tracing::{info, warn};
+
+/// Sends still waiting for an ack after this long are assumed unacked.
+const ACK_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Window the message rates are measured over.
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+
+/// Weight of the newest sample in the average latency.
+const LATENCY_SMOOTHING: f64 = 0.2;
+
+/// Traffic with one remote server.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct PeerMetrics {
+    pub messages_sent: u64,
+    pub bytes_sent: u64,
+    pub send_failures: u64,
+    pub messages_received: u64,
+    pub bytes_received: u64,
+    pub acks: u64,
+    /// Moving average of the time from sending a message to its ack.
+    pub latency_ms: Option<f64>,
+    pub last_latency_ms: Option<f64>,
+    pub sent_per_minute: usize,
+    pub received_per_minute: usize,
+    /// Whether `latency_ms` is over `metrics.latency_warning_ms`.
+    pub slow: bool,
+}
+
+#[derive(Default)]
+struct Peer {
+    metrics: PeerMetrics,
+    sent_at: VecDeque<Instant>,
+    received_at: VecDeque<Instant>,
+}
+
+#[derive(Default)]
+struct State {
+    peers: HashMap<String, Peer>,
+    /// Send times by destination and correlation ID, oldest first.
+    awaiting_ack: HashMap<(String, String), Instant>,
+    awaiting_order: VecDeque<(Instant, (String, String))>,
+}
+
+/// A per-peer counter exported to Prometheus.
+struct Counter {
+    name: &'static str,
+    /// Label the server name goes in.
+    label: &'static str,
+    help: &'static str,
+    value: fn(&PeerMetrics) -> u64,
+}
+
+const COUNTERS: [Counter; 6] = [
+    Counter {
+        name: "messages_sent_total",
+        label: "destination",
+        help: "Messages sent to each server",
+        value: |metrics| metrics.messages_sent,
+    },
+    Counter {
+        name: "bytes_sent_total",
+        label: "destination",
+        help: "Bytes sent to each server",
+        value: |metrics| metrics.bytes_sent,
+    },
+    Counter {
+        name: "send_failures_total",
+        label: "destination",
+        help: "Failed sends to each server",
+        value: |metrics| metrics.send_failures,
+    },
+    Counter {
+        name: "messages_received_total",
+        label: "source",
+        help: "Messages received from each server",
+        value: |metrics| metrics.messages_received,
+    },
+    Counter {
+        name: "bytes_received_total",
+        label: "source",
+        help: "Bytes received from each server",
+        value: |metrics| metrics.bytes_received,
+    },
+    Counter {
+        name: "acks_total",
+        label: "destination",
+        help: "Acks received from each server",
+        value: |metrics| metrics.acks,
+    },
+];
+
+pub(crate) fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+fn prune(times: &mut VecDeque<Instant>, now: Instant) {
+    while times.front().is_some_and(|time| now.duration_since(*time) > RATE_WINDOW) {
+        times.pop_front();
+    }
+}
+
+/// Per-peer latency and throughput, for the admin API and `/metrics`.
+pub struct PeerMetricsTracker {
+    /// Average latency above which a peer counts as slow; 0 disables.
+    latency_warning: Option<Duration>,
+    state: Mutex<State>,
+}
+
+impl PeerMetricsTracker {
+    pub fn new(latency_warning_ms: u64) -> Self {
+        Self {
+            latency_warning: (latency_warning_ms > 0).then(|| Duration::from_millis(latency_warning_ms)),
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    /// Count a message handed to mycelium. With a correlation ID, the
+    /// latency to its ack is measured.
+    pub async fn record_sent(&self, destination: &str, correlation_id: Option<&str>, bytes: usize) {
+        let now = Instant::now();
+        let mut state = self.state.lock().await;
+        let peer = state.peers.entry(destination.to_string()).or_default();
+        peer.metrics.messages_sent += 1;
+        peer.metrics.bytes_sent += bytes as u64;
+        peer.sent_at.push_back(now);
+        prune(&mut peer.sent_at, now);
+
+        while let Some((sent, _)) = state.awaiting_order.front() {
+            if now.duration_since(*sent) < ACK_TIMEOUT {
+                break;
+            }
+            if let Some((_, key)) = state.awaiting_order.pop_front() {
+                state.awaiting_ack.remove(&key);
+            }
+        }
+        if let Some(correlation_id) = correlation_id {
+            let key = (destination.to_string(), correlation_id.to_string());
+            state.awaiting_ack.insert(key.clone(), now);
+            state.awaiting_order.push_back((now, key));
+        }
+    }
+
+    pub async fn record_failure(&self, destination: &str) {
+        let mut state = self.state.lock().await;
+        state.peers.entry(destination.to_string()).or_default().metrics.send_failures += 1;
+    }
+
+    pub async fn record_received(&self, source: &str, bytes: usize) {
+        let now = Instant::now();
+        let mut state = self.state.lock().await;
+        let peer = state.peers.entry(source.to_string()).or_default();
+        peer.metrics.messages_received += 1;
+        peer.metrics.bytes_received += bytes as u64;
+        peer.received_at.push_back(now);
+        prune(&mut peer.received_at, now);
+    }
+
+    /// Take the latency of each acked message `source` was waiting on.
+    /// Messages already acked, or sent too long ago, are ignored.
+    pub async fn record_acks(&self, source: &str, correlation_ids: &[String]) {
+        let now = Instant::now();
+        let mut state = self.state.lock().await;
+        let samples: Vec<f64> = correlation_ids
+            .iter()
+            .filter_map(|id| state.awaiting_ack.remove(&(source.to_string(), id.clone())))
+            .map(|sent| now.duration_since(sent).as_secs_f64() * 1000.0)
+            .collect();
+        if samples.is_empty() {
+            return;
+        }
+
+        let peer = state.peers.entry(source.to_string()).or_default();
+        for latency in samples {
+            peer.metrics.acks += 1;
+            peer.metrics.last_latency_ms = Some(latency);
+            peer.metrics.latency_ms = Some(match peer.metrics.latency_ms {
+                Some(average) => average + LATENCY_SMOOTHING * (latency - average),
+                None => latency,
+            });
+        }
+
+        let Some(threshold) = self.latency_warning else {
+            return;
+        };
+        let average = peer.metrics.latency_ms.unwrap_or_default();
+        let slow = average > threshold.as_secs_f64() * 1000.0;
+        if slow && !peer.metrics.slow {
+            warn!("Latency to {} is {:.0} ms, over {:?}", source, average, threshold);
+        } else if !slow && peer.metrics.slow {
+            info!("Latency to {} is back to {:.0} ms", source, average);
+        }
+        peer.metrics.slow = slow;
+    }
+
+    pub async fn snapshot(&self) -> BTreeMap<String, PeerMetrics> {
+        let now = Instant::now();
+        let mut state = self.state.lock().await;
+        state
+            .peers
+            .iter_mut()
+            .map(|(server_name, peer)| {
+                prune(&mut peer.sent_at, now);
+                prune(&mut peer.received_at, now);
+                let mut metrics = peer.metrics.clone();
+                metrics.sent_per_minute = peer.sent_at.len();
+                metrics.received_per_minute = peer.received_at.len();
+                (server_name.clone(), metrics)
+            })
+            .collect()
+    }
+
+    /// The metrics in the Prometheus text format.
+    pub async fn render_prometheus(&self) -> String {
+        let peers = self.snapshot().await;
+        let mut output = String::new();
+        for Counter { name, label, help, value } in COUNTERS {
+            let _ = writeln!(output, "# HELP matrix_mycelium_bridge_{} {}.", name, help);
+            let _ = writeln!(output, "# TYPE matrix_mycelium_bridge_{} counter", name);
+            for (server_name, metrics) in &peers {
+                let _ = writeln!(
+                    output,
+                    "matrix_mycelium_bridge_{}{{{}=\"{}\"}} {}",
+                    name,
+                    label,
+                    escape_label(server_name),
+                    value(metrics)
+                );
+            }
+        }
+
+        let name = "matrix_mycelium_bridge_ack_latency_seconds";
+        let _ = writeln!(output, "# HELP {} Moving average of send to ack latency per server.", name);
+        let _ = writeln!(output, "# TYPE {} gauge", name);
+        for (server_name, metrics) in &peers {
+            if let Some(latency) = metrics.latency_ms {
+                let server_name = escape_label(server_name);
+                let _ = writeln!(output, "{}{{destination=\"{}\"}} {}", name, server_name, latency / 1000.0);
+            }
+        }
+        output
+    }
+}
@@ -0,0 +1,100 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use tokio::fs;
+use tracing::{error, info, warn};
+
+use crate::types::{ServerInfo, ServerStatus};
+
+/// On-disk snapshot of the server directory, in the same shape as the
+/// discovery service's registry file.
+#[derive(Debug, Serialize, Deserialize)]
+struct PersistedDirectory {
+    servers: HashMap<String, ServerInfo>,
+    #[serde(default)]
+    revoked_keys: HashSet<String>,
+    version: String,
+    saved_at: chrono::DateTime<chrono::Utc>,
+}
+
+/// A directory loaded from disk.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct LoadedDirectory {
+    pub servers: HashMap<String, ServerInfo>,
+    pub revoked_keys: HashSet<String>,
+}
+
+/// Load the directory saved at `path`, dropping servers not seen within
+/// `max_age`. A missing or unreadable snapshot yields an empty directory.
+pub async fn load_directory(path: &Path, max_age: chrono::Duration) -> LoadedDirectory {
+    if !path.exists() {
+        info!("No saved server directory at {}, starting empty", path.display());
+        return LoadedDirectory::default();
+    }
+
+    let data = match read_directory(path).await {
+        Ok(data) => data,
+        Err(e) => {
+            error!("Failed to load server directory from {}: {}", path.display(), e);
+            warn!("Starting with an empty server directory");
+            return LoadedDirectory::default();
+        }
+    };
+
+    let cutoff = chrono::Utc::now() - max_age;
+    let total = data.servers.len();
+    let servers: HashMap<String, ServerInfo> = data
+        .servers
+        .into_iter()
+        .filter(|(_, server)| server.last_seen > cutoff)
+        .map(|(name, mut server)| {
+            // Reachability is unknown until the server announces again
+            if !matches!(server.status, ServerStatus::Untrusted) {
+                server.status = ServerStatus::Unknown;
+            }
+            (name, server)
+        })
+        .collect();
+
+    info!(
+        "Loaded {} servers from {} ({} stale dropped)",
+        servers.len(),
+        path.display(),
+        total - servers.len()
+    );
+    LoadedDirectory {
+        servers,
+        revoked_keys: data.revoked_keys,
+    }
+}
+
+async fn read_directory(path: &Path) -> Result<PersistedDirectory> {
+    let content = fs::read_to_string(path).await?;
+    Ok(serde_json::from_str(&content)?)
+}
+
+/// Write the directory to `path`, through a temporary file so a crash
+/// mid-write never leaves a truncated snapshot behind.
+pub async fn save_directory(
+    path: &Path,
+    servers: &HashMap<String, ServerInfo>,
+    revoked_keys: &HashSet<String>,
+) -> Result<()> {
+    let data = PersistedDirectory {
+        servers: servers.clone(),
+        revoked_keys: revoked_keys.clone(),
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        saved_at: chrono::Utc::now(),
+    };
+    let content = serde_json::to_string_pretty(&data)?;
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).await?;
+    }
+    let temp_path = path.with_extension("tmp");
+    fs::write(&temp_path, content).await?;
+    fs::rename(&temp_path, path).await?;
+
+    Ok(())
+}
@@ -0,0 +1,68 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use tokio::sync::{oneshot, Mutex};
+
+/// Sent to check that a remote bridge is reachable through the overlay.
+pub const PING_MESSAGE_TYPE: &str = "federation_ping";
+
+/// Sent back straight away in answer to a [`PING_MESSAGE_TYPE`] message.
+pub const PONG_MESSAGE_TYPE: &str = "federation_pong";
+
+/// How long `POST /admin/ping/:server_name` waits for the pong by default.
+pub const DEFAULT_TIMEOUT_SECONDS: u64 = 10;
+
+/// Payload of both pings and pongs; a pong echoes the ping's ID.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Ping {
+    pub ping_id: String,
+}
+
+/// Parameters of `POST /admin/ping/:server_name`.
+#[derive(Debug, Default, Deserialize)]
+pub struct PingQuery {
+    pub timeout_seconds: Option<u64>,
+}
+
+struct PendingPing {
+    destination: String,
+    sent_at: Instant,
+    sender: oneshot::Sender<Duration>,
+}
+
+/// Tracks outstanding pings until their pong arrives.
+#[derive(Default)]
+pub struct PingTracker {
+    pending: Mutex<HashMap<String, PendingPing>>,
+}
+
+impl PingTracker {
+    /// Start timing a ping. The receiver gets its round trip time.
+    pub async fn register(&self, ping_id: &str, destination: &str) -> oneshot::Receiver<Duration> {
+        let (sender, receiver) = oneshot::channel();
+        let pending = PendingPing {
+            destination: destination.to_string(),
+            sent_at: Instant::now(),
+            sender,
+        };
+        self.pending.lock().await.insert(ping_id.to_string(), pending);
+        receiver
+    }
+
+    pub async fn cancel(&self, ping_id: &str) {
+        self.pending.lock().await.remove(ping_id);
+    }
+
+    /// Take a pong from `source`. Returns `false` if no ping to `source` is
+    /// waiting for it.
+    pub async fn complete(&self, source: &str, ping_id: &str) -> bool {
+        let mut pending = self.pending.lock().await;
+        if pending.get(ping_id).is_none_or(|ping| ping.destination != source) {
+            return false;
+        }
+        match pending.remove(ping_id) {
+            Some(ping) => ping.sender.send(ping.sent_at.elapsed()).is_ok(),
+            None => false,
+        }
+    }
+}
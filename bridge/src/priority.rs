@@ -0,0 +1,177 @@
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use tokio::sync::oneshot;
+
+use crate::batching::TRANSACTION_MESSAGE_TYPE;
+use crate::config::PriorityConfig;
+use crate::delivery::DELIVERY_ACK_MESSAGE_TYPE;
+use crate::edu::{EDU_MESSAGE_TYPE, RELIABLE_EDU_MESSAGE_TYPE};
+use crate::federation_error::FEDERATION_ERROR_MESSAGE_TYPE;
+use crate::media::MEDIA_CHUNK_MESSAGE_TYPE;
+use crate::ping::{PING_MESSAGE_TYPE, PONG_MESSAGE_TYPE};
+use crate::queries::{QueryKind, BACKFILL, GET_MISSING_EVENTS};
+
+/// How urgently a message should go out, highest first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    /// EDUs, acks, pings and queries a user is waiting on.
+    Interactive,
+    /// PDUs and transactions.
+    Normal,
+    /// Media transfers and backfill, which may be large.
+    Bulk,
+}
+
+impl Priority {
+    const ALL: [Priority; 3] = [Priority::Interactive, Priority::Normal, Priority::Bulk];
+
+    pub fn of(message_type: &str) -> Self {
+        match message_type {
+            EDU_MESSAGE_TYPE | PING_MESSAGE_TYPE | PONG_MESSAGE_TYPE | DELIVERY_ACK_MESSAGE_TYPE
+            | FEDERATION_ERROR_MESSAGE_TYPE => Priority::Interactive,
+            "federation_event" | TRANSACTION_MESSAGE_TYPE | RELIABLE_EDU_MESSAGE_TYPE => Priority::Normal,
+            MEDIA_CHUNK_MESSAGE_TYPE => Priority::Bulk,
+            _ => match QueryKind::from_response_type(message_type) {
+                Some(kind) if kind.name == BACKFILL.name || kind.name == GET_MISSING_EVENTS.name => {
+                    Priority::Bulk
+                }
+                Some(_) => Priority::Interactive,
+                None if QueryKind::from_request_type(message_type).is_some() => Priority::Interactive,
+                None => Priority::Normal,
+            },
+        }
+    }
+
+    fn index(&self) -> usize {
+        *self as usize
+    }
+}
+
+/// Sends to one destination, in flight and waiting for a slot.
+#[derive(Default)]
+struct Lane {
+    in_flight: usize,
+    waiting: [VecDeque<oneshot::Sender<SendSlot>>; 3],
+    /// Slots each priority may still take before the others get a turn.
+    credits: [u32; 3],
+}
+
+/// Limits the sends in flight to each destination, handing free slots to
+/// waiting sends by weighted round robin over their priorities, so bulk
+/// traffic can't hold up live messages yet is never starved.
+pub struct OutboundScheduler {
+    max_in_flight: usize,
+    weights: [u32; 3],
+    lanes: Mutex<HashMap<String, Lane>>,
+}
+
+/// Permission to send to a destination, given back when dropped.
+pub struct SendSlot {
+    scheduler: Option<Arc<OutboundScheduler>>,
+    destination: String,
+}
+
+impl Drop for SendSlot {
+    fn drop(&mut self) {
+        if let Some(scheduler) = self.scheduler.take() {
+            scheduler.release(&self.destination);
+        }
+    }
+}
+
+impl OutboundScheduler {
+    pub fn new(config: &PriorityConfig) -> Self {
+        Self {
+            max_in_flight: config.max_in_flight_per_destination.max(1),
+            weights: [
+                config.interactive_weight.max(1),
+                config.normal_weight.max(1),
+                config.bulk_weight.max(1),
+            ],
+            lanes: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Wait for a slot to send to `destination`.
+    pub async fn acquire(self: &Arc<Self>, destination: &str, priority: Priority) -> SendSlot {
+        let receiver = {
+            let mut lanes = self.lanes.lock().unwrap();
+            let lane = lanes.entry(destination.to_string()).or_default();
+            if lane.in_flight < self.max_in_flight && lane.waiting.iter().all(VecDeque::is_empty) {
+                lane.in_flight += 1;
+                return self.slot(destination);
+            }
+            let (sender, receiver) = oneshot::channel();
+            lane.waiting[priority.index()].push_back(sender);
+            receiver
+        };
+        receiver.await.expect("waiting sends are always handed a slot")
+    }
+
+    fn slot(self: &Arc<Self>, destination: &str) -> SendSlot {
+        SendSlot {
+            scheduler: Some(self.clone()),
+            destination: destination.to_string(),
+        }
+    }
+
+    /// Hand a freed slot to the next waiting send, or free it.
+    fn release(self: Arc<Self>, destination: &str) {
+        let mut lanes = self.lanes.lock().unwrap();
+        let Some(lane) = lanes.get_mut(destination) else {
+            return;
+        };
+        while let Some(priority) = self.next_priority(lane) {
+            let Some(sender) = lane.waiting[priority.index()].pop_front() else {
+                continue;
+            };
+            // A send that stopped waiting hands back its slot unused
+            if let Err(mut slot) = sender.send(self.slot(destination)) {
+                slot.scheduler = None;
+                continue;
+            }
+            return;
+        }
+        lane.in_flight -= 1;
+        if lane.in_flight == 0 {
+            lanes.remove(destination);
+        }
+    }
+
+    /// The highest priority with sends waiting and credits left, refilling
+    /// the credits once every waiting priority has used its own.
+    fn next_priority(&self, lane: &mut Lane) -> Option<Priority> {
+        let waiting: Vec<Priority> = Priority::ALL
+            .into_iter()
+            .filter(|priority| !lane.waiting[priority.index()].is_empty())
+            .collect();
+        if waiting.is_empty() {
+            return None;
+        }
+        if waiting.iter().all(|priority| lane.credits[priority.index()] == 0) {
+            lane.credits = self.weights;
+        }
+        let priority = waiting.into_iter().find(|priority| lane.credits[priority.index()] > 0)?;
+        lane.credits[priority.index()] -= 1;
+        Some(priority)
+    }
+
+    /// Sends waiting for a slot, per destination and priority.
+    pub fn waiting(&self) -> BTreeMap<String, BTreeMap<Priority, usize>> {
+        let lanes = self.lanes.lock().unwrap();
+        lanes
+            .iter()
+            .filter(|(_, lane)| lane.waiting.iter().any(|waiting| !waiting.is_empty()))
+            .map(|(destination, lane)| {
+                let counts = Priority::ALL
+                    .into_iter()
+                    .map(|priority| (priority, lane.waiting[priority.index()].len()))
+                    .filter(|(_, count)| *count > 0)
+                    .collect();
+                (destination.clone(), counts)
+            })
+            .collect()
+    }
+}
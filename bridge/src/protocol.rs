@@ -0,0 +1,90 @@
+use anyhow::Result;
+
+use crate::types::MyceliumMessage;
+
+/// Prefix of the capabilities advertising envelope versions, e.g.
+/// `protocol.1.0`.
+pub const PROTOCOL_CAPABILITY_PREFIX: &str = "protocol.";
+
+/// Versions of the `MyceliumMessage` envelope, oldest first. Minor versions
+/// may only add optional fields; anything else needs a new major version.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ProtocolVersion {
+    V1_0,
+}
+
+impl ProtocolVersion {
+    /// Every version this bridge can send and receive.
+    pub const SUPPORTED: &'static [ProtocolVersion] = &[ProtocolVersion::V1_0];
+
+    /// The version messages are built in before being translated.
+    pub const CURRENT: ProtocolVersion = ProtocolVersion::V1_0;
+
+    /// Spoken by peers that advertise no protocol capability, which predate
+    /// version negotiation.
+    pub const LEGACY: ProtocolVersion = ProtocolVersion::V1_0;
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ProtocolVersion::V1_0 => "1.0",
+        }
+    }
+
+    pub fn parse(version: &str) -> Option<Self> {
+        Self::SUPPORTED.iter().copied().find(|supported| supported.as_str() == version)
+    }
+
+    pub fn capability(&self) -> String {
+        format!("{}{}", PROTOCOL_CAPABILITY_PREFIX, self.as_str())
+    }
+
+    fn major(&self) -> &'static str {
+        self.as_str().split('.').next().unwrap_or_default()
+    }
+
+    /// The version to read an inbound message as. A newer minor version of a
+    /// supported major is read as the newest supported one, ignoring the
+    /// fields it added; an unsupported major is rejected.
+    pub fn for_inbound(version: &str) -> Result<Self> {
+        if let Some(known) = Self::parse(version) {
+            return Ok(known);
+        }
+        let major = version.split('.').next().unwrap_or_default();
+        Self::SUPPORTED
+            .iter()
+            .copied()
+            .filter(|supported| supported.major() == major)
+            .max()
+            .ok_or_else(|| anyhow::anyhow!("Unsupported protocol version {:?}", version))
+    }
+
+    /// The newest version both this bridge and a peer with `capabilities`
+    /// support, or `None` if they share none.
+    pub fn negotiate(capabilities: &[String]) -> Option<Self> {
+        let advertised: Vec<&str> = capabilities
+            .iter()
+            .filter_map(|capability| capability.strip_prefix(PROTOCOL_CAPABILITY_PREFIX))
+            .collect();
+        if advertised.is_empty() {
+            return Some(Self::LEGACY);
+        }
+        advertised.into_iter().filter_map(Self::parse).max()
+    }
+}
+
+/// Translate a message built in [`ProtocolVersion::CURRENT`] into `version`
+/// for a peer that doesn't speak the current one.
+pub fn downgrade(message: &mut MyceliumMessage, version: ProtocolVersion) {
+    match version {
+        ProtocolVersion::V1_0 => {}
+    }
+    message.version = version.as_str().to_string();
+}
+
+/// Translate a message read as `version` into [`ProtocolVersion::CURRENT`].
+pub fn upgrade(message: &mut MyceliumMessage, version: ProtocolVersion) {
+    match version {
+        ProtocolVersion::V1_0 => {}
+    }
+    message.version = ProtocolVersion::CURRENT.as_str().to_string();
+}
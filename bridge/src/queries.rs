@@ -0,0 +1,257 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use tokio::sync::{oneshot, Mutex};
+
+/// A federation API call that is proxied to a remote bridge as a
+/// `<name>_request` message and answered with a `<name>_response` message.
+#[derive(Debug)]
+pub struct QueryKind {
+    pub name: &'static str,
+    pub method: &'static str,
+    /// The receiving bridge only replays requests under this path.
+    pub path_prefix: &'static str,
+    /// Capability both bridges must advertise for this query to be used.
+    pub capability: Option<&'static str>,
+}
+
+pub const BACKFILL: QueryKind = QueryKind {
+    name: "backfill",
+    method: "GET",
+    path_prefix: "/_matrix/federation/v1/backfill/",
+    capability: None,
+};
+
+pub const GET_MISSING_EVENTS: QueryKind = QueryKind {
+    name: "get_missing_events",
+    method: "POST",
+    path_prefix: "/_matrix/federation/v1/get_missing_events/",
+    capability: None,
+};
+
+pub const MAKE_LEAVE: QueryKind = QueryKind {
+    name: "make_leave",
+    method: "GET",
+    path_prefix: "/_matrix/federation/v1/make_leave/",
+    capability: None,
+};
+
+pub const SEND_LEAVE: QueryKind = QueryKind {
+    name: "send_leave",
+    method: "PUT",
+    path_prefix: "/_matrix/federation/v2/send_leave/",
+    capability: None,
+};
+
+pub const INVITE: QueryKind = QueryKind {
+    name: "invite",
+    method: "PUT",
+    path_prefix: "/_matrix/federation/v2/invite/",
+    capability: None,
+};
+
+pub const CLAIM_KEYS: QueryKind = QueryKind {
+    name: "claim_keys",
+    method: "POST",
+    path_prefix: "/_matrix/federation/v1/user/keys/claim",
+    capability: None,
+};
+
+pub const QUERY_KEYS: QueryKind = QueryKind {
+    name: "query_keys",
+    method: "POST",
+    path_prefix: "/_matrix/federation/v1/user/keys/query",
+    capability: None,
+};
+
+/// Fetches a user's full device list after missed `m.device_list_update`s.
+pub const DEVICE_LIST_RESYNC: QueryKind = QueryKind {
+    name: "device_list_resync",
+    method: "GET",
+    path_prefix: "/_matrix/federation/v1/user/devices/",
+    capability: None,
+};
+
+/// Displayname/avatar lookups for users on the destination server.
+pub const PROFILE_QUERY: QueryKind = QueryKind {
+    name: "profile_query",
+    method: "GET",
+    path_prefix: "/_matrix/federation/v1/query/profile",
+    capability: None,
+};
+
+/// Capability advertised by bridges that relay the knock handshake.
+pub const KNOCK_CAPABILITY: &str = "federation.knock";
+
+pub const MAKE_KNOCK: QueryKind = QueryKind {
+    name: "make_knock",
+    method: "GET",
+    path_prefix: "/_matrix/federation/v1/make_knock/",
+    capability: Some(KNOCK_CAPABILITY),
+};
+
+pub const SEND_KNOCK: QueryKind = QueryKind {
+    name: "send_knock",
+    method: "PUT",
+    path_prefix: "/_matrix/federation/v1/send_knock/",
+    capability: Some(KNOCK_CAPABILITY),
+};
+
+/// Capability advertised by bridges that answer federated user directory searches.
+pub const USER_SEARCH_CAPABILITY: &str = "directory.user_search";
+
+/// Replayed against the homeserver's user directory endpoint for bridges.
+pub const USER_SEARCH: QueryKind = QueryKind {
+    name: "user_search",
+    method: "POST",
+    path_prefix: "/federation/user_directory/search",
+    capability: Some(USER_SEARCH_CAPABILITY),
+};
+
+/// All query kinds this bridge can answer.
+pub const QUERY_KINDS: &[&QueryKind] = &[
+    &BACKFILL,
+    &GET_MISSING_EVENTS,
+    &MAKE_LEAVE,
+    &SEND_LEAVE,
+    &INVITE,
+    &MAKE_KNOCK,
+    &SEND_KNOCK,
+    &CLAIM_KEYS,
+    &QUERY_KEYS,
+    &DEVICE_LIST_RESYNC,
+    &PROFILE_QUERY,
+    &USER_SEARCH,
+];
+
+impl QueryKind {
+    pub fn request_type(&self) -> String {
+        format!("{}_request", self.name)
+    }
+
+    pub fn response_type(&self) -> String {
+        format!("{}_response", self.name)
+    }
+
+    pub fn from_request_type(message_type: &str) -> Option<&'static QueryKind> {
+        let name = message_type.strip_suffix("_request")?;
+        QUERY_KINDS.iter().copied().find(|kind| kind.name == name)
+    }
+
+    pub fn from_response_type(message_type: &str) -> Option<&'static QueryKind> {
+        let name = message_type.strip_suffix("_response")?;
+        QUERY_KINDS.iter().copied().find(|kind| kind.name == name)
+    }
+
+    /// Whether a remote bridge may have `path` replayed: under the prefix,
+    /// which must end at a path segment or the query string, and without
+    /// `..` segments, percent-encoded or not.
+    pub fn allows_path(&self, path: &str) -> bool {
+        let Some(rest) = path.strip_prefix(self.path_prefix) else {
+            return false;
+        };
+        let at_boundary =
+            self.path_prefix.ends_with('/') || rest.is_empty() || rest.starts_with(['/', '?']);
+        let decoded = percent_encoding::percent_decode_str(path).decode_utf8_lossy();
+        at_boundary && !decoded.contains("..")
+    }
+}
+
+/// Merge user directory results from several servers, dropping duplicate
+/// users and keeping at most `limit`. Returns the results and whether any
+/// were cut off.
+pub fn merge_user_search_results(
+    responses: Vec<serde_json::Value>,
+    limit: usize,
+) -> (Vec<serde_json::Value>, bool) {
+    let mut seen = std::collections::HashSet::new();
+    let mut results = Vec::new();
+    let mut limited = false;
+
+    for response in responses {
+        limited |= response["limited"].as_bool().unwrap_or(false);
+        let Some(entries) = response["results"].as_array() else {
+            continue;
+        };
+        for entry in entries {
+            let Some(user_id) = entry["user_id"].as_str() else {
+                continue;
+            };
+            if !seen.insert(user_id.to_string()) {
+                continue;
+            }
+            if results.len() >= limit {
+                limited = true;
+                continue;
+            }
+            results.push(entry.clone());
+        }
+    }
+
+    (results, limited)
+}
+
+/// Payload of a `<name>_request` message.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QueryRequest {
+    pub request_id: String,
+    /// Path and query string of the federation API call, e.g.
+    /// `/_matrix/federation/v1/backfill/!room:example.org?v=$event&limit=10`.
+    pub path: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub body: Option<serde_json::Value>,
+}
+
+/// Payload of a `<name>_response` message.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QueryResponse {
+    pub request_id: String,
+    pub status: u16,
+    pub body: serde_json::Value,
+}
+
+struct PendingQuery {
+    destination: String,
+    sender: oneshot::Sender<QueryResponse>,
+}
+
+/// Tracks outstanding queries until their response arrives.
+#[derive(Default)]
+pub struct QueryTracker {
+    pending: Mutex<HashMap<String, PendingQuery>>,
+}
+
+impl QueryTracker {
+    pub async fn register(
+        &self,
+        request_id: &str,
+        destination: &str,
+    ) -> oneshot::Receiver<QueryResponse> {
+        let (sender, receiver) = oneshot::channel();
+        let pending = PendingQuery {
+            destination: destination.to_string(),
+            sender,
+        };
+        self.pending.lock().await.insert(request_id.to_string(), pending);
+        receiver
+    }
+
+    pub async fn cancel(&self, request_id: &str) {
+        self.pending.lock().await.remove(request_id);
+    }
+
+    /// Hand a response from `source` to whoever is waiting for it. Returns
+    /// `false` if nobody is (unknown ID, the query already timed out, or the
+    /// response came from a server the query wasn't sent to).
+    pub async fn complete(&self, source: &str, response: QueryResponse) -> bool {
+        let mut pending = self.pending.lock().await;
+        match pending.get(&response.request_id) {
+            Some(query) if query.destination == source => {}
+            _ => return false,
+        }
+
+        match pending.remove(&response.request_id) {
+            Some(query) => query.sender.send(response).is_ok(),
+            None => false,
+        }
+    }
+}
@@ -0,0 +1,123 @@
+use std::collections::HashMap;
+use std::sync::RwLock;
+use std::time::Instant;
+use tokio::sync::Mutex;
+
+use crate::config::RateLimitConfig;
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Token bucket per remote server, so one noisy peer can't starve the
+/// message processor.
+pub struct RateLimiter {
+    config: RwLock<RateLimitConfig>,
+    buckets: Mutex<HashMap<String, Bucket>>,
+    throttled: Mutex<HashMap<String, u64>>,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> Self {
+        Self {
+            config: RwLock::new(config),
+            buckets: Mutex::new(HashMap::new()),
+            throttled: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Apply new limits. Existing buckets keep their tokens and refill at
+    /// the new rate.
+    pub fn set_config(&self, config: RateLimitConfig) {
+        *self.config.write().unwrap() = config;
+    }
+
+    /// Take a token for a message from `server_name`. Returns the number of
+    /// messages throttled so far if there was none left.
+    pub async fn check(&self, server_name: &str) -> Result<(), u64> {
+        let config = self.config.read().unwrap().clone();
+        if !config.enabled {
+            return Ok(());
+        }
+
+        let now = Instant::now();
+        let burst = config.burst as f64;
+        let allowed = {
+            let mut buckets = self.buckets.lock().await;
+            let bucket = buckets.entry(server_name.to_string()).or_insert(Bucket {
+                tokens: burst,
+                updated: now,
+            });
+            let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+            bucket.tokens = (bucket.tokens + elapsed * config.per_second).min(burst);
+            bucket.updated = now;
+
+            if bucket.tokens >= 1.0 {
+                bucket.tokens -= 1.0;
+                true
+            } else {
+                false
+            }
+        };
+
+        if allowed {
+            return Ok(());
+        }
+        let mut throttled = self.throttled.lock().await;
+        let count = throttled.entry(server_name.to_string()).or_insert(0);
+        *count += 1;
+        Err(*count)
+    }
+
+    /// Messages throttled per server since startup.
+    pub async fn throttled_counts(&self) -> HashMap<String, u64> {
+        self.throttled.lock().await.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(burst: u32) -> RateLimiter {
+        RateLimiter::new(RateLimitConfig {
+            enabled: true,
+            burst,
+            per_second: 0.001,
+        })
+    }
+
+    #[tokio::test]
+    async fn a_burst_is_let_through_then_throttled() {
+        let limiter = limiter(3);
+        for _ in 0..3 {
+            assert_eq!(limiter.check("a.test").await, Ok(()));
+        }
+        assert_eq!(limiter.check("a.test").await, Err(1));
+        assert_eq!(limiter.check("a.test").await, Err(2));
+        assert_eq!(limiter.throttled_counts().await["a.test"], 2);
+    }
+
+    #[tokio::test]
+    async fn each_server_has_its_own_bucket() {
+        let limiter = limiter(1);
+        assert_eq!(limiter.check("a.test").await, Ok(()));
+        assert!(limiter.check("a.test").await.is_err());
+        assert_eq!(limiter.check("b.test").await, Ok(()));
+    }
+
+    #[tokio::test]
+    async fn disabling_lets_everything_through() {
+        let limiter = limiter(1);
+        assert_eq!(limiter.check("a.test").await, Ok(()));
+        limiter.set_config(RateLimitConfig {
+            enabled: false,
+            ..RateLimitConfig::default()
+        });
+        for _ in 0..10 {
+            assert_eq!(limiter.check("a.test").await, Ok(()));
+        }
+        assert!(limiter.throttled_counts().await.is_empty());
+    }
+}
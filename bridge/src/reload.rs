@@ -0,0 +1,112 @@
+use crate::config::{BridgeConfig, FederationConfig, RateLimitConfig};
+
+/// Top-level config sections that take effect without a restart.
+const RELOADABLE_KEYS: &[&str] = &["max_users", "federation", "rate_limit", "discovery"];
+
+/// The settings a running bridge picks up when its config is reloaded.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReloadableSettings {
+    pub announce_interval_seconds: u64,
+    pub announce_jitter_seconds: u64,
+    pub max_users: u32,
+    pub federation: FederationConfig,
+    pub rate_limit: RateLimitConfig,
+}
+
+impl ReloadableSettings {
+    pub fn from_config(config: &BridgeConfig) -> Self {
+        Self {
+            announce_interval_seconds: config.discovery.announce_interval_seconds,
+            announce_jitter_seconds: config.discovery.announce_jitter_seconds,
+            max_users: config.max_users,
+            federation: config.federation.clone(),
+            rate_limit: config.rate_limit.clone(),
+        }
+    }
+
+    /// Human-readable description of each setting that differs.
+    pub fn diff(&self, new: &Self) -> Vec<String> {
+        let mut changes = Vec::new();
+        let mut compare = |name: &str, old: String, new: String| {
+            if old != new {
+                changes.push(format!("{}: {} -> {}", name, old, new));
+            }
+        };
+        compare(
+            "discovery.announce_interval_seconds",
+            self.announce_interval_seconds.to_string(),
+            new.announce_interval_seconds.to_string(),
+        );
+        compare(
+            "discovery.announce_jitter_seconds",
+            self.announce_jitter_seconds.to_string(),
+            new.announce_jitter_seconds.to_string(),
+        );
+        compare("max_users", self.max_users.to_string(), new.max_users.to_string());
+        compare(
+            "federation.allowed_servers",
+            format!("{:?}", self.federation.allowed_servers),
+            format!("{:?}", new.federation.allowed_servers),
+        );
+        compare(
+            "federation.blocked_servers",
+            format!("{:?}", self.federation.blocked_servers),
+            format!("{:?}", new.federation.blocked_servers),
+        );
+        compare(
+            "federation.allowed_rooms",
+            format!("{:?}", self.federation.allowed_rooms),
+            format!("{:?}", new.federation.allowed_rooms),
+        );
+        compare(
+            "federation.blocked_rooms",
+            format!("{:?}", self.federation.blocked_rooms),
+            format!("{:?}", new.federation.blocked_rooms),
+        );
+        compare(
+            "rate_limit",
+            format!("{:?}", self.rate_limit),
+            format!("{:?}", new.rate_limit),
+        );
+        changes
+    }
+}
+
+/// Sections that differ between `running` and `new` but only take effect
+/// after a restart.
+pub fn restart_required(running: &BridgeConfig, new: &BridgeConfig) -> Vec<String> {
+    // The parts of a reloadable section that decide which routes and tasks
+    // exist
+    let discovery_changes = [
+        (
+            "discovery.decentralized",
+            running.discovery.decentralized != new.discovery.decentralized,
+        ),
+        (
+            "discovery.service_url",
+            running.discovery.service_url != new.discovery.service_url,
+        ),
+        (
+            "discovery.heartbeat_interval_seconds",
+            running.discovery.heartbeat_interval_seconds != new.discovery.heartbeat_interval_seconds,
+        ),
+    ];
+    let (Ok(serde_json::Value::Object(running)), Ok(serde_json::Value::Object(new))) =
+        (serde_json::to_value(running), serde_json::to_value(new))
+    else {
+        return Vec::new();
+    };
+
+    let mut changed: Vec<String> = new
+        .iter()
+        .filter(|(key, _)| !RELOADABLE_KEYS.contains(&key.as_str()))
+        .filter(|(key, value)| running.get(*key) != Some(*value))
+        .map(|(key, _)| key.clone())
+        .collect();
+    for (key, differs) in discovery_changes {
+        if differs {
+            changed.push(key.to_string());
+        }
+    }
+    changed
+}
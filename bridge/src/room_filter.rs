@@ -0,0 +1,52 @@
+use std::collections::{HashMap, HashSet};
+use tokio::sync::RwLock;
+
+pub const CANONICAL_ALIAS_EVENT_TYPE: &str = "m.room.canonical_alias";
+
+/// Aliases of the rooms the bridge has seen, so room filters can name rooms
+/// by alias. Learnt from `m.room.canonical_alias` events and by resolving
+/// the aliases named in the config.
+#[derive(Default)]
+pub struct RoomAliases {
+    rooms: RwLock<HashMap<String, HashSet<String>>>,
+}
+
+impl RoomAliases {
+    /// Record the aliases if `pdu` is an `m.room.canonical_alias` state
+    /// event. The event replaces the aliases it previously published.
+    pub async fn observe(&self, pdu: &serde_json::Value) {
+        if pdu["type"] != CANONICAL_ALIAS_EVENT_TYPE || pdu["state_key"] != "" {
+            return;
+        }
+        let Some(room_id) = pdu["room_id"].as_str() else {
+            return;
+        };
+        let content = &pdu["content"];
+        let aliases: HashSet<String> = content["alias"]
+            .as_str()
+            .into_iter()
+            .chain(content["alt_aliases"].as_array().into_iter().flatten().filter_map(|a| a.as_str()))
+            .map(str::to_string)
+            .collect();
+        self.rooms.write().await.insert(room_id.to_string(), aliases);
+    }
+
+    /// Record an alias resolved through the homeserver's room directory.
+    pub async fn insert(&self, room_id: &str, alias: &str) {
+        self.rooms
+            .write()
+            .await
+            .entry(room_id.to_string())
+            .or_default()
+            .insert(alias.to_string());
+    }
+
+    pub async fn aliases(&self, room_id: &str) -> Vec<String> {
+        self.rooms
+            .read()
+            .await
+            .get(room_id)
+            .map(|aliases| aliases.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+}
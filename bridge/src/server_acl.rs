@@ -0,0 +1,114 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use tokio::sync::RwLock;
+
+pub const SERVER_ACL_EVENT_TYPE: &str = "m.room.server_acl";
+
+/// Content of an `m.room.server_acl` state event.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServerAcl {
+    #[serde(default)]
+    pub allow: Vec<String>,
+    #[serde(default)]
+    pub deny: Vec<String>,
+    #[serde(default = "default_allow_ip_literals")]
+    pub allow_ip_literals: bool,
+}
+
+fn default_allow_ip_literals() -> bool {
+    true
+}
+
+impl ServerAcl {
+    /// Apply the ACL to a server name, following the Matrix spec: IP literals
+    /// are checked first, then `deny`, then `allow`. Ports are ignored.
+    pub fn is_allowed(&self, server_name: &str) -> bool {
+        let host = strip_port(server_name).to_ascii_lowercase();
+
+        if !self.allow_ip_literals && is_ip_literal(&host) {
+            return false;
+        }
+        if self.deny.iter().any(|pattern| glob_match(&pattern.to_ascii_lowercase(), &host)) {
+            return false;
+        }
+        self.allow.iter().any(|pattern| glob_match(&pattern.to_ascii_lowercase(), &host))
+    }
+}
+
+fn strip_port(server_name: &str) -> &str {
+    if let Some(rest) = server_name.strip_prefix('[') {
+        return rest.split(']').next().unwrap_or(rest);
+    }
+    match server_name.rsplit_once(':') {
+        Some((host, port)) if port.chars().all(|c| c.is_ascii_digit()) => host,
+        _ => server_name,
+    }
+}
+
+fn is_ip_literal(host: &str) -> bool {
+    host.parse::<std::net::IpAddr>().is_ok()
+}
+
+/// Match `*` (any sequence) and `?` (any single character) wildcards.
+pub fn glob_match(pattern: &str, value: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let value: Vec<char> = value.chars().collect();
+    let (mut p, mut v) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while v < value.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == value[v]) {
+            p += 1;
+            v += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, v));
+            p += 1;
+        } else if let Some((star, matched)) = backtrack {
+            p = star + 1;
+            v = matched + 1;
+            backtrack = Some((star, matched + 1));
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Current server ACL of every room the bridge has seen one for.
+#[derive(Default)]
+pub struct AclStore {
+    rooms: RwLock<HashMap<String, ServerAcl>>,
+}
+
+impl AclStore {
+    /// Record the ACL if `pdu` is an `m.room.server_acl` state event. Returns
+    /// `true` if it was one.
+    pub async fn observe(&self, pdu: &serde_json::Value) -> bool {
+        if pdu["type"] != SERVER_ACL_EVENT_TYPE || pdu["state_key"] != "" {
+            return false;
+        }
+        let Some(room_id) = pdu["room_id"].as_str() else {
+            return false;
+        };
+        let Ok(acl) = serde_json::from_value::<ServerAcl>(pdu["content"].clone()) else {
+            return false;
+        };
+
+        self.rooms.write().await.insert(room_id.to_string(), acl);
+        true
+    }
+
+    /// Rooms without an ACL allow every server.
+    pub async fn is_allowed(&self, room_id: &str, server_name: &str) -> bool {
+        self.rooms
+            .read()
+            .await
+            .get(room_id)
+            .is_none_or(|acl| acl.is_allowed(server_name))
+    }
+
+    pub async fn snapshot(&self) -> HashMap<String, ServerAcl> {
+        self.rooms.read().await.clone()
+    }
+}
@@ -0,0 +1,233 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use base64::Engine;
+use ed25519_dalek::{Signature, SigningKey, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::info;
+
+use crate::config::BridgeConfig;
+use crate::http_client::HttpClient;
+
+/// Prefix of `signer.url` values naming a Unix socket.
+pub const UNIX_SCHEME: &str = "unix:";
+
+/// Signs with the bridge's federation identity key. The bridge only signs
+/// through this trait, so the key can be kept in an HSM or another process.
+#[async_trait]
+pub trait Signer: Send + Sync {
+    fn verifying_key(&self) -> VerifyingKey;
+
+    async fn sign(&self, message: &[u8]) -> Result<Signature>;
+
+    /// The private key, when it is held in this process. Payload encryption
+    /// derives its key from it.
+    fn signing_key(&self) -> Option<&SigningKey> {
+        None
+    }
+}
+
+/// Signs with a keypair kept in a file.
+pub struct LocalSigner {
+    signing_key: SigningKey,
+}
+
+impl LocalSigner {
+    pub fn new(signing_key: SigningKey) -> Self {
+        Self { signing_key }
+    }
+
+    /// Load the keypair saved at `path`, generating and saving one if there
+    /// is none.
+    pub fn load_or_generate(path: &str) -> Result<Self> {
+        if let Ok(signing_key) = read_keypair(path) {
+            info!("Loaded existing signing keypair from {}", path);
+            return Ok(Self::new(signing_key));
+        }
+
+        let signing_key = generate_keypair();
+        write_keypair(path, &signing_key)?;
+
+        info!("Generated new signing keypair and saved to {}", path);
+        Ok(Self::new(signing_key))
+    }
+}
+
+pub fn generate_keypair() -> SigningKey {
+    SigningKey::generate(&mut rand::rngs::OsRng)
+}
+
+/// Read a keypair file: the 32 byte secret key followed by the public key.
+pub fn read_keypair(path: &str) -> Result<SigningKey> {
+    let key_data = std::fs::read(path)?;
+    let key_bytes = <[u8; 64]>::try_from(key_data.as_slice())
+        .map_err(|_| anyhow::anyhow!("{} is not a 64 byte Ed25519 keypair", path))?;
+    Ok(SigningKey::from_keypair_bytes(&key_bytes)?)
+}
+
+pub fn write_keypair(path: &str, signing_key: &SigningKey) -> Result<()> {
+    if let Some(parent) = std::path::Path::new(path).parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(path, signing_key.to_keypair_bytes())?;
+    Ok(())
+}
+
+/// Where a key rotation keeps the key rotated away from, next to the key at
+/// `path`. While it is there, announcements are countersigned with it.
+pub fn previous_key_path(path: &str) -> String {
+    format!("{}.previous", path)
+}
+
+/// The base64 public key, as announcements and `trusted_keys` carry it.
+pub fn encode_public_key(verifying_key: &VerifyingKey) -> String {
+    base64::engine::general_purpose::STANDARD.encode(verifying_key.to_bytes())
+}
+
+/// The key of a base64 public key, as announcements carry it.
+pub fn decode_public_key(encoded: &str) -> Option<VerifyingKey> {
+    let bytes = base64::engine::general_purpose::STANDARD.decode(encoded).ok()?;
+    VerifyingKey::from_bytes(&bytes.try_into().ok()?).ok()
+}
+
+/// SHA-256 fingerprint of a public key, e.g. `SHA256:q3F...`.
+pub fn fingerprint(verifying_key: &VerifyingKey) -> String {
+    use sha2::Digest;
+
+    let digest = sha2::Sha256::digest(verifying_key.to_bytes());
+    format!("SHA256:{}", base64::engine::general_purpose::STANDARD_NO_PAD.encode(digest))
+}
+
+#[async_trait]
+impl Signer for LocalSigner {
+    fn verifying_key(&self) -> VerifyingKey {
+        self.signing_key.verifying_key()
+    }
+
+    async fn sign(&self, message: &[u8]) -> Result<Signature> {
+        Ok(ed25519_dalek::Signer::sign(&self.signing_key, message))
+    }
+
+    fn signing_key(&self) -> Option<&SigningKey> {
+        Some(&self.signing_key)
+    }
+}
+
+/// Request to an external signing service. Sent as the body of a POST over
+/// HTTP, or as one line of JSON over a Unix socket, which answers with one
+/// line.
+#[derive(Debug, Serialize)]
+#[serde(tag = "method", rename_all = "snake_case")]
+enum SignerRequest<'a> {
+    PublicKey,
+    Sign { message: &'a str },
+}
+
+#[derive(Debug, Deserialize)]
+struct SignerResponse {
+    public_key: Option<String>,
+    signature: Option<String>,
+}
+
+enum Endpoint {
+    Http { client: HttpClient, url: String },
+    Unix(PathBuf),
+}
+
+/// Signs through an external service that holds the key, over HTTP or a
+/// Unix socket. Messages and signatures are base64 encoded.
+pub struct ExternalSigner {
+    endpoint: Endpoint,
+    timeout: Duration,
+    verifying_key: VerifyingKey,
+}
+
+impl ExternalSigner {
+    /// Connect to the service at `url` and fetch the public key it signs for.
+    pub async fn connect(url: &str, timeout: Duration, client: HttpClient) -> Result<Self> {
+        let endpoint = match url.strip_prefix(UNIX_SCHEME) {
+            Some(path) => Endpoint::Unix(PathBuf::from(path)),
+            None => Endpoint::Http {
+                client,
+                url: url.to_string(),
+            },
+        };
+        let response = call(&endpoint, timeout, &SignerRequest::PublicKey).await?;
+        let public_key = response
+            .public_key
+            .ok_or_else(|| anyhow::anyhow!("The signer returned no public key"))?;
+        let key_bytes = base64::engine::general_purpose::STANDARD.decode(public_key)?;
+        let key_bytes: [u8; 32] = key_bytes
+            .try_into()
+            .map_err(|_| anyhow::anyhow!("The signer's public key is not an Ed25519 key"))?;
+        let verifying_key = VerifyingKey::from_bytes(&key_bytes)?;
+        info!("Signing through the external signer at {}", url);
+        Ok(Self {
+            endpoint,
+            timeout,
+            verifying_key,
+        })
+    }
+}
+
+#[async_trait]
+impl Signer for ExternalSigner {
+    fn verifying_key(&self) -> VerifyingKey {
+        self.verifying_key
+    }
+
+    async fn sign(&self, message: &[u8]) -> Result<Signature> {
+        let encoded = base64::engine::general_purpose::STANDARD.encode(message);
+        let response = call(&self.endpoint, self.timeout, &SignerRequest::Sign { message: &encoded }).await?;
+        let signature = response
+            .signature
+            .ok_or_else(|| anyhow::anyhow!("The signer returned no signature"))?;
+        let signature = base64::engine::general_purpose::STANDARD.decode(signature)?;
+        let signature = Signature::from_slice(&signature)?;
+        // A signer holding the wrong key would get every message rejected
+        self.verifying_key.verify_strict(message, &signature)?;
+        Ok(signature)
+    }
+}
+
+async fn call(endpoint: &Endpoint, timeout: Duration, request: &SignerRequest<'_>) -> Result<SignerResponse> {
+    let exchange = async {
+        match endpoint {
+            Endpoint::Http { client, url } => {
+                let response = client.send(client.post(url).json(request)).await?.error_for_status()?;
+                Ok(response.json().await?)
+            }
+            #[cfg(unix)]
+            Endpoint::Unix(path) => {
+                use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+                let mut stream = tokio::net::UnixStream::connect(path).await?;
+                let mut line = serde_json::to_vec(request)?;
+                line.push(b'\n');
+                stream.write_all(&line).await?;
+                let mut response = String::new();
+                BufReader::new(stream).read_line(&mut response).await?;
+                Ok(serde_json::from_str(&response)?)
+            }
+            #[cfg(not(unix))]
+            Endpoint::Unix(_) => Err(anyhow::anyhow!("Unix sockets are not supported on this platform")),
+        }
+    };
+    tokio::time::timeout(timeout, exchange)
+        .await
+        .map_err(|_| anyhow::anyhow!("The signer did not answer within {:?}", timeout))?
+}
+
+/// The signer `config` asks for: the external service at `signer.url`, or
+/// else the keypair at `signing_key_path`.
+pub async fn from_config(config: &BridgeConfig, client: HttpClient) -> Result<Arc<dyn Signer>> {
+    match &config.signer.url {
+        Some(url) => {
+            let timeout = Duration::from_millis(config.signer.timeout_ms);
+            Ok(Arc::new(ExternalSigner::connect(url, timeout, client).await?))
+        }
+        None => Ok(Arc::new(LocalSigner::load_or_generate(&config.signing_key_path)?)),
+    }
+}
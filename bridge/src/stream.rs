@@ -0,0 +1,51 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::sync::Arc;
+use tokio::sync::broadcast;
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EventKind {
+    Pdu,
+    Edu,
+}
+
+/// A verified inbound event, as pushed to `/federation/stream` consumers.
+#[derive(Debug, Clone, Serialize)]
+pub struct InboundEvent {
+    pub origin: String,
+    pub kind: EventKind,
+    pub received_at: DateTime<Utc>,
+    pub event: serde_json::Value,
+}
+
+/// Fans inbound events out to every connected stream consumer. Consumers
+/// that fall more than `buffer` events behind miss the oldest ones.
+pub struct EventStream {
+    sender: broadcast::Sender<Arc<InboundEvent>>,
+}
+
+impl EventStream {
+    pub fn new(buffer: usize) -> Self {
+        let (sender, _) = broadcast::channel(buffer.max(1));
+        Self { sender }
+    }
+
+    /// Returns whether any consumer was connected to receive the event.
+    pub fn publish(&self, origin: &str, kind: EventKind, event: &serde_json::Value) -> bool {
+        if self.sender.receiver_count() == 0 {
+            return false;
+        }
+        let event = InboundEvent {
+            origin: origin.to_string(),
+            kind,
+            received_at: Utc::now(),
+            event: event.clone(),
+        };
+        self.sender.send(Arc::new(event)).is_ok()
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Arc<InboundEvent>> {
+        self.sender.subscribe()
+    }
+}
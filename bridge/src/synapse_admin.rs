@@ -0,0 +1,60 @@
+use anyhow::Result;
+
+use crate::capacity::UserCounts;
+use crate::http_client::HttpClient;
+
+/// Users fetched per page from the admin API.
+const PAGE_SIZE: u32 = 500;
+
+/// Window for counting a user as monthly active.
+const MONTHLY_ACTIVE_WINDOW: chrono::Duration = chrono::Duration::days(30);
+
+/// Count local users through Synapse's admin API, paging through
+/// `/_synapse/admin/v2/users` (guests and deactivated users excluded).
+pub async fn count_users(
+    http_client: &HttpClient,
+    homeserver_url: &str,
+    token: &str,
+) -> Result<UserCounts> {
+    let active_since = (chrono::Utc::now() - MONTHLY_ACTIVE_WINDOW).timestamp_millis();
+    let mut total = 0;
+    let mut monthly_active = 0;
+    let mut from: Option<String> = None;
+
+    loop {
+        let mut request = http_client
+            .get(format!("{}/_synapse/admin/v2/users", homeserver_url))
+            .bearer_auth(token)
+            .query(&[("guests", "false"), ("deactivated", "false")])
+            .query(&[("limit", PAGE_SIZE)]);
+        if let Some(from) = &from {
+            request = request.query(&[("from", from)]);
+        }
+
+        let response = http_client.send(request).await?;
+        if !response.status().is_success() {
+            return Err(anyhow::anyhow!("Synapse admin API returned {}", response.status()));
+        }
+        let page: serde_json::Value = response.json().await?;
+
+        let users = page["users"].as_array().cloned().unwrap_or_default();
+        total += users.len() as u32;
+        monthly_active += users
+            .iter()
+            .filter(|user| user["last_seen_ts"].as_i64().is_some_and(|ts| ts >= active_since))
+            .count() as u32;
+
+        // `next_token` is a string in current Synapse and a number in older ones
+        from = match &page["next_token"] {
+            serde_json::Value::String(token) => Some(token.clone()),
+            serde_json::Value::Number(token) => Some(token.to_string()),
+            _ => None,
+        };
+        if from.is_none() || users.is_empty() {
+            return Ok(UserCounts {
+                total,
+                monthly_active: Some(monthly_active),
+            });
+        }
+    }
+}
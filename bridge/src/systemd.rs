@@ -0,0 +1,128 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::time::Instant;
+use tracing::{info, warn};
+
+use crate::MatrixMyceliumBridge;
+
+/// How often readiness is checked again while mycelium or the homeserver is
+/// not answering at startup.
+const READINESS_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// When each of the bridge's main loops last went round, so the watchdog can
+/// tell a stuck loop from a quiet one.
+#[derive(Default)]
+pub struct LoopHeartbeats {
+    loops: Mutex<HashMap<String, Instant>>,
+}
+
+impl LoopHeartbeats {
+    pub fn beat(&self, name: &str) {
+        let mut loops = self.loops.lock().unwrap();
+        match loops.get_mut(name) {
+            Some(last) => *last = Instant::now(),
+            None => {
+                loops.insert(name.to_string(), Instant::now());
+            }
+        }
+    }
+
+    /// Loops that haven't gone round within `timeout`, by name.
+    pub fn stalled(&self, timeout: Duration) -> Vec<String> {
+        let mut stalled: Vec<_> = self
+            .loops
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, last)| last.elapsed() > timeout)
+            .map(|(name, _)| name.clone())
+            .collect();
+        stalled.sort();
+        stalled
+    }
+}
+
+/// Run `bridge` under systemd's supervision, when it was started as a
+/// `Type=notify` service. Once `bridge` has started, its HTTP server is
+/// listening, so `READY=1` is sent as soon as mycelium and the homeserver
+/// both answer. With `WatchdogSec=` set, the watchdog is then pinged for as
+/// long as every main loop keeps going round, so a deadlocked bridge is
+/// restarted. `STOPPING=1` is sent on shutdown.
+///
+/// Does nothing outside systemd, or on platforms without it.
+pub async fn supervise(bridge: MatrixMyceliumBridge) {
+    if std::env::var_os("NOTIFY_SOCKET").is_none() {
+        return;
+    }
+    tokio::select! {
+        _ = run(&bridge) => {}
+        _ = bridge.shutdown_requested() => {}
+    }
+    notify(Notification::Stopping);
+}
+
+async fn run(bridge: &MatrixMyceliumBridge) {
+    let mut waiting = false;
+    while !bridge.check_health().await {
+        if !waiting {
+            info!("Waiting for mycelium and the homeserver before notifying systemd");
+            notify(Notification::Status("Waiting for mycelium and the homeserver"));
+            waiting = true;
+        }
+        tokio::time::sleep(READINESS_CHECK_INTERVAL).await;
+    }
+    notify(Notification::Ready);
+    info!("Notified systemd that the bridge is ready");
+
+    let Some(timeout) = watchdog_timeout() else {
+        return;
+    };
+    let mut interval = tokio::time::interval(timeout / 2);
+    loop {
+        interval.tick().await;
+        let stalled = bridge.stalled_loops(timeout);
+        if stalled.is_empty() {
+            notify(Notification::Watchdog);
+        } else {
+            warn!("Not pinging the systemd watchdog, stalled: {}", stalled.join(", "));
+        }
+    }
+}
+
+enum Notification<'a> {
+    Ready,
+    Stopping,
+    Watchdog,
+    Status(&'a str),
+}
+
+#[cfg(unix)]
+fn notify(notification: Notification) {
+    use sd_notify::NotifyState;
+
+    let state = match notification {
+        Notification::Ready => NotifyState::Ready,
+        Notification::Stopping => NotifyState::Stopping,
+        Notification::Watchdog => NotifyState::Watchdog,
+        Notification::Status(status) => NotifyState::Status(status),
+    };
+    if let Err(e) = sd_notify::notify(false, &[state]) {
+        warn!("Failed to notify systemd: {}", e);
+    }
+}
+
+#[cfg(not(unix))]
+fn notify(_notification: Notification) {}
+
+/// The service's `WatchdogSec=`, if the watchdog is on for this process.
+#[cfg(unix)]
+fn watchdog_timeout() -> Option<Duration> {
+    let mut usec = 0;
+    sd_notify::watchdog_enabled(false, &mut usec).then(|| Duration::from_micros(usec))
+}
+
+#[cfg(not(unix))]
+fn watchdog_timeout() -> Option<Duration> {
+    None
+}
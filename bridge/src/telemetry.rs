@@ -0,0 +1,212 @@
+use anyhow::Result;
+use opentelemetry::propagation::Extractor;
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry::{global, Context};
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::{Sampler, SdkTracerProvider};
+use opentelemetry_sdk::Resource;
+use rolling_file::{BasicRollingFileAppender, RollingConditionBasic};
+use serde::Deserialize;
+use serde_json::value::RawValue;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::layer::{Layered, SubscriberExt};
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, Layer, Registry};
+
+use crate::config::{BridgeConfig, LogFormat, LogRotation, LoggingConfig};
+
+const TRACER_NAME: &str = "matrix-mycelium-bridge";
+
+/// Flushes buffered spans and log lines when dropped at shutdown.
+pub struct TelemetryGuard {
+    provider: Option<SdkTracerProvider>,
+    _log_file: Option<WorkerGuard>,
+}
+
+impl Drop for TelemetryGuard {
+    fn drop(&mut self) {
+        if let Some(provider) = self.provider.take() {
+            if let Err(e) = provider.shutdown() {
+                eprintln!("Failed to flush traces: {}", e);
+            }
+        }
+    }
+}
+
+/// Overrides `logging.format` (`text` or `json`).
+const LOG_FORMAT_ENV: &str = "BRIDGE_LOG_FORMAT";
+
+/// A log sink, filtered along with the others by the shared [`EnvFilter`].
+type Sink = Box<dyn Layer<Layered<EnvFilter, Registry>> + Send + Sync>;
+
+/// Install the global tracing subscriber, logging to the sinks in `[logging]`
+/// and exporting spans over OTLP when telemetry is enabled. `RUST_LOG`
+/// overrides `logging.level` and `logging.modules`.
+pub fn init(bridge_config: &BridgeConfig) -> Result<TelemetryGuard> {
+    global::set_text_map_propagator(TraceContextPropagator::new());
+
+    let config = &bridge_config.telemetry;
+    let format = match std::env::var(LOG_FORMAT_ENV) {
+        Ok(format) => serde_json::from_value(serde_json::Value::String(format.to_lowercase()))
+            .map_err(|_| anyhow::anyhow!("{} must be \"text\" or \"json\"", LOG_FORMAT_ENV))?,
+        Err(_) => bridge_config.logging.format,
+    };
+    let filter = EnvFilter::try_from_default_env()
+        .or_else(|_| EnvFilter::try_new(bridge_config.logging.filter()))?;
+    let (sinks, log_file) = sinks(&bridge_config.logging, format)?;
+
+    let provider = if config.enabled {
+        let exporter = SpanExporter::builder()
+            .with_http()
+            .with_endpoint(&config.otlp_endpoint)
+            .build()?;
+        let sampler = Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(config.sample_ratio)));
+        Some(
+            SdkTracerProvider::builder()
+                .with_batch_exporter(exporter)
+                .with_sampler(sampler)
+                .with_resource(Resource::builder().with_service_name(config.service_name.clone()).build())
+                .build(),
+        )
+    } else {
+        None
+    };
+    let otel_layer = provider
+        .as_ref()
+        .map(|provider| tracing_opentelemetry::layer().with_tracer(provider.tracer(TRACER_NAME)));
+
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(sinks)
+        .with(otel_layer)
+        .init();
+
+    Ok(TelemetryGuard {
+        provider,
+        _log_file: log_file,
+    })
+}
+
+/// The enabled sinks, and the guard that flushes the log file's writer.
+fn sinks(config: &LoggingConfig, format: LogFormat) -> Result<(Vec<Sink>, Option<WorkerGuard>)> {
+    let mut sinks = Vec::new();
+    if config.stdout {
+        sinks.push(fmt_sink(format, std::io::stdout, true));
+    }
+    if config.journald {
+        sinks.push(journald_sink()?);
+    }
+
+    let mut log_file = None;
+    if let Some(path) = &config.file_path {
+        let path = std::path::Path::new(path);
+        if let Some(directory) = path.parent().filter(|directory| !directory.as_os_str().is_empty()) {
+            std::fs::create_dir_all(directory)?;
+        }
+        let mut condition = match config.file_rotation {
+            LogRotation::Never => RollingConditionBasic::new(),
+            LogRotation::Hourly => RollingConditionBasic::new().hourly(),
+            LogRotation::Daily => RollingConditionBasic::new().daily(),
+        };
+        if config.file_max_size_mb > 0 {
+            condition = condition.max_size(config.file_max_size_mb * 1024 * 1024);
+        }
+        let appender = BasicRollingFileAppender::new(path, condition, config.file_max_files)
+            .map_err(|e| anyhow::anyhow!("Failed to open log file {}: {}", path.display(), e))?;
+        let (writer, guard) = tracing_appender::non_blocking(appender);
+        sinks.push(fmt_sink(format, writer, false));
+        log_file = Some(guard);
+    }
+
+    Ok((sinks, log_file))
+}
+
+fn fmt_sink<W>(format: LogFormat, writer: W, ansi: bool) -> Sink
+where
+    W: for<'writer> MakeWriter<'writer> + Send + Sync + 'static,
+{
+    let layer = tracing_subscriber::fmt::layer().with_writer(writer).with_ansi(ansi);
+    match format {
+        // JSON lines carry the fields of every enclosing span, so each line
+        // shows the correlation ID of the message being processed
+        LogFormat::Json => layer.json().with_current_span(true).with_span_list(true).boxed(),
+        LogFormat::Text => layer.boxed(),
+    }
+}
+
+#[cfg(unix)]
+fn journald_sink() -> Result<Sink> {
+    let layer = tracing_journald::layer()
+        .map_err(|e| anyhow::anyhow!("Failed to connect to journald: {}", e))?
+        .with_syslog_identifier(TRACER_NAME.to_string());
+    Ok(layer.boxed())
+}
+
+#[cfg(not(unix))]
+fn journald_sink() -> Result<Sink> {
+    anyhow::bail!("logging.journald is not supported on this platform")
+}
+
+/// Trace context of the current span, to carry across the overlay.
+pub fn current_context() -> Option<HashMap<String, String>> {
+    let context = tracing::Span::current().context();
+    let mut carrier = HashMap::new();
+    global::get_text_map_propagator(|propagator| propagator.inject_context(&context, &mut carrier));
+    (!carrier.is_empty()).then_some(carrier)
+}
+
+/// Add the current trace context to an outgoing HTTP request.
+pub fn inject_headers(request: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+    current_context()
+        .unwrap_or_default()
+        .into_iter()
+        .fold(request, |request, (name, value)| request.header(name, value))
+}
+
+/// Make `span` a child of the remote span described by `carrier`.
+pub fn set_remote_parent(span: &tracing::Span, carrier: &dyn Extractor) {
+    let context: Context = global::get_text_map_propagator(|propagator| propagator.extract(carrier));
+    let _ = span.set_parent(context);
+}
+
+/// Reads trace context from incoming request headers.
+pub struct HeaderExtractor<'a>(pub &'a axum::http::HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|name| name.as_str()).collect()
+    }
+}
+
+/// Correlation ID for a message's journey through the bridge: its event ID
+/// when it has one, otherwise a fresh UUID.
+pub fn correlation_id(payload: &serde_json::Value) -> String {
+    payload["event_id"]
+        .as_str()
+        .map(str::to_string)
+        .unwrap_or_else(|| uuid::Uuid::new_v4().to_string())
+}
+
+/// [`correlation_id`] of a payload that is already serialized, read without
+/// parsing the rest of it.
+pub fn raw_correlation_id(payload: &RawValue) -> String {
+    #[derive(Deserialize)]
+    struct EventId<'a> {
+        #[serde(borrow)]
+        event_id: Option<Cow<'a, str>>,
+    }
+    serde_json::from_str::<EventId>(payload.get())
+        .ok()
+        .and_then(|payload| payload.event_id)
+        .map(Cow::into_owned)
+        .unwrap_or_else(|| uuid::Uuid::new_v4().to_string())
+}
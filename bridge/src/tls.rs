@@ -0,0 +1,51 @@
+use anyhow::Result;
+use axum_server::tls_rustls::RustlsConfig;
+use std::time::SystemTime;
+use tracing::{info, warn};
+
+use crate::config::TlsConfig;
+
+/// Load the certificate chain and private key for the HTTP server.
+pub async fn load(config: &TlsConfig) -> Result<RustlsConfig> {
+    // ring is the only provider compiled in, so this only fails when it is
+    // already installed
+    let _ = rustls::crypto::ring::default_provider().install_default();
+    let rustls = RustlsConfig::from_pem_file(&config.cert_path, &config.key_path)
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to load TLS certificate {}: {}", config.cert_path, e))?;
+    Ok(rustls)
+}
+
+/// Watch the certificate and key for changes, e.g. after renewal, and
+/// swap them in without dropping connections. A pair that fails to load,
+/// such as one caught halfway through being rewritten, is retried on the
+/// next check. Never resolves, unless reloading is turned off.
+pub async fn reload_on_change(config: TlsConfig, rustls: RustlsConfig) {
+    if config.reload_interval_seconds == 0 {
+        return;
+    }
+
+    let mut loaded = modified(&config).await;
+    let period = std::time::Duration::from_secs(config.reload_interval_seconds);
+    let mut interval = tokio::time::interval(period);
+    loop {
+        interval.tick().await;
+        let current = modified(&config).await;
+        if current == loaded {
+            continue;
+        }
+        match rustls.reload_from_pem_file(&config.cert_path, &config.key_path).await {
+            Ok(()) => {
+                info!("Reloaded TLS certificate from {}", config.cert_path);
+                loaded = current;
+            }
+            Err(e) => warn!("Failed to reload TLS certificate: {}", e),
+        }
+    }
+}
+
+async fn modified(config: &TlsConfig) -> Option<(SystemTime, SystemTime)> {
+    let cert = tokio::fs::metadata(&config.cert_path).await.ok()?.modified().ok()?;
+    let key = tokio::fs::metadata(&config.key_path).await.ok()?.modified().ok()?;
+    Some((cert, key))
+}
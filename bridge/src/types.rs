@@ -1,11 +1,37 @@
-use chrono::{DateTime, Utc};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use serde_json::value::RawValue;
+use std::collections::HashMap;
+
+pub use mycelium_chat_types::{
+    AddressChallenge, AddressProof, Deregistration, Heartbeat, KeyRevocation, RegisterRequest,
+    ServerAnnouncement, ServerCapacity, ServerInfo, ServerQuery, ServerStatus, ADDRESS_CHALLENGE_MESSAGE_TYPE,
+    DISCOVERY_TOPIC, KEY_REVOCATION_MESSAGE_TYPE,
+};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FederationEvent {
     pub destination: String,
     pub event_type: String,
     pub event_data: serde_json::Value,
+    /// RFC 3339 time after which the event is dropped rather than sent.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<String>,
+}
+
+/// An event for several servers: those in `destinations`, or every known
+/// server when there is no list. With `capability`, only servers that
+/// support it are sent the event.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BroadcastEvent {
+    #[serde(default)]
+    pub destinations: Option<Vec<String>>,
+    #[serde(default)]
+    pub capability: Option<String>,
+    pub event_type: String,
+    pub event_data: serde_json::Value,
+    #[serde(default)]
+    pub expires_at: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -15,42 +41,149 @@ pub struct MyceliumMessage {
     pub destination_server: String,
     pub message_type: String,
     pub timestamp: String,
-    pub payload: serde_json::Value,
+    /// Kept as the JSON text it was sent as: the signature covers exactly
+    /// these bytes, and it is parsed only once, by whatever handles it.
+    pub payload: Box<RawValue>,
     pub signature: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_encoding: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encryption: Option<String>,
+    /// W3C trace context of the sending span. Not covered by the signature.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub trace_context: Option<HashMap<String, String>>,
+    /// Ties together the log lines of both bridges for this message.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub correlation_id: Option<String>,
+    /// RFC 3339 time after which the message is worthless: the sender stops
+    /// trying to send it and the receiver discards it. Not covered by the
+    /// signature.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<String>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct ServerAnnouncement {
-    pub server_name: String,
-    pub mycelium_address: String,
-    pub public_key: String,
-    pub capabilities: Vec<String>,
-    pub capacity: ServerCapacity,
-    pub timestamp: String,
-    pub signature: String,
+/// What the signature of a `MyceliumMessage` covers.
+#[derive(Serialize)]
+struct SignedEnvelope<'a> {
+    source_server: &'a str,
+    destination_server: &'a str,
+    message_type: &'a str,
+    correlation_id: Option<&'a str>,
+    timestamp: &'a str,
+    payload: &'a RawValue,
+    content_encoding: Option<&'a str>,
+    encryption: Option<&'a str>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct ServerCapacity {
-    pub max_users: u32,
-    pub current_users: u32,
-    pub available: bool,
+impl MyceliumMessage {
+    /// The bytes the signature is made over: the payload as sent, along with
+    /// who sent it to whom, what it is and when, so a signed payload can't
+    /// be passed off as another message or sent on to another server, and
+    /// how it is encoded, so it isn't decrypted or decompressed as something
+    /// else.
+    pub fn signing_payload(&self) -> serde_json::Result<String> {
+        serde_json::to_string(&SignedEnvelope {
+            source_server: &self.source_server,
+            destination_server: &self.destination_server,
+            message_type: &self.message_type,
+            correlation_id: self.correlation_id.as_deref(),
+            timestamp: &self.timestamp,
+            payload: &self.payload,
+            content_encoding: self.content_encoding.as_deref(),
+            encryption: self.encryption.as_deref(),
+        })
+    }
+
+    /// Parse the payload, once decrypted and decompressed.
+    pub fn payload<T: DeserializeOwned>(&self) -> serde_json::Result<T> {
+        serde_json::from_str(self.payload.get())
+    }
+
+    pub fn is_expired(&self) -> bool {
+        is_expired(self.expires_at.as_deref())
+    }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct ServerInfo {
-    pub server_name: String,
-    pub mycelium_address: String,
-    pub public_key: String,
-    pub capabilities: Vec<String>,
-    pub capacity: ServerCapacity,
-    pub last_seen: DateTime<Utc>,
-    pub status: ServerStatus,
+/// Whether an `expires_at` time has passed. Times that don't parse never
+/// expire.
+pub fn is_expired(expires_at: Option<&str>) -> bool {
+    expires_at
+        .and_then(|expires_at| chrono::DateTime::parse_from_rfc3339(expires_at).ok())
+        .is_some_and(|expires_at| expires_at < chrono::Utc::now())
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub enum ServerStatus {
-    Online,
-    Offline,
-    Unknown,
+/// Anything that can arrive on the `matrix.discovery` topic.
+#[derive(Debug, Clone)]
+pub enum DiscoveryMessage {
+    Announcement(ServerAnnouncement),
+    KeyRevocation(KeyRevocation),
+    AddressChallenge(AddressChallenge),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::Engine;
+    use ed25519_dalek::{Signer, SigningKey};
+
+    use crate::signer;
+
+    fn signed_message(key: &SigningKey) -> MyceliumMessage {
+        let mut message = MyceliumMessage {
+            version: "1.0".to_string(),
+            source_server: "a.test".to_string(),
+            destination_server: "b.test".to_string(),
+            message_type: "federation_event".to_string(),
+            timestamp: chrono::Utc::now().to_rfc3339(),
+            payload: serde_json::value::to_raw_value(&serde_json::json!({ "body": "hello" })).unwrap(),
+            signature: String::new(),
+            content_encoding: None,
+            encryption: None,
+            trace_context: None,
+            correlation_id: Some("abc".to_string()),
+            expires_at: None,
+        };
+        let signature = key.sign(message.signing_payload().unwrap().as_bytes());
+        message.signature = base64::engine::general_purpose::STANDARD.encode(signature.to_bytes());
+        message
+    }
+
+    fn verifies(message: &MyceliumMessage, key: &SigningKey) -> bool {
+        let public_key = signer::encode_public_key(&key.verifying_key());
+        crate::verify_signature(&public_key, &message.signing_payload().unwrap(), &message.signature)
+    }
+
+    #[test]
+    fn signed_envelopes_verify() {
+        let key = signer::generate_keypair();
+        let message = signed_message(&key);
+        assert!(verifies(&message, &key));
+        assert!(!verifies(&message, &signer::generate_keypair()));
+
+        // Neither is covered by the signature
+        let mut traced = message.clone();
+        traced.trace_context = Some(HashMap::from([("traceparent".to_string(), "00-abc".to_string())]));
+        traced.expires_at = Some(chrono::Utc::now().to_rfc3339());
+        assert!(verifies(&traced, &key));
+    }
+
+    #[test]
+    fn tampered_envelopes_do_not_verify() {
+        let key = signer::generate_keypair();
+        let message = signed_message(&key);
+        let tampers: [fn(&mut MyceliumMessage); 7] = [
+            |message| message.source_server = "c.test".to_string(),
+            |message| message.destination_server = "c.test".to_string(),
+            |message| message.message_type = "edu".to_string(),
+            |message| message.correlation_id = None,
+            |message| message.payload = serde_json::value::to_raw_value("hello").unwrap(),
+            |message| message.content_encoding = Some("zstd".to_string()),
+            |message| message.encryption = Some("x25519-chacha20poly1305".to_string()),
+        ];
+        for tamper in tampers {
+            let mut tampered = message.clone();
+            tamper(&mut tampered);
+            assert!(!verifies(&tampered, &key));
+        }
+    }
 }
@@ -0,0 +1,96 @@
+use serde_json::Value;
+
+use crate::config::ValidationConfig;
+
+/// Check that a PDU has the shape of a Matrix event before it is handed to
+/// the homeserver. The error describes the first problem found.
+pub fn validate_pdu(pdu: &Value, config: &ValidationConfig) -> Result<(), String> {
+    check_size(pdu, config)?;
+    let Some(event) = pdu.as_object() else {
+        return Err("event is not a JSON object".to_string());
+    };
+
+    let room_id = required_str(pdu, "room_id")?;
+    if !room_id.starts_with('!') {
+        return Err(format!("room_id {:?} is not a room ID", room_id));
+    }
+    let sender = required_str(pdu, "sender")?;
+    if !sender.starts_with('@') || !sender.contains(':') {
+        return Err(format!("sender {:?} is not a user ID", sender));
+    }
+    let event_type = required_str(pdu, "type")?;
+    if !pdu["content"].is_object() {
+        return Err("content must be an object".to_string());
+    }
+
+    // Federation fields are optional here, as the bridge also carries events
+    // before the homeserver has filled them in, but must be well-formed
+    for (field, valid) in [
+        ("origin_server_ts", event.get("origin_server_ts").map(Value::is_i64)),
+        ("depth", event.get("depth").map(Value::is_i64)),
+        ("prev_events", event.get("prev_events").map(Value::is_array)),
+        ("auth_events", event.get("auth_events").map(Value::is_array)),
+        ("hashes", event.get("hashes").map(Value::is_object)),
+        ("signatures", event.get("signatures").map(Value::is_object)),
+        ("state_key", event.get("state_key").map(Value::is_string)),
+    ] {
+        if valid == Some(false) {
+            return Err(format!("{} has the wrong type", field));
+        }
+    }
+    if let Some(event_id) = event.get("event_id") {
+        if !event_id.as_str().is_some_and(|id| id.starts_with('$')) {
+            return Err("event_id is not an event ID".to_string());
+        }
+    }
+
+    if event_type == "m.room.create" && !config.room_versions.is_empty() {
+        // Rooms created without a version are version 1
+        let version = pdu["content"]["room_version"].as_str().unwrap_or("1");
+        if !config.room_versions.iter().any(|allowed| allowed == version) {
+            return Err(format!("room version {:?} is not allowed", version));
+        }
+    }
+
+    Ok(())
+}
+
+pub fn validate_edu(edu: &Value, config: &ValidationConfig) -> Result<(), String> {
+    check_size(edu, config)?;
+    required_str(edu, "edu_type")?;
+    if !edu["content"].is_object() {
+        return Err("content must be an object".to_string());
+    }
+    Ok(())
+}
+
+/// Check a transaction as a whole; its PDUs are checked one by one.
+pub fn validate_transaction(payload: &Value, config: &ValidationConfig) -> Result<(), String> {
+    let Some(pdus) = payload["pdus"].as_array() else {
+        return Err("transaction has no pdus array".to_string());
+    };
+    if pdus.len() > config.max_transaction_pdus {
+        return Err(format!(
+            "transaction has {} PDUs, more than {}",
+            pdus.len(),
+            config.max_transaction_pdus
+        ));
+    }
+    Ok(())
+}
+
+fn check_size(value: &Value, config: &ValidationConfig) -> Result<(), String> {
+    let size = serde_json::to_vec(value).map(|json| json.len()).unwrap_or(usize::MAX);
+    if size > config.max_event_bytes {
+        return Err(format!("{} bytes is larger than {}", size, config.max_event_bytes));
+    }
+    Ok(())
+}
+
+fn required_str<'a>(value: &'a Value, field: &str) -> Result<&'a str, String> {
+    match value.get(field) {
+        Some(Value::String(s)) => Ok(s),
+        Some(_) => Err(format!("{} must be a string", field)),
+        None => Err(format!("{} is missing", field)),
+    }
+}
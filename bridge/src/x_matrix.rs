@@ -0,0 +1,110 @@
+use anyhow::Result;
+use base64::Engine;
+use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+use std::collections::HashMap;
+
+use crate::matrix_keys;
+use crate::signer::Signer;
+
+/// Parsed `Authorization: X-Matrix ...` header, as sent by homeservers on
+/// server-server requests.
+#[derive(Debug, Clone, Default)]
+pub struct XMatrixAuth {
+    pub origin: Option<String>,
+    pub destination: Option<String>,
+    pub key: Option<String>,
+    pub sig: Option<String>,
+}
+
+/// Parse an `X-Matrix` authorization header value. Returns `None` for other schemes.
+pub fn parse_authorization(header: &str) -> Option<XMatrixAuth> {
+    let params = header.trim().strip_prefix("X-Matrix")?.trim();
+
+    let mut values: HashMap<String, String> = HashMap::new();
+    for param in params.split(',') {
+        let Some((name, value)) = param.split_once('=') else {
+            continue;
+        };
+        let value = value.trim().trim_matches('"').to_string();
+        values.insert(name.trim().to_ascii_lowercase(), value);
+    }
+
+    Some(XMatrixAuth {
+        origin: values.remove("origin"),
+        destination: values.remove("destination"),
+        key: values.remove("key"),
+        sig: values.remove("sig"),
+    })
+}
+
+/// Check the signature in `auth` over a request to `uri`, made with `key`,
+/// the origin's key named in the header.
+pub fn verify_request(
+    auth: &XMatrixAuth,
+    key: &VerifyingKey,
+    method: &str,
+    uri: &str,
+    content: Option<&serde_json::Value>,
+) -> bool {
+    let (Some(origin), Some(sig)) = (&auth.origin, &auth.sig) else {
+        return false;
+    };
+    let mut request = serde_json::json!({
+        "method": method,
+        "uri": uri,
+        "origin": origin,
+    });
+    if let Some(destination) = &auth.destination {
+        request["destination"] = serde_json::json!(destination);
+    }
+    if let Some(content) = content {
+        request["content"] = content.clone();
+    }
+
+    let Ok(canonical) = serde_json::to_string(&request) else {
+        return false;
+    };
+    let engine = base64::engine::general_purpose::STANDARD_NO_PAD;
+    let Ok(signature) = engine.decode(sig.trim_end_matches('=')) else {
+        return false;
+    };
+    let Ok(signature) = Signature::from_slice(&signature) else {
+        return false;
+    };
+    key.verify(canonical.as_bytes(), &signature).is_ok()
+}
+
+/// Build the `Authorization` header value for a server-server request from
+/// `origin` to `destination`, signed by `signer`.
+pub async fn sign_request(
+    signer: &dyn Signer,
+    origin: &str,
+    destination: &str,
+    method: &str,
+    uri: &str,
+    content: Option<&serde_json::Value>,
+) -> Result<String> {
+    let mut request = serde_json::json!({
+        "method": method,
+        "uri": uri,
+        "origin": origin,
+        "destination": destination,
+    });
+    if let Some(content) = content {
+        request["content"] = content.clone();
+    }
+
+    // serde_json sorts object keys and `to_string` is compact, which gives
+    // Matrix canonical JSON
+    let canonical = serde_json::to_string(&request)?;
+    let signature = signer.sign(canonical.as_bytes()).await?;
+    let key_id = matrix_keys::key_id(&signer.verifying_key());
+
+    Ok(format!(
+        "X-Matrix origin=\"{}\",destination=\"{}\",key=\"{}\",sig=\"{}\"",
+        origin,
+        destination,
+        key_id,
+        base64::engine::general_purpose::STANDARD_NO_PAD.encode(signature.to_bytes())
+    ))
+}
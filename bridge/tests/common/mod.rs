@@ -0,0 +1,188 @@
+//! Runs several bridges in one process over a [`MemoryNetwork`], for tests
+//! of how they behave together.
+
+#![allow(dead_code)]
+
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+use base64::Engine;
+use ed25519_dalek::{Signer, SigningKey};
+use matrix_mycelium_bridge::config::{BridgeConfig, CapacityProviderKind, PeerConfig};
+use matrix_mycelium_bridge::memory_transport::MemoryNetwork;
+use matrix_mycelium_bridge::signer;
+use matrix_mycelium_bridge::types::{FederationEvent, ServerAnnouncement, ServerCapacity, ServerStatus};
+use matrix_mycelium_bridge::{BridgeHandle, MatrixMyceliumBridge};
+
+/// How often `run_until` checks its condition.
+const CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Config for a bridge named `server_name` on a memory network, whose node
+/// address is its server name, with its key and server directory saved under
+/// a fresh temporary directory.
+pub fn config(server_name: &str, signing_key: &SigningKey) -> BridgeConfig {
+    let directory = std::env::temp_dir().join(format!("bridge-test-{}", uuid::Uuid::new_v4()));
+    let signing_key_path = directory.join("signing.key").to_string_lossy().into_owned();
+    signer::write_keypair(&signing_key_path, signing_key).unwrap();
+
+    let mut config = BridgeConfig {
+        server_name: server_name.to_string(),
+        bind_address: "127.0.0.1:0".to_string(),
+        signing_key_path,
+        ..BridgeConfig::default()
+    };
+    config.persistence.directory_path = Some(directory.join("directory.json").to_string_lossy().into_owned());
+    config.capacity.provider = CapacityProviderKind::Static;
+    config.mycelium.poll_timeout_seconds = 1;
+    config
+}
+
+/// `server_name` as a statically configured peer.
+pub fn peer(server_name: &str, signing_key: &SigningKey) -> PeerConfig {
+    PeerConfig {
+        server_name: server_name.to_string(),
+        mycelium_address: server_name.to_string(),
+        public_key: signer::encode_public_key(&signing_key.verifying_key()),
+        capabilities: Vec::new(),
+    }
+}
+
+/// An announcement of `server_name` at `mycelium_address`, made at
+/// `timestamp` and signed with `signing_key`.
+pub fn announcement(
+    server_name: &str,
+    mycelium_address: &str,
+    signing_key: &SigningKey,
+    timestamp: chrono::DateTime<chrono::Utc>,
+) -> ServerAnnouncement {
+    let mut announcement = ServerAnnouncement {
+        server_name: server_name.to_string(),
+        mycelium_address: mycelium_address.to_string(),
+        public_key: signer::encode_public_key(&signing_key.verifying_key()),
+        capabilities: Vec::new(),
+        capacity: ServerCapacity {
+            max_users: 100,
+            current_users: 0,
+            available: true,
+            monthly_active_users: None,
+        },
+        timestamp: timestamp.to_rfc3339(),
+        signature: String::new(),
+        previous_key: None,
+        previous_signature: None,
+    };
+    let signature = signing_key.sign(serde_json::to_string(&announcement).unwrap().as_bytes());
+    announcement.signature = base64::engine::general_purpose::STANDARD.encode(signature.to_bytes());
+    announcement
+}
+
+/// A PDU from `origin` in a room created there.
+pub fn pdu(origin: &str, body: &str) -> serde_json::Value {
+    serde_json::json!({
+        "event_id": format!("${}", uuid::Uuid::new_v4().simple()),
+        "room_id": format!("!room:{}", origin),
+        "sender": format!("@alice:{}", origin),
+        "type": "m.room.message",
+        "origin_server_ts": 0,
+        "content": { "msgtype": "m.text", "body": body },
+    })
+}
+
+/// Bridges on one memory network, which find each other by announcing to
+/// every other bridge. Inbound events are streamed rather than delivered to
+/// a homeserver, so tests can watch them arrive.
+pub struct Simulation {
+    pub network: MemoryNetwork,
+    bridges: Vec<(String, BridgeHandle)>,
+}
+
+impl Simulation {
+    /// Start a bridge on `network` for each of `server_names`, after
+    /// `configure` has had its say on each config.
+    pub async fn start(
+        network: MemoryNetwork,
+        server_names: &[&str],
+        configure: impl Fn(&mut BridgeConfig),
+    ) -> Self {
+        // Every node is on the network before the first bridge announces
+        let transports: Vec<_> = server_names.iter().map(|name| network.transport(name)).collect();
+        let mut bridges = Vec::new();
+        for (server_name, transport) in server_names.iter().zip(transports) {
+            let mut config = config(server_name, &signer::generate_keypair());
+            config.mycelium.announce_peers = server_names
+                .iter()
+                .filter(|other| *other != server_name)
+                .map(|other| other.to_string())
+                .collect();
+            config.stream.enabled = true;
+            config.stream.exclusive = true;
+            configure(&mut config);
+
+            let bridge = MatrixMyceliumBridge::with_mycelium(config, Arc::new(transport)).await.unwrap();
+            bridges.push((server_name.to_string(), bridge.start().await.unwrap()));
+        }
+        Self { network, bridges }
+    }
+
+    pub fn bridge(&self, server_name: &str) -> &MatrixMyceliumBridge {
+        self.bridges
+            .iter()
+            .find(|(name, _)| name == server_name)
+            .map(|(_, handle)| handle.bridge())
+            .unwrap_or_else(|| panic!("no bridge {}", server_name))
+    }
+
+    /// Let the simulation run until `condition` holds, for at most `limit`.
+    /// Returns whether it held.
+    pub async fn run_until<F, Fut>(&self, limit: Duration, mut condition: F) -> bool
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = bool>,
+    {
+        let deadline = tokio::time::Instant::now() + limit;
+        while tokio::time::Instant::now() < deadline {
+            if condition().await {
+                return true;
+            }
+            tokio::time::sleep(CHECK_INTERVAL).await;
+        }
+        condition().await
+    }
+
+    /// Whether every bridge has every other one in its directory, online.
+    pub async fn directories_converged(&self) -> bool {
+        for (server_name, handle) in &self.bridges {
+            let directory = handle.bridge().directory().await;
+            for (other, _) in &self.bridges {
+                let online = directory
+                    .iter()
+                    .any(|server| &server.server_name == other && server.status == ServerStatus::Online);
+                if other != server_name && !online {
+                    return false;
+                }
+            }
+        }
+        true
+    }
+
+    /// Send a PDU from `origin` to `destination`.
+    pub async fn send(&self, origin: &str, destination: &str, pdu: serde_json::Value) {
+        let event = FederationEvent {
+            destination: destination.to_string(),
+            event_type: "m.room.message".to_string(),
+            event_data: pdu,
+            expires_at: None,
+        };
+        self.bridge(origin).send_federation_event(event).await.unwrap();
+    }
+
+    pub async fn stop(self) {
+        for (_, handle) in &self.bridges {
+            handle.shutdown();
+        }
+        for (_, handle) in self.bridges {
+            handle.join().await.unwrap();
+        }
+    }
+}
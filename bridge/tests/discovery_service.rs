@@ -0,0 +1,184 @@
+mod common;
+
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::http::{header::AUTHORIZATION, HeaderMap, StatusCode};
+use axum::routing::post;
+use axum::{Json, Router};
+use matrix_mycelium_bridge::memory_transport::MemoryNetwork;
+use matrix_mycelium_bridge::mycelium::{Destination, FederationTransport};
+use matrix_mycelium_bridge::types::{
+    AddressChallenge, AddressProof, Deregistration, Heartbeat, RegisterRequest,
+    ADDRESS_CHALLENGE_MESSAGE_TYPE,
+};
+use matrix_mycelium_bridge::{signer, verify_signature, BridgeError, MatrixMyceliumBridge, DISCOVERY_TOPIC};
+use tokio::sync::mpsc;
+
+/// What the bridge sent the discovery service.
+#[derive(Debug)]
+enum Request {
+    /// With the bearer token it carried.
+    Register(RegisterRequest, Option<String>),
+    Heartbeat(Heartbeat),
+    Verify(AddressProof),
+    Deregister(Deregistration),
+}
+
+/// A discovery service accepting everything but the heartbeat numbered
+/// `forgotten_heartbeat`, which it answers as if it didn't know the server.
+async fn discovery_service(forgotten_heartbeat: u32) -> (String, mpsc::UnboundedReceiver<Request>) {
+    let (requests, received) = mpsc::unbounded_channel();
+    let heartbeats = Arc::new(AtomicU32::new(0));
+    let (register, heartbeat, verify, deregister) =
+        (requests.clone(), requests.clone(), requests.clone(), requests);
+    let router = Router::new()
+        .route(
+            "/servers/register",
+            post(move |headers: HeaderMap, Json(registration)| async move {
+                let api_key = headers
+                    .get(AUTHORIZATION)
+                    .and_then(|value| value.to_str().ok())
+                    .and_then(|value| value.strip_prefix("Bearer "))
+                    .map(str::to_string);
+                register.send(Request::Register(registration, api_key)).unwrap();
+                "{}"
+            }),
+        )
+        .route(
+            "/servers/:server_name/heartbeat",
+            post(move |Json(sent)| async move {
+                heartbeat.send(Request::Heartbeat(sent)).unwrap();
+                match heartbeats.fetch_add(1, Ordering::SeqCst) + 1 {
+                    n if n == forgotten_heartbeat => StatusCode::NOT_FOUND,
+                    _ => StatusCode::OK,
+                }
+            }),
+        )
+        .route(
+            "/servers/:server_name",
+            axum::routing::delete(move |Json(deregistration)| async move {
+                deregister.send(Request::Deregister(deregistration)).unwrap();
+                "{}"
+            }),
+        )
+        .route(
+            "/servers/verify",
+            post(move |Json(proof)| async move {
+                verify.send(Request::Verify(proof)).unwrap();
+                "{}"
+            }),
+        );
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+    (url, received)
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn bridge_answers_address_challenges_for_itself_only() {
+    let (url, mut requests) = discovery_service(0).await;
+    let signing_key = signer::generate_keypair();
+    let mut config = common::config("a.test", &signing_key);
+    config.discovery.service_url = Some(url);
+
+    let network = MemoryNetwork::new();
+    let bridge_transport = network.transport("a.test");
+    let discovery = network.transport("discovery.test");
+    for (server_name, nonce) in [("b.test", "not-ours"), ("a.test", "nonce")] {
+        let challenge = AddressChallenge {
+            message_type: ADDRESS_CHALLENGE_MESSAGE_TYPE.to_string(),
+            server_name: server_name.to_string(),
+            nonce: nonce.to_string(),
+        };
+        let payload = serde_json::to_vec(&challenge).unwrap();
+        discovery
+            .send_message(&Destination::parse("a.test"), DISCOVERY_TOPIC, &payload)
+            .await
+            .unwrap();
+    }
+
+    let bridge = MatrixMyceliumBridge::with_mycelium(config, Arc::new(bridge_transport)).await.unwrap();
+    let handle = bridge.start().await.unwrap();
+
+    let proof = loop {
+        match tokio::time::timeout(Duration::from_secs(10), requests.recv()).await.unwrap().unwrap() {
+            Request::Verify(proof) => break proof,
+            _ => continue,
+        }
+    };
+    assert_eq!(proof.server_name, "a.test");
+    assert_eq!(proof.nonce, "nonce");
+    let public_key = signer::encode_public_key(&signing_key.verifying_key());
+    assert!(verify_signature(&public_key, &proof.signing_payload().unwrap(), &proof.signature));
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    while let Ok(request) = requests.try_recv() {
+        assert!(!matches!(request, Request::Verify(_)), "{:?}", request);
+    }
+
+    bridge.request_shutdown();
+    handle.join().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn bridge_heartbeats_between_registrations() {
+    let (url, mut requests) = discovery_service(2).await;
+    let signing_key = signer::generate_keypair();
+    let mut config = common::config("a.test", &signing_key);
+    config.discovery.service_url = Some(url);
+    config.discovery.heartbeat_interval_seconds = 1;
+    config.discovery.api_key = Some("invite".to_string());
+
+    let transport = MemoryNetwork::new().transport("a.test");
+    let bridge = MatrixMyceliumBridge::with_mycelium(config, Arc::new(transport)).await.unwrap();
+    let handle = bridge.start().await.unwrap();
+
+    let mut sent = Vec::new();
+    while sent.len() < 5 {
+        sent.push(tokio::time::timeout(Duration::from_secs(10), requests.recv()).await.unwrap().unwrap());
+    }
+    // The second heartbeat finds the server forgotten, so it registers again
+    let kinds: Vec<_> = sent
+        .iter()
+        .map(|request| match request {
+            Request::Register(..) => "register",
+            Request::Heartbeat(_) => "heartbeat",
+            Request::Verify(_) => "verify",
+            Request::Deregister(_) => "deregister",
+        })
+        .collect();
+    assert_eq!(kinds, ["register", "heartbeat", "heartbeat", "register", "heartbeat"]);
+
+    let Request::Register(registration, api_key) = &sent[0] else { unreachable!() };
+    assert_eq!(registration.server_name, "a.test");
+    assert_eq!(api_key.as_deref(), Some("invite"));
+    let Request::Heartbeat(heartbeat) = &sent[1] else { unreachable!() };
+    assert_eq!(heartbeat.server_name, "a.test");
+    assert_eq!(heartbeat.ttl_seconds, 3);
+    let public_key = signer::encode_public_key(&signing_key.verifying_key());
+    assert!(verify_signature(&public_key, &heartbeat.signing_payload().unwrap(), &heartbeat.signature));
+
+    bridge.request_shutdown();
+    handle.join().await.unwrap();
+}
+
+#[tokio::test]
+async fn deregistration_is_signed_with_the_server_key() {
+    let (url, mut requests) = discovery_service(0).await;
+    let signing_key = signer::generate_keypair();
+    let mut config = common::config("a.test", &signing_key);
+    let transport = Arc::new(MemoryNetwork::new().transport("a.test"));
+    let bridge = MatrixMyceliumBridge::with_mycelium(config.clone(), transport.clone()).await.unwrap();
+    assert!(matches!(bridge.deregister_from_discovery().await, Err(BridgeError::Config(_))));
+
+    config.discovery.service_url = Some(url);
+    let bridge = MatrixMyceliumBridge::with_mycelium(config, transport).await.unwrap();
+    assert!(bridge.deregister_from_discovery().await.unwrap());
+
+    let Some(Request::Deregister(deregistration)) = requests.recv().await else { unreachable!() };
+    assert_eq!(deregistration.server_name, "a.test");
+    let public_key = signer::encode_public_key(&signing_key.verifying_key());
+    let payload = deregistration.signing_payload().unwrap();
+    assert!(verify_signature(&public_key, &payload, &deregistration.signature));
+}
@@ -0,0 +1,221 @@
+mod common;
+
+use std::sync::{Arc, Mutex};
+
+use axum::http::HeaderMap;
+use axum::routing::{get, put};
+use axum::{Json, Router};
+use matrix_mycelium_bridge::auth::{ApiToken, TokenScope};
+use matrix_mycelium_bridge::config::{BridgeConfig, HomeserverFlavor};
+use matrix_mycelium_bridge::memory_transport::MemoryNetwork;
+use matrix_mycelium_bridge::signer::{self, LocalSigner};
+use matrix_mycelium_bridge::types::FederationEvent;
+use matrix_mycelium_bridge::{matrix_keys, x_matrix, MatrixMyceliumBridge};
+use reqwest::StatusCode;
+
+const PATH: &str = "/_matrix/federation/v1/send/txn1";
+
+/// A homeserver that only publishes `signer`'s key.
+async fn serve_keys(server_name: &str, signer: &LocalSigner) -> String {
+    let keys = matrix_keys::server_keys_response(server_name, signer).await.unwrap();
+    let router = Router::new().route("/_matrix/key/v2/server", get(move || async move { Json(keys) }));
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+    format!("http://{}", address)
+}
+
+/// Send a transaction to the bridge at `url`, signed by `signer` as `origin`.
+async fn send(url: &str, signer: Option<(&LocalSigner, &str)>) -> StatusCode {
+    let transaction = serde_json::json!({ "origin": "a.test", "pdus": [], "edus": [] });
+    let mut request = reqwest::Client::new().put(url).json(&transaction);
+    if let Some((signer, origin)) = signer {
+        let authorization = x_matrix::sign_request(signer, origin, "b.test", "PUT", PATH, Some(&transaction))
+            .await
+            .unwrap();
+        request = request.header("Authorization", authorization);
+    }
+    request.send().await.unwrap().status()
+}
+
+#[tokio::test]
+async fn federation_endpoints_need_the_homeserver_signature() {
+    let homeserver = LocalSigner::new(signer::generate_keypair());
+    let forger = LocalSigner::new(signer::generate_keypair());
+    let mut config = common::config("a.test", &signer::generate_keypair());
+    config.matrix_homeserver_url = serve_keys("a.test", &homeserver).await;
+    let network = MemoryNetwork::new();
+    let bridge = MatrixMyceliumBridge::with_mycelium(config, Arc::new(network.transport("a.test")))
+        .await
+        .unwrap();
+    let handle = bridge.start().await.unwrap();
+    let url = format!("http://{}{}", handle.local_addr(), PATH);
+
+    assert_eq!(send(&url, None).await, StatusCode::UNAUTHORIZED);
+    assert_eq!(send(&url, Some((&forger, "a.test"))).await, StatusCode::UNAUTHORIZED);
+    assert_eq!(send(&url, Some((&homeserver, "c.test"))).await, StatusCode::FORBIDDEN);
+    let status = send(&url, Some((&homeserver, "a.test"))).await;
+    assert!(status != StatusCode::UNAUTHORIZED && status != StatusCode::FORBIDDEN, "{}", status);
+
+    handle.shutdown();
+    handle.join().await.unwrap();
+}
+
+/// The status of a GET of `path` from a bridge configured by `configure`,
+/// with `token` if given.
+async fn status_of(path: &str, configure: impl Fn(&mut BridgeConfig), token: Option<&str>) -> StatusCode {
+    let mut config = common::config("a.test", &signer::generate_keypair());
+    configure(&mut config);
+    let network = MemoryNetwork::new();
+    let bridge = MatrixMyceliumBridge::with_mycelium(config, Arc::new(network.transport("a.test")))
+        .await
+        .unwrap();
+    let handle = bridge.start().await.unwrap();
+    let url = format!("http://{}{}", handle.local_addr(), path);
+    let mut request = reqwest::Client::new().get(url);
+    if let Some(token) = token {
+        request = request.bearer_auth(token);
+    }
+    let status = request.send().await.unwrap().status();
+    handle.shutdown();
+    handle.join().await.unwrap();
+    status
+}
+
+#[tokio::test]
+async fn send_endpoints_need_a_token() {
+    let servers = "/federation/servers";
+    assert_eq!(status_of(servers, |_| {}, None).await, StatusCode::UNAUTHORIZED);
+    let open = |config: &mut BridgeConfig| config.auth.allow_unauthenticated = true;
+    assert_eq!(status_of(servers, open, None).await, StatusCode::OK);
+
+    let with_token = |config: &mut BridgeConfig| {
+        config.auth.tokens = vec![ApiToken {
+            name: "synapse".to_string(),
+            token: "secret".to_string(),
+            scopes: vec![TokenScope::Send],
+        }];
+    };
+    assert_eq!(status_of(servers, with_token, None).await, StatusCode::UNAUTHORIZED);
+    assert_eq!(status_of(servers, with_token, Some("secret")).await, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn stats_need_a_metrics_token() {
+    let with_tokens = |config: &mut BridgeConfig| {
+        let token = |name: &str, scope| ApiToken {
+            name: name.to_string(),
+            token: name.to_string(),
+            scopes: vec![scope],
+        };
+        config.auth.tokens = vec![token("synapse", TokenScope::Send), token("prometheus", TokenScope::Metrics)];
+    };
+    for path in ["/stats", "/metrics"] {
+        assert_eq!(status_of(path, |_| {}, None).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(status_of(path, with_tokens, None).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(status_of(path, with_tokens, Some("synapse")).await, StatusCode::FORBIDDEN);
+        assert_eq!(status_of(path, with_tokens, Some("prometheus")).await, StatusCode::OK);
+    }
+}
+
+#[tokio::test]
+async fn media_downloads_need_the_homeserver_signature() {
+    for path in ["/_matrix/media/v3/download/b.test/abc", "/_matrix/media/v3/download/b.test/abc/cat.png"] {
+        assert_eq!(status_of(path, |_| {}, None).await, StatusCode::UNAUTHORIZED);
+    }
+}
+
+#[tokio::test]
+async fn decentralized_directory_is_public() {
+    let decentralized = |config: &mut BridgeConfig| config.discovery.decentralized = true;
+    for path in ["/servers", "/servers/select", "/servers/a.test"] {
+        assert_eq!(status_of(path, decentralized, None).await, StatusCode::OK);
+    }
+}
+
+/// Transactions a homeserver received: the path, `Authorization` header and
+/// body of each.
+type Received = Arc<Mutex<Vec<(String, String, serde_json::Value)>>>;
+
+/// A homeserver that takes any transaction, keeping it in `received`.
+async fn serve_transactions(received: Received) -> String {
+    let router = Router::new().route(
+        "/_matrix/federation/v1/send/:txn_id",
+        put(move |uri: axum::http::Uri, headers: HeaderMap, Json(body): Json<serde_json::Value>| {
+            let received = received.clone();
+            async move {
+                let authorization = headers["authorization"].to_str().unwrap().to_string();
+                received.lock().unwrap().push((uri.path().to_string(), authorization, body));
+                Json(serde_json::json!({ "pdus": {} }))
+            }
+        }),
+    );
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+    format!("http://{}", address)
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn relayed_transactions_are_signed_as_their_origin() {
+    let network = MemoryNetwork::new();
+    let a_key = signer::generate_keypair();
+    let b_key = signer::generate_keypair();
+    let received = Received::default();
+
+    let mut a_config = common::config("a.test", &a_key);
+    a_config.peers = vec![common::peer("b.test", &b_key)];
+    a_config.homeserver.flavor = HomeserverFlavor::Conduit;
+    a_config.matrix_homeserver_url = serve_transactions(received.clone()).await;
+    let mut b_config = common::config("b.test", &b_key);
+    b_config.peers = vec![common::peer("a.test", &a_key)];
+    let a = MatrixMyceliumBridge::with_mycelium(a_config, Arc::new(network.transport("a.test")))
+        .await
+        .unwrap();
+    let b = MatrixMyceliumBridge::with_mycelium(b_config, Arc::new(network.transport("b.test")))
+        .await
+        .unwrap();
+    let a = a.start().await.unwrap();
+    let b = b.start().await.unwrap();
+
+    let event = FederationEvent {
+        destination: "a.test".to_string(),
+        event_type: "m.room.message".to_string(),
+        event_data: common::pdu("b.test", "hello"),
+        expires_at: None,
+    };
+    b.bridge().send_federation_event(event).await.unwrap();
+    for _ in 0..50 {
+        if !received.lock().unwrap().is_empty() {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    }
+    let (path, authorization, transaction) = received.lock().unwrap().first().cloned().unwrap();
+    assert_eq!(transaction["origin"], "b.test");
+    let auth = x_matrix::parse_authorization(&authorization).unwrap();
+    assert_eq!(auth.origin.as_deref(), Some("b.test"));
+
+    // The homeserver looks the key up at the bridge, by the origin's name
+    let keys: serde_json::Value = reqwest::Client::new()
+        .get(format!("http://{}/_matrix/key/v2/server", a.local_addr()))
+        .header("Host", "b.test")
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(keys["server_name"], "b.test");
+    let b_key_id = matrix_keys::key_id(&b_key.verifying_key());
+    assert_eq!(matrix_keys::verify_keys(&keys)[&b_key_id], b_key.verifying_key());
+    assert!(keys["signatures"].get("b.test").is_none());
+    assert!(keys["signatures"]["a.test"].is_object());
+    let key = matrix_keys::verify_keys(&keys)[auth.key.as_deref().unwrap()];
+    assert!(x_matrix::verify_request(&auth, &key, "PUT", &path, Some(&transaction)));
+
+    a.shutdown();
+    b.shutdown();
+    a.join().await.unwrap();
+    b.join().await.unwrap();
+}
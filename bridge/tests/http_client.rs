@@ -0,0 +1,103 @@
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+
+use axum::http::StatusCode;
+use axum::routing::{get, post};
+use axum::Router;
+use matrix_mycelium_bridge::config::HttpConfig;
+use matrix_mycelium_bridge::http_client::HttpClient;
+
+/// Serve `router` on a free local port, returning its URL.
+async fn serve(router: Router) -> String {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+    format!("http://{}", address)
+}
+
+fn config() -> HttpConfig {
+    HttpConfig {
+        retry_base_delay_ms: 10,
+        ..HttpConfig::default()
+    }
+}
+
+#[tokio::test]
+async fn retries_transient_failures_up_to_the_limit() {
+    let calls = Arc::new(AtomicU32::new(0));
+    let counter = calls.clone();
+    let url = serve(Router::new().route(
+        "/flaky",
+        post(move |body: String| async move {
+            assert_eq!(body, "{\"n\":1}");
+            match counter.fetch_add(1, Ordering::SeqCst) {
+                0 => StatusCode::SERVICE_UNAVAILABLE,
+                1 => StatusCode::TOO_MANY_REQUESTS,
+                _ => StatusCode::OK,
+            }
+        }),
+    ))
+    .await;
+
+    let client = HttpClient::new(&config()).unwrap();
+    let request = client.post(format!("{}/flaky", url)).json(&serde_json::json!({ "n": 1 }));
+    let response = client.send(request).await.unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(calls.load(Ordering::SeqCst), 3);
+
+    calls.store(0, Ordering::SeqCst);
+    let client = HttpClient::new(&HttpConfig {
+        max_retries: 1,
+        ..config()
+    })
+    .unwrap();
+    let request = client.post(format!("{}/flaky", url)).json(&serde_json::json!({ "n": 1 }));
+    let response = client.send(request).await.unwrap();
+    assert_eq!(response.status().as_u16(), 429);
+    assert_eq!(calls.load(Ordering::SeqCst), 2);
+}
+
+#[tokio::test]
+async fn does_not_retry_client_errors() {
+    let calls = Arc::new(AtomicU32::new(0));
+    let counter = calls.clone();
+    let url = serve(Router::new().route(
+        "/missing",
+        get(move || async move {
+            counter.fetch_add(1, Ordering::SeqCst);
+            StatusCode::NOT_FOUND
+        }),
+    ))
+    .await;
+
+    let client = HttpClient::new(&config()).unwrap();
+    let response = client.send(client.get(format!("{}/missing", url))).await.unwrap();
+    assert_eq!(response.status().as_u16(), 404);
+    assert_eq!(calls.load(Ordering::SeqCst), 1);
+}
+
+#[tokio::test]
+async fn requests_go_through_the_proxy_except_for_no_proxy_hosts() {
+    // A plain HTTP proxy is sent the absolute URL in the request line
+    let proxied = Arc::new(AtomicU32::new(0));
+    let counter = proxied.clone();
+    let proxy = serve(Router::new().fallback(move |uri: axum::http::Uri| async move {
+        assert_eq!(uri.host(), Some("homeserver.invalid"));
+        counter.fetch_add(1, Ordering::SeqCst);
+        "proxied"
+    }))
+    .await;
+    let direct = serve(Router::new().route("/", get(|| async { "direct" }))).await;
+
+    let client = HttpClient::new(&HttpConfig {
+        proxy: Some(proxy),
+        no_proxy: vec!["127.0.0.1".to_string()],
+        ..config()
+    })
+    .unwrap();
+    let response = client.send(client.get("http://homeserver.invalid/")).await.unwrap();
+    assert_eq!(response.text().await.unwrap(), "proxied");
+    let response = client.send(client.get(&direct)).await.unwrap();
+    assert_eq!(response.text().await.unwrap(), "direct");
+    assert_eq!(proxied.load(Ordering::SeqCst), 1);
+}
@@ -0,0 +1,44 @@
+use std::collections::BTreeMap;
+
+use matrix_mycelium_bridge::config::{BridgeConfig, LogRotation, LoggingConfig};
+use matrix_mycelium_bridge::telemetry;
+
+// The subscriber is global, so everything is checked in a single test.
+#[test]
+fn file_sink_applies_module_levels_and_rotates_by_size() {
+    std::env::remove_var("RUST_LOG");
+    let directory = std::env::temp_dir().join(format!("bridge-test-{}", uuid::Uuid::new_v4()));
+    let path = directory.join("logs").join("bridge.log");
+    let config = BridgeConfig {
+        logging: LoggingConfig {
+            modules: BTreeMap::from([("matrix_mycelium_bridge::discovery".to_string(), "debug".to_string())]),
+            stdout: false,
+            file_path: Some(path.to_string_lossy().into_owned()),
+            file_max_size_mb: 1,
+            file_rotation: LogRotation::Never,
+            file_max_files: 1,
+            ..LoggingConfig::default()
+        },
+        ..BridgeConfig::default()
+    };
+    config.validate().unwrap();
+
+    let guard = telemetry::init(&config).unwrap();
+    // About 3 MB, for two rotations of which only the newest file is kept
+    for n in 0..20_000 {
+        tracing::info!(target: "matrix_mycelium_bridge::homeserver", "filler line {} {}", n, "x".repeat(80));
+    }
+    tracing::debug!(target: "matrix_mycelium_bridge::discovery", "discovery detail");
+    tracing::debug!(target: "matrix_mycelium_bridge::homeserver", "homeserver detail");
+    tracing::info!(target: "matrix_mycelium_bridge::homeserver", "homeserver summary");
+    drop(guard);
+
+    let log = std::fs::read_to_string(&path).unwrap();
+    assert!(log.contains("discovery detail"));
+    assert!(!log.contains("homeserver detail"));
+    assert!(log.contains("homeserver summary"));
+    assert!(!log.contains('\x1b'));
+    assert!(path.with_extension("log.1").exists());
+    assert!(!path.with_extension("log.2").exists());
+    std::fs::remove_dir_all(directory).unwrap();
+}
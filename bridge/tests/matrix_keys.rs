@@ -0,0 +1,46 @@
+use base64::Engine;
+use matrix_mycelium_bridge::matrix_keys;
+use matrix_mycelium_bridge::signer::{self, LocalSigner, Signer};
+
+/// `event` with a signature by `server_name` added, made as a homeserver
+/// would over the redacted event.
+async fn sign_event(event: &serde_json::Value, server_name: &str, signer: &LocalSigner) -> serde_json::Value {
+    let mut redacted = matrix_keys::redact(event, "10");
+    redacted.as_object_mut().unwrap().remove("signatures");
+    let signature = signer.sign(serde_json::to_string(&redacted).unwrap().as_bytes()).await.unwrap();
+    let key_id = matrix_keys::key_id(&signer.verifying_key());
+    let mut signed = event.clone();
+    signed["signatures"][server_name][key_id] =
+        base64::engine::general_purpose::STANDARD_NO_PAD.encode(signature.to_bytes()).into();
+    signed
+}
+
+#[tokio::test]
+async fn event_signatures_cover_the_redacted_event() {
+    let signer = LocalSigner::new(signer::generate_keypair());
+    let key = signer.verifying_key();
+    let invite = serde_json::json!({
+        "type": "m.room.member",
+        "room_id": "!room:a.test",
+        "sender": "@alice:a.test",
+        "state_key": "@bob:b.test",
+        "origin_server_ts": 0,
+        "content": { "membership": "invite", "displayname": "Bob" },
+        "signatures": { "a.test": { "ed25519:a": "c2ln" } },
+    });
+    let signed = sign_event(&invite, "b.test", &signer).await;
+    assert!(matrix_keys::verify_event(&signed, "10", "b.test", &key));
+
+    // Content that redaction strips isn't covered
+    let mut renamed = signed.clone();
+    renamed["content"]["displayname"] = "Mallory".into();
+    renamed["unsigned"] = serde_json::json!({ "age": 5 });
+    assert!(matrix_keys::verify_event(&renamed, "10", "b.test", &key));
+
+    let mut joined = signed.clone();
+    joined["content"]["membership"] = "join".into();
+    assert!(!matrix_keys::verify_event(&joined, "10", "b.test", &key));
+    assert!(!matrix_keys::verify_event(&signed, "10", "a.test", &key));
+    let other = signer::generate_keypair().verifying_key();
+    assert!(!matrix_keys::verify_event(&signed, "10", "b.test", &other));
+}
@@ -0,0 +1,97 @@
+use base64::Engine;
+use matrix_mycelium_bridge::config::MediaConfig;
+use matrix_mycelium_bridge::media::{self, MediaAssembler, MediaCache, MediaChunk, MediaFile};
+
+fn chunk(index: u32, total: u32, data: &[u8]) -> MediaChunk {
+    MediaChunk {
+        request_id: "request".to_string(),
+        index,
+        total,
+        content_type: None,
+        sha256: media::sha256_hex(data),
+        file_sha256: media::sha256_hex(data),
+        data: base64::engine::general_purpose::STANDARD.encode(data),
+        error: None,
+    }
+}
+
+#[tokio::test]
+async fn chunks_reassemble_in_any_order() {
+    let file = MediaFile {
+        content_type: Some("image/png".to_string()),
+        data: (0..=255).collect(),
+    };
+    let assembler = MediaAssembler::new(1024);
+    let done = assembler.register("request", "origin.test").await;
+    for chunk in media::split_into_chunks("request", &file, 10).into_iter().rev() {
+        assembler.add_chunk("origin.test", chunk).await;
+    }
+
+    let assembled = done.await.unwrap().unwrap();
+    assert_eq!(assembled.data, file.data);
+    assert_eq!(assembled.content_type, file.content_type);
+}
+
+#[tokio::test]
+async fn chunk_counts_beyond_the_size_limit_are_rejected() {
+    let assembler = MediaAssembler::new(1024);
+    let done = assembler.register("request", "origin.test").await;
+    assembler.add_chunk("origin.test", chunk(0, u32::MAX, b"x")).await;
+    assert!(done.await.unwrap().is_err());
+}
+
+#[tokio::test]
+async fn empty_chunks_of_a_split_file_are_rejected() {
+    let assembler = MediaAssembler::new(1024);
+    let done = assembler.register("request", "origin.test").await;
+    assembler.add_chunk("origin.test", chunk(0, 2, b"")).await;
+    assert!(done.await.unwrap().is_err());
+}
+
+#[tokio::test]
+async fn the_media_cache_keeps_whole_files_and_clears_leftovers() {
+    let directory = std::env::temp_dir().join(format!("bridge-test-{}", uuid::Uuid::new_v4()));
+    let config = MediaConfig {
+        cache_dir: directory.to_string_lossy().into_owned(),
+        max_cache_bytes: 10,
+        ..MediaConfig::default()
+    };
+    let cache = MediaCache::new(&config);
+    let file = |data: &[u8]| MediaFile {
+        content_type: Some("image/png".to_string()),
+        data: data.to_vec(),
+    };
+
+    // Left by a write cut short
+    std::fs::create_dir_all(&directory).unwrap();
+    std::fs::write(directory.join("orphan.meta"), b"{}").unwrap();
+    std::fs::write(directory.join("partial.tmp"), b"half").unwrap();
+
+    let (files, ids) = ([file(&[0; 4]), file(&[1; 4]), file(&[2; 4])], ["media0", "media1", "media2"]);
+    let written = tokio::join!(
+        cache.put("a.test", ids[0], &files[0]),
+        cache.put("a.test", ids[1], &files[1]),
+        cache.put("a.test", ids[2], &files[2]),
+    );
+    written.0.unwrap();
+    written.1.unwrap();
+    written.2.unwrap();
+
+    let mut names: Vec<String> = std::fs::read_dir(&directory)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+        .collect();
+    names.sort();
+    assert_eq!(names.len(), 4, "{:?}", names);
+    assert_eq!(names.iter().filter(|name| name.ends_with(".meta")).count(), 2, "{:?}", names);
+    let mut kept = 0;
+    for (id, file) in ids.iter().zip(&files) {
+        if let Some(cached) = cache.get("a.test", id).await {
+            assert_eq!(cached.data, file.data);
+            assert_eq!(cached.content_type, file.content_type);
+            kept += 1;
+        }
+    }
+    assert_eq!(kept, 2);
+    std::fs::remove_dir_all(&directory).unwrap();
+}
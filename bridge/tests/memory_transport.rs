@@ -0,0 +1,78 @@
+mod common;
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use common::{config, peer};
+use matrix_mycelium_bridge::memory_transport::MemoryNetwork;
+use matrix_mycelium_bridge::mycelium::{Destination, FederationTransport};
+use matrix_mycelium_bridge::signer;
+use matrix_mycelium_bridge::MatrixMyceliumBridge;
+
+#[tokio::test]
+async fn transports_deliver_by_address_and_topic() {
+    let network = MemoryNetwork::new();
+    let alice = network.transport("alice");
+    let bob = network.transport("bob");
+
+    let to_bob = Destination::parse("bob");
+    alice.send_message(&to_bob, "chat", b"hello").await.unwrap();
+    assert!(bob.receive_messages("other", 0).await.unwrap().is_empty());
+
+    let received = bob.receive_messages("chat", 0).await.unwrap();
+    assert_eq!(received.len(), 1);
+    assert_eq!(received[0].payload, b"hello");
+    assert_eq!(received[0].source_public_key.as_deref(), Some("alice"));
+
+    let message_id = received[0].id.as_deref().unwrap();
+    bob.reply(message_id, "chat", b"hi").await.unwrap();
+    assert_eq!(alice.receive_messages("chat", 0).await.unwrap()[0].payload, b"hi");
+
+    let to_nobody = Destination::parse("carol");
+    assert!(alice.send_message(&to_nobody, "chat", b"hello").await.is_err());
+}
+
+#[tokio::test]
+async fn receive_waits_for_a_message() {
+    let network = MemoryNetwork::new();
+    let alice = network.transport("alice");
+    let bob = Arc::new(network.transport("bob"));
+
+    let receiver = bob.clone();
+    let received = tokio::spawn(async move { receiver.receive_messages("chat", 5).await });
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    alice.send_message(&Destination::parse("bob"), "chat", b"hello").await.unwrap();
+
+    let received = tokio::time::timeout(Duration::from_secs(1), received).await.unwrap();
+    assert_eq!(received.unwrap().unwrap().len(), 1);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn bridges_ping_each_other_over_memory_network() {
+    let network = MemoryNetwork::new();
+    let alice_key = signer::generate_keypair();
+    let bob_key = signer::generate_keypair();
+
+    let mut alice_config = config("alice", &alice_key);
+    alice_config.peers = vec![peer("bob", &bob_key)];
+    let mut bob_config = config("bob", &bob_key);
+    bob_config.peers = vec![peer("alice", &alice_key)];
+
+    let alice = MatrixMyceliumBridge::with_mycelium(alice_config, Arc::new(network.transport("alice")))
+        .await
+        .unwrap();
+    let bob = MatrixMyceliumBridge::with_mycelium(bob_config, Arc::new(network.transport("bob")))
+        .await
+        .unwrap();
+    let alice = alice.start().await.unwrap();
+    let bob = bob.start().await.unwrap();
+
+    let rtt = alice.bridge().ping("bob", Duration::from_secs(5)).await.unwrap();
+    assert!(rtt < Duration::from_secs(5));
+    bob.bridge().ping("alice", Duration::from_secs(5)).await.unwrap();
+
+    alice.shutdown();
+    bob.shutdown();
+    alice.join().await.unwrap();
+    bob.join().await.unwrap();
+}
@@ -0,0 +1,14 @@
+use matrix_mycelium_bridge::queries::{BACKFILL, PROFILE_QUERY};
+
+#[test]
+fn query_paths_stay_under_their_prefix() {
+    assert!(BACKFILL.allows_path("/_matrix/federation/v1/backfill/!room:a.test?v=$e&limit=10"));
+    assert!(PROFILE_QUERY.allows_path("/_matrix/federation/v1/query/profile?user_id=@u:a.test"));
+    assert!(PROFILE_QUERY.allows_path("/_matrix/federation/v1/query/profile"));
+
+    assert!(!PROFILE_QUERY.allows_path("/_matrix/federation/v1/query/profileX"));
+    assert!(!BACKFILL.allows_path("/_matrix/federation/v1/send/txn"));
+    assert!(!BACKFILL.allows_path("/_matrix/federation/v1/backfill/../send/txn"));
+    assert!(!BACKFILL.allows_path("/_matrix/federation/v1/backfill/%2e%2e/send/txn"));
+    assert!(!BACKFILL.allows_path("/_matrix/federation/v1/backfill/%2E./send/txn"));
+}
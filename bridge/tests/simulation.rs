@@ -0,0 +1,303 @@
+mod common;
+
+use std::collections::HashSet;
+use std::time::Duration;
+
+use base64::Engine;
+use common::{announcement, pdu, Simulation};
+use ed25519_dalek::{Signer, SigningKey};
+use matrix_mycelium_bridge::memory_transport::MemoryNetwork;
+use matrix_mycelium_bridge::mycelium::{Destination, FederationTransport};
+use matrix_mycelium_bridge::protocol::ProtocolVersion;
+use matrix_mycelium_bridge::types::{MyceliumMessage, ServerInfo};
+use matrix_mycelium_bridge::{edu, signer, BridgeError, DISCOVERY_TOPIC};
+
+const MINUTE: Duration = Duration::from_secs(60);
+
+#[tokio::test(start_paused = true)]
+async fn directories_converge_despite_latency_and_loss() {
+    let network = MemoryNetwork::new();
+    network.seed(7);
+    network.set_latency(Duration::from_millis(200), Duration::from_millis(300));
+    network.set_loss(0.2);
+    let server_names = ["a.test", "b.test", "c.test", "d.test", "e.test"];
+    let simulation = Simulation::start(network, &server_names, |_| {}).await;
+
+    let converged = simulation
+        .run_until(30 * MINUTE, || simulation.directories_converged())
+        .await;
+    assert!(converged, "directories did not converge");
+    assert!(simulation.network.dropped() > 0);
+    simulation.stop().await;
+}
+
+#[tokio::test(start_paused = true)]
+async fn events_are_delivered_over_jittery_links() {
+    let network = MemoryNetwork::new();
+    network.set_latency(Duration::from_millis(50), Duration::from_millis(500));
+    let simulation = Simulation::start(network, &["a.test", "b.test", "c.test"], |_| {}).await;
+    assert!(simulation.run_until(5 * MINUTE, || simulation.directories_converged()).await);
+
+    let mut events = simulation.bridge("b.test").subscribe_events().unwrap();
+    let mut sent = HashSet::new();
+    for n in 0..20 {
+        let pdu = pdu("a.test", &format!("message {}", n));
+        sent.insert(pdu["event_id"].as_str().unwrap().to_string());
+        simulation.send("a.test", "b.test", pdu).await;
+    }
+
+    let mut received = HashSet::new();
+    while received.len() < sent.len() {
+        let event = tokio::time::timeout(MINUTE, events.recv()).await.unwrap().unwrap();
+        assert_eq!(event.origin, "a.test");
+        received.insert(event.event["event_id"].as_str().unwrap().to_string());
+    }
+    assert_eq!(received, sent);
+    simulation.stop().await;
+}
+
+#[tokio::test(start_paused = true)]
+async fn transactions_are_relayed_in_order() {
+    let simulation = Simulation::start(MemoryNetwork::new(), &["a.test", "b.test"], |_| {}).await;
+    assert!(simulation.run_until(5 * MINUTE, || simulation.directories_converged()).await);
+
+    let mut events = simulation.bridge("b.test").subscribe_events().unwrap();
+    let pdus: Vec<_> = (0..20).map(|n| pdu("a.test", &format!("message {}", n))).collect();
+    let sent: Vec<_> = pdus.iter().map(|pdu| pdu["event_id"].as_str().unwrap().to_string()).collect();
+    let transaction = serde_json::json!({ "pdus": pdus, "edus": [] });
+    let results = simulation
+        .bridge("a.test")
+        .relay_matrix_transaction("txn1", "b.test", transaction)
+        .await
+        .unwrap();
+    assert_eq!(results["pdus"].as_object().unwrap().len(), sent.len());
+
+    let mut received = Vec::new();
+    while received.len() < sent.len() {
+        let event = tokio::time::timeout(MINUTE, events.recv()).await.unwrap().unwrap();
+        received.push(event.event["event_id"].as_str().unwrap().to_string());
+    }
+    assert_eq!(received, sent);
+    simulation.stop().await;
+}
+
+#[tokio::test(start_paused = true)]
+async fn partitioned_bridges_reach_each_other_after_healing() {
+    let server_names = ["a.test", "b.test", "c.test", "d.test"];
+    let simulation = Simulation::start(MemoryNetwork::new(), &server_names, |_| {}).await;
+    assert!(simulation.run_until(5 * MINUTE, || simulation.directories_converged()).await);
+
+    simulation.network.partition(&["a.test", "b.test"], &["c.test", "d.test"]);
+    let timeout = Duration::from_secs(5);
+    let ping = simulation.bridge("a.test").ping("c.test", timeout).await;
+    assert!(matches!(ping, Err(BridgeError::Timeout(_))), "{:?}", ping);
+    simulation.bridge("a.test").ping("b.test", timeout).await.unwrap();
+
+    simulation.network.heal();
+    simulation.bridge("a.test").ping("c.test", timeout).await.unwrap();
+
+    let mut events = simulation.bridge("d.test").subscribe_events().unwrap();
+    simulation.send("b.test", "d.test", pdu("b.test", "hello again")).await;
+    let event = tokio::time::timeout(MINUTE, events.recv()).await.unwrap().unwrap();
+    assert_eq!(event.event["content"]["body"], "hello again");
+    simulation.stop().await;
+}
+
+#[tokio::test(start_paused = true)]
+async fn bridges_reannounce_when_their_node_restarts() {
+    let simulation = Simulation::start(MemoryNetwork::new(), &["a.test", "b.test"], |config| {
+        config.discovery.announce_interval_seconds = 3600;
+        config.mycelium.health_check_interval_seconds = 5;
+    })
+    .await;
+    assert!(simulation.run_until(5 * MINUTE, || simulation.directories_converged()).await);
+
+    let last_seen = || async {
+        let directory = simulation.bridge("a.test").directory().await;
+        directory.into_iter().find(|server| server.server_name == "b.test").unwrap().last_seen
+    };
+    let announced = last_seen().await;
+    tokio::time::sleep(MINUTE).await;
+    assert_eq!(last_seen().await, announced, "b.test announced without a restart");
+
+    simulation.network.restart("b.test");
+    let reannounced = simulation
+        .run_until(MINUTE, || async { last_seen().await > announced })
+        .await;
+    assert!(reannounced, "b.test did not re-announce after its node restarted");
+    simulation.stop().await;
+}
+
+async fn entry(simulation: &Simulation, server_name: &str, of: &str) -> Option<ServerInfo> {
+    let directory = simulation.bridge(server_name).directory().await;
+    directory.into_iter().find(|server| server.server_name == of)
+}
+
+#[tokio::test(start_paused = true)]
+async fn key_changes_without_a_countersignature_are_refused() {
+    let simulation = Simulation::start(MemoryNetwork::new(), &["a.test", "b.test"], |_| {}).await;
+    assert!(simulation.run_until(5 * MINUTE, || simulation.directories_converged()).await);
+    let held = entry(&simulation, "a.test", "b.test").await.unwrap();
+
+    let mallory = simulation.network.transport("mallory");
+    let key = signer::generate_keypair();
+    let now = chrono::Utc::now();
+    for server_name in ["b.test", "m.test"] {
+        let announcement = announcement(server_name, "mallory", &key, now);
+        let data = serde_json::to_vec(&announcement).unwrap();
+        mallory
+            .send_message(&Destination::parse("a.test"), DISCOVERY_TOPIC, &data)
+            .await
+            .unwrap();
+    }
+
+    let admitted = simulation
+        .run_until(5 * MINUTE, || async { entry(&simulation, "a.test", "m.test").await.is_some() })
+        .await;
+    assert!(admitted, "a.test did not take in a new server from mallory");
+    let current = entry(&simulation, "a.test", "b.test").await.unwrap();
+    assert_eq!(current.public_key, held.public_key);
+    assert_eq!(current.mycelium_address, "b.test");
+    simulation.stop().await;
+}
+
+#[tokio::test(start_paused = true)]
+async fn announcements_dated_in_the_future_are_refused() {
+    let simulation = Simulation::start(MemoryNetwork::new(), &["a.test"], |_| {}).await;
+    let mallory = simulation.network.transport("mallory");
+    let key = signer::generate_keypair();
+    let announce = |server_name, timestamp| {
+        let data = serde_json::to_vec(&announcement(server_name, "mallory", &key, timestamp)).unwrap();
+        let mallory = &mallory;
+        async move {
+            let to_a = Destination::parse("a.test");
+            mallory.send_message(&to_a, DISCOVERY_TOPIC, &data).await.unwrap();
+        }
+    };
+    let known = |server_name| {
+        let simulation = &simulation;
+        async move { entry(simulation, "a.test", server_name).await.is_some() }
+    };
+
+    let now = chrono::Utc::now();
+    announce("m.test", now + chrono::Duration::days(365)).await;
+    announce("n.test", now).await;
+    assert!(simulation.run_until(5 * MINUTE, || known("n.test")).await);
+    assert!(!known("m.test").await, "an announcement dated next year was taken in");
+
+    // It doesn't outrank the real announcements that follow
+    announce("m.test", chrono::Utc::now()).await;
+    assert!(simulation.run_until(5 * MINUTE, || known("m.test")).await);
+    simulation.stop().await;
+}
+
+/// Announce `count` servers named `{prefix}{n}.test` to a.test from one node.
+async fn flood(simulation: &Simulation, prefix: &str, count: usize) {
+    let mallory = simulation.network.transport("mallory");
+    let key = signer::generate_keypair();
+    for n in 0..count {
+        let server_name = format!("{}{}.test", prefix, n);
+        let announcement = announcement(&server_name, "mallory", &key, chrono::Utc::now());
+        let data = serde_json::to_vec(&announcement).unwrap();
+        mallory
+            .send_message(&Destination::parse("a.test"), DISCOVERY_TOPIC, &data)
+            .await
+            .unwrap();
+    }
+}
+
+async fn flooded_names(simulation: &Simulation, prefix: &str) -> usize {
+    let directory = simulation.bridge("a.test").directory().await;
+    directory.iter().filter(|server| server.server_name.starts_with(prefix)).count()
+}
+
+#[tokio::test(start_paused = true)]
+async fn a_node_cannot_flood_the_directory() {
+    let simulation = Simulation::start(MemoryNetwork::new(), &["a.test", "b.test"], |config| {
+        config.directory.new_servers_per_hour = 5;
+    })
+    .await;
+    assert!(simulation.run_until(5 * MINUTE, || simulation.directories_converged()).await);
+
+    flood(&simulation, "m", 20).await;
+    let first_five = || async { flooded_names(&simulation, "m").await == 5 };
+    assert!(simulation.run_until(5 * MINUTE, first_five).await, "a.test did not take in the first servers");
+    tokio::time::sleep(MINUTE).await;
+    assert_eq!(flooded_names(&simulation, "m").await, 5);
+    simulation.stop().await;
+}
+
+#[tokio::test(start_paused = true)]
+async fn new_servers_do_not_evict_online_peers() {
+    let simulation = Simulation::start(MemoryNetwork::new(), &["a.test", "b.test"], |config| {
+        config.directory.max_servers = 3;
+    })
+    .await;
+    assert!(simulation.run_until(5 * MINUTE, || simulation.directories_converged()).await);
+
+    flood(&simulation, "m", 10).await;
+    let full = || async { simulation.bridge("a.test").directory().await.len() == 3 };
+    assert!(simulation.run_until(5 * MINUTE, full).await, "a.test did not fill its directory");
+    let admitted = flooded_names(&simulation, "m").await;
+    tokio::time::sleep(MINUTE).await;
+    assert_eq!(flooded_names(&simulation, "m").await, admitted);
+    assert!(entry(&simulation, "a.test", "b.test").await.is_some(), "b.test was evicted");
+    simulation.stop().await;
+}
+
+/// A PDU envelope from `source` to `destination`, signed with `key`.
+fn signed_pdu(key: &SigningKey, source: &str, destination: &str) -> MyceliumMessage {
+    let mut message = MyceliumMessage {
+        version: ProtocolVersion::CURRENT.as_str().to_string(),
+        source_server: source.to_string(),
+        destination_server: destination.to_string(),
+        message_type: "federation_event".to_string(),
+        timestamp: chrono::Utc::now().to_rfc3339(),
+        payload: serde_json::value::to_raw_value(&pdu(source, "hello")).unwrap(),
+        signature: String::new(),
+        content_encoding: None,
+        encryption: None,
+        trace_context: None,
+        correlation_id: None,
+        expires_at: None,
+    };
+    let signature = key.sign(message.signing_payload().unwrap().as_bytes());
+    message.signature = base64::engine::general_purpose::STANDARD.encode(signature.to_bytes());
+    message
+}
+
+#[tokio::test(start_paused = true)]
+async fn signed_messages_cannot_be_replayed_elsewhere() {
+    let simulation = Simulation::start(MemoryNetwork::new(), &["a.test", "b.test"], |_| {}).await;
+    let mallory = simulation.network.transport("mallory");
+    let key = signer::generate_keypair();
+    let data = serde_json::to_vec(&announcement("m.test", "mallory", &key, chrono::Utc::now())).unwrap();
+    let to_a = Destination::parse("a.test");
+    mallory.send_message(&to_a, DISCOVERY_TOPIC, &data).await.unwrap();
+    let known = || async { entry(&simulation, "a.test", "m.test").await.is_some() };
+    assert!(simulation.run_until(5 * MINUTE, known).await);
+
+    let mut events = simulation.bridge("a.test").subscribe_events().unwrap();
+    let send = |message: &MyceliumMessage| {
+        let data = serde_json::to_vec(message).unwrap();
+        let (mallory, to_a) = (&mallory, &to_a);
+        async move { mallory.send_message(to_a, &edu::federation_topic("a.test"), &data).await.unwrap() }
+    };
+    // Signed for b.test, then readdressed to a.test
+    let for_b = signed_pdu(&key, "m.test", "b.test");
+    send(&for_b).await;
+    let mut readdressed = signed_pdu(&key, "m.test", "b.test");
+    readdressed.destination_server = "a.test".to_string();
+    send(&readdressed).await;
+    let for_a = signed_pdu(&key, "m.test", "a.test");
+    send(&for_a).await;
+
+    tokio::time::sleep(MINUTE).await;
+    let mut received = Vec::new();
+    while let Ok(event) = events.try_recv() {
+        received.push(event.event["event_id"].as_str().unwrap().to_string());
+    }
+    let sent: serde_json::Value = serde_json::from_str(for_a.payload.get()).unwrap();
+    assert_eq!(received, [sent["event_id"].as_str().unwrap()]);
+    simulation.stop().await;
+}
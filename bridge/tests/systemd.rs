@@ -0,0 +1,73 @@
+#![cfg(unix)]
+
+mod common;
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::routing::get;
+use axum::Router;
+use matrix_mycelium_bridge::memory_transport::MemoryNetwork;
+use matrix_mycelium_bridge::systemd::{self, LoopHeartbeats};
+use matrix_mycelium_bridge::{signer, MatrixMyceliumBridge};
+use tokio::net::UnixDatagram;
+
+async fn next_notification(socket: &UnixDatagram) -> String {
+    let mut buffer = [0; 256];
+    let length = tokio::time::timeout(Duration::from_secs(10), socket.recv(&mut buffer))
+        .await
+        .expect("no notification")
+        .unwrap();
+    String::from_utf8_lossy(&buffer[..length]).trim().to_string()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn notifies_readiness_watchdog_and_stopping() {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let homeserver_url = format!("http://{}", listener.local_addr().unwrap());
+    let homeserver = Router::new().route("/_matrix/client/versions", get(|| async { "{}" }));
+    tokio::spawn(async move { axum::serve(listener, homeserver).await.unwrap() });
+
+    let directory = std::env::temp_dir().join(format!("bridge-test-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&directory).unwrap();
+    let socket_path = directory.join("notify.sock");
+    let socket = UnixDatagram::bind(&socket_path).unwrap();
+    std::env::set_var("NOTIFY_SOCKET", &socket_path);
+    std::env::set_var("WATCHDOG_USEC", "1000000");
+    std::env::set_var("WATCHDOG_PID", std::process::id().to_string());
+
+    let mut config = common::config("a.test", &signer::generate_keypair());
+    config.matrix_homeserver_url = homeserver_url;
+    let transport = MemoryNetwork::new().transport("a.test");
+    let bridge = MatrixMyceliumBridge::with_mycelium(config, Arc::new(transport)).await.unwrap();
+    let handle = bridge.start().await.unwrap();
+    let supervisor = tokio::spawn(systemd::supervise(bridge.clone()));
+
+    assert_eq!(next_notification(&socket).await, "READY=1");
+    assert_eq!(next_notification(&socket).await, "WATCHDOG=1");
+    assert_eq!(next_notification(&socket).await, "WATCHDOG=1");
+    assert!(bridge.stalled_loops(Duration::from_secs(1)).is_empty());
+
+    bridge.request_shutdown();
+    supervisor.await.unwrap();
+    let mut last = next_notification(&socket).await;
+    while last == "WATCHDOG=1" {
+        last = next_notification(&socket).await;
+    }
+    assert_eq!(last, "STOPPING=1");
+    handle.join().await.unwrap();
+    std::fs::remove_dir_all(directory).unwrap();
+}
+
+#[tokio::test(start_paused = true)]
+async fn loops_that_stop_going_round_are_stalled() {
+    let heartbeats = LoopHeartbeats::default();
+    heartbeats.beat("poller");
+    heartbeats.beat("monitor");
+    tokio::time::sleep(Duration::from_secs(30)).await;
+    heartbeats.beat("poller");
+    tokio::time::sleep(Duration::from_secs(10)).await;
+
+    assert_eq!(heartbeats.stalled(Duration::from_secs(20)), vec!["monitor"]);
+    assert!(heartbeats.stalled(Duration::from_secs(60)).is_empty());
+}
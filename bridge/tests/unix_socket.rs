@@ -0,0 +1,77 @@
+#![cfg(unix)]
+
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::UnixListener;
+use tokio::sync::mpsc;
+
+use matrix_mycelium_bridge::config::MyceliumConfig;
+use matrix_mycelium_bridge::mycelium::{Destination, FederationTransport, MyceliumClient};
+
+/// Serve `responses` in turn over a Unix socket at `path`, one request per
+/// connection, and pass on each request line and body received.
+fn serve(path: &std::path::Path, responses: Vec<&'static str>) -> mpsc::UnboundedReceiver<(String, String)> {
+    let listener = UnixListener::bind(path).unwrap();
+    let (requests, received) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        for body in responses {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = BufReader::new(stream);
+            let mut request_line = String::new();
+            stream.read_line(&mut request_line).await.unwrap();
+            let mut content_length = 0;
+            loop {
+                let mut header = String::new();
+                stream.read_line(&mut header).await.unwrap();
+                if header.trim().is_empty() {
+                    break;
+                }
+                if let Some((name, value)) = header.split_once(':') {
+                    if name.eq_ignore_ascii_case("content-length") {
+                        content_length = value.trim().parse().unwrap();
+                    }
+                }
+            }
+            let mut request_body = vec![0; content_length];
+            stream.read_exact(&mut request_body).await.unwrap();
+            requests
+                .send((request_line.trim().to_string(), String::from_utf8(request_body).unwrap()))
+                .unwrap();
+
+            let response = format!(
+                "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ncontent-length: {}\r\n\
+                 connection: close\r\n\r\n{}",
+                body.len(),
+                body
+            );
+            stream.get_mut().write_all(response.as_bytes()).await.unwrap();
+        }
+    });
+    received
+}
+
+#[tokio::test]
+async fn client_reaches_the_api_over_a_unix_socket() {
+    let directory = std::env::temp_dir().join(format!("bridge-test-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&directory).unwrap();
+    let path = directory.join("api.sock");
+    let mut requests = serve(&path, vec![r#"{"nodeSubnet":"400::/64","nodePubkey":"abcd"}"#, "{}"]);
+
+    let url = format!("unix://{}", path.display());
+    let client = MyceliumClient::new(url.clone(), &MyceliumConfig::default()).unwrap();
+    assert_eq!(client.api_url(), url);
+
+    let info = client.get_info().await.unwrap();
+    assert_eq!(info.public_key, "abcd");
+    let (request_line, _) = requests.recv().await.unwrap();
+    assert_eq!(request_line, "GET /api/v1/admin HTTP/1.1");
+
+    let destination = Destination::parse("abcd");
+    client.send_message(&destination, "chat", b"hello").await.unwrap();
+    let (request_line, body) = requests.recv().await.unwrap();
+    assert_eq!(request_line, "POST /api/v1/messages HTTP/1.1");
+    let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(body["dst"]["pk"], "abcd");
+    assert_eq!(body["topic"], base64::Engine::encode(&base64::engine::general_purpose::STANDARD, "chat"));
+
+    std::fs::remove_dir_all(directory).unwrap();
+}
@@ -0,0 +1,22 @@
+use matrix_mycelium_bridge::signer::{self, LocalSigner, Signer};
+use matrix_mycelium_bridge::x_matrix;
+
+#[tokio::test]
+async fn signed_requests_verify_only_unchanged() {
+    let signer = LocalSigner::new(signer::generate_keypair());
+    let content = serde_json::json!({ "pdus": [], "edus": [] });
+    let uri = "/_matrix/federation/v1/send/txn1";
+    let header = x_matrix::sign_request(&signer, "a.test", "b.test", "PUT", uri, Some(&content))
+        .await
+        .unwrap();
+    let auth = x_matrix::parse_authorization(&header).unwrap();
+    let key = signer.verifying_key();
+
+    assert!(x_matrix::verify_request(&auth, &key, "PUT", uri, Some(&content)));
+    let other_uri = "/_matrix/federation/v1/send/txn2";
+    assert!(!x_matrix::verify_request(&auth, &key, "PUT", other_uri, Some(&content)));
+    let forged = serde_json::json!({ "pdus": [{}], "edus": [] });
+    assert!(!x_matrix::verify_request(&auth, &key, "PUT", uri, Some(&forged)));
+    let other = signer::generate_keypair().verifying_key();
+    assert!(!x_matrix::verify_request(&auth, &other, "PUT", uri, Some(&content)));
+}
@@ -0,0 +1,377 @@
+use axum::{
+    extract::{Path, Request, State},
+    http::{header::AUTHORIZATION, HeaderMap, StatusCode},
+    middleware::Next,
+    response::{Json, Response},
+    routing::{delete, get, put},
+    Router,
+};
+use mycelium_chat_types::{RegisterRequest, ServerQuery};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use tracing::{info, warn};
+
+use crate::config::AdminToken;
+use crate::{storage_error, AppState};
+
+/// Bans, availability overrides and API keys set through the admin API, and
+/// the keys servers revoked. They outlast re-registrations, and are saved
+/// with the registry.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Moderation {
+    pub banned_names: BTreeSet<String>,
+    pub banned_keys: BTreeSet<String>,
+    /// Servers listed as unavailable whatever capacity they report.
+    pub unavailable: BTreeSet<String>,
+    /// Keys allowing registration when `security.require_api_key` is set,
+    /// by id.
+    pub api_keys: BTreeMap<String, ApiKey>,
+    /// Public keys revoked through `/servers/revoke`, refused from then on.
+    pub revoked_keys: BTreeSet<String>,
+}
+
+impl Moderation {
+    pub fn is_banned(&self, server_name: &str, public_key: &str) -> bool {
+        self.banned_names.contains(server_name) || self.banned_keys.contains(public_key)
+    }
+
+    /// The id of the issued API key `given` is, if any.
+    pub fn api_key_id(&self, given: &str) -> Option<String> {
+        let hash = hash_api_key(given);
+        self.api_keys.iter().find(|(_, key)| key.key_hash == hash).map(|(id, _)| id.clone())
+    }
+}
+
+/// A key an operator issued for registering. Only its hash is kept, so it is
+/// shown once, when issued.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ApiKey {
+    pub label: String,
+    pub key_hash: String,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    /// Servers registered with the key, removed when it is revoked.
+    #[serde(default)]
+    pub servers: BTreeSet<String>,
+}
+
+fn hash_api_key(key: &str) -> String {
+    format!("{:x}", Sha256::digest(key.as_bytes()))
+}
+
+/// A call to `/servers/register`, and the status it was answered with.
+#[derive(Debug, Clone, Serialize)]
+pub struct RegistrationAttempt {
+    pub server_name: String,
+    pub mycelium_address: String,
+    pub public_key: String,
+    pub at: chrono::DateTime<chrono::Utc>,
+    pub status: u16,
+}
+
+/// The most recent registration attempts, newest last.
+pub struct RegistrationLog {
+    attempts: Mutex<VecDeque<RegistrationAttempt>>,
+    capacity: usize,
+}
+
+impl RegistrationLog {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            attempts: Mutex::new(VecDeque::with_capacity(capacity)),
+            capacity,
+        }
+    }
+
+    pub async fn record(&self, request: &RegisterRequest, status: StatusCode) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut attempts = self.attempts.lock().await;
+        if attempts.len() == self.capacity {
+            attempts.pop_front();
+        }
+        attempts.push_back(RegistrationAttempt {
+            server_name: request.server_name.clone(),
+            mycelium_address: request.mycelium_address.clone(),
+            public_key: request.public_key.clone(),
+            at: chrono::Utc::now(),
+            status: status.as_u16(),
+        });
+    }
+}
+
+/// The `/admin` routes, answered only for requests bearing one of the
+/// configured admin tokens. Without tokens the admin API is disabled.
+pub fn routes(app_state: Arc<AppState>) -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/admin/registrations", get(list_registrations))
+        .route("/admin/servers/:server_name", delete(remove_server))
+        .route("/admin/servers/:server_name/metadata", put(set_metadata))
+        .route("/admin/servers/:server_name/availability", put(set_availability))
+        .route("/admin/bans", get(list_bans).post(ban).delete(unban))
+        .route("/admin/api-keys", get(list_api_keys).post(issue_api_key))
+        .route("/admin/api-keys/:id", delete(revoke_api_key))
+        .route_layer(axum::middleware::from_fn_with_state(app_state, require_admin_token))
+}
+
+/// Compare secrets in time independent of where they differ. Hashing
+/// first also hides their lengths.
+fn tokens_match(given: &str, expected: &str) -> bool {
+    let given = Sha256::digest(given.as_bytes());
+    let expected = Sha256::digest(expected.as_bytes());
+    given
+        .iter()
+        .zip(expected.iter())
+        .fold(0u8, |difference, (a, b)| difference | (a ^ b))
+        == 0
+}
+
+/// The configured token matching `given`, if any.
+fn authenticate<'a>(tokens: &'a [AdminToken], given: &str) -> Option<&'a AdminToken> {
+    // Compare against every token, so timing doesn't reveal which matched
+    let mut found = None;
+    for token in tokens {
+        if tokens_match(given, &token.token) && found.is_none() {
+            found = Some(token);
+        }
+    }
+    found
+}
+
+async fn require_admin_token(
+    State(app_state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    request: Request,
+    next: Next,
+) -> Result<Response, StatusCode> {
+    let tokens = &app_state.config.admin.tokens;
+    if tokens.is_empty() {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    let given = headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .ok_or(StatusCode::UNAUTHORIZED)?;
+    let token = authenticate(tokens, given).ok_or(StatusCode::UNAUTHORIZED)?;
+    info!("Admin {} {} by {}", request.method(), request.uri().path(), token.name);
+    Ok(next.run(request).await)
+}
+
+async fn list_registrations(State(app_state): State<Arc<AppState>>) -> Json<serde_json::Value> {
+    let attempts = app_state.registrations.attempts.lock().await;
+    let newest_first: Vec<_> = attempts.iter().rev().collect();
+    Json(serde_json::json!({ "registrations": newest_first }))
+}
+
+async fn remove_server(
+    State(app_state): State<Arc<AppState>>,
+    Path(server_name): Path<String>,
+) -> Result<Json<serde_json::Value>, StatusCode> {
+    let _updating = app_state.updates.lock().await;
+    let registry = &app_state.registry;
+    registry.get(&server_name).await.map_err(storage_error)?.ok_or(StatusCode::NOT_FOUND)?;
+    registry.remove(std::slice::from_ref(&server_name)).await.map_err(storage_error)?;
+    warn!("Removed server {} through the admin API", server_name);
+    Ok(Json(serde_json::json!({ "success": true, "server_name": server_name })))
+}
+
+/// Replace a server's metadata; `null` clears it. Kept across its
+/// re-registrations.
+async fn set_metadata(
+    State(app_state): State<Arc<AppState>>,
+    Path(server_name): Path<String>,
+    Json(metadata): Json<serde_json::Value>,
+) -> Result<Json<serde_json::Value>, StatusCode> {
+    let metadata = (!metadata.is_null()).then_some(metadata);
+    let server = app_state
+        .registry
+        .update_server(&server_name, &mut |server, _| {
+            server.metadata = metadata.clone();
+            true
+        })
+        .await
+        .map_err(storage_error)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    Ok(Json(serde_json::json!({ "success": true, "server": server })))
+}
+
+#[derive(Debug, Deserialize)]
+struct Availability {
+    available: bool,
+}
+
+/// Take a server out of `/servers/select`, or put it back. While taken out
+/// it is listed as unavailable whatever capacity it reports.
+async fn set_availability(
+    State(app_state): State<Arc<AppState>>,
+    Path(server_name): Path<String>,
+    Json(availability): Json<Availability>,
+) -> Result<Json<serde_json::Value>, StatusCode> {
+    let registry = &app_state.registry;
+    registry.get(&server_name).await.map_err(storage_error)?.ok_or(StatusCode::NOT_FOUND)?;
+    registry
+        .update_moderation(&mut |moderation| {
+            if availability.available {
+                moderation.unavailable.remove(&server_name);
+            } else {
+                moderation.unavailable.insert(server_name.clone());
+            }
+        })
+        .await
+        .map_err(storage_error)?;
+    let server = registry
+        .update_server(&server_name, &mut |server, _| {
+            server.capacity.available &= availability.available;
+            true
+        })
+        .await
+        .map_err(storage_error)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    info!("Set {} {}", server_name, if availability.available { "available" } else { "unavailable" });
+    Ok(Json(serde_json::json!({ "success": true, "server": server })))
+}
+
+async fn list_bans(
+    State(app_state): State<Arc<AppState>>,
+) -> Result<Json<serde_json::Value>, StatusCode> {
+    let moderation = app_state.registry.moderation().await.map_err(storage_error)?;
+    Ok(Json(serde_json::json!({
+        "server_names": moderation.banned_names,
+        "public_keys": moderation.banned_keys
+    })))
+}
+
+/// A server name or public key to ban or unban, or both.
+#[derive(Debug, Deserialize)]
+struct Ban {
+    server_name: Option<String>,
+    public_key: Option<String>,
+}
+
+/// Ban a server name or public key from registering, removing the servers
+/// already registered with it.
+async fn ban(
+    State(app_state): State<Arc<AppState>>,
+    Json(ban): Json<Ban>,
+) -> Result<Json<serde_json::Value>, StatusCode> {
+    if ban.server_name.is_none() && ban.public_key.is_none() {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    let _updating = app_state.updates.lock().await;
+    let registry = &app_state.registry;
+    let moderation = registry
+        .update_moderation(&mut |moderation| {
+            moderation.banned_names.extend(ban.server_name.clone());
+            moderation.banned_keys.extend(ban.public_key.clone());
+        })
+        .await
+        .map_err(storage_error)?;
+
+    let removed: Vec<String> = registry
+        .list(&ServerQuery::default())
+        .await
+        .map_err(storage_error)?
+        .into_iter()
+        .filter(|server| moderation.is_banned(&server.server_name, &server.public_key))
+        .map(|server| server.server_name)
+        .collect();
+    registry.remove(&removed).await.map_err(storage_error)?;
+    warn!("Banned servers through the admin API, removing {:?}", removed);
+
+    Ok(Json(serde_json::json!({ "success": true, "removed_servers": removed })))
+}
+
+async fn unban(
+    State(app_state): State<Arc<AppState>>,
+    Json(ban): Json<Ban>,
+) -> Result<Json<serde_json::Value>, StatusCode> {
+    let mut lifted = false;
+    app_state
+        .registry
+        .update_moderation(&mut |moderation| {
+            let name = ban.server_name.as_ref();
+            let key = ban.public_key.as_ref();
+            let name_unbanned = name.is_some_and(|name| moderation.banned_names.remove(name));
+            let key_unbanned = key.is_some_and(|key| moderation.banned_keys.remove(key));
+            lifted = name_unbanned || key_unbanned;
+        })
+        .await
+        .map_err(storage_error)?;
+    if !lifted {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    Ok(Json(serde_json::json!({ "success": true })))
+}
+
+async fn list_api_keys(
+    State(app_state): State<Arc<AppState>>,
+) -> Result<Json<serde_json::Value>, StatusCode> {
+    let moderation = app_state.registry.moderation().await.map_err(storage_error)?;
+    let keys: Vec<_> = moderation
+        .api_keys
+        .iter()
+        .map(|(id, key)| {
+            serde_json::json!({
+                "id": id,
+                "label": key.label,
+                "created_at": key.created_at,
+                "servers": key.servers
+            })
+        })
+        .collect();
+    Ok(Json(serde_json::json!({ "api_keys": keys })))
+}
+
+#[derive(Debug, Deserialize)]
+struct NewApiKey {
+    label: String,
+}
+
+/// Issue an API key for registering. The key itself is only in this
+/// response.
+async fn issue_api_key(
+    State(app_state): State<Arc<AppState>>,
+    Json(new_key): Json<NewApiKey>,
+) -> Result<Json<serde_json::Value>, StatusCode> {
+    let id = uuid::Uuid::new_v4().simple().to_string()[..8].to_string();
+    let key = uuid::Uuid::new_v4().simple().to_string();
+    let api_key = ApiKey {
+        label: new_key.label.clone(),
+        key_hash: hash_api_key(&key),
+        created_at: chrono::Utc::now(),
+        servers: BTreeSet::new(),
+    };
+    app_state
+        .registry
+        .update_moderation(&mut |moderation| {
+            moderation.api_keys.insert(id.clone(), api_key.clone());
+        })
+        .await
+        .map_err(storage_error)?;
+    info!("Issued API key {} ({})", id, new_key.label);
+    Ok(Json(serde_json::json!({ "success": true, "id": id, "key": key })))
+}
+
+/// Revoke an API key, removing the servers registered with it.
+async fn revoke_api_key(
+    State(app_state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> Result<Json<serde_json::Value>, StatusCode> {
+    let _updating = app_state.updates.lock().await;
+    let registry = &app_state.registry;
+    let mut revoked = None;
+    registry
+        .update_moderation(&mut |moderation| revoked = moderation.api_keys.remove(&id))
+        .await
+        .map_err(storage_error)?;
+    let key = revoked.ok_or(StatusCode::NOT_FOUND)?;
+    let removed: Vec<String> = key.servers.iter().cloned().collect();
+    registry.remove(&removed).await.map_err(storage_error)?;
+    warn!("Revoked API key {} ({}), removing {:?}", id, key.label, key.servers);
+    Ok(Json(serde_json::json!({ "success": true, "removed_servers": key.servers })))
+}
@@ -7,6 +7,12 @@ pub struct DiscoveryConfig {
     pub cleanup: CleanupConfig,
     pub persistence: PersistenceConfig,
     pub security: SecurityConfig,
+    #[serde(default)]
+    pub verification: VerificationConfig,
+    #[serde(default)]
+    pub heartbeat: HeartbeatConfig,
+    #[serde(default)]
+    pub admin: AdminConfig,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -26,16 +32,132 @@ pub struct CleanupConfig {
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PersistenceConfig {
+    /// Without persistence the registry is only kept in memory.
     pub enabled: bool,
+    /// The JSON snapshot, or with a database backend the snapshot migrated
+    /// into the database on first start.
     pub file_path: Option<PathBuf>,
     pub save_interval_seconds: u64,
+    #[serde(default)]
+    pub backend: PersistenceBackend,
+    #[serde(default = "default_database_path")]
+    pub database_path: PathBuf,
+    /// Connection string for the PostgreSQL backend, such as
+    /// `postgres://discovery@db/discovery`.
+    #[serde(default)]
+    pub database_url: Option<String>,
+    /// Server for the Redis backend, such as `redis://127.0.0.1/`.
+    #[serde(default)]
+    pub redis_url: Option<String>,
+}
+
+/// Where the registry is kept.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PersistenceBackend {
+    /// In memory, snapshot to `file_path` every `save_interval_seconds`.
+    #[default]
+    Json,
+    /// The SQLite database at `database_path`.
+    Sqlite,
+    /// The PostgreSQL database at `database_url`, which several instances
+    /// can share.
+    Postgres,
+    /// The Redis server at `redis_url`, which several instances can share.
+    /// Servers expire from it when stale, without the cleanup task.
+    Redis,
+}
+
+fn default_database_path() -> PathBuf {
+    PathBuf::from("discovery.db")
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SecurityConfig {
     pub require_signature: bool,
     pub trusted_keys: Vec<String>,
+    /// Registrations need an API key issued through the admin API, as a
+    /// bearer token, making the service invite-only.
+    #[serde(default)]
+    pub require_api_key: bool,
+    /// Requests a client IP may make to the public endpoints; 0 disables.
     pub rate_limit_per_minute: u32,
+    /// Registrations allowed per public key, on top of the per-IP limit;
+    /// 0 disables.
+    #[serde(default = "default_register_rate_limit")]
+    pub register_rate_limit_per_minute: u32,
+}
+
+fn default_register_rate_limit() -> u32 {
+    6
+}
+
+/// Challenges sent over the overlay to registered addresses, through the
+/// local mycelium node, so registrants prove they own them.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct VerificationConfig {
+    pub enabled: bool,
+    pub mycelium_api_url: String,
+    pub challenge_timeout_seconds: u64,
+    /// Timeouts of calls to the mycelium node, as for the bridge's HTTP
+    /// client, so a node that doesn't answer can't hold up a registration.
+    pub connect_timeout_seconds: u64,
+    pub request_timeout_seconds: u64,
+}
+
+impl Default for VerificationConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            mycelium_api_url: "http://localhost:8989".to_string(),
+            challenge_timeout_seconds: 300, // bridges poll for challenges every minute
+            connect_timeout_seconds: 10,
+            request_timeout_seconds: 60,
+        }
+    }
+}
+
+/// Bounds on the TTL servers ask for in their heartbeats.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HeartbeatConfig {
+    pub min_ttl_seconds: u64,
+    pub max_ttl_seconds: u64,
+}
+
+impl Default for HeartbeatConfig {
+    fn default() -> Self {
+        Self {
+            min_ttl_seconds: 60,
+            max_ttl_seconds: 3600,
+        }
+    }
+}
+
+/// The `/admin` API, enabled by configuring at least one token.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AdminConfig {
+    pub tokens: Vec<AdminToken>,
+    /// Registration attempts kept for `/admin/registrations`.
+    pub recent_registrations: usize,
+}
+
+/// A bearer token for the admin API. The name is logged with each call.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AdminToken {
+    pub name: String,
+    pub token: String,
+}
+
+impl Default for AdminConfig {
+    fn default() -> Self {
+        Self {
+            tokens: vec![],
+            recent_registrations: 100,
+        }
+    }
 }
 
 impl Default for DiscoveryConfig {
@@ -44,7 +166,7 @@ impl Default for DiscoveryConfig {
             server: ServerConfig {
                 bind_address: "0.0.0.0".to_string(),
                 port: 3000,
-                cors_origins: vec!["*".to_string()],
+                cors_origins: vec![],
                 max_servers: 1000,
             },
             cleanup: CleanupConfig {
@@ -56,12 +178,21 @@ impl Default for DiscoveryConfig {
                 enabled: true,
                 file_path: Some(PathBuf::from("servers.json")),
                 save_interval_seconds: 60,
+                backend: PersistenceBackend::Json,
+                database_path: default_database_path(),
+                database_url: None,
+                redis_url: None,
             },
             security: SecurityConfig {
                 require_signature: false, // Disabled for development
                 trusted_keys: vec![],
+                require_api_key: false,
                 rate_limit_per_minute: 60,
+                register_rate_limit_per_minute: default_register_rate_limit(),
             },
+            verification: VerificationConfig::default(),
+            heartbeat: HeartbeatConfig::default(),
+            admin: AdminConfig::default(),
         }
     }
 }
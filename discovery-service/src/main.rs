@@ -1,24 +1,42 @@
 use anyhow::Result;
 use axum::{
-    extract::{Query, State},
-    http::StatusCode,
-    response::Json,
+    extract::{ConnectInfo, Query, Request, State},
+    http::{
+        header::{AUTHORIZATION, CONTENT_TYPE},
+        HeaderMap, HeaderValue, Method, StatusCode,
+    },
+    middleware::Next,
+    response::{IntoResponse, Json, Response},
     routing::{get, post},
     Router,
 };
 use clap::Parser;
-use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::net::SocketAddr;
 use std::sync::Arc;
-use tokio::sync::RwLock;
-use tower_http::cors::CorsLayer;
-use tracing::{info, warn, error, Level};
+use tokio::sync::Mutex;
+use tower_http::cors::{AllowOrigin, Any, CorsLayer};
+use tracing::{error, info, warn, Level};
 
+mod admin;
 mod config;
-mod persistence;
-
+mod memory;
+mod postgres;
+mod rate_limit;
+mod redis_store;
+mod signing;
+mod sqlite;
+mod store;
+mod verification;
+
+use admin::{Moderation, RegistrationLog};
 use config::DiscoveryConfig;
-use persistence::PersistenceManager;
+use mycelium_chat_types::{
+    AddressProof, Deregistration, Heartbeat, KeyRevocation, RegisterRequest, ServerInfo, ServerQuery,
+    ServerStatus,
+};
+use rate_limit::{too_many_requests, KeyedRateLimiter};
+use store::RegistryStore;
+use verification::AddressVerifier;
 
 #[derive(Parser)]
 #[command(name = "mycelium-discovery-service")]
@@ -34,47 +52,21 @@ struct Cli {
     generate_config: bool,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct ServerInfo {
-    pub server_name: String,
-    pub mycelium_address: String,
-    pub public_key: String,
-    pub capabilities: Vec<String>,
-    pub capacity: ServerCapacity,
-    pub last_seen: chrono::DateTime<chrono::Utc>,
-    pub status: String,
-    pub metadata: Option<serde_json::Value>,
-}
-
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct ServerCapacity {
-    max_users: u32,
-    current_users: u32,
-    available: bool,
-}
-
-#[derive(Debug, Serialize, Deserialize)]
-struct RegisterRequest {
-    server_name: String,
-    mycelium_address: String,
-    public_key: String,
-    capabilities: Vec<String>,
-    capacity: ServerCapacity,
-    metadata: Option<serde_json::Value>,
-}
-
-#[derive(Debug, Deserialize)]
-struct QueryParams {
-    available_only: Option<bool>,
-    capability: Option<String>,
-}
-
-pub type ServerRegistry = Arc<RwLock<HashMap<String, ServerInfo>>>;
+/// How far the timestamp of a heartbeat or deregistration may be from the
+/// service's clock, which bounds how long a captured one can be replayed.
+const MAX_CLOCK_SKEW_SECONDS: i64 = 300;
 
 struct AppState {
-    registry: ServerRegistry,
+    registry: Arc<dyn RegistryStore>,
+    /// Held while servers are registered or removed, so those made on this
+    /// instance don't overwrite each other. Changes to a registered server
+    /// go through [`RegistryStore::update_server`] instead.
+    updates: Mutex<()>,
     config: DiscoveryConfig,
-    persistence: PersistenceManager,
+    verifier: Option<AddressVerifier>,
+    registrations: RegistrationLog,
+    client_limits: KeyedRateLimiter,
+    registration_limits: KeyedRateLimiter,
 }
 
 #[tokio::main]
@@ -101,62 +93,82 @@ async fn main() -> Result<()> {
         DiscoveryConfig::default()
     };
     
-    // Initialize persistence manager
-    let persistence = PersistenceManager::new(
-        config.persistence.file_path.clone(),
-        config.persistence.save_interval_seconds,
-    );
-    
-    // Load existing servers from persistence
-    let servers = if config.persistence.enabled {
-        persistence.load_servers().await?
-    } else {
-        HashMap::new()
-    };
-    
-    let registry: ServerRegistry = Arc::new(RwLock::new(servers));
+    // Open the registry in the configured store
+    let registry = store::open(&config).await?;
+    let verifier = config
+        .verification
+        .enabled
+        .then(|| AddressVerifier::new(&config.verification, registry.clone()))
+        .transpose()?;
     
     let app_state = Arc::new(AppState {
-        registry: registry.clone(),
+        registry,
+        updates: Mutex::new(()),
         config: config.clone(),
-        persistence,
+        verifier,
+        registrations: RegistrationLog::new(config.admin.recent_registrations),
+        client_limits: KeyedRateLimiter::new(config.security.rate_limit_per_minute),
+        registration_limits: KeyedRateLimiter::new(config.security.register_rate_limit_per_minute),
     });
 
     let app = Router::new()
-        .route("/health", get(health_check))
         .route("/servers", get(list_servers))
         .route("/servers/register", post(register_server))
         .route("/servers/select", get(select_server))
-        .route("/servers/:server_name", get(get_server_info))
+        .route("/servers/revoke", post(revoke_key))
+        .route("/servers/verify", post(verify_address))
+        .route("/servers/:server_name", get(get_server_info).delete(deregister_server))
+        .route("/servers/:server_name/heartbeat", post(record_heartbeat))
         .route("/stats", get(get_stats))
-        .layer(CorsLayer::permissive())
+        .route_layer(axum::middleware::from_fn_with_state(app_state.clone(), limit_per_client))
+        .route("/health", get(health_check))
+        .merge(admin::routes(app_state.clone()))
+        .layer(cors_layer(&config.server.cors_origins))
         .with_state(app_state.clone());
 
-    // Start cleanup task
-    let cleanup_state = app_state.clone();
-    tokio::spawn(async move {
-        let mut interval = tokio::time::interval(
-            std::time::Duration::from_secs(cleanup_state.config.cleanup.interval_seconds)
-        );
-        loop {
-            interval.tick().await;
-            cleanup_stale_servers(cleanup_state.clone()).await;
-        }
-    });
-    
-    // Start persistence task if enabled
-    if config.persistence.enabled {
-        let _persistence_task = app_state.persistence.start_periodic_save(registry.clone()).await;
+    // Start cleanup task, unless stale servers expire from the store itself
+    if !app_state.registry.expires_stale_servers() {
+        let cleanup_state = app_state.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(
+                std::time::Duration::from_secs(cleanup_state.config.cleanup.interval_seconds)
+            );
+            loop {
+                interval.tick().await;
+                cleanup_stale_servers(cleanup_state.clone()).await;
+            }
+        });
     }
 
     let bind_addr = format!("{}:{}", config.server.bind_address, config.server.port);
     let listener = tokio::net::TcpListener::bind(&bind_addr).await?;
     info!("Discovery service listening on {}", bind_addr);
 
-    axum::serve(listener, app).await?;
+    axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).await?;
     Ok(())
 }
 
+/// CORS for the configured origins, `*` allowing any. Without origins no
+/// cross-origin requests are allowed.
+fn cors_layer(origins: &[String]) -> CorsLayer {
+    let layer = CorsLayer::new()
+        .allow_methods([Method::GET, Method::POST])
+        .allow_headers([AUTHORIZATION, CONTENT_TYPE])
+        .max_age(std::time::Duration::from_secs(3600));
+    
+    if origins.iter().any(|origin| origin == "*") {
+        return layer.allow_origin(Any);
+    }
+    let mut allowed = Vec::new();
+    for origin in origins {
+        match origin.parse::<HeaderValue>() {
+            Ok(origin) => allowed.push(origin),
+            Err(_) => warn!("Ignoring invalid CORS origin {:?}", origin),
+        }
+    }
+    layer.allow_origin(AllowOrigin::list(allowed))
+}
+
 async fn health_check() -> Json<serde_json::Value> {
     Json(serde_json::json!({
         "status": "healthy",
@@ -164,57 +176,157 @@ async fn health_check() -> Json<serde_json::Value> {
     }))
 }
 
+/// Log a failed read or write of the registry, answered with a 500.
+fn storage_error(e: anyhow::Error) -> StatusCode {
+    error!("Registry storage failed: {}", e);
+    StatusCode::INTERNAL_SERVER_ERROR
+}
+
 async fn list_servers(
     State(app_state): State<Arc<AppState>>,
-    Query(params): Query<QueryParams>,
-) -> Json<serde_json::Value> {
-    let servers = app_state.registry.read().await;
-    let mut filtered_servers: Vec<&ServerInfo> = servers.values().collect();
-
-    if params.available_only.unwrap_or(false) {
-        filtered_servers.retain(|server| server.capacity.available);
-    }
-
-    if let Some(capability) = params.capability {
-        filtered_servers.retain(|server| server.capabilities.contains(&capability));
-    }
+    Query(query): Query<ServerQuery>,
+) -> Result<Json<serde_json::Value>, StatusCode> {
+    let filtered_servers = app_state.registry.list(&query).await.map_err(storage_error)?;
 
-    Json(serde_json::json!({
+    Ok(Json(serde_json::json!({
         "servers": filtered_servers,
         "total": filtered_servers.len(),
         "timestamp": chrono::Utc::now()
-    }))
+    })))
+}
+
+/// Rate limit the public endpoints by client IP.
+async fn limit_per_client(
+    State(app_state): State<Arc<AppState>>,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
+    request: Request,
+    next: Next,
+) -> Response {
+    match app_state.client_limits.check(&client.ip().to_string()).await {
+        Ok(()) => next.run(request).await,
+        Err(retry_after) => too_many_requests(retry_after),
+    }
 }
 
 async fn register_server(
     State(app_state): State<Arc<AppState>>,
+    headers: HeaderMap,
     Json(req): Json<RegisterRequest>,
+) -> Result<Json<serde_json::Value>, Response> {
+    // Registrations are limited more strictly, by the key they claim
+    if let Err(retry_after) = app_state.registration_limits.check(&req.public_key).await {
+        app_state.registrations.record(&req, StatusCode::TOO_MANY_REQUESTS).await;
+        return Err(too_many_requests(retry_after));
+    }
+    let attempt = req.clone();
+    let api_key = headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    let result = register(&app_state, req, api_key).await;
+    let status = result.as_ref().map_or_else(|status| *status, |_| StatusCode::OK);
+    app_state.registrations.record(&attempt, status).await;
+    result.map_err(IntoResponse::into_response)
+}
+
+async fn register(
+    app_state: &Arc<AppState>,
+    req: RegisterRequest,
+    api_key: Option<&str>,
 ) -> Result<Json<serde_json::Value>, StatusCode> {
     // Validate server registration
     if req.server_name.is_empty() || req.mycelium_address.is_empty() {
         return Err(StatusCode::BAD_REQUEST);
     }
     
+    let moderation = app_state.registry.moderation().await.map_err(storage_error)?;
+    let unavailable = moderation.unavailable.contains(&req.server_name);
+    if moderation.is_banned(&req.server_name, &req.public_key) {
+        warn!("Rejected registration for banned server {}", req.server_name);
+        return Err(StatusCode::FORBIDDEN);
+    }
+    
+    if moderation.revoked_keys.contains(&req.public_key) {
+        warn!("Rejected registration for {} with a revoked key", req.server_name);
+        return Err(StatusCode::FORBIDDEN);
+    }
+    
+    let api_key_id = if app_state.config.security.require_api_key {
+        let Some(id) = api_key.and_then(|given| moderation.api_key_id(given)) else {
+            warn!("Rejected registration for {} without a valid API key", req.server_name);
+            return Err(StatusCode::UNAUTHORIZED);
+        };
+        Some(id)
+    } else {
+        None
+    };
+    
+    if app_state.config.security.require_signature {
+        verify_registration(app_state, &moderation, &req).await?;
+    }
+    
     // Check server limit
-    let current_count = app_state.registry.read().await.len();
-    if current_count >= app_state.config.server.max_servers {
+    let current_count = app_state.registry.stats().await.map_err(storage_error)?.total_servers;
+    if current_count >= app_state.config.server.max_servers as u64 {
         return Err(StatusCode::SERVICE_UNAVAILABLE);
     }
     
+    let updating = app_state.updates.lock().await;
+    let previous = app_state.registry.get(&req.server_name).await.map_err(storage_error)?;
+    let is_update = previous.is_some();
+    // Proof of the address holds until the server moves or changes key
+    let verified = previous.as_ref().is_some_and(|previous| {
+        previous.verified
+            && previous.mycelium_address == req.mycelium_address
+            && previous.public_key == req.public_key
+    });
+    // Metadata is set by admins, and kept for them
+    let metadata = previous.and_then(|previous| previous.metadata);
+    let mut capacity = req.capacity;
+    capacity.available &= !unavailable;
     let server_info = ServerInfo {
         server_name: req.server_name.clone(),
-        mycelium_address: req.mycelium_address,
+        mycelium_address: req.mycelium_address.clone(),
         public_key: req.public_key,
         capabilities: req.capabilities,
-        capacity: req.capacity,
+        capacity,
         last_seen: chrono::Utc::now(),
-        status: "online".to_string(),
-        metadata: None,
+        status: ServerStatus::Online,
+        metadata,
+        verified,
+        heartbeat_ttl_seconds: None,
     };
-
-    let mut servers = app_state.registry.write().await;
-    let is_update = servers.contains_key(&req.server_name);
-    servers.insert(req.server_name.clone(), server_info);
+    app_state.registry.upsert(&server_info).await.map_err(storage_error)?;
+    let api_key = api_key_id.filter(|id| {
+        moderation.api_keys.get(id).is_some_and(|key| !key.servers.contains(&req.server_name))
+    });
+    if let Some(id) = api_key {
+        // Revoking the key removes the servers registered with it
+        let server_name = &req.server_name;
+        app_state
+            .registry
+            .update_moderation(&mut |moderation| {
+                if let Some(key) = moderation.api_keys.get_mut(&id) {
+                    key.servers.insert(server_name.clone());
+                }
+            })
+            .await
+            .map_err(storage_error)?;
+    }
+    drop(updating);
+    
+    if app_state.verifier.is_some() && !verified {
+        let app_state = app_state.clone();
+        let (server_name, address) = (req.server_name.clone(), req.mycelium_address);
+        tokio::spawn(async move {
+            let Some(verifier) = &app_state.verifier else {
+                return;
+            };
+            if let Err(e) = verifier.challenge(&server_name, &address).await {
+                warn!("Failed to send address challenge to {} at {}: {}", server_name, address, e);
+            }
+        });
+    }
 
     if is_update {
         info!("Updated server registration: {}", req.server_name);
@@ -229,48 +341,289 @@ async fn register_server(
     })))
 }
 
-async fn select_server(
+/// Check that `req` is signed with the key it carries, and that a server
+/// already registered keeps its key unless the stored one was revoked.
+async fn verify_registration(
+    app_state: &AppState,
+    moderation: &Moderation,
+    req: &RegisterRequest,
+) -> Result<(), StatusCode> {
+    if req.signature.is_empty() {
+        warn!("Rejected unsigned registration for {}", req.server_name);
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+    let message = req.signing_payload().map_err(|_| StatusCode::BAD_REQUEST)?;
+    if !signing::verify_signature(&req.public_key, &message, &req.signature) {
+        warn!("Rejected registration for {} with a bad signature", req.server_name);
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+    
+    let stored_key = app_state
+        .registry
+        .get(&req.server_name)
+        .await
+        .map_err(storage_error)?
+        .map(|server| server.public_key);
+    if let Some(stored_key) = stored_key {
+        if stored_key != req.public_key && !moderation.revoked_keys.contains(&stored_key) {
+            warn!("Rejected registration for {} with a different key than registered", req.server_name);
+            return Err(StatusCode::CONFLICT);
+        }
+    }
+    Ok(())
+}
+
+/// Whether an RFC 3339 `timestamp` is within [`MAX_CLOCK_SKEW_SECONDS`] of now.
+fn is_current(timestamp: &str) -> Result<bool, StatusCode> {
+    let timestamp = chrono::DateTime::parse_from_rfc3339(timestamp).map_err(|_| StatusCode::BAD_REQUEST)?;
+    Ok((chrono::Utc::now() - timestamp.to_utc()).num_seconds().abs() <= MAX_CLOCK_SKEW_SECONDS)
+}
+
+/// Remove a server at its own request, signed with its registered key, so
+/// it stops being listed at once rather than after the cleanup task.
+async fn deregister_server(
     State(app_state): State<Arc<AppState>>,
-    Query(params): Query<QueryParams>,
-) -> Json<serde_json::Value> {
-    let servers = app_state.registry.read().await;
-    let mut available_servers: Vec<&ServerInfo> = servers
-        .values()
-        .filter(|server| server.capacity.available && server.status == "online")
-        .collect();
+    axum::extract::Path(server_name): axum::extract::Path<String>,
+    Json(deregistration): Json<Deregistration>,
+) -> Result<Json<serde_json::Value>, StatusCode> {
+    if deregistration.server_name != server_name {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    if !is_current(&deregistration.timestamp)? {
+        warn!("Rejected deregistration for {} with a stale timestamp", server_name);
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+    let message = deregistration.signing_payload().map_err(|_| StatusCode::BAD_REQUEST)?;
+    
+    let server = app_state
+        .registry
+        .get(&server_name)
+        .await
+        .map_err(storage_error)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    if server.status == ServerStatus::Untrusted {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    if !signing::verify_signature(&server.public_key, &message, &deregistration.signature) {
+        warn!("Rejected deregistration for {} with a bad signature", server_name);
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+    
+    app_state.registry.remove(std::slice::from_ref(&server_name)).await.map_err(storage_error)?;
+    info!("Deregistered server: {}", server_name);
+    
+    Ok(Json(serde_json::json!({
+        "success": true,
+        "server_name": server_name
+    })))
+}
+
+/// Keep a registered server alive for the TTL it asks for, within the
+/// configured bounds, and update its capacity.
+async fn record_heartbeat(
+    State(app_state): State<Arc<AppState>>,
+    axum::extract::Path(server_name): axum::extract::Path<String>,
+    Json(heartbeat): Json<Heartbeat>,
+) -> Result<Json<serde_json::Value>, StatusCode> {
+    if heartbeat.server_name != server_name {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    if !is_current(&heartbeat.timestamp)? {
+        warn!("Rejected heartbeat for {} with a stale timestamp", server_name);
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+    let message = heartbeat.signing_payload().map_err(|_| StatusCode::BAD_REQUEST)?;
+    let limits = &app_state.config.heartbeat;
+    let ttl_seconds = heartbeat.ttl_seconds.clamp(limits.min_ttl_seconds, limits.max_ttl_seconds);
     
-    // Filter by capability if requested
-    if let Some(capability) = &params.capability {
-        available_servers.retain(|server| server.capabilities.contains(capability));
+    let mut refused = None;
+    app_state
+        .registry
+        .update_server(&server_name, &mut |server, moderation| {
+            refused = None;
+            if untrust_if_revoked(server, moderation) {
+                refused = Some(StatusCode::FORBIDDEN);
+                return true;
+            }
+            if server.status == ServerStatus::Untrusted {
+                refused = Some(StatusCode::FORBIDDEN);
+                return false;
+            }
+            if !signing::verify_signature(&server.public_key, &message, &heartbeat.signature) {
+                refused = Some(StatusCode::UNAUTHORIZED);
+                return false;
+            }
+            server.capacity = heartbeat.capacity.clone();
+            server.capacity.available &= !moderation.unavailable.contains(&server.server_name);
+            server.last_seen = chrono::Utc::now();
+            server.heartbeat_ttl_seconds = Some(ttl_seconds);
+            true
+        })
+        .await
+        .map_err(storage_error)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    if let Some(status) = refused {
+        if status == StatusCode::UNAUTHORIZED {
+            warn!("Rejected heartbeat for {} with a bad signature", server_name);
+        }
+        return Err(status);
     }
+    
+    Ok(Json(serde_json::json!({
+        "success": true,
+        "ttl_seconds": ttl_seconds
+    })))
+}
 
-    if available_servers.is_empty() {
-        return Json(serde_json::json!({
-            "server": null,
-            "message": "No available servers matching criteria",
-            "total_servers": servers.len()
-        }));
+/// Mark a server verified once it hands back the nonce of the challenge sent
+/// to its registered address, signed with its registered key.
+async fn verify_address(
+    State(app_state): State<Arc<AppState>>,
+    Json(proof): Json<AddressProof>,
+) -> Result<Json<serde_json::Value>, StatusCode> {
+    let Some(verifier) = &app_state.verifier else {
+        return Err(StatusCode::NOT_FOUND);
+    };
+    let message = proof.signing_payload().map_err(|_| StatusCode::BAD_REQUEST)?;
+    
+    let server = app_state
+        .registry
+        .get(&proof.server_name)
+        .await
+        .map_err(storage_error)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    if !signing::verify_signature(&server.public_key, &message, &proof.signature) {
+        warn!("Rejected address proof for {} with a bad signature", proof.server_name);
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+    let answered = verifier
+        .answer(&proof.server_name, &server.mycelium_address, &proof.nonce)
+        .await
+        .map_err(storage_error)?;
+    if !answered {
+        warn!("Rejected address proof for {} with no matching challenge", proof.server_name);
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+    
+    // Only the registration the proof was checked against is verified
+    let mut refused = None;
+    app_state
+        .registry
+        .update_server(&proof.server_name, &mut |current, moderation| {
+            refused = None;
+            if untrust_if_revoked(current, moderation) {
+                refused = Some(StatusCode::FORBIDDEN);
+                return true;
+            }
+            if current.status == ServerStatus::Untrusted {
+                refused = Some(StatusCode::FORBIDDEN);
+                return false;
+            }
+            if current.public_key != server.public_key || current.mycelium_address != server.mycelium_address {
+                refused = Some(StatusCode::CONFLICT);
+                return false;
+            }
+            current.verified = true;
+            true
+        })
+        .await
+        .map_err(storage_error)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    if let Some(status) = refused {
+        if status == StatusCode::CONFLICT {
+            warn!("Rejected address proof for {}, which registered again meanwhile", proof.server_name);
+        }
+        return Err(status);
     }
+    info!("Verified {} owns {}", proof.server_name, server.mycelium_address);
+    
+    Ok(Json(serde_json::json!({
+        "success": true,
+        "server_name": proof.server_name
+    })))
+}
 
-    // Select server with lowest user count (load balancing)
-    let selected_server = available_servers
-        .into_iter()
-        .min_by_key(|server| server.capacity.current_users);
+async fn revoke_key(
+    State(app_state): State<Arc<AppState>>,
+    Json(revocation): Json<KeyRevocation>,
+) -> Result<Json<serde_json::Value>, StatusCode> {
+    let mut unsigned = revocation.clone();
+    unsigned.signature = String::new();
+    let message = serde_json::to_string(&unsigned).map_err(|_| StatusCode::BAD_REQUEST)?;
+    
+    if !signing::verify_signature(&revocation.revoked_key, &message, &revocation.signature) {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+    
+    app_state
+        .registry
+        .update_moderation(&mut |moderation| {
+            moderation.revoked_keys.insert(revocation.revoked_key.clone());
+        })
+        .await
+        .map_err(storage_error)?;
+    
+    // A server written with the key meanwhile is marked by its next update
+    let servers = app_state.registry.list(&ServerQuery::default()).await.map_err(storage_error)?;
+    let mut untrusted = Vec::new();
+    for server in servers.iter().filter(|server| server.public_key == revocation.revoked_key) {
+        let updated = app_state
+            .registry
+            .update_server(&server.server_name, &mut |current, moderation| {
+                untrust_if_revoked(current, moderation)
+            })
+            .await
+            .map_err(storage_error)?;
+        if updated.is_some_and(|server| server.status == ServerStatus::Untrusted) {
+            untrusted.push(server.server_name.clone());
+        }
+    }
+    
+    warn!("Revoked key for {}, marked {} servers untrusted", revocation.server_name, untrusted.len());
+    
+    Ok(Json(serde_json::json!({
+        "success": true,
+        "untrusted_servers": untrusted
+    })))
+}
 
-    Json(serde_json::json!({
-        "server": selected_server,
-        "message": "Server selected successfully",
-        "selection_method": "lowest_load"
-    }))
+/// Mark `server` untrusted if its key was revoked since it was last
+/// written. Returns whether it was marked.
+fn untrust_if_revoked(server: &mut ServerInfo, moderation: &Moderation) -> bool {
+    let revoked = moderation.revoked_keys.contains(&server.public_key);
+    if !revoked || server.status == ServerStatus::Untrusted {
+        return false;
+    }
+    server.status = ServerStatus::Untrusted;
+    true
+}
+
+async fn select_server(
+    State(app_state): State<Arc<AppState>>,
+    Query(query): Query<ServerQuery>,
+) -> Result<Json<serde_json::Value>, StatusCode> {
+    let candidates = app_state.registry.list(&query).await.map_err(storage_error)?;
+    
+    // Select server with lowest user count (load balancing)
+    match query.select(&candidates) {
+        Some(selected_server) => Ok(Json(serde_json::json!({
+            "server": selected_server,
+            "message": "Server selected successfully",
+            "selection_method": "lowest_load"
+        }))),
+        None => Ok(Json(serde_json::json!({
+            "server": null,
+            "message": "No available servers matching criteria",
+            "total_servers": app_state.registry.stats().await.map_err(storage_error)?.total_servers
+        }))),
+    }
 }
 
 async fn get_server_info(
     State(app_state): State<Arc<AppState>>,
     axum::extract::Path(server_name): axum::extract::Path<String>,
 ) -> Result<Json<serde_json::Value>, StatusCode> {
-    let servers = app_state.registry.read().await;
-    
-    match servers.get(&server_name) {
+    match app_state.registry.get(&server_name).await.map_err(storage_error)? {
         Some(server) => Ok(Json(serde_json::json!({
             "server": server,
             "found": true
@@ -281,42 +634,48 @@ async fn get_server_info(
 
 async fn get_stats(
     State(app_state): State<Arc<AppState>>,
-) -> Json<serde_json::Value> {
-    let servers = app_state.registry.read().await;
-    
-    let total_servers = servers.len();
-    let online_servers = servers.values().filter(|s| s.status == "online").count();
-    let available_servers = servers.values().filter(|s| s.capacity.available).count();
-    let total_capacity: u32 = servers.values().map(|s| s.capacity.max_users).sum();
-    let total_users: u32 = servers.values().map(|s| s.capacity.current_users).sum();
+) -> Result<Json<serde_json::Value>, StatusCode> {
+    let stats = app_state.registry.stats().await.map_err(storage_error)?;
+    let (total_capacity, total_users) = (stats.total_capacity, stats.total_users);
     
-    Json(serde_json::json!({
-        "total_servers": total_servers,
-        "online_servers": online_servers,
-        "available_servers": available_servers,
+    Ok(Json(serde_json::json!({
+        "total_servers": stats.total_servers,
+        "online_servers": stats.online_servers,
+        "available_servers": stats.available_servers,
         "total_capacity": total_capacity,
         "total_users": total_users,
         "utilization_percent": if total_capacity > 0 { 
             (total_users as f64 / total_capacity as f64 * 100.0).round() 
         } else { 0.0 },
         "timestamp": chrono::Utc::now()
-    }))
+    })))
 }
 
+/// Remove servers not seen within their heartbeat TTL, or the stale
+/// threshold for those that only register.
 async fn cleanup_stale_servers(app_state: Arc<AppState>) {
-    let cutoff = chrono::Utc::now() - chrono::Duration::minutes(
-        app_state.config.cleanup.stale_threshold_minutes
-    );
-    let mut servers = app_state.registry.write().await;
+    let now = chrono::Utc::now();
+    let stale_threshold = chrono::Duration::minutes(app_state.config.cleanup.stale_threshold_minutes);
+    let _updating = app_state.updates.lock().await;
+    let servers = match app_state.registry.list(&ServerQuery::default()).await {
+        Ok(servers) => servers,
+        Err(e) => {
+            error!("Failed to list servers for cleanup: {}", e);
+            return;
+        }
+    };
     
     let stale_servers: Vec<String> = servers
-        .iter()
-        .filter(|(_, server)| server.last_seen < cutoff)
-        .map(|(name, _)| name.clone())
+        .into_iter()
+        .filter(|server| server.last_seen + store::stale_after(server, stale_threshold) < now)
+        .map(|server| server.server_name)
         .collect();
 
+    if let Err(e) = app_state.registry.remove(&stale_servers).await {
+        error!("Failed to remove stale servers: {}", e);
+        return;
+    }
     for server_name in &stale_servers {
-        servers.remove(server_name);
         info!("Removed stale server: {}", server_name);
     }
     
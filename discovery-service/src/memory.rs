@@ -0,0 +1,208 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use mycelium_chat_types::{ServerInfo, ServerQuery};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::fs;
+use tokio::sync::RwLock;
+use tracing::{error, info, warn};
+
+use crate::admin::Moderation;
+use crate::store::{ModerationUpdate, PendingChallenge, RegistryStats, RegistryStore, ServerUpdate};
+
+#[derive(Debug, Serialize, Deserialize)]
+struct PersistedData {
+    servers: HashMap<String, ServerInfo>,
+    #[serde(default)]
+    moderation: Moderation,
+    version: String,
+    saved_at: chrono::DateTime<chrono::Utc>,
+}
+
+/// The registry in memory, snapshot to a JSON file every save interval when
+/// there is one. A crash loses the changes since the last snapshot.
+#[derive(Default)]
+pub struct MemoryStore {
+    servers: RwLock<HashMap<String, ServerInfo>>,
+    moderation: RwLock<Moderation>,
+    /// Not snapshot, as they expire long before a restart.
+    challenges: RwLock<HashMap<String, PendingChallenge>>,
+}
+
+impl MemoryStore {
+    /// Restore the snapshot at `file_path`, if there is one, and keep saving
+    /// to it.
+    pub async fn open(file_path: Option<PathBuf>, save_interval_seconds: u64) -> Arc<Self> {
+        let store = Arc::new(Self::default());
+        let Some(path) = file_path else {
+            return store;
+        };
+
+        if !path.exists() {
+            info!("Persistence file does not exist, starting with empty registry");
+        } else {
+            match read_snapshot(&path).await {
+                Ok((servers, moderation)) => {
+                    info!("Loaded {} servers from persistence file", servers.len());
+                    *store.servers.write().await = servers;
+                    *store.moderation.write().await = moderation;
+                }
+                Err(e) => {
+                    error!("Failed to load servers from persistence file: {}", e);
+                    warn!("Starting with empty registry");
+                }
+            }
+        }
+
+        let saved = store.clone();
+        tokio::spawn(async move {
+            let period = std::time::Duration::from_secs(save_interval_seconds);
+            let mut interval_timer = tokio::time::interval(period);
+
+            loop {
+                interval_timer.tick().await;
+
+                let servers = saved.servers.read().await.clone();
+                let moderation = saved.moderation.read().await.clone();
+
+                if let Err(e) = save_snapshot(&path, &servers, &moderation).await {
+                    error!("Failed to save servers to persistence file: {}", e);
+                }
+            }
+        });
+        store
+    }
+}
+
+#[async_trait]
+impl RegistryStore for MemoryStore {
+    async fn get(&self, server_name: &str) -> Result<Option<ServerInfo>> {
+        Ok(self.servers.read().await.get(server_name).cloned())
+    }
+
+    async fn list(&self, query: &ServerQuery) -> Result<Vec<ServerInfo>> {
+        let servers = self.servers.read().await;
+        Ok(servers.values().filter(|server| query.matches(server)).cloned().collect())
+    }
+
+    async fn upsert(&self, server: &ServerInfo) -> Result<()> {
+        self.servers.write().await.insert(server.server_name.clone(), server.clone());
+        Ok(())
+    }
+
+    async fn update_server(
+        &self,
+        server_name: &str,
+        update: &mut ServerUpdate<'_>,
+    ) -> Result<Option<ServerInfo>> {
+        let mut servers = self.servers.write().await;
+        let moderation = self.moderation.read().await;
+        let Some(server) = servers.get_mut(server_name) else {
+            return Ok(None);
+        };
+        let mut updated = server.clone();
+        if update(&mut updated, &moderation) {
+            *server = updated.clone();
+        }
+        Ok(Some(updated))
+    }
+
+    async fn remove(&self, server_names: &[String]) -> Result<()> {
+        let mut servers = self.servers.write().await;
+        for server_name in server_names {
+            servers.remove(server_name);
+        }
+        Ok(())
+    }
+
+    async fn stats(&self) -> Result<RegistryStats> {
+        Ok(RegistryStats::of(self.servers.read().await.values()))
+    }
+
+    async fn moderation(&self) -> Result<Moderation> {
+        Ok(self.moderation.read().await.clone())
+    }
+
+    async fn update_moderation(
+        &self,
+        update: &mut ModerationUpdate<'_>,
+    ) -> Result<Moderation> {
+        let mut moderation = self.moderation.write().await;
+        update(&mut moderation);
+        Ok(moderation.clone())
+    }
+
+    async fn add_challenge(&self, challenge: &PendingChallenge) -> Result<bool> {
+        let mut challenges = self.challenges.write().await;
+        let now = chrono::Utc::now();
+        challenges.retain(|_, pending| pending.expires_at > now);
+        if challenges
+            .get(&challenge.server_name)
+            .is_some_and(|pending| pending.address == challenge.address)
+        {
+            return Ok(false);
+        }
+        challenges.insert(challenge.server_name.clone(), challenge.clone());
+        Ok(true)
+    }
+
+    async fn take_challenge(&self, server_name: &str, address: &str, nonce: &str) -> Result<bool> {
+        let mut challenges = self.challenges.write().await;
+        let answered = challenges.get(server_name).is_some_and(|pending| {
+            pending.address == address && pending.nonce == nonce && pending.expires_at > chrono::Utc::now()
+        });
+        if answered {
+            challenges.remove(server_name);
+        }
+        Ok(answered)
+    }
+}
+
+/// The servers and moderation in a snapshot, leaving out servers not seen
+/// for a day.
+pub async fn read_snapshot(path: &Path) -> Result<(HashMap<String, ServerInfo>, Moderation)> {
+    let content = fs::read_to_string(path).await?;
+    let data: PersistedData = serde_json::from_str(&content)?;
+
+    // Filter out stale servers on load
+    let cutoff = chrono::Utc::now() - chrono::Duration::hours(24);
+    let total = data.servers.len();
+    let fresh_servers: HashMap<String, ServerInfo> = data
+        .servers
+        .into_iter()
+        .filter(|(_, server)| server.last_seen > cutoff)
+        .collect();
+
+    if fresh_servers.len() != total {
+        info!(
+            "Filtered out {} stale servers during load",
+            total - fresh_servers.len()
+        );
+    }
+
+    Ok((fresh_servers, data.moderation))
+}
+
+async fn save_snapshot(
+    path: &Path,
+    servers: &HashMap<String, ServerInfo>,
+    moderation: &Moderation,
+) -> Result<()> {
+    let data = PersistedData {
+        servers: servers.clone(),
+        moderation: moderation.clone(),
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        saved_at: chrono::Utc::now(),
+    };
+
+    let content = serde_json::to_string_pretty(&data)?;
+
+    // Write to temporary file first, then rename for atomic operation
+    let temp_path = path.with_extension("tmp");
+    fs::write(&temp_path, content).await?;
+    fs::rename(&temp_path, path).await?;
+
+    Ok(())
+}
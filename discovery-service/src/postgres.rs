@@ -0,0 +1,272 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use deadpool_postgres::{GenericClient, Pool, Runtime};
+use mycelium_chat_types::{ServerInfo, ServerQuery, ServerStatus};
+use tokio_postgres::types::ToSql;
+use tokio_postgres::NoTls;
+
+use crate::admin::Moderation;
+use crate::store::{status_name, ModerationUpdate, PendingChallenge, RegistryStats, RegistryStore, ServerUpdate};
+
+/// Created under an advisory lock, so instances starting together don't
+/// race to create it.
+const SCHEMA: &str = "
+    BEGIN;
+    SELECT pg_advisory_xact_lock(7262934515);
+    CREATE TABLE IF NOT EXISTS discovery_servers (
+        server_name TEXT PRIMARY KEY,
+        status TEXT NOT NULL,
+        available BOOLEAN NOT NULL,
+        verified BOOLEAN NOT NULL,
+        capabilities TEXT[] NOT NULL,
+        last_seen TIMESTAMPTZ NOT NULL,
+        info TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS discovery_servers_by_status
+        ON discovery_servers (status, available, verified);
+    CREATE INDEX IF NOT EXISTS discovery_servers_by_capability
+        ON discovery_servers USING GIN (capabilities);
+    CREATE TABLE IF NOT EXISTS discovery_moderation (
+        id INTEGER PRIMARY KEY CHECK (id = 0),
+        data TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS discovery_challenges (
+        server_name TEXT PRIMARY KEY,
+        address TEXT NOT NULL,
+        nonce TEXT NOT NULL,
+        expires_at TIMESTAMPTZ NOT NULL
+    );
+    COMMIT;
+";
+
+/// The registry in PostgreSQL, shared by every discovery service instance
+/// pointed at the same database.
+pub struct PostgresStore {
+    pool: Pool,
+}
+
+impl PostgresStore {
+    pub async fn connect(url: &str) -> Result<Self> {
+        let mut config = deadpool_postgres::Config::new();
+        config.url = Some(url.to_string());
+        let pool = config.create_pool(Some(Runtime::Tokio1), NoTls)?;
+        pool.get().await?.batch_execute(SCHEMA).await?;
+        Ok(Self { pool })
+    }
+}
+
+#[async_trait]
+impl RegistryStore for PostgresStore {
+    async fn get(&self, server_name: &str) -> Result<Option<ServerInfo>> {
+        self.pool
+            .get()
+            .await?
+            .query_opt("SELECT info FROM discovery_servers WHERE server_name = $1", &[&server_name])
+            .await?
+            .map(|row| Ok(serde_json::from_str(row.get(0))?))
+            .transpose()
+    }
+
+    async fn list(&self, query: &ServerQuery) -> Result<Vec<ServerInfo>> {
+        let status = query.status.map(status_name).transpose()?;
+        let mut sql = String::from("SELECT info FROM discovery_servers WHERE TRUE");
+        let mut values: Vec<&(dyn ToSql + Sync)> = Vec::new();
+        if let Some(status) = &status {
+            values.push(status);
+            sql.push_str(&format!(" AND status = ${}", values.len()));
+        }
+        if query.available_only.unwrap_or(false) {
+            sql.push_str(" AND available");
+        }
+        if query.verified_only.unwrap_or(false) {
+            sql.push_str(" AND verified");
+        }
+        if let Some(capability) = &query.capability {
+            values.push(capability);
+            sql.push_str(&format!(" AND capabilities @> ARRAY[${}]", values.len()));
+        }
+        self.pool
+            .get()
+            .await?
+            .query(&sql, &values)
+            .await?
+            .iter()
+            .map(|row| Ok(serde_json::from_str(row.get(0))?))
+            .collect()
+    }
+
+    async fn upsert(&self, server: &ServerInfo) -> Result<()> {
+        upsert(&self.pool.get().await?, server).await
+    }
+
+    async fn update_server(
+        &self,
+        server_name: &str,
+        update: &mut ServerUpdate<'_>,
+    ) -> Result<Option<ServerInfo>> {
+        let mut client = self.pool.get().await?;
+        let transaction = client.transaction().await?;
+        // The row is locked before the moderation is read, so a change to
+        // the moderation followed by one to the row can't come in between
+        let row = transaction
+            .query_opt(
+                "SELECT info FROM discovery_servers WHERE server_name = $1 FOR UPDATE",
+                &[&server_name],
+            )
+            .await?;
+        let Some(row) = row else {
+            return Ok(None);
+        };
+        let mut server: ServerInfo = serde_json::from_str(row.get(0))?;
+        let moderation: Moderation = transaction
+            .query_opt("SELECT data FROM discovery_moderation WHERE id = 0 FOR SHARE", &[])
+            .await?
+            .map(|row| serde_json::from_str(row.get(0)))
+            .transpose()?
+            .unwrap_or_default();
+        if update(&mut server, &moderation) {
+            upsert(&transaction, &server).await?;
+            transaction.commit().await?;
+        }
+        Ok(Some(server))
+    }
+
+    async fn remove(&self, server_names: &[String]) -> Result<()> {
+        self.pool
+            .get()
+            .await?
+            .execute("DELETE FROM discovery_servers WHERE server_name = ANY($1)", &[&server_names])
+            .await?;
+        Ok(())
+    }
+
+    async fn stats(&self) -> Result<RegistryStats> {
+        let online = status_name(ServerStatus::Online)?;
+        let row = self
+            .pool
+            .get()
+            .await?
+            .query_one(
+                "SELECT COUNT(*),
+                        COUNT(*) FILTER (WHERE status = $1),
+                        COUNT(*) FILTER (WHERE available),
+                        COALESCE(SUM((info::jsonb -> 'capacity' ->> 'max_users')::bigint), 0)::bigint,
+                        COALESCE(SUM((info::jsonb -> 'capacity' ->> 'current_users')::bigint), 0)::bigint
+                 FROM discovery_servers",
+                &[&online],
+            )
+            .await?;
+        let count = |index: usize| row.get::<_, i64>(index) as u64;
+        Ok(RegistryStats {
+            total_servers: count(0),
+            online_servers: count(1),
+            available_servers: count(2),
+            total_capacity: count(3),
+            total_users: count(4),
+        })
+    }
+
+    async fn moderation(&self) -> Result<Moderation> {
+        Ok(self
+            .pool
+            .get()
+            .await?
+            .query_opt("SELECT data FROM discovery_moderation WHERE id = 0", &[])
+            .await?
+            .map(|row| serde_json::from_str(row.get(0)))
+            .transpose()?
+            .unwrap_or_default())
+    }
+
+    async fn update_moderation(
+        &self,
+        update: &mut ModerationUpdate<'_>,
+    ) -> Result<Moderation> {
+        let mut client = self.pool.get().await?;
+        let transaction = client.transaction().await?;
+        // The row must exist to be locked
+        transaction
+            .execute(
+                "INSERT INTO discovery_moderation (id, data) VALUES (0, '{}') ON CONFLICT (id) DO NOTHING",
+                &[],
+            )
+            .await?;
+        let row = transaction
+            .query_one("SELECT data FROM discovery_moderation WHERE id = 0 FOR UPDATE", &[])
+            .await?;
+        let mut moderation: Moderation = serde_json::from_str(row.get(0))?;
+        update(&mut moderation);
+        transaction
+            .execute(
+                "UPDATE discovery_moderation SET data = $1 WHERE id = 0",
+                &[&serde_json::to_string(&moderation)?],
+            )
+            .await?;
+        transaction.commit().await?;
+        Ok(moderation)
+    }
+
+    async fn add_challenge(&self, challenge: &PendingChallenge) -> Result<bool> {
+        let client = self.pool.get().await?;
+        client
+            .execute("DELETE FROM discovery_challenges WHERE expires_at <= now()", &[])
+            .await?;
+        // Replaces the challenge pending for the server, unless it was sent
+        // to the same address
+        let added = client
+            .execute(
+                "INSERT INTO discovery_challenges (server_name, address, nonce, expires_at)
+                 VALUES ($1, $2, $3, $4)
+                 ON CONFLICT (server_name) DO UPDATE SET
+                     address = excluded.address,
+                     nonce = excluded.nonce,
+                     expires_at = excluded.expires_at
+                 WHERE discovery_challenges.address <> excluded.address
+                     OR discovery_challenges.expires_at <= now()",
+                &[&challenge.server_name, &challenge.address, &challenge.nonce, &challenge.expires_at],
+            )
+            .await?;
+        Ok(added > 0)
+    }
+
+    async fn take_challenge(&self, server_name: &str, address: &str, nonce: &str) -> Result<bool> {
+        let taken = self
+            .pool
+            .get()
+            .await?
+            .execute(
+                "DELETE FROM discovery_challenges
+                 WHERE server_name = $1 AND address = $2 AND nonce = $3 AND expires_at > now()",
+                &[&server_name, &address, &nonce],
+            )
+            .await?;
+        Ok(taken > 0)
+    }
+}
+
+async fn upsert(client: &impl GenericClient, server: &ServerInfo) -> Result<()> {
+    client
+        .execute(
+            "INSERT INTO discovery_servers
+                 (server_name, status, available, verified, capabilities, last_seen, info)
+             VALUES ($1, $2, $3, $4, $5, $6, $7)
+             ON CONFLICT (server_name) DO UPDATE SET
+                 status = excluded.status,
+                 available = excluded.available,
+                 verified = excluded.verified,
+                 capabilities = excluded.capabilities,
+                 last_seen = excluded.last_seen,
+                 info = excluded.info",
+            &[
+                &server.server_name,
+                &status_name(server.status)?,
+                &server.capacity.available,
+                &server.verified,
+                &server.capabilities,
+                &server.last_seen,
+                &serde_json::to_string(server)?,
+            ],
+        )
+        .await?;
+    Ok(())
+}
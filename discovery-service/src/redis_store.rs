@@ -0,0 +1,337 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use mycelium_chat_types::{ServerInfo, ServerQuery};
+use redis::aio::ConnectionManager;
+use redis::AsyncCommands;
+
+use crate::admin::Moderation;
+use crate::store::{
+    stale_after, status_name, ModerationUpdate, PendingChallenge, RegistryStats, RegistryStore, ServerUpdate,
+};
+
+/// Prefix of every key the discovery service uses.
+const PREFIX: &str = "mycelium-discovery";
+
+/// Keep a challenge, as `address nonce` expiring with it, unless one sent to
+/// the same address is still pending.
+const ADD_CHALLENGE: &str = r#"
+local pending = redis.call('GET', KEYS[1])
+if pending and string.sub(pending, 1, #ARGV[1] + 1) == ARGV[1] .. ' ' then
+    return 0
+end
+redis.call('SET', KEYS[1], ARGV[1] .. ' ' .. ARGV[2], 'PX', ARGV[3])
+return 1
+"#;
+
+/// Delete a challenge if it is the one answered.
+const TAKE_CHALLENGE: &str = r#"
+if redis.call('GET', KEYS[1]) == ARGV[1] then
+    return redis.call('DEL', KEYS[1])
+end
+return 0
+"#;
+
+/// The registry in Redis, shared by every discovery service instance pointed
+/// at the same server, which keeps no state of its own. Each server is a
+/// JSON string expiring when the server goes stale, so Redis drops it without
+/// a cleanup task. Sets of names per status, capability, and for available
+/// and verified servers make the `/servers` filters set intersections. The
+/// sets each server is in are kept in a hash that doesn't expire, by which
+/// expired servers are taken out of them when next read.
+pub struct RedisStore {
+    client: redis::Client,
+    connection: ConnectionManager,
+    stale_threshold: chrono::Duration,
+}
+
+impl RedisStore {
+    pub async fn connect(url: &str, stale_threshold: chrono::Duration) -> Result<Self> {
+        let client = redis::Client::open(url)?;
+        Ok(Self {
+            connection: ConnectionManager::new(client.clone()).await?,
+            client,
+            stale_threshold,
+        })
+    }
+
+    async fn servers(&self, server_names: &[String]) -> Result<Vec<Option<ServerInfo>>> {
+        if server_names.is_empty() {
+            return Ok(Vec::new());
+        }
+        let keys: Vec<String> = server_names.iter().map(|name| server_key(name)).collect();
+        let values: Vec<Option<String>> = redis::cmd("MGET")
+            .arg(keys)
+            .query_async(&mut self.connection.clone())
+            .await?;
+        values
+            .into_iter()
+            .map(|value| value.map(|info| serde_json::from_str(&info)).transpose().map_err(Into::into))
+            .collect()
+    }
+
+    /// The sets each of `server_names` was last put in.
+    async fn indexed(&self, server_names: &[String]) -> Result<Vec<Vec<String>>> {
+        if server_names.is_empty() {
+            return Ok(Vec::new());
+        }
+        let values: Vec<Option<String>> = redis::cmd("HMGET")
+            .arg(indexes_key())
+            .arg(server_names)
+            .query_async(&mut self.connection.clone())
+            .await?;
+        values
+            .into_iter()
+            .map(|value| Ok(value.map(|keys| serde_json::from_str(&keys)).transpose()?.unwrap_or_default()))
+            .collect()
+    }
+
+    /// Add to `pipe` taking `server_names` out of the sets they are in.
+    async fn unindex(&self, pipe: &mut redis::Pipeline, server_names: &[String]) -> Result<()> {
+        let indexed = self.indexed(server_names).await?;
+        for (server_name, keys) in server_names.iter().zip(indexed) {
+            for key in keys {
+                pipe.srem(key, server_name).ignore();
+            }
+            pipe.hdel(indexes_key(), server_name).ignore();
+        }
+        Ok(())
+    }
+
+    /// Add to `pipe` writing `server`, moving it from the sets in `previous`
+    /// to the ones it belongs in now.
+    fn write_server(&self, pipe: &mut redis::Pipeline, server: &ServerInfo, previous: &[String]) -> Result<()> {
+        let keys = index_keys(server)?;
+        for key in previous {
+            pipe.srem(key, &server.server_name).ignore();
+        }
+        for key in &keys {
+            pipe.sadd(key, &server.server_name).ignore();
+        }
+        pipe.hset(indexes_key(), &server.server_name, serde_json::to_string(&keys)?).ignore();
+        let info = serde_json::to_string(server)?;
+        pipe.set_ex(server_key(&server.server_name), info, self.seconds_left(server)).ignore();
+        Ok(())
+    }
+
+    /// Seconds until `server` goes stale, at least one so it can still be
+    /// written.
+    fn seconds_left(&self, server: &ServerInfo) -> u64 {
+        let stale_at = server.last_seen + stale_after(server, self.stale_threshold);
+        (stale_at - chrono::Utc::now()).num_seconds().max(1) as u64
+    }
+}
+
+fn server_key(server_name: &str) -> String {
+    format!("{}:server:{}", PREFIX, server_name)
+}
+
+fn indexes_key() -> String {
+    format!("{}:indexes", PREFIX)
+}
+
+fn challenge_key(server_name: &str) -> String {
+    format!("{}:challenge:{}", PREFIX, server_name)
+}
+
+fn moderation_key() -> String {
+    format!("{}:moderation", PREFIX)
+}
+
+async fn read_moderation(connection: &mut impl AsyncCommands) -> Result<Moderation> {
+    let data: Option<String> = connection.get(moderation_key()).await?;
+    Ok(data.map(|data| serde_json::from_str(&data)).transpose()?.unwrap_or_default())
+}
+
+/// The name sets a server belongs to.
+fn index_keys(server: &ServerInfo) -> Result<Vec<String>> {
+    let mut keys = vec![format!("{}:status:{}", PREFIX, status_name(server.status)?)];
+    if server.capacity.available {
+        keys.push(format!("{}:available", PREFIX));
+    }
+    if server.verified {
+        keys.push(format!("{}:verified", PREFIX));
+    }
+    for capability in &server.capabilities {
+        keys.push(format!("{}:capability:{}", PREFIX, capability));
+    }
+    Ok(keys)
+}
+
+/// The name sets whose intersection matches `query`, none for every server.
+fn query_keys(query: &ServerQuery) -> Result<Vec<String>> {
+    let mut keys = Vec::new();
+    if let Some(status) = query.status {
+        keys.push(format!("{}:status:{}", PREFIX, status_name(status)?));
+    }
+    if query.available_only.unwrap_or(false) {
+        keys.push(format!("{}:available", PREFIX));
+    }
+    if query.verified_only.unwrap_or(false) {
+        keys.push(format!("{}:verified", PREFIX));
+    }
+    if let Some(capability) = &query.capability {
+        keys.push(format!("{}:capability:{}", PREFIX, capability));
+    }
+    Ok(keys)
+}
+
+#[async_trait]
+impl RegistryStore for RedisStore {
+    async fn get(&self, server_name: &str) -> Result<Option<ServerInfo>> {
+        let info: Option<String> = self.connection.clone().get(server_key(server_name)).await?;
+        Ok(info.map(|info| serde_json::from_str(&info)).transpose()?)
+    }
+
+    async fn list(&self, query: &ServerQuery) -> Result<Vec<ServerInfo>> {
+        let keys = query_keys(query)?;
+        let server_names: Vec<String> = if keys.is_empty() {
+            self.connection.clone().hkeys(indexes_key()).await?
+        } else {
+            self.connection.clone().sinter(keys).await?
+        };
+        let servers = self.servers(&server_names).await?;
+
+        // Take the servers that expired out of their sets. One registered
+        // again meanwhile is put back in them by its next heartbeat.
+        let expired: Vec<String> = server_names
+            .iter()
+            .zip(&servers)
+            .filter(|(_, server)| server.is_none())
+            .map(|(server_name, _)| server_name.clone())
+            .collect();
+        if !expired.is_empty() {
+            let mut pipe = redis::pipe();
+            pipe.atomic();
+            self.unindex(&mut pipe, &expired).await?;
+            pipe.query_async::<()>(&mut self.connection.clone()).await?;
+        }
+        Ok(servers.into_iter().flatten().filter(|server| query.matches(server)).collect())
+    }
+
+    async fn upsert(&self, server: &ServerInfo) -> Result<()> {
+        // WATCH applies to the whole connection, so it can't be the shared one
+        let mut connection = self.client.get_multiplexed_async_connection().await?;
+        loop {
+            redis::cmd("WATCH")
+                .arg(server_key(&server.server_name))
+                .query_async::<()>(&mut connection)
+                .await?;
+            let previous: Option<String> = connection.hget(indexes_key(), &server.server_name).await?;
+            let previous: Vec<String> =
+                previous.map(|keys| serde_json::from_str(&keys)).transpose()?.unwrap_or_default();
+            let mut pipe = redis::pipe();
+            pipe.atomic();
+            self.write_server(&mut pipe, server, &previous)?;
+            // None when another instance wrote the server since the WATCH
+            let written: Option<()> = pipe.query_async(&mut connection).await?;
+            if written.is_some() {
+                return Ok(());
+            }
+        }
+    }
+
+    async fn update_server(
+        &self,
+        server_name: &str,
+        update: &mut ServerUpdate<'_>,
+    ) -> Result<Option<ServerInfo>> {
+        // WATCH applies to the whole connection, so it can't be the shared one
+        let mut connection = self.client.get_multiplexed_async_connection().await?;
+        loop {
+            redis::cmd("WATCH")
+                .arg(server_key(server_name))
+                .arg(moderation_key())
+                .query_async::<()>(&mut connection)
+                .await?;
+            let info: Option<String> = connection.get(server_key(server_name)).await?;
+            let Some(info) = info else {
+                redis::cmd("UNWATCH").query_async::<()>(&mut connection).await?;
+                return Ok(None);
+            };
+            let mut server: ServerInfo = serde_json::from_str(&info)?;
+            let moderation = read_moderation(&mut connection).await?;
+            if !update(&mut server, &moderation) {
+                redis::cmd("UNWATCH").query_async::<()>(&mut connection).await?;
+                return Ok(Some(server));
+            }
+            let previous: Option<String> = connection.hget(indexes_key(), server_name).await?;
+            let previous: Vec<String> =
+                previous.map(|keys| serde_json::from_str(&keys)).transpose()?.unwrap_or_default();
+            let mut pipe = redis::pipe();
+            pipe.atomic();
+            self.write_server(&mut pipe, &server, &previous)?;
+            // None when another instance changed either since the WATCH
+            let written: Option<()> = pipe.query_async(&mut connection).await?;
+            if written.is_some() {
+                return Ok(Some(server));
+            }
+        }
+    }
+
+    async fn remove(&self, server_names: &[String]) -> Result<()> {
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+        self.unindex(&mut pipe, server_names).await?;
+        for server_name in server_names {
+            pipe.del(server_key(server_name)).ignore();
+        }
+        pipe.query_async::<()>(&mut self.connection.clone()).await?;
+        Ok(())
+    }
+
+    async fn stats(&self) -> Result<RegistryStats> {
+        Ok(RegistryStats::of(&self.list(&ServerQuery::default()).await?))
+    }
+
+    async fn moderation(&self) -> Result<Moderation> {
+        read_moderation(&mut self.connection.clone()).await
+    }
+
+    async fn update_moderation(
+        &self,
+        update: &mut ModerationUpdate<'_>,
+    ) -> Result<Moderation> {
+        // WATCH applies to the whole connection, so it can't be the shared one
+        let mut connection = self.client.get_multiplexed_async_connection().await?;
+        loop {
+            redis::cmd("WATCH").arg(moderation_key()).query_async::<()>(&mut connection).await?;
+            let mut moderation = read_moderation(&mut connection).await?;
+            update(&mut moderation);
+            let written: Option<()> = redis::pipe()
+                .atomic()
+                .set(moderation_key(), serde_json::to_string(&moderation)?)
+                .ignore()
+                .query_async(&mut connection)
+                .await?;
+            // None when another instance changed it since the WATCH
+            if written.is_some() {
+                return Ok(moderation);
+            }
+        }
+    }
+
+    async fn add_challenge(&self, challenge: &PendingChallenge) -> Result<bool> {
+        let ttl = (challenge.expires_at - chrono::Utc::now()).num_milliseconds().max(1);
+        let added: i64 = redis::Script::new(ADD_CHALLENGE)
+            .key(challenge_key(&challenge.server_name))
+            .arg(&challenge.address)
+            .arg(&challenge.nonce)
+            .arg(ttl)
+            .invoke_async(&mut self.connection.clone())
+            .await?;
+        Ok(added == 1)
+    }
+
+    async fn take_challenge(&self, server_name: &str, address: &str, nonce: &str) -> Result<bool> {
+        let taken: i64 = redis::Script::new(TAKE_CHALLENGE)
+            .key(challenge_key(server_name))
+            .arg(format!("{} {}", address, nonce))
+            .invoke_async(&mut self.connection.clone())
+            .await?;
+        Ok(taken == 1)
+    }
+
+    fn expires_stale_servers(&self) -> bool {
+        true
+    }
+}
@@ -0,0 +1,23 @@
+use base64::Engine;
+use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+
+/// Verify a base64 Ed25519 signature over `message` with a base64 public key.
+pub fn verify_signature(public_key: &str, message: &str, signature: &str) -> bool {
+    let engine = base64::engine::general_purpose::STANDARD;
+    let Ok(key_bytes) = engine.decode(public_key) else {
+        return false;
+    };
+    let Ok(signature_bytes) = engine.decode(signature) else {
+        return false;
+    };
+    let Ok(key_bytes) = <[u8; 32]>::try_from(key_bytes.as_slice()) else {
+        return false;
+    };
+    let Ok(signature) = Signature::from_slice(&signature_bytes) else {
+        return false;
+    };
+
+    VerifyingKey::from_bytes(&key_bytes)
+        .map(|key| key.verify(message.as_bytes(), &signature).is_ok())
+        .unwrap_or(false)
+}
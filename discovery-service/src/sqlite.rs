@@ -0,0 +1,321 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use mycelium_chat_types::{ServerInfo, ServerQuery, ServerStatus};
+use rusqlite::{params, Connection, OptionalExtension};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use crate::admin::Moderation;
+use crate::store::{status_name, ModerationUpdate, PendingChallenge, RegistryStats, RegistryStore, ServerUpdate};
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS servers (
+        server_name TEXT PRIMARY KEY,
+        status TEXT NOT NULL,
+        available INTEGER NOT NULL,
+        verified INTEGER NOT NULL,
+        last_seen TEXT NOT NULL,
+        info TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS servers_by_status ON servers (status, available, verified);
+    CREATE TABLE IF NOT EXISTS server_capabilities (
+        capability TEXT NOT NULL,
+        server_name TEXT NOT NULL REFERENCES servers (server_name) ON DELETE CASCADE,
+        PRIMARY KEY (capability, server_name)
+    );
+    CREATE INDEX IF NOT EXISTS server_capabilities_by_server ON server_capabilities (server_name);
+    CREATE TABLE IF NOT EXISTS moderation (
+        id INTEGER PRIMARY KEY CHECK (id = 0),
+        data TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS address_challenges (
+        server_name TEXT PRIMARY KEY,
+        address TEXT NOT NULL,
+        nonce TEXT NOT NULL,
+        expires_at TEXT NOT NULL
+    );
+";
+
+/// The registry in SQLite, each server a row written as it changes. The
+/// columns the `/servers` filters use are indexed; the rest of a server is
+/// kept as JSON.
+pub struct SqliteStore {
+    connection: Arc<Mutex<Connection>>,
+}
+
+impl SqliteStore {
+    pub fn open(path: &Path) -> Result<Self> {
+        let connection = Connection::open(path)?;
+        connection.pragma_update(None, "journal_mode", "WAL")?;
+        connection.pragma_update(None, "synchronous", "NORMAL")?;
+        connection.pragma_update(None, "foreign_keys", true)?;
+        connection.execute_batch(SCHEMA)?;
+        Ok(Self {
+            connection: Arc::new(Mutex::new(connection)),
+        })
+    }
+
+    /// Run `f` on the connection off the async runtime.
+    async fn with_connection<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&mut Connection) -> Result<T> + Send + 'static,
+    ) -> Result<T> {
+        let connection = self.connection.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut connection =
+                connection.lock().map_err(|_| anyhow::anyhow!("SQLite connection poisoned"))?;
+            f(&mut connection)
+        })
+        .await?
+    }
+}
+
+#[async_trait]
+impl RegistryStore for SqliteStore {
+    async fn get(&self, server_name: &str) -> Result<Option<ServerInfo>> {
+        let server_name = server_name.to_string();
+        self.with_connection(move |connection| {
+            connection
+                .query_row("SELECT info FROM servers WHERE server_name = ?1", params![server_name], |row| {
+                    row.get::<_, String>(0)
+                })
+                .optional()?
+                .map(|info| Ok(serde_json::from_str(&info)?))
+                .transpose()
+        })
+        .await
+    }
+
+    async fn list(&self, query: &ServerQuery) -> Result<Vec<ServerInfo>> {
+        let query = query.clone();
+        self.with_connection(move |connection| {
+            let mut sql = String::from("SELECT info FROM servers WHERE 1 = 1");
+            let mut values: Vec<String> = Vec::new();
+            if let Some(status) = query.status {
+                values.push(status_name(status)?);
+                sql.push_str(&format!(" AND status = ?{}", values.len()));
+            }
+            if query.available_only.unwrap_or(false) {
+                sql.push_str(" AND available = 1");
+            }
+            if query.verified_only.unwrap_or(false) {
+                sql.push_str(" AND verified = 1");
+            }
+            if let Some(capability) = &query.capability {
+                values.push(capability.clone());
+                sql.push_str(&format!(
+                    " AND server_name IN \
+                     (SELECT server_name FROM server_capabilities WHERE capability = ?{})",
+                    values.len()
+                ));
+            }
+            let mut statement = connection.prepare(&sql)?;
+            let servers = statement
+                .query_map(rusqlite::params_from_iter(&values), |row| row.get::<_, String>(0))?
+                .map(|info| Ok(serde_json::from_str(&info?)?))
+                .collect::<Result<_>>()?;
+            Ok(servers)
+        })
+        .await
+    }
+
+    async fn upsert(&self, server: &ServerInfo) -> Result<()> {
+        let server = server.clone();
+        self.with_connection(move |connection| {
+            let transaction = connection.transaction()?;
+            upsert(&transaction, &server)?;
+            transaction.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn update_server(
+        &self,
+        server_name: &str,
+        update: &mut ServerUpdate<'_>,
+    ) -> Result<Option<ServerInfo>> {
+        // `update` borrows from the caller, so can't move to a blocking task
+        tokio::task::block_in_place(|| {
+            let mut connection =
+                self.connection.lock().map_err(|_| anyhow::anyhow!("SQLite connection poisoned"))?;
+            // Immediate, so another process can't write in between
+            let transaction =
+                connection.transaction_with_behavior(rusqlite::TransactionBehavior::Immediate)?;
+            let info = transaction
+                .query_row("SELECT info FROM servers WHERE server_name = ?1", params![server_name], |row| {
+                    row.get::<_, String>(0)
+                })
+                .optional()?;
+            let Some(info) = info else {
+                return Ok(None);
+            };
+            let mut server: ServerInfo = serde_json::from_str(&info)?;
+            let moderation = read_moderation(&transaction)?;
+            if update(&mut server, &moderation) {
+                upsert(&transaction, &server)?;
+                transaction.commit()?;
+            }
+            Ok(Some(server))
+        })
+    }
+
+    async fn remove(&self, server_names: &[String]) -> Result<()> {
+        let server_names = server_names.to_vec();
+        self.with_connection(move |connection| {
+            let transaction = connection.transaction()?;
+            for server_name in &server_names {
+                transaction.execute("DELETE FROM servers WHERE server_name = ?1", params![server_name])?;
+            }
+            transaction.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn stats(&self) -> Result<RegistryStats> {
+        let online = status_name(ServerStatus::Online)?;
+        self.with_connection(move |connection| {
+            let stats = connection.query_row(
+                "SELECT COUNT(*),
+                        COUNT(*) FILTER (WHERE status = ?1),
+                        COUNT(*) FILTER (WHERE available),
+                        COALESCE(SUM(json_extract(info, '$.capacity.max_users')), 0),
+                        COALESCE(SUM(json_extract(info, '$.capacity.current_users')), 0)
+                 FROM servers",
+                params![online],
+                |row| {
+                    Ok(RegistryStats {
+                        total_servers: row.get(0)?,
+                        online_servers: row.get(1)?,
+                        available_servers: row.get(2)?,
+                        total_capacity: row.get(3)?,
+                        total_users: row.get(4)?,
+                    })
+                },
+            )?;
+            Ok(stats)
+        })
+        .await
+    }
+
+    async fn moderation(&self) -> Result<Moderation> {
+        self.with_connection(|connection| read_moderation(connection)).await
+    }
+
+    async fn update_moderation(
+        &self,
+        update: &mut ModerationUpdate<'_>,
+    ) -> Result<Moderation> {
+        // `update` borrows from the caller, so can't move to a blocking task
+        tokio::task::block_in_place(|| {
+            let mut connection =
+                self.connection.lock().map_err(|_| anyhow::anyhow!("SQLite connection poisoned"))?;
+            // Immediate, so another process can't write in between
+            let transaction =
+                connection.transaction_with_behavior(rusqlite::TransactionBehavior::Immediate)?;
+            let mut moderation = read_moderation(&transaction)?;
+            update(&mut moderation);
+            save_moderation(&transaction, &moderation)?;
+            transaction.commit()?;
+            Ok(moderation)
+        })
+    }
+
+    async fn add_challenge(&self, challenge: &PendingChallenge) -> Result<bool> {
+        let challenge = challenge.clone();
+        self.with_connection(move |connection| {
+            let transaction = connection.transaction()?;
+            let now = timestamp(chrono::Utc::now());
+            transaction.execute("DELETE FROM address_challenges WHERE expires_at <= ?1", params![now])?;
+            // Replaces the challenge pending for the server, unless it was
+            // sent to the same address
+            let added = transaction.execute(
+                "INSERT INTO address_challenges (server_name, address, nonce, expires_at)
+                 VALUES (?1, ?2, ?3, ?4)
+                 ON CONFLICT (server_name) DO UPDATE SET
+                     address = excluded.address,
+                     nonce = excluded.nonce,
+                     expires_at = excluded.expires_at
+                 WHERE address != excluded.address",
+                params![
+                    challenge.server_name,
+                    challenge.address,
+                    challenge.nonce,
+                    timestamp(challenge.expires_at),
+                ],
+            )?;
+            transaction.commit()?;
+            Ok(added > 0)
+        })
+        .await
+    }
+
+    async fn take_challenge(&self, server_name: &str, address: &str, nonce: &str) -> Result<bool> {
+        let (server_name, address, nonce) = (server_name.to_string(), address.to_string(), nonce.to_string());
+        self.with_connection(move |connection| {
+            let taken = connection.execute(
+                "DELETE FROM address_challenges
+                 WHERE server_name = ?1 AND address = ?2 AND nonce = ?3 AND expires_at > ?4",
+                params![server_name, address, nonce, timestamp(chrono::Utc::now())],
+            )?;
+            Ok(taken > 0)
+        })
+        .await
+    }
+}
+
+fn upsert(connection: &Connection, server: &ServerInfo) -> Result<()> {
+    connection.execute(
+        "INSERT INTO servers (server_name, status, available, verified, last_seen, info)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+         ON CONFLICT (server_name) DO UPDATE SET
+             status = excluded.status,
+             available = excluded.available,
+             verified = excluded.verified,
+             last_seen = excluded.last_seen,
+             info = excluded.info",
+        params![
+            server.server_name,
+            status_name(server.status)?,
+            server.capacity.available,
+            server.verified,
+            timestamp(server.last_seen),
+            serde_json::to_string(server)?,
+        ],
+    )?;
+    connection.execute(
+        "DELETE FROM server_capabilities WHERE server_name = ?1",
+        params![server.server_name],
+    )?;
+    for capability in &server.capabilities {
+        connection.execute(
+            "INSERT OR IGNORE INTO server_capabilities (capability, server_name) VALUES (?1, ?2)",
+            params![capability, server.server_name],
+        )?;
+    }
+    Ok(())
+}
+
+fn read_moderation(connection: &Connection) -> Result<Moderation> {
+    Ok(connection
+        .query_row("SELECT data FROM moderation WHERE id = 0", [], |row| row.get::<_, String>(0))
+        .optional()?
+        .map(|data| serde_json::from_str(&data))
+        .transpose()?
+        .unwrap_or_default())
+}
+
+fn save_moderation(connection: &Connection, moderation: &Moderation) -> Result<()> {
+    connection.execute(
+        "INSERT INTO moderation (id, data) VALUES (0, ?1)
+         ON CONFLICT (id) DO UPDATE SET data = excluded.data",
+        params![serde_json::to_string(moderation)?],
+    )?;
+    Ok(())
+}
+
+/// Fixed-width UTC timestamps, which compare correctly as text.
+fn timestamp(at: chrono::DateTime<chrono::Utc>) -> String {
+    at.to_rfc3339_opts(chrono::SecondsFormat::Micros, true)
+}
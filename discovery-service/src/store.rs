@@ -0,0 +1,173 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use mycelium_chat_types::{ServerInfo, ServerQuery, ServerStatus};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::sync::Arc;
+use tracing::info;
+
+use crate::admin::Moderation;
+use crate::config::{DiscoveryConfig, PersistenceBackend};
+use crate::memory::{self, MemoryStore};
+use crate::postgres::PostgresStore;
+use crate::redis_store::RedisStore;
+use crate::sqlite::SqliteStore;
+
+/// A change to the moderation, see [`RegistryStore::update_moderation`].
+pub type ModerationUpdate<'a> = dyn FnMut(&mut Moderation) + Send + 'a;
+
+/// A change to a registered server, see [`RegistryStore::update_server`].
+/// It is given the moderation as of the change, and returns whether to save
+/// the server.
+pub type ServerUpdate<'a> = dyn FnMut(&mut ServerInfo, &Moderation) -> bool + Send + 'a;
+
+/// Where the registered servers and the admins' moderation are kept. The
+/// handlers only go through this, so backends can change without them.
+#[async_trait]
+pub trait RegistryStore: Send + Sync {
+    async fn get(&self, server_name: &str) -> Result<Option<ServerInfo>>;
+
+    /// The servers matching `query`; the default query matches them all.
+    async fn list(&self, query: &ServerQuery) -> Result<Vec<ServerInfo>>;
+
+    /// Add a server, or replace the one registered under its name.
+    async fn upsert(&self, server: &ServerInfo) -> Result<()>;
+
+    /// Change the server registered as `server_name` with `update` and
+    /// return it, or `None` if there is none, atomically against the other
+    /// instances sharing the store and changes to the moderation. `update`
+    /// may run more than once, on the latest copy each time.
+    async fn update_server(
+        &self,
+        server_name: &str,
+        update: &mut ServerUpdate<'_>,
+    ) -> Result<Option<ServerInfo>>;
+
+    async fn remove(&self, server_names: &[String]) -> Result<()>;
+
+    async fn stats(&self) -> Result<RegistryStats>;
+
+    async fn moderation(&self) -> Result<Moderation>;
+
+    /// Change the moderation with `update` and return it, atomically
+    /// against the other instances sharing the store. `update` may run more
+    /// than once, on the latest copy each time.
+    async fn update_moderation(
+        &self,
+        update: &mut ModerationUpdate<'_>,
+    ) -> Result<Moderation>;
+
+    /// Keep `challenge` until it is answered or expires, unless one sent to
+    /// the same address is still pending for the server. Returns whether it
+    /// was kept.
+    async fn add_challenge(&self, challenge: &PendingChallenge) -> Result<bool>;
+
+    /// Take the challenge pending for `server_name` at `address` if `nonce`
+    /// answers it, and return whether it did. A challenge can only be
+    /// answered once.
+    async fn take_challenge(&self, server_name: &str, address: &str, nonce: &str) -> Result<bool>;
+
+    /// Whether stale servers expire from the store by themselves, so the
+    /// cleanup task isn't needed.
+    fn expires_stale_servers(&self) -> bool {
+        false
+    }
+}
+
+/// An address challenge waiting for its answer. It is kept in the store, so
+/// the answer can reach any instance.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PendingChallenge {
+    pub server_name: String,
+    pub address: String,
+    pub nonce: String,
+    pub expires_at: chrono::DateTime<chrono::Utc>,
+}
+
+/// Totals over the registered servers, for `/stats` and the server limit.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct RegistryStats {
+    pub total_servers: u64,
+    pub online_servers: u64,
+    pub available_servers: u64,
+    pub total_capacity: u64,
+    pub total_users: u64,
+}
+
+impl RegistryStats {
+    pub fn of<'a>(servers: impl IntoIterator<Item = &'a ServerInfo>) -> Self {
+        let mut stats = Self::default();
+        for server in servers {
+            stats.total_servers += 1;
+            stats.online_servers += u64::from(server.status == ServerStatus::Online);
+            stats.available_servers += u64::from(server.capacity.available);
+            stats.total_capacity += u64::from(server.capacity.max_users);
+            stats.total_users += u64::from(server.capacity.current_users);
+        }
+        stats
+    }
+}
+
+/// The store `config` selects. Without persistence the registry is only
+/// kept in memory.
+pub async fn open(config: &DiscoveryConfig) -> Result<Arc<dyn RegistryStore>> {
+    let stale_threshold = chrono::Duration::minutes(config.cleanup.stale_threshold_minutes);
+    let config = &config.persistence;
+    let store: Arc<dyn RegistryStore> = match config.backend {
+        _ if !config.enabled => return Ok(MemoryStore::open(None, config.save_interval_seconds).await),
+        PersistenceBackend::Json => {
+            return Ok(MemoryStore::open(config.file_path.clone(), config.save_interval_seconds).await)
+        }
+        PersistenceBackend::Sqlite => Arc::new(SqliteStore::open(&config.database_path)?),
+        PersistenceBackend::Postgres => {
+            let Some(url) = &config.database_url else {
+                anyhow::bail!("persistence.database_url is required with the postgres backend");
+            };
+            Arc::new(PostgresStore::connect(url).await?)
+        }
+        PersistenceBackend::Redis => {
+            let Some(url) = &config.redis_url else {
+                anyhow::bail!("persistence.redis_url is required with the redis backend");
+            };
+            Arc::new(RedisStore::connect(url, stale_threshold).await?)
+        }
+    };
+    if let Some(path) = config.file_path.as_deref().filter(|path| path.exists()) {
+        migrate_snapshot(store.as_ref(), path).await?;
+    }
+    Ok(store)
+}
+
+/// Move a JSON snapshot into a store that has nothing yet, renaming the
+/// file so it is only imported once.
+async fn migrate_snapshot(store: &dyn RegistryStore, path: &Path) -> Result<()> {
+    if store.stats().await?.total_servers > 0 || store.moderation().await? != Moderation::default() {
+        return Ok(());
+    }
+    let (servers, moderation) = memory::read_snapshot(path).await?;
+    for server in servers.values() {
+        store.upsert(server).await?;
+    }
+    store.update_moderation(&mut |current| *current = moderation.clone()).await?;
+    let mut migrated = path.as_os_str().to_owned();
+    migrated.push(".migrated");
+    tokio::fs::rename(path, &migrated).await?;
+    info!("Migrated {} servers from {} into the database", servers.len(), path.display());
+    Ok(())
+}
+
+/// How long after it was last seen `server` is stale: its heartbeat TTL if
+/// it announced one, else the configured threshold.
+pub fn stale_after(server: &ServerInfo, stale_threshold: chrono::Duration) -> chrono::Duration {
+    server
+        .heartbeat_ttl_seconds
+        .map_or(stale_threshold, |ttl| chrono::Duration::seconds(ttl as i64))
+}
+
+/// Statuses as they are serialized, so the column reads like the API.
+pub fn status_name(status: ServerStatus) -> Result<String> {
+    match serde_json::to_value(status)? {
+        serde_json::Value::String(name) => Ok(name),
+        other => Err(anyhow::anyhow!("Unexpected status {}", other)),
+    }
+}
@@ -0,0 +1,88 @@
+use anyhow::Result;
+use base64::Engine;
+use mycelium_chat_types::{AddressChallenge, ADDRESS_CHALLENGE_MESSAGE_TYPE, DISCOVERY_TOPIC};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::config::VerificationConfig;
+use crate::store::{PendingChallenge, RegistryStore};
+
+/// Sends address challenges through the local mycelium node and keeps them
+/// in the registry's store until they are answered or expire, so the answer
+/// can reach any instance sharing it.
+pub struct AddressVerifier {
+    http: reqwest::Client,
+    api_url: String,
+    timeout: Duration,
+    store: Arc<dyn RegistryStore>,
+}
+
+impl AddressVerifier {
+    pub fn new(config: &VerificationConfig, store: Arc<dyn RegistryStore>) -> Result<Self> {
+        let http = reqwest::Client::builder()
+            .connect_timeout(Duration::from_secs(config.connect_timeout_seconds))
+            .timeout(Duration::from_secs(config.request_timeout_seconds))
+            .build()?;
+        Ok(Self {
+            http,
+            api_url: config.mycelium_api_url.trim_end_matches('/').to_string(),
+            timeout: Duration::from_secs(config.challenge_timeout_seconds),
+            store,
+        })
+    }
+
+    /// Challenge `server_name` at `address`, unless a challenge sent there is
+    /// still waiting for its answer.
+    pub async fn challenge(&self, server_name: &str, address: &str) -> Result<()> {
+        let pending = PendingChallenge {
+            server_name: server_name.to_string(),
+            address: address.to_string(),
+            nonce: uuid::Uuid::new_v4().simple().to_string(),
+            expires_at: chrono::Utc::now() + chrono::Duration::from_std(self.timeout)?,
+        };
+        if !self.store.add_challenge(&pending).await? {
+            return Ok(());
+        }
+
+        let challenge = AddressChallenge {
+            message_type: ADDRESS_CHALLENGE_MESSAGE_TYPE.to_string(),
+            server_name: server_name.to_string(),
+            nonce: pending.nonce.clone(),
+        };
+        if let Err(e) = self.send(address, &serde_json::to_vec(&challenge)?).await {
+            self.store.take_challenge(server_name, address, &pending.nonce).await?;
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Whether `nonce` answers the challenge sent to `server_name` at
+    /// `address`. A challenge can only be answered once.
+    pub async fn answer(&self, server_name: &str, address: &str, nonce: &str) -> Result<bool> {
+        self.store.take_challenge(server_name, address, nonce).await
+    }
+
+    async fn send(&self, address: &str, payload: &[u8]) -> Result<()> {
+        let engine = base64::engine::general_purpose::STANDARD;
+        // Mycelium routes to an overlay IP or a node's public key
+        let destination = if address.parse::<std::net::IpAddr>().is_ok() {
+            serde_json::json!({ "ip": address })
+        } else {
+            serde_json::json!({ "pk": address })
+        };
+        let response = self
+            .http
+            .post(format!("{}/api/v1/messages", self.api_url))
+            .json(&serde_json::json!({
+                "dst": destination,
+                "topic": engine.encode(DISCOVERY_TOPIC),
+                "payload": engine.encode(payload),
+            }))
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            return Err(anyhow::anyhow!("Mycelium returned {}", response.status()));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,128 @@
+use anyhow::Result;
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Json, Response},
+    routing::{get, post},
+    Router,
+};
+use clap::Parser;
+use serde::Deserialize;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinSet;
+use tracing::{info, warn, Level};
+
+mod network;
+
+use network::{Destination, Network, Node};
+
+/// Longest a read may wait for a message, as in mycelium.
+const MAX_TIMEOUT_SECONDS: u64 = 60;
+
+#[derive(Parser)]
+#[command(name = "mock-mycelium")]
+#[command(about = "Mycelium nodes simulated in one process, for running bridges locally")]
+struct Cli {
+    /// Address to serve a node's API on. Repeat for more nodes, which can
+    /// all reach each other.
+    #[arg(short, long = "listen", default_value = "127.0.0.1:8989")]
+    listen: Vec<SocketAddr>,
+}
+
+#[derive(Clone)]
+struct AppState {
+    network: Arc<Network>,
+    node: Arc<Node>,
+}
+
+#[derive(Deserialize)]
+struct SendRequest {
+    dst: Option<Destination>,
+    topic: String,
+    payload: String,
+}
+
+#[derive(Deserialize)]
+struct PopQuery {
+    #[serde(default)]
+    peek: bool,
+    #[serde(default)]
+    timeout: u64,
+    topic: Option<String>,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    tracing_subscriber::fmt().with_max_level(Level::INFO).init();
+
+    let cli = Cli::parse();
+    let network = Arc::new(Network::new(&cli.listen));
+
+    let mut servers = JoinSet::new();
+    for (index, listen) in cli.listen.iter().enumerate() {
+        let node = network.node(index);
+        info!("Node {} ({}) listening on {}", node.public_key, node.ip, listen);
+        let state = AppState {
+            network: network.clone(),
+            node,
+        };
+        let app = Router::new()
+            .route("/api/v1/admin", get(node_info))
+            .route("/api/v1/messages", post(send_message).get(pop_message))
+            .route("/api/v1/messages/reply/:id", post(reply))
+            .with_state(state);
+        let listener = tokio::net::TcpListener::bind(listen).await?;
+        servers.spawn(async move { axum::serve(listener, app).await });
+    }
+
+    while let Some(served) = servers.join_next().await {
+        served??;
+    }
+    Ok(())
+}
+
+async fn node_info(State(state): State<AppState>) -> Json<serde_json::Value> {
+    Json(serde_json::json!({
+        "version": "mock",
+        "nodeSubnet": state.node.subnet,
+        "nodePubkey": state.node.public_key,
+        "startedAt": state.node.started_at,
+    }))
+}
+
+/// Like mycelium, accepts messages for unknown nodes, which are then lost.
+async fn send_message(State(state): State<AppState>, Json(request): Json<SendRequest>) -> Response {
+    let Some(destination) = request.dst else {
+        return StatusCode::BAD_REQUEST.into_response();
+    };
+    let id = match state.network.find(&destination) {
+        Some(target) => state.network.deliver(&state.node, &target, request.topic, request.payload),
+        None => {
+            warn!("Dropping message for unknown node {:?}", destination);
+            uuid::Uuid::new_v4().simple().to_string()
+        }
+    };
+    (StatusCode::CREATED, Json(serde_json::json!({ "id": id }))).into_response()
+}
+
+async fn pop_message(State(state): State<AppState>, Query(query): Query<PopQuery>) -> Response {
+    let timeout = Duration::from_secs(query.timeout.min(MAX_TIMEOUT_SECONDS));
+    match state.node.pop(query.topic.as_deref(), query.peek, timeout).await {
+        Some(message) => Json(message).into_response(),
+        None => StatusCode::NO_CONTENT.into_response(),
+    }
+}
+
+async fn reply(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Json(request): Json<SendRequest>,
+) -> StatusCode {
+    let Some(sender) = state.network.sender(&id) else {
+        return StatusCode::NOT_FOUND;
+    };
+    state.network.deliver(&state.node, &sender, request.topic, request.payload);
+    StatusCode::NO_CONTENT
+}
@@ -0,0 +1,172 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::Notify;
+
+/// Messages remembered for replies. Older ones can no longer be replied to.
+const MAX_REPLYABLE: usize = 10_000;
+
+/// A received message, as mycelium hands it out. Topic and payload stay
+/// base64 encoded as they were sent.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Message {
+    pub id: String,
+    pub src_ip: String,
+    pub src_pk: String,
+    pub dst_ip: String,
+    pub dst_pk: String,
+    pub topic: String,
+    pub payload: String,
+}
+
+/// Where a message is sent: a node's overlay IP or its public key.
+#[derive(Debug, Deserialize)]
+pub struct Destination {
+    pub ip: Option<String>,
+    pub pk: Option<String>,
+}
+
+/// One simulated mycelium node, served on its own listen address.
+pub struct Node {
+    pub public_key: String,
+    pub ip: String,
+    pub subnet: String,
+    /// When this process started the node, in Unix milliseconds, so bridges
+    /// can tell it restarted.
+    pub started_at: String,
+    inbox: Mutex<VecDeque<Message>>,
+    arrived: Notify,
+}
+
+impl Node {
+    /// The node's identity is derived from its listen address, so it stays
+    /// the same across restarts and bridge configs pointing at it stay valid.
+    fn new(index: usize, listen: &SocketAddr) -> Self {
+        let public_key: String = Sha256::digest(listen.to_string().as_bytes())
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        Self {
+            public_key,
+            ip: format!("400:0:0:{:x}::1", index + 1),
+            subnet: format!("400:0:0:{:x}::/64", index + 1),
+            started_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis()
+                .to_string(),
+            inbox: Mutex::new(VecDeque::new()),
+            arrived: Notify::new(),
+        }
+    }
+
+    fn push(&self, message: Message) {
+        self.inbox.lock().unwrap().push_back(message);
+        self.arrived.notify_waiters();
+    }
+
+    fn take(&self, topic: Option<&str>, peek: bool) -> Option<Message> {
+        let mut inbox = self.inbox.lock().unwrap();
+        let position = inbox
+            .iter()
+            .position(|message| topic.is_none_or(|topic| message.topic == topic))?;
+        match peek {
+            true => inbox.get(position).cloned(),
+            false => inbox.remove(position),
+        }
+    }
+
+    /// The oldest message on `topic` (base64), or on any topic without one,
+    /// waiting up to `timeout` for one to arrive.
+    pub async fn pop(&self, topic: Option<&str>, peek: bool, timeout: Duration) -> Option<Message> {
+        let deadline = tokio::time::Instant::now() + timeout;
+        loop {
+            // Registered before looking, so a message arriving in between
+            // still wakes this read
+            let arrived = self.arrived.notified();
+            tokio::pin!(arrived);
+            arrived.as_mut().enable();
+
+            if let Some(message) = self.take(topic, peek) {
+                return Some(message);
+            }
+            if tokio::time::timeout_at(deadline, arrived).await.is_err() {
+                return None;
+            }
+        }
+    }
+}
+
+/// The nodes of the mock overlay. Every node reaches every other one.
+pub struct Network {
+    nodes: Vec<Arc<Node>>,
+    /// Sender of each recently delivered message, so it can be replied to.
+    senders: Mutex<Senders>,
+}
+
+#[derive(Default)]
+struct Senders {
+    by_id: HashMap<String, Arc<Node>>,
+    order: VecDeque<String>,
+}
+
+impl Network {
+    pub fn new(listen: &[SocketAddr]) -> Self {
+        Self {
+            nodes: listen
+                .iter()
+                .enumerate()
+                .map(|(index, listen)| Arc::new(Node::new(index, listen)))
+                .collect(),
+            senders: Mutex::new(Senders::default()),
+        }
+    }
+
+    pub fn node(&self, index: usize) -> Arc<Node> {
+        self.nodes[index].clone()
+    }
+
+    pub fn find(&self, destination: &Destination) -> Option<Arc<Node>> {
+        self.nodes
+            .iter()
+            .find(|node| {
+                destination.ip.as_deref() == Some(node.ip.as_str())
+                    || destination.pk.as_deref() == Some(node.public_key.as_str())
+            })
+            .cloned()
+    }
+
+    /// Queue a message for `destination`. Returns its ID.
+    pub fn deliver(&self, source: &Arc<Node>, destination: &Node, topic: String, payload: String) -> String {
+        let id = uuid::Uuid::new_v4().simple().to_string();
+        {
+            let mut senders = self.senders.lock().unwrap();
+            if senders.order.len() >= MAX_REPLYABLE {
+                if let Some(oldest) = senders.order.pop_front() {
+                    senders.by_id.remove(&oldest);
+                }
+            }
+            senders.order.push_back(id.clone());
+            senders.by_id.insert(id.clone(), source.clone());
+        }
+        destination.push(Message {
+            id: id.clone(),
+            src_ip: source.ip.clone(),
+            src_pk: source.public_key.clone(),
+            dst_ip: destination.ip.clone(),
+            dst_pk: destination.public_key.clone(),
+            topic,
+            payload,
+        });
+        id
+    }
+
+    /// The node that sent message `id`.
+    pub fn sender(&self, id: &str) -> Option<Arc<Node>> {
+        self.senders.lock().unwrap().by_id.get(id).cloned()
+    }
+}
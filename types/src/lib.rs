@@ -0,0 +1,281 @@
+//! Types shared by the bridge and the discovery service: the server
+//! directory and the messages carried on the discovery topic.
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+/// Mycelium topic carrying server announcements, key revocations and
+/// address challenges.
+pub const DISCOVERY_TOPIC: &str = "matrix.discovery";
+
+/// Message type carried by [`KeyRevocation`] on the discovery topic.
+pub const KEY_REVOCATION_MESSAGE_TYPE: &str = "key_revocation";
+
+/// Message type carried by [`AddressChallenge`] on the discovery topic.
+pub const ADDRESS_CHALLENGE_MESSAGE_TYPE: &str = "address_challenge";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ServerCapacity {
+    pub max_users: u32,
+    pub current_users: u32,
+    pub available: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub monthly_active_users: Option<u32>,
+}
+
+/// Serialized in lowercase, as the discovery service always has. The
+/// capitalized names older bridges wrote are accepted too.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ServerStatus {
+    #[serde(alias = "Online")]
+    Online,
+    #[serde(alias = "Offline")]
+    Offline,
+    #[serde(alias = "Unknown")]
+    Unknown,
+    /// The server announced with a key that has since been revoked.
+    #[serde(alias = "Untrusted")]
+    Untrusted,
+}
+
+impl ServerStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ServerStatus::Online => "online",
+            ServerStatus::Offline => "offline",
+            ServerStatus::Unknown => "unknown",
+            ServerStatus::Untrusted => "untrusted",
+        }
+    }
+}
+
+impl fmt::Display for ServerStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for ServerStatus {
+    type Err = String;
+
+    fn from_str(status: &str) -> Result<Self, Self::Err> {
+        match status.to_ascii_lowercase().as_str() {
+            "online" => Ok(ServerStatus::Online),
+            "offline" => Ok(ServerStatus::Offline),
+            "unknown" => Ok(ServerStatus::Unknown),
+            "untrusted" => Ok(ServerStatus::Untrusted),
+            _ => Err(format!("Unknown server status: {}", status)),
+        }
+    }
+}
+
+/// A server directory entry.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ServerInfo {
+    pub server_name: String,
+    pub mycelium_address: String,
+    pub public_key: String,
+    pub capabilities: Vec<String>,
+    pub capacity: ServerCapacity,
+    pub last_seen: DateTime<Utc>,
+    pub status: ServerStatus,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<serde_json::Value>,
+    /// Whether the server has proven to the discovery service that it owns
+    /// `mycelium_address`, by answering an [`AddressChallenge`].
+    #[serde(default)]
+    pub verified: bool,
+    /// How long the discovery service considers the server alive after its
+    /// last heartbeat. Servers that only register get the service's default.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub heartbeat_ttl_seconds: Option<u64>,
+}
+
+impl ServerInfo {
+    pub fn supports(&self, capability: &str) -> bool {
+        self.capabilities.iter().any(|c| c == capability)
+    }
+}
+
+/// Filters taken by the `/servers` and `/servers/select` endpoints.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ServerQuery {
+    pub available_only: Option<bool>,
+    pub capability: Option<String>,
+    pub status: Option<ServerStatus>,
+    pub verified_only: Option<bool>,
+}
+
+impl ServerQuery {
+    pub fn matches(&self, server: &ServerInfo) -> bool {
+        if self.available_only.unwrap_or(false) && !server.capacity.available {
+            return false;
+        }
+        if self.status.is_some_and(|status| status != server.status) {
+            return false;
+        }
+        if self.verified_only.unwrap_or(false) && !server.verified {
+            return false;
+        }
+        self.capability.as_ref().is_none_or(|capability| server.supports(capability))
+    }
+
+    /// The least loaded online server with room for users that matches the
+    /// query.
+    pub fn select<'a>(&self, servers: impl IntoIterator<Item = &'a ServerInfo>) -> Option<&'a ServerInfo> {
+        servers
+            .into_iter()
+            .filter(|server| server.capacity.available && server.status == ServerStatus::Online)
+            .filter(|server| self.matches(server))
+            .min_by_key(|server| server.capacity.current_users)
+    }
+}
+
+/// Signed announcement a bridge periodically broadcasts about itself.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ServerAnnouncement {
+    pub server_name: String,
+    pub mycelium_address: String,
+    pub public_key: String,
+    pub capabilities: Vec<String>,
+    pub capacity: ServerCapacity,
+    pub timestamp: String,
+    pub signature: String,
+    /// During a key rotation, the key being rotated away from.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub previous_key: Option<String>,
+    /// Signature by `previous_key` over the same bytes as `signature`, which
+    /// leave out both rotation fields. It vouches for the new key.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub previous_signature: Option<String>,
+}
+
+impl From<ServerAnnouncement> for ServerInfo {
+    /// The directory entry for a server that has just announced itself.
+    fn from(announcement: ServerAnnouncement) -> Self {
+        Self {
+            server_name: announcement.server_name,
+            mycelium_address: announcement.mycelium_address,
+            public_key: announcement.public_key,
+            capabilities: announcement.capabilities,
+            capacity: announcement.capacity,
+            last_seen: Utc::now(),
+            status: ServerStatus::Online,
+            metadata: None,
+            verified: false,
+            heartbeat_ttl_seconds: None,
+        }
+    }
+}
+
+/// Body of `POST /servers/register` on the discovery service. Bridges sign
+/// it with their key over the request with an empty `signature`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RegisterRequest {
+    pub server_name: String,
+    pub mycelium_address: String,
+    pub public_key: String,
+    pub capabilities: Vec<String>,
+    pub capacity: ServerCapacity,
+    pub metadata: Option<serde_json::Value>,
+    #[serde(default)]
+    pub timestamp: String,
+    #[serde(default)]
+    pub signature: String,
+}
+
+impl RegisterRequest {
+    /// The bytes the signature is made over.
+    pub fn signing_payload(&self) -> serde_json::Result<String> {
+        let mut unsigned = self.clone();
+        unsigned.signature = String::new();
+        serde_json::to_string(&unsigned)
+    }
+}
+
+/// Body of `POST /servers/:name/heartbeat`, which keeps a registration
+/// alive between full registrations and updates its capacity. Signed like a
+/// registration, with the key it was registered with.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Heartbeat {
+    pub server_name: String,
+    pub capacity: ServerCapacity,
+    /// How long to consider the server alive without another heartbeat.
+    pub ttl_seconds: u64,
+    pub timestamp: String,
+    #[serde(default)]
+    pub signature: String,
+}
+
+impl Heartbeat {
+    /// The bytes the signature is made over.
+    pub fn signing_payload(&self) -> serde_json::Result<String> {
+        let mut unsigned = self.clone();
+        unsigned.signature = String::new();
+        serde_json::to_string(&unsigned)
+    }
+}
+
+/// Body of `DELETE /servers/:name`, by which a server decommissioned by its
+/// operator leaves the discovery service. Signed like a registration, with
+/// the key it was registered with.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Deregistration {
+    pub server_name: String,
+    pub timestamp: String,
+    #[serde(default)]
+    pub signature: String,
+}
+
+impl Deregistration {
+    /// The bytes the signature is made over.
+    pub fn signing_payload(&self) -> serde_json::Result<String> {
+        let mut unsigned = self.clone();
+        unsigned.signature = String::new();
+        serde_json::to_string(&unsigned)
+    }
+}
+
+/// Sent by the discovery service over the overlay to the mycelium address a
+/// server registered with. Only the node at that address receives it, so
+/// answering with its nonce proves the registrant owns the address.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AddressChallenge {
+    pub message_type: String,
+    pub server_name: String,
+    pub nonce: String,
+}
+
+/// Body of `POST /servers/verify`: the nonce of an [`AddressChallenge`],
+/// signed with the registered key over the proof with an empty `signature`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AddressProof {
+    pub server_name: String,
+    pub nonce: String,
+    #[serde(default)]
+    pub signature: String,
+}
+
+impl AddressProof {
+    /// The bytes the signature is made over.
+    pub fn signing_payload(&self) -> serde_json::Result<String> {
+        let mut unsigned = self.clone();
+        unsigned.signature = String::new();
+        serde_json::to_string(&unsigned)
+    }
+}
+
+/// Broadcast by a bridge whose signing key has been compromised. It is signed
+/// with the revoked key itself, which proves the sender held that key.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct KeyRevocation {
+    pub message_type: String,
+    pub server_name: String,
+    pub revoked_key: String,
+    pub reason: Option<String>,
+    pub timestamp: String,
+    pub signature: String,
+}
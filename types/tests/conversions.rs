@@ -0,0 +1,154 @@
+use mycelium_chat_types::{RegisterRequest, ServerAnnouncement, ServerCapacity, ServerInfo, ServerStatus};
+use serde_json::json;
+
+fn capacity() -> ServerCapacity {
+    ServerCapacity {
+        max_users: 100,
+        current_users: 10,
+        available: true,
+        monthly_active_users: None,
+    }
+}
+
+#[test]
+fn status_serializes_lowercase() {
+    assert_eq!(serde_json::to_value(ServerStatus::Online).unwrap(), json!("online"));
+    assert_eq!(serde_json::to_value(ServerStatus::Untrusted).unwrap(), json!("untrusted"));
+}
+
+#[test]
+fn status_accepts_both_casings() {
+    for (raw, status) in [
+        ("online", ServerStatus::Online),
+        ("Online", ServerStatus::Online),
+        ("offline", ServerStatus::Offline),
+        ("Offline", ServerStatus::Offline),
+        ("unknown", ServerStatus::Unknown),
+        ("Unknown", ServerStatus::Unknown),
+        ("untrusted", ServerStatus::Untrusted),
+        ("Untrusted", ServerStatus::Untrusted),
+    ] {
+        assert_eq!(serde_json::from_value::<ServerStatus>(json!(raw)).unwrap(), status);
+        assert_eq!(raw.parse::<ServerStatus>().unwrap(), status);
+    }
+    assert!(serde_json::from_value::<ServerStatus>(json!("gone")).is_err());
+    assert!("gone".parse::<ServerStatus>().is_err());
+}
+
+#[test]
+fn status_display_round_trips() {
+    for status in [
+        ServerStatus::Online,
+        ServerStatus::Offline,
+        ServerStatus::Unknown,
+        ServerStatus::Untrusted,
+    ] {
+        assert_eq!(status.to_string().parse::<ServerStatus>().unwrap(), status);
+    }
+}
+
+#[test]
+fn reads_discovery_service_entry() {
+    // As written by the discovery service before the types were shared
+    let entry = json!({
+        "server_name": "example.org",
+        "mycelium_address": "400::1",
+        "public_key": "key",
+        "capabilities": ["federation"],
+        "capacity": {"max_users": 100, "current_users": 10, "available": true},
+        "last_seen": "2024-01-01T00:00:00Z",
+        "status": "online",
+        "metadata": null
+    });
+    let info: ServerInfo = serde_json::from_value(entry).unwrap();
+    assert_eq!(info.status, ServerStatus::Online);
+    assert_eq!(info.capacity, capacity());
+    assert_eq!(info.metadata, None);
+}
+
+#[test]
+fn reads_bridge_entry() {
+    // As written by the bridge before the types were shared
+    let entry = json!({
+        "server_name": "example.org",
+        "mycelium_address": "400::1",
+        "public_key": "key",
+        "capabilities": [],
+        "capacity": {
+            "max_users": 100,
+            "current_users": 10,
+            "available": true,
+            "monthly_active_users": 5
+        },
+        "last_seen": "2024-01-01T00:00:00Z",
+        "status": "Untrusted"
+    });
+    let info: ServerInfo = serde_json::from_value(entry).unwrap();
+    assert_eq!(info.status, ServerStatus::Untrusted);
+    assert_eq!(info.capacity.monthly_active_users, Some(5));
+}
+
+#[test]
+fn server_info_round_trips() {
+    let info = ServerInfo {
+        server_name: "example.org".to_string(),
+        mycelium_address: "400::1".to_string(),
+        public_key: "key".to_string(),
+        capabilities: vec!["federation".to_string()],
+        capacity: capacity(),
+        last_seen: "2024-01-01T00:00:00Z".parse().unwrap(),
+        status: ServerStatus::Offline,
+        metadata: Some(json!({"region": "eu"})),
+        verified: true,
+        heartbeat_ttl_seconds: Some(360),
+    };
+    let value = serde_json::to_value(&info).unwrap();
+    assert!(value["capacity"].get("monthly_active_users").is_none());
+    assert_eq!(serde_json::from_value::<ServerInfo>(value).unwrap(), info);
+}
+
+#[test]
+fn announcement_becomes_online_entry() {
+    let announcement = ServerAnnouncement {
+        server_name: "example.org".to_string(),
+        mycelium_address: "400::1".to_string(),
+        public_key: "key".to_string(),
+        capabilities: vec!["federation".to_string()],
+        capacity: capacity(),
+        timestamp: "2024-01-01T00:00:00Z".to_string(),
+        signature: "signature".to_string(),
+        previous_key: None,
+        previous_signature: None,
+    };
+    let info = ServerInfo::from(announcement.clone());
+    assert_eq!(info.server_name, announcement.server_name);
+    assert_eq!(info.mycelium_address, announcement.mycelium_address);
+    assert_eq!(info.public_key, announcement.public_key);
+    assert_eq!(info.capabilities, announcement.capabilities);
+    assert_eq!(info.capacity, announcement.capacity);
+    assert_eq!(info.status, ServerStatus::Online);
+    assert_eq!(info.metadata, None);
+}
+
+#[test]
+fn registration_signing_payload_leaves_out_the_signature() {
+    let registration = RegisterRequest {
+        server_name: "example.org".to_string(),
+        mycelium_address: "400::1".to_string(),
+        public_key: "key".to_string(),
+        capabilities: vec!["federation".to_string()],
+        capacity: capacity(),
+        metadata: Some(json!({ "version": "1.0.0", "location": "eu" })),
+        timestamp: "2024-01-01T00:00:00Z".to_string(),
+        signature: String::new(),
+    };
+    let signed = RegisterRequest {
+        signature: "signature".to_string(),
+        ..registration.clone()
+    };
+
+    let payload = signed.signing_payload().unwrap();
+    assert_eq!(payload, registration.signing_payload().unwrap());
+    assert_eq!(payload, serde_json::to_string(&registration).unwrap());
+    assert!(payload.ends_with(r#""signature":""}"#));
+}
@@ -0,0 +1,102 @@
+use mycelium_chat_types::{ServerCapacity, ServerInfo, ServerQuery, ServerStatus};
+
+fn server(name: &str, current_users: u32, available: bool, status: ServerStatus) -> ServerInfo {
+    ServerInfo {
+        server_name: name.to_string(),
+        mycelium_address: "400::1".to_string(),
+        public_key: "key".to_string(),
+        capabilities: vec!["federation".to_string()],
+        capacity: ServerCapacity {
+            max_users: 100,
+            current_users,
+            available,
+            monthly_active_users: None,
+        },
+        last_seen: chrono::Utc::now(),
+        status,
+        metadata: None,
+        verified: false,
+        heartbeat_ttl_seconds: None,
+    }
+}
+
+#[test]
+fn select_picks_least_loaded_online_server() {
+    let servers = [
+        server("busy", 50, true, ServerStatus::Online),
+        server("quiet", 5, true, ServerStatus::Online),
+        server("full", 0, false, ServerStatus::Online),
+        server("revoked", 1, true, ServerStatus::Untrusted),
+    ];
+    let selected = ServerQuery::default().select(&servers).unwrap();
+    assert_eq!(selected.server_name, "quiet");
+}
+
+#[test]
+fn capability_filter_applies() {
+    let mut media = server("media", 80, true, ServerStatus::Online);
+    media.capabilities.push("media".to_string());
+    let servers = [server("plain", 1, true, ServerStatus::Online), media];
+    let query = ServerQuery {
+        available_only: None,
+        capability: Some("media".to_string()),
+        status: None,
+        verified_only: None,
+    };
+    assert_eq!(query.select(&servers).unwrap().server_name, "media");
+    assert_eq!(servers.iter().filter(|server| query.matches(server)).count(), 1);
+
+    let query = ServerQuery {
+        available_only: None,
+        capability: Some("voip".to_string()),
+        status: None,
+        verified_only: None,
+    };
+    assert!(query.select(&servers).is_none());
+}
+
+#[test]
+fn available_only_hides_full_servers() {
+    let servers = [
+        server("open", 1, true, ServerStatus::Online),
+        server("full", 100, false, ServerStatus::Online),
+    ];
+    assert_eq!(servers.iter().filter(|server| ServerQuery::default().matches(server)).count(), 2);
+    let query = ServerQuery {
+        available_only: Some(true),
+        capability: None,
+        status: None,
+        verified_only: None,
+    };
+    assert_eq!(servers.iter().filter(|server| query.matches(server)).count(), 1);
+}
+
+#[test]
+fn status_filter_applies() {
+    let servers = [
+        server("up", 1, true, ServerStatus::Online),
+        server("down", 1, true, ServerStatus::Offline),
+    ];
+    let query = ServerQuery {
+        available_only: None,
+        capability: None,
+        status: Some(ServerStatus::Offline),
+        verified_only: None,
+    };
+    let matching: Vec<&ServerInfo> = servers.iter().filter(|server| query.matches(server)).collect();
+    assert_eq!(matching.len(), 1);
+    assert_eq!(matching[0].server_name, "down");
+}
+
+#[test]
+fn verified_only_hides_unproven_addresses() {
+    let mut verified = server("verified", 50, true, ServerStatus::Online);
+    verified.verified = true;
+    let servers = [server("unverified", 1, true, ServerStatus::Online), verified];
+    let query = ServerQuery {
+        verified_only: Some(true),
+        ..ServerQuery::default()
+    };
+    assert_eq!(query.select(&servers).unwrap().server_name, "verified");
+    assert_eq!(ServerQuery::default().select(&servers).unwrap().server_name, "unverified");
+}